@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use crate::core_time::get_cached_time_ms;
+
+// ACL LOG 最多保留多少条 和 redis 的 acllog-max-len 默认值一样
+const ACL_LOG_MAX_LEN: usize = 128;
+// 同一个原因、同一个对象、同一个用户 60 秒内的拒绝合并成一条 只增加计数
+const ACL_LOG_GROUPING_MS: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+pub struct AclLogEntry {
+    pub entry_id: u64,
+    pub count: u64,
+    // command / key / channel / auth
+    pub reason: &'static str,
+    // toplevel / lua
+    pub context: &'static str,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+
+#[derive(Default)]
+pub struct AclLog {
+    // 新的在前面
+    entries: VecDeque<AclLogEntry>,
+    next_id: u64,
+}
+
+impl AclLog {
+    pub fn add(
+        &mut self,
+        reason: &'static str,
+        context: &'static str,
+        object: &str,
+        username: &str,
+        client_info: String,
+    ) {
+        let now = get_cached_time_ms();
+        if let Some(entry) = self.entries.iter_mut().find(|e| {
+            e.reason == reason
+                && e.context == context
+                && e.object == object
+                && e.username == username
+                && now.saturating_sub(e.updated_ms) < ACL_LOG_GROUPING_MS
+        }) {
+            entry.count += 1;
+            entry.updated_ms = now;
+            entry.client_info = client_info;
+            return;
+        }
+        self.entries.push_front(AclLogEntry {
+            entry_id: self.next_id,
+            count: 1,
+            reason,
+            context,
+            object: object.to_string(),
+            username: username.to_string(),
+            client_info,
+            created_ms: now,
+            updated_ms: now,
+        });
+        self.next_id += 1;
+        self.entries.truncate(ACL_LOG_MAX_LEN);
+    }
+
+    pub fn entries(&self, count: usize) -> Vec<AclLogEntry> {
+        self.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
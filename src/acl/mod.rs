@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    context::CONN_STATE,
+    core_keyspec::{CommandFlag, KeyAccess},
+    error::Command,
+};
+
+pub mod log;
+pub mod user;
+
+use log::{AclLog, AclLogEntry};
+use user::User;
+
+/*
+   ACL 权限体系
+   1.连接建立的时候如果 default 用户不需要密码 就直接以 default 登录 否则必须先 AUTH
+   2.每条命令在 Command::try_from 之后、真正执行之前检查：命令/子命令、key 的读写、频道
+   3.lua 里的 redis.call 也走同一个检查 用的是发起脚本的连接的用户
+   4.被拒绝的请求记进 ACL LOG
+   用户表是全局的 ACL SETUSER 改完之后已经登录的连接下一条命令立刻生效
+*/
+
+pub const NOAUTH: &str = "NOAUTH Authentication required.";
+pub const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+pub static ACL: Lazy<Acl> = Lazy::new(Acl::new);
+
+pub struct Acl {
+    users: RwLock<HashMap<String, User>>,
+    log: Mutex<AclLog>,
+}
+
+impl Acl {
+    fn new() -> Self {
+        let mut users = HashMap::new();
+        users.insert("default".to_string(), User::new_default());
+        Acl {
+            users: RwLock::new(users),
+            log: Mutex::new(AclLog::default()),
+        }
+    }
+
+    /// 新连接默认登录的用户 default 需要密码的话就是未认证状态
+    pub fn default_login(&self) -> Option<String> {
+        let users = self.users.read().unwrap();
+        users
+            .get("default")
+            .filter(|user| user.enabled && user.nopass)
+            .map(|user| user.name.clone())
+    }
+
+    /// TLS 客户端证书登录：证书已经被 CA 校验过了 用户存在并且是开启的就不用再要密码
+    pub fn certificate_login(&self, name: &str) -> Option<String> {
+        let users = self.users.read().unwrap();
+        users
+            .get(name)
+            .filter(|user| user.enabled)
+            .map(|user| user.name.clone())
+    }
+
+    pub fn default_nopass(&self) -> bool {
+        self.users
+            .read()
+            .unwrap()
+            .get("default")
+            .is_some_and(|user| user.nopass)
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<(), String> {
+        let ok = self
+            .users
+            .read()
+            .unwrap()
+            .get(username)
+            .is_some_and(|user| user.enabled && user.check_password(password));
+        if ok {
+            return Ok(());
+        }
+        self.add_log("auth", "toplevel", "AUTH", username);
+        Err(WRONGPASS.into())
+    }
+
+    /// 执行前的权限检查 context 是 toplevel 或者 lua
+    pub fn check_command(&self, command: &Command, context: &'static str) -> Result<(), String> {
+        // AOF 恢复这种内部执行没有连接上下文 不检查
+        let Ok(username) = CONN_STATE.try_with(|state| state.user.borrow().clone()) else {
+            return Ok(());
+        };
+        let spec = command.spec();
+        if spec.is_some_and(|spec| spec.has_flag(CommandFlag::NoAuth)) {
+            return Ok(());
+        }
+        let Some(username) = username else {
+            return Err(NOAUTH.into());
+        };
+        let users = self.users.read().unwrap();
+        // 用户被删掉或者被关掉之后 已经登录的连接也不能再用了
+        let Some(user) = users.get(&username).filter(|user| user.enabled) else {
+            return Err(NOAUTH.into());
+        };
+        // 不在命令表里的命令 (还没实现的也算) 只有 allcommands 的用户能执行
+        let Some(spec) = spec else {
+            let name = command.name().to_lowercase();
+            if user.can_run(&name, None) {
+                return Ok(());
+            }
+            drop(users);
+            self.add_log("command", context, &name, &username);
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                username, name
+            ));
+        };
+
+        // CLIENT / ACL 这种容器命令可以按子命令授权
+        let subcommand = match spec.has_subcommands() {
+            true => command
+                .argv()
+                .get(1)
+                .map(|sub| String::from_utf8_lossy(sub).to_lowercase()),
+            false => None,
+        };
+        if !user.can_run(spec.name, subcommand.as_deref()) {
+            let object = match &subcommand {
+                Some(sub) => format!("{}|{}", spec.name, sub),
+                None => spec.name.to_string(),
+            };
+            drop(users);
+            self.add_log("command", context, &object, &username);
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                username, object
+            ));
+        }
+
+        for key in command.keys() {
+            let allowed = match key.access {
+                KeyAccess::Read => user.can_read_key(&key.key),
+                KeyAccess::Write => user.can_write_key(&key.key),
+            };
+            if !allowed {
+                drop(users);
+                self.add_log("key", context, &key.key, &username);
+                return Err("NOPERM No permissions to access a key".into());
+            }
+        }
+
+        for channel in command.channels() {
+            if !user.can_access_channel(&channel) {
+                drop(users);
+                self.add_log("channel", context, &channel, &username);
+                return Err("NOPERM No permissions to access a channel".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// ACL SETUSER 规则全部校验通过才生效 中间出错用户保持原样
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("ERR Usernames can't contain spaces or null characters".into());
+        }
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply_rule(rule)
+                .map_err(|e| format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn del_users(&self, names: &[String]) -> Result<i64, String> {
+        if names.iter().any(|name| name == "default") {
+            return Err("ERR The 'default' user cannot be removed".into());
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(names.iter().filter(|name| users.remove(*name).is_some()).count() as i64)
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<User> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.users.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// ACL LIST 的输出 也是 ACL 文件的内容
+    pub fn describe_users(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        let mut names: Vec<&String> = users.keys().collect();
+        names.sort();
+        names.into_iter().map(|name| users[name].describe()).collect()
+    }
+
+    fn add_log(&self, reason: &'static str, context: &'static str, object: &str, username: &str) {
+        let client_info = CONN_STATE
+            .try_with(|state| {
+                format!(
+                    "id={} addr={} name={} user={}",
+                    state.client_id,
+                    state.client_address.as_deref().unwrap_or(""),
+                    state.client_name.borrow().as_deref().unwrap_or(""),
+                    state.user.borrow().as_deref().unwrap_or("")
+                )
+            })
+            .unwrap_or_default();
+        self.log
+            .lock()
+            .unwrap()
+            .add(reason, context, object, username, client_info);
+    }
+
+    pub fn log_entries(&self, count: usize) -> Vec<AclLogEntry> {
+        self.log.lock().unwrap().entries(count)
+    }
+
+    pub fn reset_log(&self) {
+        self.log.lock().unwrap().reset();
+    }
+
+    /// 从 ACL 文件加载 整个文件都没问题才替换现有的用户表
+    pub fn load_file(&self, path: &str) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("ERR Error loading ACLs, opening file '{}': {}", path, e))?;
+        let mut users = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |msg: &str| format!("ERR {}:{}: {}", path, index + 1, msg);
+            let mut parts = line.split_whitespace();
+            if parts.next() != Some("user") {
+                return Err(err("should start with user keyword"));
+            }
+            let Some(name) = parts.next() else {
+                return Err(err("missing username"));
+            };
+            if users.contains_key(name) {
+                return Err(err(&format!("duplicate user '{}' found", name)));
+            }
+            let mut user = User::new(name);
+            for rule in parts {
+                user.apply_rule(rule)
+                    .map_err(|e| err(&format!("{}. Rule: '{}'", e, rule)))?;
+            }
+            users.insert(name.to_string(), user);
+        }
+        // 文件里没写 default 就用默认配置补上
+        users
+            .entry("default".to_string())
+            .or_insert_with(User::new_default);
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    /// 先写临时文件再 rename 写到一半挂掉也不会把原来的文件弄坏
+    pub fn save_file(&self, path: &str) -> Result<(), String> {
+        let mut content = self.describe_users().join("\n");
+        content.push('\n');
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| format!("ERR There was an error trying to save the ACLs: {}", e))
+    }
+}
+
+/// redis 的 glob 匹配规则 * ? [abc] [^a] [a-z] 以及 \ 转义
+/// 不递归 失配的时候只回到最近的一个 * 让它多吃一个字符 a*a*a*...b 这种也是线性乘模式长度
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 最近一个 * 后面的位置 和这个 * 吃到了 string 的哪里
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if p < pattern.len()
+            && let Some(next) = match_token(pattern, p, string[s])
+        {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    // string 用完了 pattern 剩下的只能是 *
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// pattern[p] 开头的一个单元 (? [...] \x 或者普通字符) 能不能匹配 c 能的话返回下一个单元的位置
+fn match_token(pattern: &[u8], mut p: usize, c: u8) -> Option<usize> {
+    let matched = match pattern[p] {
+        b'?' => true,
+        b'[' => {
+            p += 1;
+            let not = pattern.get(p) == Some(&b'^');
+            if not {
+                p += 1;
+            }
+            let mut matched = false;
+            while p < pattern.len() && pattern[p] != b']' {
+                if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    matched |= pattern[p] == c;
+                } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+                    let (lo, hi) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    p += 2;
+                } else {
+                    matched |= pattern[p] == c;
+                }
+                p += 1;
+            }
+            matched != not
+        }
+        b'\\' if p + 1 < pattern.len() => {
+            p += 1;
+            pattern[p] == c
+        }
+        literal => literal == c,
+    };
+    // 没有闭合的 [ 会一直吃到结尾
+    matched.then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:1000"));
+        assert!(!glob_match(b"user:*", b"order:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"*:*:end", b"a:b:c:end"));
+        assert!(!glob_match(b"*:*:end", b"a:b:c:en"));
+        assert!(glob_match(b"a*", b"a") && glob_match(b"**", b""));
+        assert!(!glob_match(b"a?", b"a") && !glob_match(b"", b"a"));
+        assert!(glob_match(b"h[ab", b"ha") && !glob_match(b"h[ab", b"hc"));
+        assert!(glob_match(b"a\\", b"a\\"));
+        // 以前是递归的 这种模式会指数级回溯
+        let string = vec![b'a'; 100];
+        assert!(!glob_match(b"a*a*a*a*a*a*a*a*a*a*a*a*a*a*b", &string));
+        assert!(glob_match(b"a*a*a*a*a*a*a*a*a*a*a*a*a*a*a", &string));
+    }
+
+    #[test]
+    fn test_user_rules() {
+        let mut user = User::new("alice");
+        user.apply_rules(&["on", ">secret", "%R~cache:*", "~app:*", "+@read", "+set", "+client|id"])
+            .unwrap();
+        assert!(user.check_password("secret"));
+        assert!(!user.check_password("other"));
+        assert!(user.can_read_key("cache:1") && !user.can_write_key("cache:1"));
+        assert!(user.can_write_key("app:1") && !user.can_read_key("other"));
+        assert!(user.can_run("get", None) && user.can_run("set", None));
+        assert!(!user.can_run("del", None));
+        assert!(user.can_run("client", Some("id")) && !user.can_run("client", Some("tracking")));
+
+        // describe 出来的规则重新 apply 一遍要得到同样的结果
+        let line = user.describe();
+        let mut parts = line.split_whitespace().skip(1);
+        let mut copy = User::new(parts.next().unwrap());
+        copy.apply_rules(&parts.collect::<Vec<_>>()).unwrap();
+        assert_eq!(copy.describe(), line);
+        assert!(copy.check_password("secret"));
+
+        assert!(user.apply_rule("+nosuchcommand").is_err());
+        assert!(user.apply_rule("%X~foo").is_err());
+    }
+
+    #[test]
+    fn test_check_command() {
+        use bytes::Bytes;
+
+        use crate::context::ConnectionState;
+        use crate::error::Frame;
+
+        let acl = Acl::new();
+        acl.set_user("reader", &["on".to_string(), "nopass".to_string(), "+@read".to_string(), "~*".to_string()])
+            .unwrap();
+        acl.set_user("admin", &["on".to_string(), "nopass".to_string(), "allcommands".to_string(), "~*".to_string()])
+            .unwrap();
+        let command = |args: &[&str]| {
+            let frames = args
+                .iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect();
+            Command::try_from(Frame::Array(frames)).unwrap()
+        };
+        let check = |user: &str, args: &[&str]| {
+            let state = ConnectionState::new(0, None, 1);
+            *state.user.borrow_mut() = Some(user.to_string());
+            CONN_STATE.sync_scope(state, || acl.check_command(&command(args), "toplevel"))
+        };
+        assert!(check("reader", &["GET", "a"]).is_ok());
+        assert!(check("reader", &["SET", "a", "1"]).is_err());
+        // 命令表里没有的命令 只有 allcommands 的用户能执行
+        assert!(check("reader", &["FLUSHALL"]).unwrap_err().starts_with("NOPERM"));
+        assert!(check("admin", &["FLUSHALL"]).is_ok());
+        assert_eq!(acl.log.lock().unwrap().entries(10).len(), 2);
+    }
+}
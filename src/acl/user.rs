@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    acl::glob_match,
+    core_keyspec::{ACL_CATEGORIES, COMMAND_TABLE, lookup_command},
+};
+
+/*
+   一个 ACL 用户 规则和 redis 的 ACL SETUSER 一样 按顺序一条条作用上去
+   1.on/off nopass >密码 <密码 #哈希 !哈希 resetpass
+   2.~pattern %R~pattern %W~pattern %RW~pattern allkeys resetkeys
+   3.&pattern allchannels resetchannels
+   4.+cmd -cmd +@category -@category +cmd|sub allcommands nocommands
+   5.reset
+   密码只存 sha256 的十六进制 明文不落地
+*/
+
+#[derive(Debug, Clone)]
+pub struct KeyPattern {
+    pub pattern: String,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    pub nopass: bool,
+    pub passwords: Vec<String>,
+    // +@all 会顺带放行以后新加的命令 这个标记只要有任何一条减法规则就失效
+    all_commands: bool,
+    allowed_commands: HashSet<&'static str>,
+    // client|id 这种只放行某个子命令
+    allowed_subcommands: HashSet<String>,
+    // 命令规则原样记一份 GETUSER / ACL LIST 的时候用
+    command_rules: Vec<String>,
+    pub keys: Vec<KeyPattern>,
+    pub channels: Vec<String>,
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // 长度都是 sha256 的 64 位十六进制 不算秘密
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl User {
+    /// 新建的用户什么权限都没有 并且是关闭状态
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            all_commands: false,
+            allowed_commands: HashSet::new(),
+            allowed_subcommands: HashSet::new(),
+            command_rules: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// 默认用户：不需要密码 什么都能干
+    pub fn new_default() -> Self {
+        let mut user = User::new("default");
+        user.apply_rules(&["on", "nopass", "~*", "&*", "+@all"]).unwrap();
+        user
+    }
+
+    pub fn apply_rules<S: AsRef<str>>(&mut self, rules: &[S]) -> Result<(), String> {
+        for rule in rules {
+            self.apply_rule(rule.as_ref())?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.apply_rule("~*")?,
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.apply_rule("&*")?,
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.apply_rule("+@all")?,
+            "nocommands" => self.apply_rule("-@all")?,
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "resetchannels", "off", "-@all"] {
+                    self.apply_rule(rule)?;
+                }
+            }
+            _ => self.apply_complex_rule(rule)?,
+        }
+        Ok(())
+    }
+
+    fn apply_complex_rule(&mut self, rule: &str) -> Result<(), String> {
+        if let Some(password) = rule.strip_prefix('>') {
+            self.add_password_hash(hash_password(password));
+        } else if let Some(password) = rule.strip_prefix('<') {
+            self.remove_password_hash(&hash_password(password))?;
+        } else if let Some(hash) = rule.strip_prefix('#') {
+            if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".into());
+            }
+            self.add_password_hash(hash.to_lowercase());
+        } else if let Some(hash) = rule.strip_prefix('!') {
+            self.remove_password_hash(&hash.to_lowercase())?;
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.add_key_pattern(pattern, true, true);
+        } else if let Some(rest) = rule.strip_prefix('%') {
+            let Some((perm, pattern)) = rest.split_once('~') else {
+                return Err("Syntax error".into());
+            };
+            let perm = perm.to_uppercase();
+            if perm.is_empty() || !perm.chars().all(|c| c == 'R' || c == 'W') {
+                return Err("Syntax error".into());
+            }
+            self.add_key_pattern(pattern, perm.contains('R'), perm.contains('W'));
+        } else if let Some(pattern) = rule.strip_prefix('&') {
+            if !self.channels.iter().any(|p| p == pattern) {
+                self.channels.push(pattern.to_string());
+            }
+        } else if let Some(command) = rule.strip_prefix('+') {
+            self.allow_command(&command.to_lowercase())?;
+        } else if let Some(command) = rule.strip_prefix('-') {
+            self.deny_command(&command.to_lowercase())?;
+        } else {
+            return Err("Syntax error".into());
+        }
+        Ok(())
+    }
+
+    fn add_password_hash(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password_hash(&mut self, hash: &str) -> Result<(), String> {
+        let before = self.passwords.len();
+        self.passwords.retain(|p| p != hash);
+        if self.passwords.len() == before {
+            return Err("The password you are trying to remove from the user does not exist".into());
+        }
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) {
+        match self.keys.iter_mut().find(|k| k.pattern == pattern) {
+            Some(existing) => {
+                existing.read |= read;
+                existing.write |= write;
+            }
+            None => self.keys.push(KeyPattern {
+                pattern: pattern.to_string(),
+                read,
+                write,
+            }),
+        }
+    }
+
+    fn allow_command(&mut self, command: &str) -> Result<(), String> {
+        if let Some(category) = command.strip_prefix('@') {
+            let names = category_commands(category)?;
+            if category == "all" {
+                self.all_commands = true;
+                self.command_rules.clear();
+            }
+            self.allowed_commands.extend(names);
+        } else if let Some((name, sub)) = command.split_once('|') {
+            let spec = lookup_command(name).ok_or("Unknown command or category name in ACL")?;
+            if sub.is_empty() || sub.contains('|') {
+                return Err("Syntax error".into());
+            }
+            if !self.allowed_commands.contains(spec.name) {
+                self.allowed_subcommands.insert(format!("{}|{}", spec.name, sub));
+            }
+        } else {
+            let spec = lookup_command(command).ok_or("Unknown command or category name in ACL")?;
+            self.allowed_commands.insert(spec.name);
+            let prefix = format!("{}|", spec.name);
+            self.allowed_subcommands.retain(|s| !s.starts_with(&prefix));
+        }
+        self.command_rules.push(format!("+{}", command));
+        Ok(())
+    }
+
+    fn deny_command(&mut self, command: &str) -> Result<(), String> {
+        if let Some(category) = command.strip_prefix('@') {
+            let names = category_commands(category)?;
+            if category == "all" {
+                // 相当于从头开始 描述的时候会自动补上 -@all
+                self.all_commands = false;
+                self.allowed_commands.clear();
+                self.allowed_subcommands.clear();
+                self.command_rules.clear();
+                return Ok(());
+            }
+            for name in names {
+                self.allowed_commands.remove(name);
+                let prefix = format!("{}|", name);
+                self.allowed_subcommands.retain(|s| !s.starts_with(&prefix));
+            }
+        } else if let Some((name, sub)) = command.split_once('|') {
+            let spec = lookup_command(name).ok_or("Unknown command or category name in ACL")?;
+            if self.allowed_commands.contains(spec.name) {
+                return Err("Removing a subcommand from a fully allowed command is not supported".into());
+            }
+            self.allowed_subcommands.remove(&format!("{}|{}", spec.name, sub));
+        } else {
+            let spec = lookup_command(command).ok_or("Unknown command or category name in ACL")?;
+            self.allowed_commands.remove(spec.name);
+            let prefix = format!("{}|", spec.name);
+            self.allowed_subcommands.retain(|s| !s.starts_with(&prefix));
+        }
+        self.all_commands = false;
+        self.command_rules.push(format!("-{}", command));
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+        // 每个都比完 比较本身也不提前退出 不让耗时泄露哪一位对上了
+        let hash = hash_password(password);
+        self.passwords
+            .iter()
+            .fold(false, |found, stored| found | constant_time_eq(stored.as_bytes(), hash.as_bytes()))
+    }
+
+    /// name 是命令表里的小写名字 不在命令表里的命令只有 +@all 的用户能执行
+    pub fn can_run(&self, name: &str, subcommand: Option<&str>) -> bool {
+        if self.all_commands || self.allowed_commands.contains(name) {
+            return true;
+        }
+        match subcommand {
+            Some(sub) => self
+                .allowed_subcommands
+                .contains(&format!("{}|{}", name, sub.to_lowercase())),
+            None => false,
+        }
+    }
+
+    pub fn can_read_key(&self, key: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|k| k.read && glob_match(k.pattern.as_bytes(), key.as_bytes()))
+    }
+
+    pub fn can_write_key(&self, key: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|k| k.write && glob_match(k.pattern.as_bytes(), key.as_bytes()))
+    }
+
+    pub fn can_access_channel(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|p| glob_match(p.as_bytes(), channel.as_bytes()))
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn describe_commands(&self) -> String {
+        if self.command_rules.is_empty() || !self.command_rules[0].starts_with("+@all") {
+            // 规则都是在 -@all 的基础上叠加的
+            let mut rules = vec!["-@all".to_string()];
+            rules.extend(self.command_rules.iter().cloned());
+            return rules.join(" ");
+        }
+        self.command_rules.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(|k| match (k.read, k.write) {
+                (true, true) => format!("~{}", k.pattern),
+                (true, false) => format!("%R~{}", k.pattern),
+                _ => format!("%W~{}", k.pattern),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        self.channels
+            .iter()
+            .map(|c| format!("&{}", c))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// ACL LIST 和 ACL 文件里的一行 重新 apply 一遍能得到同样的用户
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name)];
+        parts.extend(self.flags().into_iter().map(String::from));
+        parts.extend(self.passwords.iter().map(|p| format!("#{}", p)));
+        for part in [self.describe_keys(), self.describe_channels()] {
+            if !part.is_empty() {
+                parts.push(part);
+            }
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".into());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+}
+
+fn category_commands(category: &str) -> Result<Vec<&'static str>, String> {
+    let names: Vec<&'static str> = COMMAND_TABLE
+        .values()
+        .filter(|spec| spec.in_category(category))
+        .map(|spec| spec.name)
+        .collect();
+    if category != "all" && !ACL_CATEGORIES.contains(&category) {
+        return Err("Unknown command or category name in ACL".into());
+    }
+    Ok(names)
+}
@@ -1,9 +1,19 @@
+use std::sync::atomic::Ordering;
+
 use bytes::Bytes;
 
 use itoa::Buffer;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc::Sender, oneshot};
 
-use crate::{core_time::get_cached_time_ms, error::Command};
+use crate::{
+    config::{AppendFsync, CONFIG},
+    context::CONN_STATE,
+    core_aof::{AOF_OFFSET, AofMessage},
+    core_snapshot::SNAPSHOT,
+    core_keyspec::CommandFlag,
+    error::{Command, Frame},
+    types::{Element, Value, ValueEntry},
+};
 
 mod string;
 
@@ -20,29 +30,166 @@ pub trait CommandAofExchange {
 基于这个command 指令 实现对应方法
 模块是分开的 并不一定就是代表数据结构是分开的 都是针对command 这个命令的
 所以一个模块是功能性划分 结构是实体划分 承载结构
+命令表里标了 write 的命令默认 argv 原样写进 AOF 只有需要改写参数的 (比如相对过期时间) 才单独实现
 */
 impl Command {
     pub async fn exe_aof_command<'a>(&self, ctx: AofContent<'a>) {
         match self {
             Command::Set(set_command) => set_command.execute_aof(ctx).await,
-            // 把里面的变量都换成 _，表示“我不关心里面是啥”
-            Command::Get(_)
-            | Command::Ping(_)
-            | Command::Unimplement(_)
-            | Command::EvalCommand(_) => {
+            // EVAL 在 lua worker 里按提交的结果写 AOF 这里不管
+            Command::EvalCommand(_) => {}
+            command if command.spec().is_some_and(|spec| spec.has_flag(CommandFlag::Write)) => {
+                send_argv_aof(ctx, command.argv()).await
             }
+            _ => {}
         }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct AofContent<'a> {
-    pub aof_tx: &'a Sender<Vec<u8>>,
+    pub aof_tx: &'a Sender<AofMessage>,
     pub shutdown_tx: &'a tokio::sync::broadcast::Sender<()>,
+    // 执行的时候进入的快照纪元 (LockedShards::epoch) 写入任务按它切分
+    pub epoch: u64,
+}
+
+// 参数里没有相对时间的命令 argv 原样写进 AOF 就行
+pub async fn send_argv_aof(ctx: AofContent<'_>, argv: Vec<Bytes>) {
+    send_aof(ctx, argv_to_frame(argv).serialize()).await
+}
+
+/// 几条命令包在 MULTI/EXEC 里作为一条消息发出去 写文件的时候不会被别的命令插进来
+/// 恢复的时候也是整段执行 文件尾巴上只有半段的话整段丢掉
+pub async fn send_multi_aof(ctx: AofContent<'_>, commands: Vec<Vec<Bytes>>) {
+    if commands.is_empty() {
+        return;
+    }
+    let mut payload = argv_to_frame(vec![Bytes::from_static(b"MULTI")]).serialize();
+    for argv in commands {
+        payload.extend_from_slice(&argv_to_frame(argv).serialize());
+    }
+    payload.extend_from_slice(&argv_to_frame(vec![Bytes::from_static(b"EXEC")]).serialize());
+    send_aof(ctx, payload).await
 }
 
-pub fn exchange_absolute_time(expire_time: u64) -> Bytes {
-    parse_int_from_bytes(get_cached_time_ms() + expire_time)
+// 带上当前连接选中的库 写入任务发现库变了会先补一条 SELECT
+// appendfsync always 的时候把落盘回执挂到连接上 回复发出去之前要等它
+// 所有写入都从这里过 save 规则的改动次数也在这里记
+async fn send_aof(ctx: AofContent<'_>, payload: Vec<u8>) {
+    SNAPSHOT.changed();
+    let (db, master_link) = CONN_STATE
+        .try_with(|state| (state.selected_db.get(), state.master_link))
+        .unwrap_or((0, false));
+    let (ack, ack_rx) = match CONFIG.appendfsync {
+        AppendFsync::Always => {
+            let (ack, ack_rx) = oneshot::channel();
+            (Some(ack), Some(ack_rx))
+        }
+        _ => (None, None),
+    };
+    let message = AofMessage {
+        db,
+        payload,
+        ack,
+        replicate: !master_link,
+        offset: 0,
+        epoch: ctx.epoch,
+    };
+    let permit = match ctx.aof_tx.reserve().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("发送AOF消息失败: {}", e);
+            return;
+        }
+    };
+    // 记下这个连接最后一条写入的偏移量 WAITAOF 等它落盘
+    let offset = AOF_OFFSET.send(permit, message);
+    let _ = CONN_STATE.try_with(|state| state.aof_offset.fetch_max(offset, Ordering::SeqCst));
+    if let Some(ack_rx) = ack_rx {
+        let _ = CONN_STATE.try_with(|state| *state.pending_aof.lock().unwrap() = Some(ack_rx));
+    }
+}
+
+pub fn argv_to_frame(argv: Vec<Bytes>) -> Frame {
+    Frame::Array(argv.into_iter().map(Frame::Bulk).collect())
+}
+
+/// 把一个 key 的最终状态还原成能重放的命令 脚本提交的时候用
+/// 写的是结果而不是脚本本身 脚本里用了时间或者随机数 重放出来也是一样的
+pub fn entry_effect_argv(key: &str, entry: Option<&ValueEntry>) -> Vec<Vec<Bytes>> {
+    let key = Bytes::from(key.to_string());
+    let Some(entry) = entry else {
+        return vec![vec![Bytes::from_static(b"DEL"), key]];
+    };
+    // 集合类型先删再整个重建 不依赖重放前 key 里原来有什么
+    // 重建命令带不了过期时间 后面再补一条 PEXPIREAT
+    match &entry.data {
+        Value::Simple(_) => vec![entry_rebuild_argv(key, entry)],
+        _ => {
+            let mut commands = vec![
+                vec![Bytes::from_static(b"DEL"), key.clone()],
+                entry_rebuild_argv(key.clone(), entry),
+            ];
+            if let Some(expires_at) = entry.expires_at {
+                commands.push(vec![
+                    Bytes::from_static(b"PEXPIREAT"),
+                    key,
+                    parse_int_from_bytes(expires_at),
+                ]);
+            }
+            commands
+        }
+    }
+}
+
+/// 从空库重建一个 key 的一条命令 AOF 重写的时候每个 key 就写这一条
+pub fn entry_rebuild_argv(key: Bytes, entry: &ValueEntry) -> Vec<Bytes> {
+    let element = |element: &Element| match element {
+        Element::String(bytes) => bytes.clone(),
+        Element::Int(i) => Bytes::from(i.to_string()),
+    };
+    match &entry.data {
+        Value::Simple(value) => {
+            let mut argv = vec![Bytes::from_static(b"SET"), key, element(value)];
+            if let Some(expires_at) = entry.expires_at {
+                argv.push(Bytes::from_static(b"PXAT"));
+                argv.push(parse_int_from_bytes(expires_at));
+            }
+            // memcached 写进来的 flags 重写之后也要在
+            if entry.flags != 0 {
+                argv.push(Bytes::from_static(b"MCFLAGS"));
+                argv.push(parse_int_from_bytes(entry.flags as u64));
+            }
+            argv
+        }
+        Value::Hash(map) => {
+            let mut argv = vec![Bytes::from_static(b"HSET"), key];
+            for (field, value) in map {
+                argv.push(Bytes::from(field.clone()));
+                argv.push(element(value));
+            }
+            argv
+        }
+        Value::List(list) => {
+            let mut argv = vec![Bytes::from_static(b"RPUSH"), key];
+            argv.extend(list.iter().map(element));
+            argv
+        }
+        Value::Set(set) => {
+            let mut argv = vec![Bytes::from_static(b"SADD"), key];
+            argv.extend(set.iter().map(element));
+            argv
+        }
+        Value::ZSet(zset) => {
+            let mut argv = vec![Bytes::from_static(b"ZADD"), key];
+            for (member, score) in zset {
+                argv.push(Bytes::from(score.to_string()));
+                argv.push(element(member));
+            }
+            argv
+        }
+    }
 }
 
 //高效的int 转byte 方法
@@ -55,3 +202,56 @@ pub fn parse_int_from_bytes(i: u64) -> Bytes {
     // 3. 从结果切片创建 Bytes (这里有一次复制，但避免了堆分配)
     Bytes::copy_from_slice(printed_str.as_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_effect_argv() {
+        let argv = |commands: Vec<Vec<Bytes>>| -> Vec<Vec<String>> {
+            commands
+                .into_iter()
+                .map(|argv| {
+                    argv.iter()
+                        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(argv(entry_effect_argv("k", None)), vec![vec!["DEL", "k"]]);
+
+        let entry = ValueEntry::new(Value::Simple(Element::Int(7)), Some(1700000000000));
+        assert_eq!(
+            argv(entry_effect_argv("k", Some(&entry))),
+            vec![vec!["SET", "k", "7", "PXAT", "1700000000000"]]
+        );
+
+        // memcached 写进来的 flags 要跟着走
+        let entry = ValueEntry::new(Value::Simple(Element::Int(7)), None).with_flags(42);
+        assert_eq!(
+            argv(entry_effect_argv("k", Some(&entry))),
+            vec![vec!["SET", "k", "7", "MCFLAGS", "42"]]
+        );
+
+        let hash = [("f".to_string(), Element::String(Bytes::from_static(b"v")))]
+            .into_iter()
+            .collect();
+        let entry = ValueEntry::new(Value::Hash(hash), None);
+        assert_eq!(
+            argv(entry_effect_argv("h", Some(&entry))),
+            vec![vec!["DEL", "h"], vec!["HSET", "h", "f", "v"]]
+        );
+
+        // 带过期时间的 hash 重放出来也要过期
+        let entry = ValueEntry::new(entry.data.clone(), Some(1700000000000));
+        assert_eq!(
+            argv(entry_effect_argv("h", Some(&entry))),
+            vec![
+                vec!["DEL", "h"],
+                vec!["HSET", "h", "f", "v"],
+                vec!["PEXPIREAT", "h", "1700000000000"]
+            ]
+        );
+    }
+}
@@ -1,9 +1,9 @@
 use bytes::Bytes;
-use futures::SinkExt;
 
 use crate::{
-    aof_exchange::{AofContent, CommandAofExchange, exchange_absolute_time, parse_int_from_bytes},
-    error::{Frame, SetCommand},
+    aof_exchange::{AofContent, CommandAofExchange, parse_int_from_bytes, send_argv_aof},
+    command_execute::calculate_expiration_timestamp_ms,
+    error::{SetCommand, SetCondition},
 };
 
 impl CommandAofExchange for SetCommand {
@@ -12,36 +12,26 @@ impl CommandAofExchange for SetCommand {
         // 2. 将这个生命周期 'ctx 应用到 CommandContext 的引用上
         ctx: AofContent<'a>,
     ) {
-        let mut frame_vec = vec![crate::error::Frame::Bulk(Bytes::from("SET".to_string()))];
-        frame_vec.push(crate::error::Frame::Bulk(Bytes::from(self.key.to_string())));
-        frame_vec.push(crate::error::Frame::Bulk(Bytes::from(self.value.clone())));
+        let mut argv = vec![
+            Bytes::from_static(b"SET"),
+            Bytes::from(self.key.to_string()),
+            self.value.clone(),
+        ];
+        // 执行之前已经换成了 PXAT (pin_expiration) 这里按同一个时间写 重放的时候才不会把过期往后推
         if let Some(expire) = &self.expiration {
-            match expire {
-                crate::error::Expiration::EX(s) => {
-                    frame_vec.push(crate::error::Frame::Bulk(Bytes::from("EXAT".to_string())));
-                    let expire_bytes = exchange_absolute_time(s * 1000);
-                    frame_vec.push(crate::error::Frame::Bulk(expire_bytes));
-                }
-                crate::error::Expiration::PX(ms) => {
-                    frame_vec.push(crate::error::Frame::Bulk(Bytes::from("PXAT".to_string())));
-                    let expire_bytes = exchange_absolute_time(ms.clone());
-                    frame_vec.push(crate::error::Frame::Bulk(expire_bytes));
-                }
-                crate::error::Expiration::EXAT(s) => {
-                    frame_vec.push(crate::error::Frame::Bulk(Bytes::from("EXAT".to_string())));
-                    let expire_bytes = parse_int_from_bytes(s.clone());
-                    frame_vec.push(crate::error::Frame::Bulk(expire_bytes));
-                }
-                crate::error::Expiration::PXAT(ms) => {
-                    frame_vec.push(crate::error::Frame::Bulk(Bytes::from("PXAT".to_string())));
-                    let expire_bytes = parse_int_from_bytes(ms.clone());
-                    frame_vec.push(crate::error::Frame::Bulk(expire_bytes));
-                }
-            }
+            argv.push(Bytes::from_static(b"PXAT"));
+            argv.push(parse_int_from_bytes(calculate_expiration_timestamp_ms(expire)));
         }
-        if let Err(e) = ctx.aof_tx.send(Frame::Array(frame_vec).serialize()).await {
-            eprintln!("发送AOF消息失败: {}", e);
-        };
-        ()
+        if self.flags != 0 {
+            argv.push(Bytes::from_static(b"MCFLAGS"));
+            argv.push(parse_int_from_bytes(self.flags as u64));
+        }
+        // NX/XX 也要带上 不然条件不满足没写进去的 重放的时候反而写进去了
+        match self.condition {
+            Some(SetCondition::NX) => argv.push(Bytes::from_static(b"NX")),
+            Some(SetCondition::XX) => argv.push(Bytes::from_static(b"XX")),
+            None => {}
+        }
+        send_argv_aof(ctx, argv).await
     }
 }
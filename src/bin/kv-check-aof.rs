@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() {
+    std::process::exit(kv::check_aof(std::env::args().skip(1)).await)
+}
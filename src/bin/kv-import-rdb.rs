@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() {
+    std::process::exit(kv::import_rdb(std::env::args().skip(1)).await)
+}
@@ -0,0 +1,108 @@
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_string},
+    error::{AclCommand, AclSubCommand, Command, Frame, KvError},
+};
+
+impl CommandExchange for AclCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let sub_name = extract_bulk_string(itor.next())?.to_uppercase();
+        let rest = itor
+            .map(|frame| extract_bulk_string(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let wrong_args = || {
+            KvError::ProtocolError(format!(
+                "wrong number of arguments for 'acl|{}' command",
+                sub_name.to_lowercase()
+            ))
+        };
+        let sub = match sub_name.as_str() {
+            "SETUSER" => {
+                let mut rest = rest.into_iter();
+                let name = rest.next().ok_or_else(wrong_args)?;
+                AclSubCommand::SetUser {
+                    name,
+                    rules: rest.collect(),
+                }
+            }
+            "DELUSER" if !rest.is_empty() => AclSubCommand::DelUser(rest),
+            "GETUSER" if rest.len() == 1 => AclSubCommand::GetUser(rest[0].clone()),
+            "LIST" if rest.is_empty() => AclSubCommand::List,
+            "USERS" if rest.is_empty() => AclSubCommand::Users,
+            "WHOAMI" if rest.is_empty() => AclSubCommand::WhoAmI,
+            "CAT" if rest.len() <= 1 => AclSubCommand::Cat(rest.first().map(|c| c.to_lowercase())),
+            "LOG" if rest.len() <= 1 => match rest.first() {
+                None => AclSubCommand::Log {
+                    count: None,
+                    reset: false,
+                },
+                Some(arg) if arg.eq_ignore_ascii_case("RESET") => AclSubCommand::Log {
+                    count: None,
+                    reset: true,
+                },
+                Some(arg) => AclSubCommand::Log {
+                    count: Some(arg.parse::<usize>().map_err(|_| {
+                        KvError::ProtocolError("value is out of range, must be positive".into())
+                    })?),
+                    reset: false,
+                },
+            },
+            "SAVE" if rest.is_empty() => AclSubCommand::Save,
+            "LOAD" if rest.is_empty() => AclSubCommand::Load,
+            "DELUSER" | "GETUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT" | "LOG" | "SAVE"
+            | "LOAD" => return Err(wrong_args()),
+            _ => {
+                return Err(KvError::ProtocolError(format!(
+                    "unknown subcommand '{}'. Try ACL HELP.",
+                    sub_name
+                )));
+            }
+        };
+        Ok(Command::Acl(AclCommand { sub }))
+    }
+}
+
+impl CommandArgv for AclCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"ACL")];
+        let mut push = |s: &str| argv.push(Bytes::copy_from_slice(s.as_bytes()));
+        match &self.sub {
+            AclSubCommand::SetUser { name, rules } => {
+                push("SETUSER");
+                push(name);
+                rules.iter().for_each(|rule| push(rule));
+            }
+            AclSubCommand::DelUser(names) => {
+                push("DELUSER");
+                names.iter().for_each(|name| push(name));
+            }
+            AclSubCommand::GetUser(name) => {
+                push("GETUSER");
+                push(name);
+            }
+            AclSubCommand::List => push("LIST"),
+            AclSubCommand::Users => push("USERS"),
+            AclSubCommand::WhoAmI => push("WHOAMI"),
+            AclSubCommand::Cat(category) => {
+                push("CAT");
+                if let Some(category) = category {
+                    push(category);
+                }
+            }
+            AclSubCommand::Log { count, reset } => {
+                push("LOG");
+                if *reset {
+                    push("RESET");
+                } else if let Some(count) = count {
+                    push(&count.to_string());
+                }
+            }
+            AclSubCommand::Save => push("SAVE"),
+            AclSubCommand::Load => push("LOAD"),
+        }
+        argv
+    }
+}
@@ -0,0 +1,383 @@
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{
+        CommandArgv, CommandExchange, connection::validate_client_name, extract_bulk_string,
+    },
+    error::{
+        ClientCommand, ClientKillFilter, ClientReply, ClientSubCommand, Command, Frame, KvError,
+        TrackingMode, TrackingOptions,
+    },
+};
+
+impl CommandExchange for ClientCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let sub_name = extract_bulk_string(itor.next())?.to_uppercase();
+        let sub = match sub_name.as_str() {
+            "ID" => {
+                expect_no_more(&itor, "CLIENT ID")?;
+                ClientSubCommand::Id
+            }
+            "GETREDIR" => {
+                expect_no_more(&itor, "CLIENT GETREDIR")?;
+                ClientSubCommand::GetRedir
+            }
+            "TRACKINGINFO" => {
+                expect_no_more(&itor, "CLIENT TRACKINGINFO")?;
+                ClientSubCommand::TrackingInfo
+            }
+            "CACHING" => {
+                let flag = extract_bulk_string(itor.next())?.to_uppercase();
+                expect_no_more(&itor, "CLIENT CACHING")?;
+                match flag.as_str() {
+                    "YES" => ClientSubCommand::Caching(true),
+                    "NO" => ClientSubCommand::Caching(false),
+                    _ => return Err(KvError::ProtocolError("syntax error".into())),
+                }
+            }
+            "TRACKING" => ClientSubCommand::Tracking(parse_tracking(itor)?),
+            "LIST" => parse_list(itor)?,
+            "INFO" => {
+                expect_no_more(&itor, "CLIENT INFO")?;
+                ClientSubCommand::Info
+            }
+            "SETNAME" => {
+                let name = extract_bulk_string(itor.next())?;
+                expect_no_more(&itor, "CLIENT SETNAME")?;
+                validate_client_name(&name)?;
+                ClientSubCommand::SetName(name)
+            }
+            "GETNAME" => {
+                expect_no_more(&itor, "CLIENT GETNAME")?;
+                ClientSubCommand::GetName
+            }
+            "KILL" => ClientSubCommand::Kill(parse_kill(itor)?),
+            "PAUSE" => {
+                let timeout = extract_bulk_string(itor.next())?
+                    .parse::<u64>()
+                    .map_err(|_| KvError::ProtocolError("timeout is not an integer or out of range".into()))?;
+                let all = match itor.next() {
+                    None => true,
+                    Some(frame) => match extract_bulk_string(Some(frame))?.to_uppercase().as_str() {
+                        "ALL" => true,
+                        "WRITE" => false,
+                        _ => return Err(KvError::ProtocolError("syntax error".into())),
+                    },
+                };
+                expect_no_more(&itor, "CLIENT PAUSE")?;
+                ClientSubCommand::Pause { timeout, all }
+            }
+            "UNPAUSE" => {
+                expect_no_more(&itor, "CLIENT UNPAUSE")?;
+                ClientSubCommand::Unpause
+            }
+            "REPLY" => {
+                let mode = extract_bulk_string(itor.next())?.to_uppercase();
+                expect_no_more(&itor, "CLIENT REPLY")?;
+                match mode.as_str() {
+                    "ON" => ClientSubCommand::Reply(ClientReply::On),
+                    "OFF" => ClientSubCommand::Reply(ClientReply::Off),
+                    "SKIP" => ClientSubCommand::Reply(ClientReply::Skip),
+                    _ => return Err(KvError::ProtocolError("syntax error".into())),
+                }
+            }
+            "NO-EVICT" => {
+                let flag = extract_bulk_string(itor.next())?.to_uppercase();
+                expect_no_more(&itor, "CLIENT NO-EVICT")?;
+                match flag.as_str() {
+                    "ON" => ClientSubCommand::NoEvict(true),
+                    "OFF" => ClientSubCommand::NoEvict(false),
+                    _ => return Err(KvError::ProtocolError("syntax error".into())),
+                }
+            }
+            "SETINFO" => {
+                let attr = extract_bulk_string(itor.next())?.to_uppercase();
+                let value = extract_bulk_string(itor.next())?;
+                expect_no_more(&itor, "CLIENT SETINFO")?;
+                if attr != "LIB-NAME" && attr != "LIB-VER" {
+                    return Err(KvError::ProtocolError(format!(
+                        "Unrecognized option '{}'",
+                        attr
+                    )));
+                }
+                // 和连接名一样 不能带空格 不然 CLIENT LIST 的输出没法解析
+                if value.bytes().any(|c| !(b'!'..=b'~').contains(&c)) {
+                    return Err(KvError::ProtocolError(format!(
+                        "{} cannot contain spaces, newlines or special characters.",
+                        attr
+                    )));
+                }
+                ClientSubCommand::SetInfo { attr, value }
+            }
+            _ => {
+                return Err(KvError::ProtocolError(format!(
+                    "unknown subcommand '{}'. Try CLIENT HELP.",
+                    sub_name
+                )));
+            }
+        };
+        Ok(Command::Client(ClientCommand { sub }))
+    }
+}
+
+/// CLIENT LIST [TYPE normal|master|replica|pubsub] [ID id [id ...]]
+fn parse_list(mut itor: IntoIter<Frame>) -> Result<ClientSubCommand, KvError> {
+    let mut client_type = None;
+    let mut ids = Vec::new();
+    while let Some(frame) = itor.next() {
+        match extract_bulk_string(Some(frame))?.to_uppercase().as_str() {
+            "TYPE" => {
+                let name = extract_bulk_string(itor.next())?.to_lowercase();
+                if !matches!(name.as_str(), "normal" | "master" | "replica" | "pubsub") {
+                    return Err(KvError::ProtocolError(format!(
+                        "Unknown client type '{}'",
+                        name
+                    )));
+                }
+                client_type = Some(name);
+            }
+            "ID" => {
+                // ID 后面剩下的全是 id
+                for frame in itor.by_ref() {
+                    ids.push(parse_client_id(Some(frame))?);
+                }
+                if ids.is_empty() {
+                    return Err(KvError::ProtocolError("syntax error".into()));
+                }
+            }
+            _ => return Err(KvError::ProtocolError("syntax error".into())),
+        }
+    }
+    Ok(ClientSubCommand::List { client_type, ids })
+}
+
+/// CLIENT KILL addr 或者 CLIENT KILL <filter> <value> ...
+fn parse_kill(mut itor: IntoIter<Frame>) -> Result<ClientKillFilter, KvError> {
+    if itor.len() == 1 {
+        return Ok(ClientKillFilter {
+            legacy: true,
+            addr: Some(extract_bulk_string(itor.next())?),
+            ..Default::default()
+        });
+    }
+    if itor.len() == 0 || !itor.len().is_multiple_of(2) {
+        return Err(KvError::ProtocolError("syntax error".into()));
+    }
+    let mut filter = ClientKillFilter {
+        skipme: true,
+        ..Default::default()
+    };
+    while let Some(frame) = itor.next() {
+        let option = extract_bulk_string(Some(frame))?.to_uppercase();
+        match option.as_str() {
+            "ID" => filter.id = Some(parse_client_id(itor.next())?),
+            "ADDR" => filter.addr = Some(extract_bulk_string(itor.next())?),
+            "USER" => filter.user = Some(extract_bulk_string(itor.next())?),
+            "IDLE" => filter.idle = Some(parse_seconds(itor.next())?),
+            "MAXAGE" => filter.maxage = Some(parse_seconds(itor.next())?),
+            "SKIPME" => {
+                filter.skipme = match extract_bulk_string(itor.next())?.to_uppercase().as_str() {
+                    "YES" => true,
+                    "NO" => false,
+                    _ => return Err(KvError::ProtocolError("syntax error".into())),
+                }
+            }
+            _ => return Err(KvError::ProtocolError("syntax error".into())),
+        }
+    }
+    Ok(filter)
+}
+
+fn parse_client_id(frame: Option<Frame>) -> Result<u64, KvError> {
+    extract_bulk_string(frame)?
+        .parse::<u64>()
+        .map_err(|_| KvError::ProtocolError("Invalid client ID".into()))
+}
+
+fn parse_seconds(frame: Option<Frame>) -> Result<u64, KvError> {
+    extract_bulk_string(frame)?
+        .parse::<u64>()
+        .map_err(|_| KvError::ProtocolError("value is not an integer or out of range".into()))
+}
+
+/// CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX p ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]
+fn parse_tracking(mut itor: IntoIter<Frame>) -> Result<TrackingOptions, KvError> {
+    let enable = match extract_bulk_string(itor.next())?.to_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => return Err(KvError::ProtocolError("syntax error".into())),
+    };
+    let mut redirect = None;
+    let mut prefixes = Vec::new();
+    let mut bcast = false;
+    let mut optin = false;
+    let mut optout = false;
+    let mut noloop = false;
+    while let Some(frame) = itor.next() {
+        let option = extract_bulk_string(Some(frame))?.to_uppercase();
+        match option.as_str() {
+            "REDIRECT" => {
+                let id = extract_bulk_string(itor.next())?
+                    .parse::<u64>()
+                    .map_err(|_| KvError::ProtocolError("Invalid client ID".into()))?;
+                redirect = Some(id);
+            }
+            "PREFIX" => prefixes.push(extract_bulk_string(itor.next())?),
+            "BCAST" => bcast = true,
+            "OPTIN" => optin = true,
+            "OPTOUT" => optout = true,
+            "NOLOOP" => noloop = true,
+            _ => return Err(KvError::ProtocolError("syntax error".into())),
+        }
+    }
+    // 参数组合校验 和 redis 保持一致
+    if !prefixes.is_empty() && !bcast {
+        return Err(KvError::ProtocolError(
+            "PREFIX option requires BCAST mode to be enabled".into(),
+        ));
+    }
+    if optin && optout {
+        return Err(KvError::ProtocolError(
+            "You can't use both OPTIN and OPTOUT".into(),
+        ));
+    }
+    if bcast && (optin || optout) {
+        return Err(KvError::ProtocolError(
+            "OPTIN and OPTOUT are not compatible with BCAST".into(),
+        ));
+    }
+    let mode = if bcast {
+        TrackingMode::Bcast
+    } else if optin {
+        TrackingMode::OptIn
+    } else if optout {
+        TrackingMode::OptOut
+    } else {
+        TrackingMode::Default
+    };
+    Ok(TrackingOptions {
+        enable,
+        mode,
+        redirect,
+        prefixes,
+        noloop,
+    })
+}
+
+fn expect_no_more(itor: &IntoIter<Frame>, name: &str) -> Result<(), KvError> {
+    if itor.len() > 0 {
+        return Err(KvError::ProtocolError(format!(
+            "wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl CommandArgv for ClientCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"CLIENT")];
+        match &self.sub {
+            ClientSubCommand::Id => argv.push(Bytes::from_static(b"ID")),
+            ClientSubCommand::GetRedir => argv.push(Bytes::from_static(b"GETREDIR")),
+            ClientSubCommand::TrackingInfo => argv.push(Bytes::from_static(b"TRACKINGINFO")),
+            ClientSubCommand::Caching(yes) => {
+                argv.push(Bytes::from_static(b"CACHING"));
+                argv.push(Bytes::from_static(if *yes { b"YES" } else { b"NO" }));
+            }
+            ClientSubCommand::Tracking(options) => {
+                argv.push(Bytes::from_static(b"TRACKING"));
+                argv.push(Bytes::from_static(if options.enable { b"ON" } else { b"OFF" }));
+                if let Some(redirect) = options.redirect {
+                    argv.push(Bytes::from_static(b"REDIRECT"));
+                    argv.push(Bytes::from(redirect.to_string()));
+                }
+                for prefix in &options.prefixes {
+                    argv.push(Bytes::from_static(b"PREFIX"));
+                    argv.push(Bytes::from(prefix.clone()));
+                }
+                match options.mode {
+                    TrackingMode::Bcast => argv.push(Bytes::from_static(b"BCAST")),
+                    TrackingMode::OptIn => argv.push(Bytes::from_static(b"OPTIN")),
+                    TrackingMode::OptOut => argv.push(Bytes::from_static(b"OPTOUT")),
+                    TrackingMode::Default => {}
+                }
+                if options.noloop {
+                    argv.push(Bytes::from_static(b"NOLOOP"));
+                }
+            }
+            ClientSubCommand::List { client_type, ids } => {
+                argv.push(Bytes::from_static(b"LIST"));
+                if let Some(client_type) = client_type {
+                    argv.push(Bytes::from_static(b"TYPE"));
+                    argv.push(Bytes::from(client_type.clone()));
+                }
+                if !ids.is_empty() {
+                    argv.push(Bytes::from_static(b"ID"));
+                    argv.extend(ids.iter().map(|id| Bytes::from(id.to_string())));
+                }
+            }
+            ClientSubCommand::Info => argv.push(Bytes::from_static(b"INFO")),
+            ClientSubCommand::SetName(name) => {
+                argv.push(Bytes::from_static(b"SETNAME"));
+                argv.push(Bytes::from(name.clone()));
+            }
+            ClientSubCommand::GetName => argv.push(Bytes::from_static(b"GETNAME")),
+            ClientSubCommand::Kill(filter) => {
+                argv.push(Bytes::from_static(b"KILL"));
+                if filter.legacy {
+                    argv.push(Bytes::from(filter.addr.clone().unwrap_or_default()));
+                    return argv;
+                }
+                let mut push = |name: &'static [u8], value: String| {
+                    argv.push(Bytes::from_static(name));
+                    argv.push(Bytes::from(value));
+                };
+                if let Some(id) = filter.id {
+                    push(b"ID", id.to_string());
+                }
+                if let Some(addr) = &filter.addr {
+                    push(b"ADDR", addr.clone());
+                }
+                if let Some(user) = &filter.user {
+                    push(b"USER", user.clone());
+                }
+                if let Some(idle) = filter.idle {
+                    push(b"IDLE", idle.to_string());
+                }
+                if let Some(maxage) = filter.maxage {
+                    push(b"MAXAGE", maxage.to_string());
+                }
+                if !filter.skipme {
+                    push(b"SKIPME", "no".into());
+                }
+            }
+            ClientSubCommand::Pause { timeout, all } => {
+                argv.push(Bytes::from_static(b"PAUSE"));
+                argv.push(Bytes::from(timeout.to_string()));
+                argv.push(Bytes::from_static(if *all { b"ALL" } else { b"WRITE" }));
+            }
+            ClientSubCommand::Unpause => argv.push(Bytes::from_static(b"UNPAUSE")),
+            ClientSubCommand::Reply(mode) => {
+                argv.push(Bytes::from_static(b"REPLY"));
+                argv.push(Bytes::from_static(match mode {
+                    ClientReply::On => b"ON",
+                    ClientReply::Off => b"OFF",
+                    ClientReply::Skip => b"SKIP",
+                }));
+            }
+            ClientSubCommand::NoEvict(on) => {
+                argv.push(Bytes::from_static(b"NO-EVICT"));
+                argv.push(Bytes::from_static(if *on { b"ON" } else { b"OFF" }));
+            }
+            ClientSubCommand::SetInfo { attr, value } => {
+                argv.push(Bytes::from_static(b"SETINFO"));
+                argv.push(Bytes::from(attr.clone()));
+                argv.push(Bytes::from(value.clone()));
+            }
+        }
+        argv
+    }
+}
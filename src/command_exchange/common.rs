@@ -1,7 +1,9 @@
 use std::vec::IntoIter;
 
+use bytes::Bytes;
+
 use crate::{
-    command_exchange::{CommandExchange, extract_bulk_string},
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_string},
     error::{Command, EvalCommand, Frame, KvError, PingCommand, UnimplementCommand},
 };
 
@@ -77,3 +79,33 @@ impl CommandExchange for EvalCommand {
         Ok(Command::EvalCommand(EvalCommand { script, keys, args }))
     }
 }
+
+impl CommandArgv for PingCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"PING")];
+        if let Some(value) = &self.value {
+            argv.push(Bytes::from(value.clone()));
+        }
+        argv
+    }
+}
+
+impl CommandArgv for UnimplementCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from(self.command.clone())];
+        argv.extend(self.args.iter().cloned());
+        argv
+    }
+}
+
+impl CommandArgv for EvalCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = Vec::with_capacity(self.keys.len() + self.args.len() + 3);
+        argv.push(Bytes::from_static(b"EVAL"));
+        argv.push(Bytes::from(self.script.clone()));
+        argv.push(Bytes::from(self.keys.len().to_string()));
+        argv.extend(self.keys.iter().map(|key| Bytes::from(key.clone())));
+        argv.extend(self.args.iter().map(|arg| Bytes::from(arg.clone())));
+        argv
+    }
+}
@@ -0,0 +1,161 @@
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_string},
+    error::{
+        AuthCommand, Command, Frame, HelloCommand, KvError, SelectCommand, SubscribeCommand,
+        UnsubscribeCommand,
+    },
+};
+
+impl CommandExchange for AuthCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let auth = match itor.len() {
+            1 => AuthCommand {
+                username: None,
+                password: extract_bulk_string(itor.next())?,
+            },
+            2 => AuthCommand {
+                username: Some(extract_bulk_string(itor.next())?),
+                password: extract_bulk_string(itor.next())?,
+            },
+            _ => {
+                return Err(KvError::ProtocolError(
+                    "wrong number of arguments for 'auth' command".into(),
+                ));
+            }
+        };
+        Ok(Command::Auth(auth))
+    }
+}
+
+impl CommandArgv for AuthCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"AUTH")];
+        if let Some(username) = &self.username {
+            argv.push(Bytes::from(username.clone()));
+        }
+        argv.push(Bytes::from(self.password.clone()));
+        argv
+    }
+}
+
+impl CommandExchange for SelectCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let index = extract_bulk_string(itor.next())?
+            .parse::<i64>()
+            .map_err(|_| KvError::Reply("ERR value is not an integer or out of range".into()))?;
+        Ok(Command::Select(SelectCommand { index }))
+    }
+}
+
+impl CommandArgv for SelectCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"SELECT"), Bytes::from(self.index.to_string())]
+    }
+}
+
+impl CommandExchange for HelloCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let mut hello = HelloCommand {
+            protover: None,
+            auth: None,
+            setname: None,
+        };
+        // 不带参数的 HELLO 只返回连接信息 不切换协议
+        let Some(frame) = itor.next() else {
+            return Ok(Command::Hello(hello));
+        };
+        let protover = extract_bulk_string(Some(frame))?.parse::<i64>().map_err(|_| {
+            KvError::ProtocolError("Protocol version is not an integer or out of range".into())
+        })?;
+        hello.protover = Some(protover);
+        while let Some(frame) = itor.next() {
+            let option = extract_bulk_string(Some(frame))?.to_uppercase();
+            match option.as_str() {
+                "AUTH" if itor.len() >= 2 => {
+                    let username = extract_bulk_string(itor.next())?;
+                    let password = extract_bulk_string(itor.next())?;
+                    hello.auth = Some((username, password));
+                }
+                "SETNAME" if itor.len() >= 1 => {
+                    let name = extract_bulk_string(itor.next())?;
+                    validate_client_name(&name)?;
+                    hello.setname = Some(name);
+                }
+                _ => {
+                    return Err(KvError::ProtocolError(format!(
+                        "Syntax error in HELLO option '{}'",
+                        option
+                    )));
+                }
+            }
+        }
+        Ok(Command::Hello(hello))
+    }
+}
+
+/// 连接名里不能有空格、换行这些字符 不然 CLIENT LIST 的输出就没法解析了
+pub fn validate_client_name(name: &str) -> Result<(), KvError> {
+    if name.bytes().any(|c| !(b'!'..=b'~').contains(&c)) {
+        return Err(KvError::ProtocolError(
+            "Client names cannot contain spaces, newlines or special characters.".into(),
+        ));
+    }
+    Ok(())
+}
+
+impl CommandArgv for HelloCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"HELLO")];
+        if let Some(protover) = self.protover {
+            argv.push(Bytes::from(protover.to_string()));
+        }
+        if let Some((username, password)) = &self.auth {
+            argv.push(Bytes::from_static(b"AUTH"));
+            argv.push(Bytes::from(username.clone()));
+            argv.push(Bytes::from(password.clone()));
+        }
+        if let Some(name) = &self.setname {
+            argv.push(Bytes::from_static(b"SETNAME"));
+            argv.push(Bytes::from(name.clone()));
+        }
+        argv
+    }
+}
+
+impl CommandExchange for SubscribeCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let channels = itor
+            .map(|frame| extract_bulk_string(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::Subscribe(SubscribeCommand { channels }))
+    }
+}
+
+impl CommandArgv for SubscribeCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"SUBSCRIBE")];
+        argv.extend(self.channels.iter().map(|channel| Bytes::from(channel.clone())));
+        argv
+    }
+}
+
+impl CommandExchange for UnsubscribeCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let channels = itor
+            .map(|frame| extract_bulk_string(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::Unsubscribe(UnsubscribeCommand { channels }))
+    }
+}
+
+impl CommandArgv for UnsubscribeCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"UNSUBSCRIBE")];
+        argv.extend(self.channels.iter().map(|channel| Bytes::from(channel.clone())));
+        argv
+    }
+}
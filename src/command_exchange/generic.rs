@@ -0,0 +1,104 @@
+use std::{sync::Arc, vec::IntoIter};
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_string, keys_to_argv},
+    error::{Command, DelCommand, Frame, KvError, PExpireAtCommand, RenameCommand, WaitAofCommand},
+};
+
+impl CommandExchange for DelCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let keys = itor
+            .map(|frame| extract_bulk_string(Some(frame)).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::Del(DelCommand { keys }))
+    }
+}
+
+impl CommandArgv for DelCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"DEL")];
+        argv.extend(keys_to_argv(&self.keys));
+        argv
+    }
+}
+
+impl CommandExchange for RenameCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let new_key = extract_bulk_string(itor.next())?;
+        Ok(Command::Rename(RenameCommand {
+            key: Arc::new(key),
+            new_key: Arc::new(new_key),
+        }))
+    }
+}
+
+impl CommandArgv for RenameCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"RENAME"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+            Bytes::copy_from_slice(self.new_key.as_bytes()),
+        ]
+    }
+}
+
+impl CommandExchange for PExpireAtCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let timestamp_ms = extract_bulk_string(itor.next())?
+            .parse::<i64>()
+            .map_err(|_| KvError::ProtocolError("value is not an integer or out of range".into()))?;
+        Ok(Command::PExpireAt(PExpireAtCommand {
+            key: Arc::new(key),
+            timestamp_ms,
+        }))
+    }
+}
+
+impl CommandArgv for PExpireAtCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"PEXPIREAT"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+            Bytes::from(self.timestamp_ms.to_string()),
+        ]
+    }
+}
+
+impl CommandExchange for WaitAofCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let numlocal = parse_integer(itor.next())?;
+        let numreplicas = parse_integer(itor.next())?;
+        let timeout_ms = extract_bulk_string(itor.next())?
+            .parse::<i64>()
+            .map_err(|_| KvError::ProtocolError("timeout is not an integer or out of range".into()))?;
+        if timeout_ms < 0 {
+            return Err(KvError::ProtocolError("timeout is negative".into()));
+        }
+        Ok(Command::WaitAof(WaitAofCommand {
+            numlocal,
+            numreplicas,
+            timeout_ms: timeout_ms as u64,
+        }))
+    }
+}
+
+impl CommandArgv for WaitAofCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"WAITAOF"),
+            Bytes::from(self.numlocal.to_string()),
+            Bytes::from(self.numreplicas.to_string()),
+            Bytes::from(self.timeout_ms.to_string()),
+        ]
+    }
+}
+
+fn parse_integer(frame: Option<Frame>) -> Result<i64, KvError> {
+    extract_bulk_string(frame)?
+        .parse::<i64>()
+        .map_err(|_| KvError::ProtocolError("value is not an integer or out of range".into()))
+}
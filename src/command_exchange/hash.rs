@@ -0,0 +1,81 @@
+use std::{sync::Arc, vec::IntoIter};
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_bytes, extract_bulk_string},
+    error::{Command, Frame, HGetAllCommand, HGetCommand, HSetCommand, KvError},
+};
+
+impl CommandExchange for HSetCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        // field value 必须成对出现
+        if !itor.len().is_multiple_of(2) {
+            return Err(KvError::ProtocolError(
+                "wrong number of arguments for 'hset' command".into(),
+            ));
+        }
+        let mut pairs = Vec::with_capacity(itor.len() / 2);
+        while let Some(frame) = itor.next() {
+            let field = extract_bulk_string(Some(frame))?;
+            let value = extract_bulk_bytes(itor.next())?;
+            pairs.push((field, value));
+        }
+        Ok(Command::HSet(HSetCommand {
+            key: Arc::new(key),
+            pairs,
+        }))
+    }
+}
+
+impl CommandArgv for HSetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"HSET"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+        ];
+        for (field, value) in &self.pairs {
+            argv.push(Bytes::copy_from_slice(field.as_bytes()));
+            argv.push(value.clone());
+        }
+        argv
+    }
+}
+
+impl CommandExchange for HGetCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let field = extract_bulk_string(itor.next())?;
+        Ok(Command::HGet(HGetCommand {
+            key: Arc::new(key),
+            field,
+        }))
+    }
+}
+
+impl CommandArgv for HGetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"HGET"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+            Bytes::copy_from_slice(self.field.as_bytes()),
+        ]
+    }
+}
+
+impl CommandExchange for HGetAllCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        Ok(Command::HGetAll(HGetAllCommand { key: Arc::new(key) }))
+    }
+}
+
+impl CommandArgv for HGetAllCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"HGETALL"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+        ]
+    }
+}
@@ -0,0 +1,32 @@
+use std::{sync::Arc, vec::IntoIter};
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_bytes, extract_bulk_string},
+    error::{Command, Frame, KvError, RPushCommand},
+};
+
+impl CommandExchange for RPushCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let values = itor
+            .map(|frame| extract_bulk_bytes(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::RPush(RPushCommand {
+            key: Arc::new(key),
+            values,
+        }))
+    }
+}
+
+impl CommandArgv for RPushCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"RPUSH"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+        ];
+        argv.extend(self.values.iter().cloned());
+        argv
+    }
+}
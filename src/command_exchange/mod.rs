@@ -5,6 +5,15 @@ use bytes::Bytes;
 use crate::error::{Command, Frame, KvError};
 mod string;
 mod common;
+mod client;
+mod generic;
+mod acl;
+mod connection;
+mod hash;
+mod list;
+mod set;
+mod zset;
+mod server;
 /// 尝试从一个 Frame 中提取出 Bulk String 并转换为 String
 pub fn extract_bulk_string(frame: Option<Frame>) -> Result<String, KvError> {
     match frame {
@@ -31,3 +40,14 @@ fn extract_bulk_bytes(frame: Option<Frame>) -> Result<Bytes, KvError> {
 pub trait CommandExchange {
      fn exchange( itor: IntoIter<Frame>,command_name:String) -> Result<Command, KvError>;
 }
+
+/// 和 exchange 反过来：把命令还原成 argv
+/// key 的提取、AOF 都是从 argv 出发的
+pub trait CommandArgv {
+    fn to_argv(&self) -> Vec<Bytes>;
+}
+
+/// 把一批 key 转成 argv 的一部分
+fn keys_to_argv(keys: &[std::sync::Arc<String>]) -> impl Iterator<Item = Bytes> + '_ {
+    keys.iter().map(|key| Bytes::copy_from_slice(key.as_bytes()))
+}
@@ -0,0 +1,239 @@
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_string},
+    error::{
+        BgRewriteAofCommand, BgSaveCommand, Command, DebugCommand, DebugSubCommand, Frame,
+        InfoCommand, KvError, LastSaveCommand, PsyncCommand, ReplconfCommand, ReplconfOption,
+        ReplicaOfCommand, SaveCommand,
+    },
+};
+
+impl CommandExchange for BgRewriteAofCommand {
+    fn exchange(_itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        Ok(Command::BgRewriteAof(BgRewriteAofCommand))
+    }
+}
+
+impl CommandArgv for BgRewriteAofCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"BGREWRITEAOF")]
+    }
+}
+
+impl CommandExchange for SaveCommand {
+    fn exchange(_itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        Ok(Command::Save(SaveCommand))
+    }
+}
+
+impl CommandArgv for SaveCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"SAVE")]
+    }
+}
+
+impl CommandExchange for BgSaveCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        // 只认一个可选的 SCHEDULE
+        let schedule = match itor.next() {
+            None => false,
+            Some(frame) => {
+                if itor.len() > 0 || !extract_bulk_string(Some(frame))?.eq_ignore_ascii_case("schedule") {
+                    return Err(KvError::ProtocolError("syntax error".into()));
+                }
+                true
+            }
+        };
+        Ok(Command::BgSave(BgSaveCommand { schedule }))
+    }
+}
+
+impl CommandArgv for BgSaveCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"BGSAVE")];
+        if self.schedule {
+            argv.push(Bytes::from_static(b"SCHEDULE"));
+        }
+        argv
+    }
+}
+
+impl CommandExchange for LastSaveCommand {
+    fn exchange(_itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        Ok(Command::LastSave(LastSaveCommand))
+    }
+}
+
+impl CommandArgv for LastSaveCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"LASTSAVE")]
+    }
+}
+
+impl CommandExchange for DebugCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let sub_name = extract_bulk_string(itor.next())?.to_uppercase();
+        let rest = itor
+            .map(|frame| extract_bulk_string(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sub = match sub_name.as_str() {
+            "EXPORT-RDB" if rest.len() == 1 => DebugSubCommand::ExportRdb(rest[0].clone()),
+            "EXPORT-RDB" => {
+                return Err(KvError::ProtocolError(
+                    "wrong number of arguments for 'debug|export-rdb' command".into(),
+                ));
+            }
+            _ => {
+                return Err(KvError::ProtocolError(format!(
+                    "unknown subcommand '{}'. Try DEBUG HELP.",
+                    sub_name
+                )));
+            }
+        };
+        Ok(Command::Debug(DebugCommand { sub }))
+    }
+}
+
+impl CommandArgv for DebugCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        match &self.sub {
+            DebugSubCommand::ExportRdb(path) => vec![
+                Bytes::from_static(b"DEBUG"),
+                Bytes::from_static(b"EXPORT-RDB"),
+                Bytes::copy_from_slice(path.as_bytes()),
+            ],
+        }
+    }
+}
+
+impl CommandExchange for InfoCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        // 段名不区分大小写 统一转小写
+        let sections = itor
+            .map(|frame| extract_bulk_string(Some(frame)).map(|section| section.to_lowercase()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::Info(InfoCommand { sections }))
+    }
+}
+
+impl CommandArgv for InfoCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"INFO")];
+        argv.extend(self.sections.iter().map(|section| Bytes::from(section.clone())));
+        argv
+    }
+}
+
+impl CommandExchange for ReplicaOfCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let host = extract_bulk_string(itor.next())?;
+        let port = extract_bulk_string(itor.next())?;
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(Command::ReplicaOf(ReplicaOfCommand { master: None }));
+        }
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| KvError::ProtocolError("Invalid master port".into()))?;
+        Ok(Command::ReplicaOf(ReplicaOfCommand {
+            master: Some((host, port)),
+        }))
+    }
+}
+
+impl CommandArgv for ReplicaOfCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"REPLICAOF")];
+        match &self.master {
+            Some((host, port)) => {
+                argv.push(Bytes::from(host.clone()));
+                argv.push(Bytes::from(port.to_string()));
+            }
+            None => {
+                argv.push(Bytes::from_static(b"NO"));
+                argv.push(Bytes::from_static(b"ONE"));
+            }
+        }
+        argv
+    }
+}
+
+impl CommandExchange for ReplconfCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let args = itor
+            .map(|frame| extract_bulk_string(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        // 参数都是 option value 成对出现的
+        if args.len() % 2 != 0 {
+            return Err(KvError::ProtocolError("syntax error".into()));
+        }
+        let not_integer = || KvError::ProtocolError("value is not an integer or out of range".into());
+        let options = args
+            .chunks(2)
+            .map(|pair| {
+                let (option, value) = (&pair[0], &pair[1]);
+                Ok(match option.to_lowercase().as_str() {
+                    "listening-port" => {
+                        ReplconfOption::ListeningPort(value.parse().map_err(|_| not_integer())?)
+                    }
+                    "capa" => ReplconfOption::Capa(value.to_lowercase()),
+                    "ack" => ReplconfOption::Ack(value.parse().map_err(|_| not_integer())?),
+                    "getack" => ReplconfOption::GetAck,
+                    _ => {
+                        return Err(KvError::ProtocolError(format!(
+                            "Unrecognized REPLCONF option: {}",
+                            option
+                        )));
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::Replconf(ReplconfCommand { options }))
+    }
+}
+
+impl CommandArgv for ReplconfCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"REPLCONF")];
+        for option in &self.options {
+            let (name, value) = match option {
+                ReplconfOption::ListeningPort(port) => ("listening-port", port.to_string()),
+                ReplconfOption::Capa(capa) => ("capa", capa.clone()),
+                ReplconfOption::Ack(offset) => ("ack", offset.to_string()),
+                ReplconfOption::GetAck => ("getack", "*".to_string()),
+            };
+            argv.push(Bytes::from_static(name.as_bytes()));
+            argv.push(Bytes::from(value));
+        }
+        argv
+    }
+}
+
+impl CommandExchange for PsyncCommand {
+    fn exchange(mut itor: IntoIter<Frame>, command_name: String) -> Result<Command, KvError> {
+        // 老的 SYNC 没有参数 就是每次都要全量同步
+        if command_name == "SYNC" {
+            return Ok(Command::Psync(PsyncCommand {
+                replid: "?".into(),
+                offset: -1,
+            }));
+        }
+        let replid = extract_bulk_string(itor.next())?;
+        let offset = extract_bulk_string(itor.next())?
+            .parse::<i64>()
+            .map_err(|_| KvError::ProtocolError("value is not an integer or out of range".into()))?;
+        Ok(Command::Psync(PsyncCommand { replid, offset }))
+    }
+}
+
+impl CommandArgv for PsyncCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"PSYNC"),
+            Bytes::from(self.replid.clone()),
+            Bytes::from(self.offset.to_string()),
+        ]
+    }
+}
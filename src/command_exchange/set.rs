@@ -0,0 +1,32 @@
+use std::{sync::Arc, vec::IntoIter};
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_bytes, extract_bulk_string},
+    error::{Command, Frame, KvError, SAddCommand},
+};
+
+impl CommandExchange for SAddCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let members = itor
+            .map(|frame| extract_bulk_bytes(Some(frame)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::SAdd(SAddCommand {
+            key: Arc::new(key),
+            members,
+        }))
+    }
+}
+
+impl CommandArgv for SAddCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"SADD"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+        ];
+        argv.extend(self.members.iter().cloned());
+        argv
+    }
+}
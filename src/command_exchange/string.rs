@@ -1,6 +1,8 @@
 use std::{sync::Arc, vec::IntoIter};
 
-use crate::{command_exchange::{extract_bulk_bytes, extract_bulk_integer, extract_bulk_string, CommandExchange}, error::{Command, Expiration, Frame, GetCommand, KvError, SetCommand, SetCondition}};
+use bytes::Bytes;
+
+use crate::{command_execute::checked_expiration_timestamp_ms, command_exchange::{extract_bulk_bytes, extract_bulk_integer, extract_bulk_string, keys_to_argv, CommandArgv, CommandExchange}, error::{Command, Expiration, Frame, GetCommand, KvError, MGetCommand, MSetCommand, SetCommand, SetCondition}};
 
 impl CommandExchange for SetCommand {
      fn exchange( mut itor: IntoIter<Frame>,_command_name:String) -> Result<Command, KvError> {
@@ -71,11 +73,20 @@ impl CommandExchange for SetCommand {
                 }
             }
         }
+        // 过期时间太大 换算成毫秒会溢出
+        if expiration
+            .as_ref()
+            .is_some_and(|expiration| checked_expiration_timestamp_ms(expiration).is_none())
+        {
+            return Err(KvError::Reply("ERR invalid expire time in 'set' command".into()));
+        }
         Ok(Command::Set(SetCommand {
             key: Arc::new(key),
             value,
             expiration,
             condition,
+            // memcached 的 flags 只有重放的时候才有 见 Command::from_replay
+            flags: 0,
         }))
     }
 }
@@ -86,4 +97,86 @@ impl CommandExchange for GetCommand {
         Ok(Command::Get(GetCommand { key :Arc::new(key) }))
     }
     
-}
\ No newline at end of file
+}
+
+impl CommandArgv for SetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"SET"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+            self.value.clone(),
+        ];
+        if let Some(expire) = &self.expiration {
+            let (name, time): (&'static [u8], u64) = match expire {
+                Expiration::EX(s) => (b"EX", *s),
+                Expiration::PX(ms) => (b"PX", *ms),
+                Expiration::EXAT(s) => (b"EXAT", *s),
+                Expiration::PXAT(ms) => (b"PXAT", *ms),
+            };
+            argv.push(Bytes::from_static(name));
+            argv.push(Bytes::from(time.to_string()));
+        }
+        if self.flags != 0 {
+            argv.push(Bytes::from_static(b"MCFLAGS"));
+            argv.push(Bytes::from(self.flags.to_string()));
+        }
+        match self.condition {
+            Some(SetCondition::NX) => argv.push(Bytes::from_static(b"NX")),
+            Some(SetCondition::XX) => argv.push(Bytes::from_static(b"XX")),
+            None => {}
+        }
+        argv
+    }
+}
+
+impl CommandArgv for GetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"GET"), Bytes::copy_from_slice(self.key.as_bytes())]
+    }
+}
+
+impl CommandExchange for MGetCommand {
+    fn exchange(itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let keys = itor
+            .map(|frame| extract_bulk_string(Some(frame)).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Command::MGet(MGetCommand { keys }))
+    }
+}
+
+impl CommandArgv for MGetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![Bytes::from_static(b"MGET")];
+        argv.extend(keys_to_argv(&self.keys));
+        argv
+    }
+}
+
+impl CommandExchange for MSetCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        if !itor.len().is_multiple_of(2) {
+            return Err(KvError::ProtocolError(
+                "wrong number of arguments for 'mset' command".into(),
+            ));
+        }
+        let mut pairs = Vec::with_capacity(itor.len() / 2);
+        while let Some(key_frame) = itor.next() {
+            let key = extract_bulk_string(Some(key_frame))?;
+            let value = extract_bulk_bytes(itor.next())?;
+            pairs.push((Arc::new(key), value));
+        }
+        Ok(Command::MSet(MSetCommand { pairs }))
+    }
+}
+
+impl CommandArgv for MSetCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = Vec::with_capacity(self.pairs.len() * 2 + 1);
+        argv.push(Bytes::from_static(b"MSET"));
+        for (key, value) in &self.pairs {
+            argv.push(Bytes::copy_from_slice(key.as_bytes()));
+            argv.push(value.clone());
+        }
+        argv
+    }
+}
@@ -0,0 +1,86 @@
+use std::{sync::Arc, vec::IntoIter};
+
+use bytes::Bytes;
+
+use crate::{
+    command_exchange::{CommandArgv, CommandExchange, extract_bulk_bytes, extract_bulk_string},
+    error::{Command, Frame, KvError, ZAddCommand, ZRangeCommand},
+};
+
+impl CommandExchange for ZAddCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        // score member 必须成对出现
+        if itor.len() == 0 || !itor.len().is_multiple_of(2) {
+            return Err(KvError::ProtocolError("syntax error".into()));
+        }
+        let mut members = Vec::with_capacity(itor.len() / 2);
+        while let Some(frame) = itor.next() {
+            let score = extract_bulk_string(Some(frame))?
+                .parse::<f64>()
+                .ok()
+                .filter(|score| !score.is_nan())
+                .ok_or_else(|| KvError::ProtocolError("value is not a valid float".into()))?;
+            members.push((score, extract_bulk_bytes(itor.next())?));
+        }
+        Ok(Command::ZAdd(ZAddCommand {
+            key: Arc::new(key),
+            members,
+        }))
+    }
+}
+
+impl CommandArgv for ZAddCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"ZADD"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+        ];
+        for (score, member) in &self.members {
+            argv.push(Bytes::from(score.to_string()));
+            argv.push(member.clone());
+        }
+        argv
+    }
+}
+
+impl CommandExchange for ZRangeCommand {
+    fn exchange(mut itor: IntoIter<Frame>, _command_name: String) -> Result<Command, KvError> {
+        let key = extract_bulk_string(itor.next())?;
+        let mut index = || {
+            extract_bulk_string(itor.next())?
+                .parse::<i64>()
+                .map_err(|_| KvError::ProtocolError("value is not an integer or out of range".into()))
+        };
+        let start = index()?;
+        let stop = index()?;
+        let mut with_scores = false;
+        for frame in itor {
+            if !extract_bulk_string(Some(frame))?.eq_ignore_ascii_case("WITHSCORES") {
+                return Err(KvError::ProtocolError("syntax error".into()));
+            }
+            with_scores = true;
+        }
+        Ok(Command::ZRange(ZRangeCommand {
+            key: Arc::new(key),
+            start,
+            stop,
+            with_scores,
+        }))
+    }
+}
+
+impl CommandArgv for ZRangeCommand {
+    fn to_argv(&self) -> Vec<Bytes> {
+        let mut argv = vec![
+            Bytes::from_static(b"ZRANGE"),
+            Bytes::copy_from_slice(self.key.as_bytes()),
+            Bytes::from(self.start.to_string()),
+            Bytes::from(self.stop.to_string()),
+        ];
+        if self.with_scores {
+            argv.push(Bytes::from_static(b"WITHSCORES"));
+        }
+        argv
+    }
+}
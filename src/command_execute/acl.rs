@@ -0,0 +1,114 @@
+use bytes::Bytes;
+
+use crate::{
+    acl::ACL,
+    command_execute::{CommandContext, CommandExecutor},
+    config::CONFIG,
+    context::CONN_STATE,
+    core_keyspec::{ACL_CATEGORIES, COMMAND_TABLE},
+    core_time::get_cached_time_ms,
+    db::lock_plan::LockedShards,
+    error::{AclCommand, AclSubCommand, Frame, KvError},
+};
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
+
+fn ok_or_error(result: Result<(), String>) -> Frame {
+    match result {
+        Ok(()) => Frame::Simple("OK".into()),
+        Err(msg) => Frame::Error(msg),
+    }
+}
+
+impl CommandExecutor for AclCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let frame = match &self.sub {
+            AclSubCommand::SetUser { name, rules } => ok_or_error(ACL.set_user(name, rules)),
+            AclSubCommand::DelUser(names) => match ACL.del_users(names) {
+                Ok(deleted) => Frame::Integer(deleted),
+                Err(msg) => Frame::Error(msg),
+            },
+            AclSubCommand::GetUser(name) => match ACL.get_user(name) {
+                Some(user) => Frame::Map(vec![
+                    (
+                        bulk("flags"),
+                        Frame::Array(user.flags().into_iter().map(bulk).collect()),
+                    ),
+                    (
+                        bulk("passwords"),
+                        Frame::Array(user.passwords.iter().map(|p| bulk(p)).collect()),
+                    ),
+                    (bulk("commands"), bulk(&user.describe_commands())),
+                    (bulk("keys"), bulk(&user.describe_keys())),
+                    (bulk("channels"), bulk(&user.describe_channels())),
+                    (bulk("selectors"), Frame::Array(vec![])),
+                ]),
+                None => Frame::Null,
+            },
+            AclSubCommand::List => {
+                Frame::Array(ACL.describe_users().iter().map(|line| bulk(line)).collect())
+            }
+            AclSubCommand::Users => {
+                Frame::Array(ACL.usernames().iter().map(|name| bulk(name)).collect())
+            }
+            AclSubCommand::WhoAmI => match CONN_STATE.with(|state| state.user.borrow().clone()) {
+                Some(name) => bulk(&name),
+                None => Frame::Null,
+            },
+            AclSubCommand::Cat(None) => Frame::Array(ACL_CATEGORIES.iter().map(|c| bulk(c)).collect()),
+            AclSubCommand::Cat(Some(category)) => {
+                if !ACL_CATEGORIES.contains(&category.as_str()) {
+                    return Ok(Frame::Error(format!("ERR Unknown category '{}'", category)));
+                }
+                let mut names: Vec<&str> = COMMAND_TABLE
+                    .values()
+                    .filter(|spec| spec.in_category(category))
+                    .map(|spec| spec.name)
+                    .collect();
+                names.sort_unstable();
+                Frame::Array(names.into_iter().map(bulk).collect())
+            }
+            AclSubCommand::Log { reset: true, .. } => {
+                ACL.reset_log();
+                Frame::Simple("OK".into())
+            }
+            AclSubCommand::Log { count, .. } => {
+                let now = get_cached_time_ms();
+                Frame::Array(
+                    ACL.log_entries(count.unwrap_or(10))
+                        .into_iter()
+                        .map(|entry| {
+                            Frame::Map(vec![
+                                (bulk("count"), Frame::Integer(entry.count as i64)),
+                                (bulk("reason"), bulk(entry.reason)),
+                                (bulk("context"), bulk(entry.context)),
+                                (bulk("object"), bulk(&entry.object)),
+                                (bulk("username"), bulk(&entry.username)),
+                                (
+                                    bulk("age-seconds"),
+                                    Frame::Double(now.saturating_sub(entry.created_ms) as f64 / 1000.0),
+                                ),
+                                (bulk("client-info"), bulk(&entry.client_info)),
+                                (bulk("entry-id"), Frame::Integer(entry.entry_id as i64)),
+                                (bulk("timestamp-created"), Frame::Integer(entry.created_ms as i64)),
+                                (
+                                    bulk("timestamp-last-updated"),
+                                    Frame::Integer(entry.updated_ms as i64),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            AclSubCommand::Save => ok_or_error(ACL.save_file(&CONFIG.aclfile)),
+            AclSubCommand::Load => ok_or_error(ACL.load_file(&CONFIG.aclfile)),
+        };
+        Ok(frame)
+    }
+}
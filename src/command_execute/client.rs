@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor},
+    context::CONN_STATE,
+    core_client::CLIENTS,
+    core_tracking::TRACKING,
+    db::lock_plan::LockedShards,
+    error::{ClientCommand, ClientSubCommand, Frame, KvError, TrackingMode},
+};
+
+impl CommandExecutor for ClientCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let client_id = CONN_STATE.with(|state| state.client_id);
+        match &self.sub {
+            ClientSubCommand::Id => Ok(Frame::Integer(client_id as i64)),
+            ClientSubCommand::List { client_type, ids } => {
+                // 这里只有普通连接 问别的类型就是空的
+                let list = match client_type.as_deref() {
+                    None | Some("normal") => CLIENTS.list(ids),
+                    Some(_) => String::new(),
+                };
+                Ok(Frame::Verbatim("txt".into(), Bytes::from(list)))
+            }
+            ClientSubCommand::Info => {
+                let info = CLIENTS.info(client_id).unwrap_or_default();
+                Ok(Frame::Verbatim("txt".into(), Bytes::from(info + "\n")))
+            }
+            ClientSubCommand::SetName(name) => {
+                // 空字符串就是清掉名字
+                CONN_STATE.with(|state| {
+                    *state.client_name.borrow_mut() = (!name.is_empty()).then(|| name.clone())
+                });
+                CLIENTS.refresh();
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::GetName => {
+                Ok(CONN_STATE.with(|state| match state.client_name.borrow().as_ref() {
+                    Some(name) => Frame::Bulk(Bytes::from(name.clone())),
+                    None => Frame::Null,
+                }))
+            }
+            ClientSubCommand::Kill(filter) => {
+                let killed = CLIENTS.kill(filter, client_id);
+                match filter.legacy {
+                    true if killed == 0 => Ok(Frame::Error("ERR No such client".into())),
+                    true => Ok(Frame::Simple("OK".into())),
+                    false => Ok(Frame::Integer(killed as i64)),
+                }
+            }
+            ClientSubCommand::Pause { timeout, all } => {
+                CLIENTS.pause(Duration::from_millis(*timeout), *all);
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::Unpause => {
+                CLIENTS.unpause();
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::Reply(mode) => {
+                // OFF/SKIP 连这条命令自己的回复也不发 连接循环里按这个状态决定写不写
+                CONN_STATE.with(|state| state.reply.set(*mode));
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::NoEvict(on) => {
+                CLIENTS.set_no_evict(client_id, *on);
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::SetInfo { attr, value } => {
+                let value = Some(value.clone());
+                match attr.as_str() {
+                    "LIB-NAME" => CLIENTS.set_lib_info(client_id, value, None),
+                    _ => CLIENTS.set_lib_info(client_id, None, value),
+                }
+                Ok(Frame::Simple("OK".into()))
+            }
+            ClientSubCommand::Tracking(options) => {
+                if !options.enable {
+                    TRACKING.disable(client_id);
+                    return Ok(Frame::Simple("OK".into()));
+                }
+                match TRACKING.enable(client_id, options.clone()) {
+                    Ok(()) => Ok(Frame::Simple("OK".into())),
+                    Err(msg) => Ok(Frame::Error(format!("ERR {}", msg))),
+                }
+            }
+            ClientSubCommand::Caching(yes) => match TRACKING.set_caching(client_id, *yes) {
+                Ok(()) => Ok(Frame::Simple("OK".into())),
+                Err(msg) => Ok(Frame::Error(format!("ERR {}", msg))),
+            },
+            ClientSubCommand::GetRedir => Ok(Frame::Integer(TRACKING.get_redirect(client_id))),
+            ClientSubCommand::TrackingInfo => {
+                let info = TRACKING.info(client_id);
+                let mut flags = Vec::new();
+                let mut redirect = -1;
+                let mut prefixes = Vec::new();
+                match &info.options {
+                    Some(options) => {
+                        flags.push("on");
+                        match options.mode {
+                            TrackingMode::Bcast => flags.push("bcast"),
+                            TrackingMode::OptIn => flags.push("optin"),
+                            TrackingMode::OptOut => flags.push("optout"),
+                            TrackingMode::Default => {}
+                        }
+                        match info.caching {
+                            Some(true) => flags.push("caching-yes"),
+                            Some(false) => flags.push("caching-no"),
+                            None => {}
+                        }
+                        if options.noloop {
+                            flags.push("noloop");
+                        }
+                        if info.redirect_broken {
+                            flags.push("broken_redirect");
+                        }
+                        redirect = options.redirect.map(|id| id as i64).unwrap_or(0);
+                        prefixes = options.prefixes.clone();
+                    }
+                    None => flags.push("off"),
+                }
+                Ok(Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"flags")),
+                    Frame::Array(
+                        flags
+                            .into_iter()
+                            .map(|f| Frame::Bulk(Bytes::from_static(f.as_bytes())))
+                            .collect(),
+                    ),
+                    Frame::Bulk(Bytes::from_static(b"redirect")),
+                    Frame::Integer(redirect),
+                    Frame::Bulk(Bytes::from_static(b"prefixes")),
+                    Frame::Array(
+                        prefixes
+                            .into_iter()
+                            .map(|p| Frame::Bulk(Bytes::from(p)))
+                            .collect(),
+                    ),
+                ]))
+            }
+        }
+    }
+}
@@ -3,7 +3,7 @@ use bytes::Bytes;
 use crate::{
     command_execute::{CommandContext, CommandExecutor},
     context::{CONN_STATE, ConnectionState},
-    db::LockedDb,
+    db::lock_plan::LockedShards,
     error::{EvalCommand, Frame, KvError, PingCommand, UnimplementCommand},
     lua::lua_work::LuaTask,
 };
@@ -12,7 +12,7 @@ impl CommandExecutor for PingCommand {
     async fn execute(
         &self,
         _ctx: CommandContext,
-        db_lock: Option<&mut LockedDb>,
+        _db_lock: Option<&mut LockedShards>,
     ) -> Result<Frame, KvError> {
         if let Some(value) = &self.value {
             Ok(Frame::Bulk(Bytes::from(value.clone())))
@@ -27,7 +27,7 @@ impl CommandExecutor for UnimplementCommand {
         &self,
         // 2. 将这个生命周期 'ctx 应用到 CommandContext 的引用上
         _ctx: CommandContext,
-        db_lock: Option<&mut LockedDb>,
+        _db_lock: Option<&mut LockedShards>,
     ) -> Result<Frame, KvError> {
         Ok(Frame::Error(format!(
             "ERR unknown command '{}'",
@@ -36,6 +36,22 @@ impl CommandExecutor for UnimplementCommand {
     }
 }
 
+// 脚本里的读也算在发起脚本的连接头上 脚本里看到的回复固定是 RESP2
+fn script_connection_state() -> ConnectionState {
+    let mut connect_state = ConnectionState::new(
+        CONN_STATE.with(|state| state.selected_db.get()),
+        None,
+        CONN_STATE.with(|state| state.client_id),
+    );
+    // appendfsync always 的时候脚本写入的落盘回执要挂回发起脚本的连接上 回复之前等它
+    connect_state.pending_aof = CONN_STATE.with(|state| state.pending_aof.clone());
+    // 脚本里写入的偏移量记到发起脚本的连接上 之后的 WAITAOF 要等它
+    connect_state.aof_offset = CONN_STATE.with(|state| state.aof_offset.clone());
+    // redis.call 的权限按发起脚本的用户来检查
+    *connect_state.user.borrow_mut() = CONN_STATE.with(|state| state.user.borrow().clone());
+    connect_state
+}
+
 /*
 这个是比较特殊的执行层
 */
@@ -43,7 +59,7 @@ impl CommandExecutor for EvalCommand {
     async fn execute(
         &self,
         ctx: CommandContext,
-        _db_lock: Option<&mut LockedDb>,
+        _db_lock: Option<&mut LockedShards>,
     ) -> Result<Frame, KvError> {
         //   let result =   self.lua_vm_redis_call(
         // CommandContext {
@@ -57,16 +73,14 @@ impl CommandExecutor for EvalCommand {
         let (tx, rx) = oneshot::channel::<Result<Frame, KvError>>();
 
         //这一步记得传递上下文
+        let connect_state = script_connection_state();
         content
             .lua_sender
             .send(LuaTask {
                 ctx: ctx.clone(),
                 resp: tx,
                 command: self.clone(),
-                connect_state: ConnectionState {
-                    selected_db: CONN_STATE.with(|state| state.selected_db),
-                    client_address: None,
-                },
+                connect_state,
             })
             .await // <--- 关键！驱动发送动作
             .map_err(|_| KvError::ProtocolError("Lua Worker 已挂掉".into()))?;
@@ -95,3 +109,33 @@ impl CommandExecutor for EvalCommand {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use super::*;
+    use crate::core_aof::wait_aof_durable;
+
+    // appendfsync always 下 EVAL 里的写入落盘之前 发起脚本的连接不能回复
+    #[tokio::test]
+    async fn test_eval_waits_for_script_fsync() {
+        let caller = ConnectionState::new(0, None, 1);
+        CONN_STATE
+            .scope(caller, async {
+                let script = script_connection_state();
+                // lua worker 里 send_aof 挂上去的回执
+                let (ack, ack_rx) = oneshot::channel();
+                *script.pending_aof.lock().unwrap() = Some(ack_rx);
+                script.aof_offset.store(7, Ordering::SeqCst);
+                assert_eq!(CONN_STATE.with(|state| state.aof_offset.load(Ordering::SeqCst)), 7);
+
+                let durable = wait_aof_durable();
+                tokio::pin!(durable);
+                assert!(tokio::time::timeout(Duration::from_millis(20), &mut durable).await.is_err());
+                ack.send(Ok(())).unwrap();
+                assert!(durable.await.is_ok());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,160 @@
+use bytes::Bytes;
+
+use crate::{
+    acl::ACL,
+    command_execute::{CommandContext, CommandExecutor},
+    context::CONN_STATE,
+    core_client::CLIENTS,
+    core_replication::REPLICATION,
+    core_tracking::TRACKING,
+    db::lock_plan::LockedShards,
+    error::{
+        AuthCommand, Frame, HelloCommand, KvError, SelectCommand, SubscribeCommand,
+        UnsubscribeCommand,
+    },
+};
+
+impl CommandExecutor for AuthCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        // 老式的 AUTH password 只能用来登录 default
+        if self.username.is_none() && ACL.default_nopass() {
+            return Ok(Frame::Error(
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"
+                    .into(),
+            ));
+        }
+        let username = self.username.as_deref().unwrap_or("default");
+        Ok(match login(username, &self.password) {
+            Ok(()) => Frame::Simple("OK".into()),
+            Err(msg) => Frame::Error(msg),
+        })
+    }
+}
+
+fn login(username: &str, password: &str) -> Result<(), String> {
+    ACL.authenticate(username, password)?;
+    CONN_STATE.with(|state| *state.user.borrow_mut() = Some(username.to_string()));
+    CLIENTS.refresh();
+    Ok(())
+}
+
+impl CommandExecutor for SelectCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let databases = ctx.db.as_ref().map_or(0, |db| db.store.store.len());
+        let Some(index) = usize::try_from(self.index).ok().filter(|index| *index < databases) else {
+            return Ok(Frame::Error("ERR DB index is out of range".into()));
+        };
+        // 之后的命令按新库加锁 写 AOF 的时候也带上新库号
+        CONN_STATE.with(|state| state.selected_db.set(index));
+        CLIENTS.refresh();
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+impl CommandExecutor for HelloCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        if let Some(protover) = self.protover
+            && !(2..=3).contains(&protover)
+        {
+            return Ok(Frame::Error("NOPROTO unsupported protocol version".into()));
+        }
+        // HELLO 可以顺带登录 没有登录又没带 AUTH 的话什么都不改
+        match &self.auth {
+            Some((username, password)) => {
+                if let Err(msg) = login(username, password) {
+                    return Ok(Frame::Error(msg));
+                }
+            }
+            None if CONN_STATE.with(|state| state.user.borrow().is_none()) => {
+                return Ok(Frame::Error(
+                    "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"
+                        .into(),
+                ));
+            }
+            None => {}
+        }
+        let (client_id, protocol) = CONN_STATE.with(|state| {
+            if let Some(name) = &self.setname {
+                *state.client_name.borrow_mut() = Some(name.clone());
+            }
+            // 切换之后 这条 HELLO 自己的回复就已经按新协议发出去了
+            if let Some(protover) = self.protover {
+                state.protocol.set(protover as u8);
+            }
+            (state.client_id, state.protocol.get())
+        });
+        CLIENTS.refresh();
+        TRACKING.set_protocol(client_id, protocol);
+        let role = if REPLICATION.is_replica() { "replica" } else { "master" };
+        Ok(Frame::Map(vec![
+            (bulk("server"), bulk("kv")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Frame::Integer(protocol as i64)),
+            (bulk("id"), Frame::Integer(client_id as i64)),
+            (bulk("mode"), bulk("standalone")),
+            (bulk("role"), bulk(role)),
+            (bulk("modules"), Frame::Array(vec![])),
+        ]))
+    }
+}
+
+fn bulk(s: &'static str) -> Frame {
+    Frame::Bulk(Bytes::from_static(s.as_bytes()))
+}
+
+// 每个频道各回一条 外面按 Array 拆开一条一条写
+impl CommandExecutor for SubscribeCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let client_id = CONN_STATE.with(|state| state.client_id);
+        let counts = TRACKING.subscribe(client_id, &self.channels);
+        Ok(Frame::Array(
+            self.channels
+                .iter()
+                .zip(counts)
+                .map(|(channel, count)| subscription_reply("subscribe", Some(channel), count))
+                .collect(),
+        ))
+    }
+}
+
+impl CommandExecutor for UnsubscribeCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let client_id = CONN_STATE.with(|state| state.client_id);
+        Ok(Frame::Array(
+            TRACKING
+                .unsubscribe(client_id, &self.channels)
+                .into_iter()
+                .map(|(channel, count)| subscription_reply("unsubscribe", channel.as_deref(), count))
+                .collect(),
+        ))
+    }
+}
+
+// RESP3 是推送 RESP2 序列化的时候就是普通数组
+fn subscription_reply(kind: &'static str, channel: Option<&str>, count: usize) -> Frame {
+    Frame::Push(vec![
+        bulk(kind),
+        channel.map_or(Frame::Null, |channel| Frame::Bulk(Bytes::copy_from_slice(channel.as_bytes()))),
+        Frame::Integer(count as i64),
+    ])
+}
@@ -0,0 +1,129 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor},
+    context::CONN_STATE,
+    core_aof::AOF_OFFSET,
+    core_client::unless_killed,
+    core_replication::REPLICATION,
+    core_time::get_cached_time_ms,
+    db::lock_plan::LockedShards,
+    error::{DelCommand, Frame, KvError, PExpireAtCommand, RenameCommand, WaitAofCommand},
+};
+
+impl CommandExecutor for DelCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let mut deleted = 0;
+        if let Some(view) = db_lock {
+            for key in &self.keys {
+                if let Some(map) = view.write(key) {
+                    // select 会顺带把过期的 key 清掉 过期的不算删除成功
+                    if map.select(key).await.is_some() {
+                        map.delete(key).await;
+                        deleted += 1;
+                    }
+                }
+            }
+        }
+        Ok(Frame::Integer(deleted))
+    }
+}
+
+impl CommandExecutor for RenameCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(view) = db_lock else {
+            return Ok(Frame::Error("ERR no such key".into()));
+        };
+        // 两个 key 可能在同一个分片 也可能不在 视图里都已经按顺序锁好了
+        let entry = match view.write(&self.key) {
+            Some(map) => map.select(&self.key).await.cloned(),
+            None => None,
+        };
+        let Some(entry) = entry else {
+            return Ok(Frame::Error("ERR no such key".into()));
+        };
+        if self.key == self.new_key {
+            return Ok(Frame::Simple("OK".into()));
+        }
+        if let Some(map) = view.write(&self.key) {
+            map.delete(&self.key).await;
+        }
+        // 过期时间跟着值一起搬过去
+        if let Some(map) = view.write(&self.new_key) {
+            map.insert(self.new_key.clone(), entry).await;
+        }
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+impl CommandExecutor for PExpireAtCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(Frame::Integer(0));
+        };
+        let Some(mut entry) = map.select(&self.key).await.cloned() else {
+            return Ok(Frame::Integer(0));
+        };
+        // 时间点已经过了 和 redis 一样直接删掉
+        if self.timestamp_ms <= get_cached_time_ms() as i64 {
+            map.delete(&self.key).await;
+            return Ok(Frame::Integer(1));
+        }
+        entry.expires_at = Some(self.timestamp_ms as u64);
+        map.insert(self.key.clone(), entry).await;
+        Ok(Frame::Integer(1))
+    }
+}
+
+impl CommandExecutor for WaitAofCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        if REPLICATION.is_replica() {
+            return Ok(Frame::Error(
+                "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+                    .into(),
+            ));
+        }
+        // replica 只回 ACK 复制偏移量 不会告诉主节点它 fsync 到了哪里
+        if self.numreplicas > 0 {
+            return Ok(Frame::Error(
+                "ERR WAITAOF numreplicas is not supported, replicas don't report their fsynced offset".into(),
+            ));
+        }
+        // 这个连接还没写过东西就是 0 一定已经落盘了
+        let offset = CONN_STATE.with(|state| state.aof_offset.load(Ordering::SeqCst));
+        let fsynced = if self.numlocal > 0 {
+            let timeout = (self.timeout_ms > 0).then(|| Duration::from_millis(self.timeout_ms));
+            // 超时为 0 会一直等 被 CLIENT KILL 了就不等了 连接马上会断开
+            match unless_killed(AOF_OFFSET.wait_fsynced(offset, timeout)).await {
+                Some(Ok(fsynced)) => fsynced,
+                // AOF 写坏了 这条写入再也不会落盘了
+                Some(Err(msg)) => {
+                    return Ok(Frame::Error(format!("MISCONF Errors writing to the AOF file: {}", msg)));
+                }
+                None => false,
+            }
+        } else {
+            AOF_OFFSET.fsynced() >= offset
+        };
+        Ok(Frame::Array(vec![
+            Frame::Integer(fsynced as i64),
+            Frame::Integer(0),
+        ]))
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor, WRONGTYPE, not_locked, element_reply},
+    core_tracking::TRACKING,
+    db::lock_plan::LockedShards,
+    error::{Frame, HGetAllCommand, HGetCommand, HSetCommand, KvError},
+    types::{Element, Value, ValueEntry},
+};
+
+impl CommandExecutor for HSetCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(not_locked(&self.key));
+        };
+        if matches!(map.select(&self.key).await, Some(entry) if !matches!(entry.data, Value::Hash(_))) {
+            return Ok(Frame::Error(WRONGTYPE.into()));
+        }
+        // 哈希是整体存的 拿出来原地改完再 insert 回去 内存统计和失效通知都走 insert 里的逻辑
+        let (mut hash, expires_at) = match map.take(&self.key).await {
+            Some(ValueEntry {
+                data: Value::Hash(hash),
+                expires_at,
+                ..
+            }) => (hash, expires_at),
+            _ => (HashMap::new(), None),
+        };
+        let mut added = 0;
+        for (field, value) in &self.pairs {
+            if hash
+                .insert(field.clone(), Element::String(value.clone()))
+                .is_none()
+            {
+                added += 1;
+            }
+        }
+        map.insert(self.key.clone(), ValueEntry::new(Value::Hash(hash), expires_at))
+            .await;
+        Ok(Frame::Integer(added))
+    }
+}
+
+impl CommandExecutor for HGetCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let reply = match db_lock.and_then(|view| view.read(&self.key)) {
+            Some(map) => match map.select(&self.key).await.map(|entry| &entry.data) {
+                Some(Value::Hash(hash)) => hash.get(&self.field).map_or(Frame::Null, element_reply),
+                Some(_) => return Ok(Frame::Error(WRONGTYPE.into())),
+                None => Frame::Null,
+            },
+            None => Frame::Null,
+        };
+        TRACKING.track_read(&self.key);
+        Ok(reply)
+    }
+}
+
+impl CommandExecutor for HGetAllCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        // RESP3 的连接直接拿到 Map RESP2 在序列化的时候会平铺成数组
+        let reply = match db_lock.and_then(|view| view.read(&self.key)) {
+            Some(map) => match map.select(&self.key).await.map(|entry| &entry.data) {
+                Some(Value::Hash(hash)) => Frame::Map(
+                    hash.iter()
+                        .map(|(field, value)| {
+                            (
+                                Frame::Bulk(Bytes::copy_from_slice(field.as_bytes())),
+                                element_reply(value),
+                            )
+                        })
+                        .collect(),
+                ),
+                Some(_) => return Ok(Frame::Error(WRONGTYPE.into())),
+                None => Frame::Map(vec![]),
+            },
+            None => Frame::Map(vec![]),
+        };
+        TRACKING.track_read(&self.key);
+        Ok(reply)
+    }
+}
@@ -0,0 +1,36 @@
+use std::collections::VecDeque;
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor, WRONGTYPE, not_locked, bytes_to_element},
+    db::lock_plan::LockedShards,
+    error::{Frame, KvError, RPushCommand},
+    types::{Value, ValueEntry},
+};
+
+impl CommandExecutor for RPushCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(not_locked(&self.key));
+        };
+        if matches!(map.select(&self.key).await, Some(entry) if !matches!(entry.data, Value::List(_))) {
+            return Ok(Frame::Error(WRONGTYPE.into()));
+        }
+        let (mut list, expires_at) = match map.take(&self.key).await {
+            Some(ValueEntry {
+                data: Value::List(list),
+                expires_at,
+                ..
+            }) => (list, expires_at),
+            _ => (VecDeque::new(), None),
+        };
+        list.extend(self.values.iter().map(bytes_to_element));
+        let len = list.len();
+        map.insert(self.key.clone(), ValueEntry::new(Value::List(list), expires_at))
+            .await;
+        Ok(Frame::Integer(len as i64))
+    }
+}
@@ -3,10 +3,19 @@ use bytes::Bytes;
 use itoa::Buffer;
 
 use crate::{
-    context::ConnectionContent, core_time::get_cached_time_ms, db::{Db, LockedDb}, error::{Frame, KvError}
+    context::ConnectionContent, core_time::get_cached_time_ms, db::{Db, lock_plan::LockedShards}, error::{Command, Expiration, Frame, KvError}, types::Element
 };
+ mod acl;
+ mod client;
  mod common;
+ mod connection;
+ mod generic;
+ mod hash;
+ mod list;
+ mod set;
+ mod server;
  mod string;
+ mod zset;
  #[derive(Clone)]
 pub struct CommandContext {
     pub db: Option<Db>,
@@ -20,7 +29,8 @@ pub trait CommandExecutor {
         &self,
         // ✅ 核心改动：从 &mut CommandContext 变成了 &CommandContext
         ctx:  CommandContext,
-        db_lock: Option<& mut LockedDb>
+        // 锁规划器按 key_specs 拿好的多分片视图
+        db_lock: Option<& mut LockedShards>
     ) -> impl std::future::Future<Output = Result<Frame, KvError>> + Send ;
 
     // “原语”方法
@@ -40,15 +50,63 @@ pub trait CommandExecutor {
     // }
 }
 // 修正后的方法，返回一个可以存储的u64相对时间戳
+// SET 解析的时候已经把放不下的拒掉了 这里兜个底 不会溢出
 pub fn calculate_expiration_timestamp_ms(expiration: &crate::error::Expiration) -> u64 {
-    let now = get_cached_time_ms();
-    match expiration {
-        crate::error::Expiration::PX(ms) => now + ms,
-        crate::error::Expiration::EX(s) => now + s * 1000,
-        crate::error::Expiration::EXAT(s) => *s,
-        crate::error::Expiration::PXAT(ms) => *ms,
+    checked_expiration_timestamp_ms(expiration).unwrap_or(i64::MAX as u64)
+}
+
+/// 和 redis 一样 换算成毫秒的绝对时间要放得进 i64 放不下就是 None
+pub fn checked_expiration_timestamp_ms(expiration: &crate::error::Expiration) -> Option<u64> {
+    let now = get_cached_time_ms() as i64;
+    let at = match *expiration {
+        crate::error::Expiration::PX(ms) => i64::try_from(ms).ok()?.checked_add(now),
+        crate::error::Expiration::EX(s) => i64::try_from(s).ok()?.checked_mul(1000)?.checked_add(now),
+        crate::error::Expiration::EXAT(s) => i64::try_from(s).ok()?.checked_mul(1000),
+        crate::error::Expiration::PXAT(ms) => i64::try_from(ms).ok(),
+    }?;
+    Some(at as u64)
+}
+
+impl Command {
+    /// 相对的过期时间在执行之前就换成绝对的 PXAT
+    /// 执行和写 AOF 用的是同一个时间 重放出来的过期时间和原来一模一样
+    pub fn pin_expiration(self) -> Command {
+        match self {
+            Command::Set(mut set) => {
+                set.expiration = set
+                    .expiration
+                    .as_ref()
+                    .map(|expiration| Expiration::PXAT(calculate_expiration_timestamp_ms(expiration)));
+                Command::Set(set)
+            }
+            command => command,
+        }
     }
 }
+// 各个类型的命令碰到别的类型的 key 都回这个
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+// 拿不到 key 的写锁说明命令表里的 key 声明和执行器对不上 不能假装写成功了
+fn not_locked(key: &str) -> Frame {
+    Frame::Error(format!("ERR key '{}' is not locked for write by this command", key))
+}
+
+// 集合里的元素回给客户端 整数也按字符串回
+fn element_reply(element: &Element) -> Frame {
+    match element {
+        Element::String(bytes) => Frame::Bulk(bytes.clone()),
+        Element::Int(i) => Frame::Bulk(parse_int_from_bytes(*i)),
+    }
+}
+
+// 能当整数存的就当整数存 和 SET 以及 RDB 导入进来的一样 集合里同一个成员才不会存成两份
+fn bytes_to_element(bytes: &Bytes) -> Element {
+    match bytes_to_i64_fast(bytes) {
+        Some(i) => Element::Int(i),
+        None => Element::String(bytes.clone()),
+    }
+}
+
 //高效的int 转byte 方法
 pub fn parse_int_from_bytes(i: i64) -> Bytes {
     let mut buffer = Buffer::new();
@@ -0,0 +1,230 @@
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor},
+    config::CONFIG,
+    context::CONN_STATE,
+    core_aof::AOF_OFFSET,
+    core_aof_rewrite::AOF_REWRITE,
+    core_loading::LOADING,
+    core_rdb::write_rdb,
+    core_replication::REPLICATION,
+    core_snapshot::SNAPSHOT,
+    db::lock_plan::LockedShards,
+    error::{
+        BgRewriteAofCommand, BgSaveCommand, DebugCommand, DebugSubCommand, Frame, InfoCommand,
+        KvError, LastSaveCommand, PsyncCommand, ReplconfCommand, ReplconfOption, ReplicaOfCommand,
+        SaveCommand,
+    },
+};
+
+impl CommandExecutor for BgRewriteAofCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(db) = ctx.db else {
+            return Ok(Frame::Error("ERR BGREWRITEAOF needs the database".into()));
+        };
+        Ok(match AOF_REWRITE.start(db) {
+            Ok(()) => Frame::Simple("Background append only file rewriting started".into()),
+            Err(msg) => Frame::Error(msg),
+        })
+    }
+}
+
+impl CommandExecutor for SaveCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(db) = ctx.db else {
+            return Ok(Frame::Error("ERR SAVE needs the database".into()));
+        };
+        Ok(match SNAPSHOT.save(&db).await {
+            Ok(()) => Frame::Simple("OK".into()),
+            Err(msg) => Frame::Error(msg),
+        })
+    }
+}
+
+impl CommandExecutor for BgSaveCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(db) = ctx.db else {
+            return Ok(Frame::Error("ERR BGSAVE needs the database".into()));
+        };
+        Ok(match SNAPSHOT.start_background(db, self.schedule) {
+            Ok(true) => Frame::Simple("Background saving started".into()),
+            Ok(false) => Frame::Simple("Background saving scheduled".into()),
+            Err(msg) => Frame::Error(msg),
+        })
+    }
+}
+
+impl CommandExecutor for LastSaveCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        Ok(Frame::Integer(SNAPSHOT.lastsave() as i64))
+    }
+}
+
+impl CommandExecutor for DebugCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        if !CONFIG.enable_debug_command {
+            return Ok(Frame::Error(
+                "ERR DEBUG command not allowed. If the enable-debug-command option is set to \"no\", you can not run this command".into(),
+            ));
+        }
+        let Some(db) = ctx.db else {
+            return Ok(Frame::Error("ERR DEBUG needs the database".into()));
+        };
+        match &self.sub {
+            // 和 SAVE 一样在当前连接里导出完再回复 别的连接照常读写
+            DebugSubCommand::ExportRdb(path) => Ok(match write_rdb(&db, Path::new(path)).await {
+                Ok(exported) => {
+                    tracing::info!("导出 RDB 到 {} 一共 {} 个 key", path, exported);
+                    Frame::Simple("OK".into())
+                }
+                Err(e) => Frame::Error(format!("ERR Error exporting RDB to {}: {}", path, e)),
+            }),
+        }
+    }
+}
+
+impl CommandExecutor for ReplicaOfCommand {
+    async fn execute(
+        &self,
+        ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let (Some(db), Some(content)) = (ctx.db, ctx.connect_content) else {
+            return Ok(Frame::Error("ERR REPLICAOF needs the database".into()));
+        };
+        Ok(Frame::Simple(
+            REPLICATION.replica_of(self.master.clone(), db, content).await,
+        ))
+    }
+}
+
+impl CommandExecutor for ReplconfCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        // 握手阶段只记一下端口 ACK 在复制流的连接里单独处理
+        for option in &self.options {
+            if let ReplconfOption::ListeningPort(port) = option {
+                CONN_STATE.with(|state| state.replica_port.set(Some(*port)));
+            }
+        }
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+impl CommandExecutor for PsyncCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        // 正常的 RESP 连接在执行之前就转去发复制流了 走到这里的是 HTTP 这种
+        Ok(Frame::Error("ERR PSYNC is only supported on RESP connections".into()))
+    }
+}
+
+type InfoSection = (&'static str, fn() -> String);
+
+// INFO 的各个段 段名和 redis 一样 按顺序输出
+const SECTIONS: &[InfoSection] = &[
+    ("persistence", persistence_section),
+    ("replication", replication_section),
+];
+
+impl CommandExecutor for InfoCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        _db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let everything = self.sections.is_empty()
+            || self
+                .sections
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "everything" | "default"));
+        let info = SECTIONS
+            .iter()
+            .filter(|(name, _)| everything || self.sections.iter().any(|section| section == name))
+            .map(|(_, section)| section())
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        Ok(Frame::Verbatim("txt".into(), Bytes::from(info)))
+    }
+}
+
+fn persistence_section() -> String {
+    let snapshot = SNAPSHOT.status();
+    let rewrite = AOF_REWRITE.status();
+    let loading = LOADING.status();
+    let aof = AOF_OFFSET.status();
+    let seconds = |time: Option<std::time::Duration>| time.map_or(-1, |time| time.as_secs() as i64);
+    let status = |ok: bool| if ok { "ok" } else { "err" };
+    let mut lines = vec![
+        "# Persistence".to_string(),
+        format!("loading:{}", loading.loading as u8),
+    ];
+    // 和 redis 一样 加载进度只在加载的时候才有
+    if loading.loading {
+        lines.extend([
+            format!("loading_start_time:{}", loading.start_time),
+            format!("loading_total_bytes:{}", loading.total_bytes),
+            format!("loading_loaded_bytes:{}", loading.loaded_bytes),
+            format!("loading_loaded_perc:{:.2}", loading.loaded_perc()),
+            format!("loading_eta_seconds:{}", loading.eta_secs()),
+        ]);
+    }
+    lines.extend([
+        format!("rdb_changes_since_last_save:{}", snapshot.changes),
+        format!("rdb_bgsave_in_progress:{}", snapshot.in_progress as u8),
+        format!("rdb_last_save_time:{}", snapshot.lastsave),
+        format!("rdb_last_bgsave_status:{}", status(snapshot.last_ok)),
+        format!("rdb_last_bgsave_time_sec:{}", seconds(snapshot.last_time)),
+        format!("rdb_current_bgsave_time_sec:{}", seconds(snapshot.current_time)),
+        format!("rdb_saves:{}", snapshot.saves),
+        "aof_enabled:1".to_string(),
+        format!("aof_rewrite_in_progress:{}", rewrite.in_progress as u8),
+        format!("aof_rewrite_scheduled:{}", rewrite.scheduled as u8),
+        format!("aof_last_rewrite_time_sec:{}", seconds(rewrite.last_time)),
+        format!("aof_current_rewrite_time_sec:{}", seconds(rewrite.current_time)),
+        format!("aof_last_bgrewrite_status:{}", status(rewrite.last_ok)),
+        format!("aof_rewrites:{}", rewrite.rewrites),
+        format!("aof_current_size:{}", rewrite.current_size),
+        format!("aof_base_size:{}", rewrite.base_size),
+        format!("aof_last_write_status:{}", status(aof.last_write_ok)),
+        format!("aof_delayed_fsync:{}", aof.delayed_fsync),
+        format!("aof_fsyncs:{}", aof.fsyncs),
+        format!("aof_fsync_errors:{}", aof.fsync_errors),
+        format!("aof_last_fsync_usec:{}", aof.last_fsync_usec),
+        format!("aof_max_fsync_usec:{}", aof.max_fsync_usec),
+    ]);
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+fn replication_section() -> String {
+    REPLICATION.info()
+}
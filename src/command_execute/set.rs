@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+
+use crate::{
+    command_execute::{CommandContext, CommandExecutor, WRONGTYPE, not_locked, bytes_to_element},
+    db::lock_plan::LockedShards,
+    error::{Frame, KvError, SAddCommand},
+    types::{Value, ValueEntry},
+};
+
+impl CommandExecutor for SAddCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(not_locked(&self.key));
+        };
+        if matches!(map.select(&self.key).await, Some(entry) if !matches!(entry.data, Value::Set(_))) {
+            return Ok(Frame::Error(WRONGTYPE.into()));
+        }
+        let (mut set, expires_at) = match map.take(&self.key).await {
+            Some(ValueEntry {
+                data: Value::Set(set),
+                expires_at,
+                ..
+            }) => (set, expires_at),
+            _ => (HashSet::new(), None),
+        };
+        let mut added = 0;
+        for member in &self.members {
+            if set.insert(bytes_to_element(member)) {
+                added += 1;
+            }
+        }
+        map.insert(self.key.clone(), ValueEntry::new(Value::Set(set), expires_at))
+            .await;
+        Ok(Frame::Integer(added))
+    }
+}
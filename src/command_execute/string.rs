@@ -2,30 +2,40 @@ use bytes::Bytes;
 
 use crate::{
     command_execute::{
-        CommandContext, CommandExecutor, bytes_to_i64_fast, calculate_expiration_timestamp_ms,
-        parse_int_from_bytes,
-    }, db::LockedDb, error::{Frame, GetCommand, KvError, SetCommand}, types::{Element, Value, ValueEntry}
+        CommandContext, CommandExecutor, WRONGTYPE, bytes_to_i64_fast, calculate_expiration_timestamp_ms,
+        not_locked, parse_int_from_bytes,
+    }, core_keyspec::{KeyAccess, KeyRef}, core_tracking::TRACKING, db::lock_plan::LockedShards, error::{Frame, GetCommand, KvError, MGetCommand, MSetCommand, SetCommand}, types::{Element, Value, ValueEntry}
 };
 
+// 字符串的值 能当整数存的就当整数存
+fn string_value_entry(value: &Bytes, expires_at: Option<u64>) -> ValueEntry {
+    match bytes_to_i64_fast(value) {
+        Some(i) => ValueEntry::new(Value::Simple(Element::Int(i)), expires_at),
+        None => ValueEntry::new(Value::Simple(Element::String(value.clone())), expires_at),
+    }
+}
+
+// 把存储里的值还原成回复 不是字符串类型返回 None GET 回 WRONGTYPE MGET 当作不存在
+fn string_reply(entry: Option<&ValueEntry>) -> Option<Frame> {
+    match entry.map(|entry| &entry.data) {
+        None => Some(Frame::Null),
+        Some(Value::Simple(Element::String(bytes))) => Some(Frame::Bulk(bytes.clone())),
+        //性能优化
+        Some(Value::Simple(Element::Int(i))) => Some(Frame::Bulk(parse_int_from_bytes(*i))),
+        Some(_) => None,
+    }
+}
+
 impl CommandExecutor for SetCommand {
     // 必须在这里也加上 <'ctx> 和对应的生命周期标注
-    async fn execute(&self, ctx: CommandContext,db_lock: Option<& mut LockedDb>) -> Result<Frame, KvError> {
-        let time_expire_u64: Option<u64>;
-        let time_expire = if let Some(expire) = &self.expiration {
-            time_expire_u64 = Some(calculate_expiration_timestamp_ms(expire));
-            time_expire_u64
-        } else {
-            None
-        };
+    async fn execute(&self, _ctx: CommandContext,db_lock: Option<& mut LockedShards>) -> Result<Frame, KvError> {
+        let time_expire = self.expiration.as_ref().map(calculate_expiration_timestamp_ms);
         //再这里创建value
-        let value_obj = match bytes_to_i64_fast(&self.value) {
-            Some(i) => ValueEntry::new(Value::Simple(Element::Int(i)), time_expire),
-            None => ValueEntry::new(Value::Simple(Element::String(self.value.clone())), time_expire),
+        let value_obj = string_value_entry(&self.value, time_expire).with_flags(self.flags);
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(not_locked(&self.key));
         };
-        //ctx.db_lock.unwrap().set_string(self.key.clone(), value_obj);
-        if let Some(LockedDb::Write(  map)) = db_lock {
-            map.insert(self.key.clone(), value_obj).await;
-        }
+        map.insert(self.key.clone(), value_obj).await;
         Ok(Frame::Simple("OK".to_string()))
     }
 }
@@ -35,29 +45,198 @@ impl CommandExecutor for GetCommand {
         &self,
         // 2. 将这个生命周期 'ctx 应用到 CommandContext 的引用上
         _ctx: CommandContext,
-        db_lock: Option<& mut LockedDb>
+        db_lock: Option<& mut LockedShards>
+    ) -> Result<Frame, KvError> {
+        let reply = match db_lock.and_then(|view| view.read(&self.key)) {
+            Some(map) => string_reply(map.select(&self.key).await)
+                .unwrap_or_else(|| Frame::Error(WRONGTYPE.into())),
+            None => Frame::Null,
+        };
+        // 客户端缓存：不管 key 在不在都要记下来 之后写入时才能通知到
+        TRACKING.track_read(&self.key);
+        Ok(reply)
+    }
+}
+
+impl CommandExecutor for MGetCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(view) = db_lock else {
+            return Ok(Frame::Array(vec![Frame::Null; self.keys.len()]));
+        };
+        let mut replies = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            let reply = match view.read(key) {
+                Some(map) => string_reply(map.select(key).await).unwrap_or(Frame::Null),
+                None => Frame::Null,
+            };
+            TRACKING.track_read(key);
+            replies.push(reply);
+        }
+        Ok(Frame::Array(replies))
+    }
+}
+
+impl CommandExecutor for MSetCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
     ) -> Result<Frame, KvError> {
-        let value= if let Some(LockedDb::Read( map)) = db_lock {
-            map.select(&self.key.clone()).await
-        }else {
-            None
-        };
-        match value {
-            Some(entry) => {
-                let data = entry.data.clone();
-
-                //这是处理字符串的方法
-                match data {
-                    Value::Simple(Element::String(bytes)) => Ok(Frame::Bulk(bytes)),
-                    //性能优化
-                    Value::Simple(Element::Int(i)) => {
-                        let bytes = parse_int_from_bytes(i);
-                        Ok(Frame::Bulk(Bytes::from(bytes)))
-                    }
-                    _ => Ok(Frame::Null), // 如果不是字符串类型，返回 Null
-                }
+        let Some(view) = db_lock else {
+            return Ok(not_locked(&self.pairs[0].0));
+        };
+        // 先确认每个 key 都拿到了写锁 不能写了一半再报错
+        if let Some((key, _)) = self.pairs.iter().find(|(key, _)| {
+            !view.covers(&KeyRef {
+                key: key.to_string(),
+                access: KeyAccess::Write,
+            })
+        }) {
+            return Ok(not_locked(key));
+        }
+        // 所有 key 的分片锁都已经拿到了 对外表现就是原子的
+        for (key, value) in &self.pairs {
+            if let Some(map) = view.write(key) {
+                map.insert(key.clone(), string_value_entry(value, None)).await;
             }
-            None => Ok(Frame::Null),
         }
+        Ok(Frame::Simple("OK".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+    use crate::{
+        aof_exchange::argv_to_frame,
+        command_exchange::CommandArgv,
+        config::EvictionType,
+        context::{CONN_STATE, ConnectionState},
+        core_execute::{execute_command, execute_command_hook},
+        db::{Db, eviction::MemoryCache, lock_plan::LockPlan},
+        error::{Command, SetCondition},
+    };
+
+    fn command(args: &[&str]) -> Command {
+        let frames = args
+            .iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect();
+        Command::try_from(Frame::Array(frames)).unwrap()
+    }
+
+    #[test]
+    fn test_set_expire_out_of_range() {
+        crate::core_time::refresh_cached_time();
+        let parse = |args: &[&str]| {
+            let frames = args
+                .iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect();
+            Command::try_from(Frame::Array(frames))
+        };
+        let max = i64::MAX.to_string();
+        // 换算成毫秒会溢出的和 redis 一样拒掉
+        for unit in ["EX", "EXAT", "PX"] {
+            let err = parse(&["SET", "k", "v", unit, &max]).unwrap_err();
+            assert_eq!(err.to_string(), "ERR invalid expire time in 'set' command");
+        }
+        // 刚好放得下的可以
+        let largest = (i64::MAX / 1000).to_string();
+        let Ok(Command::Set(set)) = parse(&["SET", "k", "v", "EXAT", &largest]) else {
+            panic!("EXAT {} 应该能解析", largest);
+        };
+        let expires_at = set.expiration.as_ref().map(calculate_expiration_timestamp_ms);
+        assert_eq!(expires_at, Some((i64::MAX / 1000 * 1000) as u64));
+        assert!(matches!(parse(&["SET", "k", "v", "PXAT", &max]), Ok(Command::Set(_))));
+    }
+
+    #[test]
+    fn test_mcflags_only_from_replay() {
+        let frame = |args: &[&str]| {
+            Frame::Array(
+                args.iter()
+                    .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                    .collect(),
+            )
+        };
+        // 客户端发来的 SET 不认 MCFLAGS
+        assert!(Command::try_from(frame(&["SET", "k", "v", "MCFLAGS", "7"])).is_err());
+        // AOF 和复制流里的可以 放在别的选项中间也行
+        let replayed = Command::from_replay(frame(&["SET", "k", "v", "PXAT", "4102444800000", "MCFLAGS", "7", "NX"]));
+        let Ok(Command::Set(set)) = replayed else {
+            panic!("重放的 SET 应该能解析");
+        };
+        assert_eq!(set.flags, 7);
+        assert!(matches!(set.condition, Some(SetCondition::NX)));
+        assert!(set.expiration.is_some());
+        // 还原回去的 argv 还能重放出同样的 flags
+        let Ok(Command::Set(again)) = Command::from_replay(argv_to_frame(set.to_argv())) else {
+            panic!("还原的 argv 应该能重放");
+        };
+        assert_eq!(again.flags, 7);
+        // key 和 value 叫 MCFLAGS 不是选项
+        let Ok(Command::Set(set)) = Command::from_replay(frame(&["SET", "MCFLAGS", "MCFLAGS"])) else {
+            panic!("key 叫 MCFLAGS 也是普通的 SET");
+        };
+        assert_eq!((set.key.as_str(), set.flags), ("MCFLAGS", 0));
+        assert!(Command::from_replay(frame(&["SET", "k", "v", "MCFLAGS"])).is_err());
+        assert!(Command::from_replay(frame(&["SET", "k", "v", "MCFLAGS", "-1"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_string_commands_on_other_types() {
+        let db = Db::new(&EvictionType::LRU);
+        let hash = HashMap::from([("f".to_string(), Element::Int(1))]);
+        db.store
+            .restore(0, Arc::new("h".into()), ValueEntry::new(Value::Hash(hash), None))
+            .await;
+        CONN_STATE
+            .scope(ConnectionState::new(0, None, 1), async {
+                // GET 一个 hash 要报错 不能当作不存在
+                let get = execute_command(&command(&["GET", "h"]), &db).await.unwrap();
+                assert_eq!(get, Frame::Error(WRONGTYPE.into()));
+                // MGET 和 redis 一样 别的类型回 nil
+                let mget = execute_command(&command(&["MGET", "h", "nosuch"]), &db).await.unwrap();
+                assert_eq!(mget, Frame::Array(vec![Frame::Null, Frame::Null]));
+                // SET 直接覆盖
+                let set = execute_command(&command(&["SET", "h", "v"]), &db).await.unwrap();
+                assert_eq!(set, Frame::Simple("OK".into()));
+                let get = execute_command(&command(&["GET", "h"]), &db).await.unwrap();
+                assert_eq!(get, Frame::Bulk(Bytes::from_static(b"v")));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_set_without_lock() {
+        let db = Db::new(&EvictionType::LRU);
+        // 找一个和 a 不在同一个分片的 key
+        let other = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| MemoryCache::get_shard_index(&key.as_str()) != MemoryCache::get_shard_index(&"a"))
+            .unwrap();
+        CONN_STATE
+            .scope(ConnectionState::new(0, None, 1), async {
+                // 没拿到写锁的话要报错 不能回 OK 却什么都没写
+                let set = command(&["SET", "a", "1"]);
+                let reply = execute_command_hook(&set, None, None, None).await.unwrap();
+                assert!(matches!(reply, Frame::Error(msg) if msg.contains("not locked")));
+                // 只锁了 a 的分片 MSET 一个都不能写
+                let mset = command(&["MSET", "a", "1", &other, "2"]);
+                let mut view = db.store.lock_plan(&LockPlan::for_command(&set)).await;
+                let reply = execute_command_hook(&mset, None, None, Some(&mut view)).await.unwrap();
+                drop(view);
+                assert!(matches!(reply, Frame::Error(msg) if msg.contains(other.as_str())));
+                let get = execute_command(&command(&["GET", "a"]), &db).await.unwrap();
+                assert_eq!(get, Frame::Null);
+            })
+            .await;
     }
 }
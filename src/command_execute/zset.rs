@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use crate::{
+    command_execute::{
+        CommandContext, CommandExecutor, WRONGTYPE, not_locked, bytes_to_element, element_reply, parse_int_from_bytes,
+    },
+    context::CONN_STATE,
+    core_tracking::TRACKING,
+    db::lock_plan::LockedShards,
+    error::{Frame, KvError, ZAddCommand, ZRangeCommand},
+    types::{Element, Value, ValueEntry},
+};
+
+// 和 redis 一样 先按分数排 分数一样的按成员的字节序排
+fn sorted_members(zset: &HashMap<Element, f64>) -> Vec<(&Element, f64)> {
+    let member_bytes = |member: &Element| match member {
+        Element::String(bytes) => bytes.clone(),
+        Element::Int(i) => parse_int_from_bytes(*i),
+    };
+    let mut members: Vec<(&Element, f64)> = zset.iter().map(|(member, score)| (member, *score)).collect();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| member_bytes(a.0).cmp(&member_bytes(b.0))));
+    members
+}
+
+// 负数从后往前数 越界的收到两头 区间是空的返回 None
+fn range_bounds(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
+
+impl CommandExecutor for ZAddCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let Some(map) = db_lock.and_then(|view| view.write(&self.key)) else {
+            return Ok(not_locked(&self.key));
+        };
+        if matches!(map.select(&self.key).await, Some(entry) if !matches!(entry.data, Value::ZSet(_))) {
+            return Ok(Frame::Error(WRONGTYPE.into()));
+        }
+        let (mut zset, expires_at) = match map.take(&self.key).await {
+            Some(ValueEntry {
+                data: Value::ZSet(zset),
+                expires_at,
+                ..
+            }) => (zset, expires_at),
+            _ => (HashMap::new(), None),
+        };
+        // 已经在里面的只更新分数 不算新加的
+        let mut added = 0;
+        for (score, member) in &self.members {
+            if zset.insert(bytes_to_element(member), *score).is_none() {
+                added += 1;
+            }
+        }
+        map.insert(self.key.clone(), ValueEntry::new(Value::ZSet(zset), expires_at))
+            .await;
+        Ok(Frame::Integer(added))
+    }
+}
+
+impl CommandExecutor for ZRangeCommand {
+    async fn execute(
+        &self,
+        _ctx: CommandContext,
+        db_lock: Option<&mut LockedShards>,
+    ) -> Result<Frame, KvError> {
+        let reply = match db_lock.and_then(|view| view.read(&self.key)) {
+            Some(map) => match map.select(&self.key).await.map(|entry| &entry.data) {
+                Some(Value::ZSet(zset)) => {
+                    let members = sorted_members(zset);
+                    let range = match range_bounds(self.start, self.stop, members.len()) {
+                        Some((start, stop)) => &members[start..=stop],
+                        None => &[],
+                    };
+                    // RESP3 每个成员和分数是一对 RESP2 平铺开 分数在序列化的时候变成字符串
+                    let resp3 = CONN_STATE.try_with(|state| state.protocol.get() == 3).unwrap_or(false);
+                    let mut frames = Vec::new();
+                    for (member, score) in range {
+                        if !self.with_scores {
+                            frames.push(element_reply(member));
+                        } else if resp3 {
+                            frames.push(Frame::Array(vec![element_reply(member), Frame::Double(*score)]));
+                        } else {
+                            frames.push(element_reply(member));
+                            frames.push(Frame::Double(*score));
+                        }
+                    }
+                    Frame::Array(frames)
+                }
+                Some(_) => return Ok(Frame::Error(WRONGTYPE.into())),
+                None => Frame::Array(vec![]),
+            },
+            None => Frame::Array(vec![]),
+        };
+        TRACKING.track_read(&self.key);
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        config::EvictionType,
+        context::ConnectionState,
+        core_execute::execute_command,
+        db::Db,
+        error::Command,
+    };
+
+    #[test]
+    fn test_range_bounds() {
+        assert_eq!(range_bounds(0, -1, 3), Some((0, 2)));
+        assert_eq!(range_bounds(-2, 10, 3), Some((1, 2)));
+        assert_eq!(range_bounds(-10, 0, 3), Some((0, 0)));
+        assert_eq!(range_bounds(2, 1, 3), None);
+        assert_eq!(range_bounds(3, 5, 3), None);
+        assert_eq!(range_bounds(0, -1, 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_zrange_with_scores() {
+        let zset: HashMap<Element, f64> = [
+            (Element::String(Bytes::from_static(b"b")), 2.5),
+            (Element::String(Bytes::from_static(b"a")), 2.5),
+            (Element::Int(7), 1.0),
+        ]
+        .into_iter()
+        .collect();
+        let db = Db::new(&EvictionType::LRU);
+        db.store
+            .restore(0, Arc::new("z".into()), ValueEntry::new(Value::ZSet(zset), None))
+            .await;
+        db.store
+            .restore(0, Arc::new("s".into()), ValueEntry::new(Value::Simple(Element::Int(1)), None))
+            .await;
+        let zrange = |key: &str, protocol: u8, with_scores: bool| {
+            let state = ConnectionState::new(0, None, 1);
+            state.protocol.set(protocol);
+            let command = Command::ZRange(ZRangeCommand {
+                key: Arc::new(key.into()),
+                start: 0,
+                stop: -1,
+                with_scores,
+            });
+            let db = db.clone();
+            CONN_STATE.scope(state, async move { execute_command(&command, &db).await.unwrap() })
+        };
+        let bulk = |s: &'static str| Frame::Bulk(Bytes::from_static(s.as_bytes()));
+
+        // 分数一样的按成员排
+        assert_eq!(zrange("z", 2, false).await, Frame::Array(vec![bulk("7"), bulk("a"), bulk("b")]));
+        // RESP3 是成员和 double 一对一对的
+        assert_eq!(
+            zrange("z", 3, true).await,
+            Frame::Array(vec![
+                Frame::Array(vec![bulk("7"), Frame::Double(1.0)]),
+                Frame::Array(vec![bulk("a"), Frame::Double(2.5)]),
+                Frame::Array(vec![bulk("b"), Frame::Double(2.5)]),
+            ])
+        );
+        // RESP2 平铺 分数序列化成字符串
+        assert_eq!(
+            zrange("z", 2, true).await.serialize_with(2),
+            b"*6\r\n$1\r\n7\r\n$1\r\n1\r\n$1\r\na\r\n$3\r\n2.5\r\n$1\r\nb\r\n$3\r\n2.5\r\n"
+        );
+        assert_eq!(zrange("missing", 3, true).await, Frame::Array(vec![]));
+        assert_eq!(zrange("s", 2, false).await, Frame::Error(WRONGTYPE.into()));
+    }
+}
@@ -1,20 +1,412 @@
-
 use once_cell::sync::Lazy;
 
 pub struct Config {
     pub eviction_type: EvictionType,
+    // ACL 用户文件 启动时存在就加载 ACL SAVE/LOAD 也是读写它
+    pub aclfile: String,
+    // TCP 监听地址 每个地址都和 port 组合起来绑定 支持 IPv6 比如 ::1
+    pub bind: Vec<String>,
+    // 0 表示让系统随便分配一个端口 启动时会打印实际地址 测试用
+    pub port: u16,
+    // unix domain socket 路径 不配就不监听
+    pub unixsocket: Option<String>,
+    // socket 文件的权限 八进制 比如 700
+    pub unixsocketperm: Option<u32>,
+    // TLS 端口 和 port 一样绑定在每个 bind 地址上 不配就不开 0 同样是系统分配
+    pub tls_port: Option<u16>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // 用来校验客户端证书的 CA
+    pub tls_ca_cert_file: Option<String>,
+    pub tls_auth_clients: TlsAuthClients,
+    // 打开之后 客户端证书的 CN 如果正好是一个 ACL 用户 连上来就直接以这个用户登录
+    pub tls_auth_clients_user: bool,
+    // memcached 文本协议的端口 不配就不开 和 RESP 共用同一份数据 (0 号库)
+    pub memcached_port: Option<u16>,
+    // HTTP/JSON 网关的端口 不配就不开
+    pub http_port: Option<u16>,
+    // AOF 刷盘策略 always / everysec / no 和 redis 的 appendfsync 一样
+    pub appendfsync: AppendFsync,
+    // AOF 的 base/incr 文件和清单都放在这个目录下
+    pub appenddirname: String,
+    // AOF 文件名前缀 老版本的单文件 AOF 也是这个名字 启动的时候会挪进目录
+    pub appendfilename: String,
+    // 最后一个 AOF 文件结尾的命令不完整 (一般是写到一半断电了) yes 就截掉继续启动 no 就拒绝启动
+    pub aof_load_truncated: bool,
+    // AOF 比上次重写之后大了这么多百分比就自动重写 0 表示不自动重写
+    pub auto_aof_rewrite_percentage: u64,
+    // 小于这个大小的 AOF 不自动重写 字节
+    pub auto_aof_rewrite_min_size: u64,
+    // 快照文件 SAVE/BGSAVE 写它 启动的时候 AOF 是空的就加载它
+    pub dbfilename: String,
+    // 启动的时候导入这个 redis 的 RDB 文件 只在 AOF 是空的时候导入 导入完会重写一次 AOF
+    pub import_rdb: Option<String>,
+    // (秒, 改动次数) 任意一条满足就 BGSAVE 和 redis 的 save 一样 save "" 关掉
+    pub save: Vec<(u64, u64)>,
+    // 启动的时候就作为这个主节点的 replica replicaof no one 表示自己就是主节点
+    pub replicaof: Option<(String, u16)>,
+    // 连主节点的时候用的 ACL 用户和密码 主节点要求认证才需要配
+    pub masteruser: Option<String>,
+    pub masterauth: Option<String>,
+    // replica 只读 主节点同步过来的命令除外
+    pub replica_read_only: bool,
+    // 复制积压缓冲区的大小 断开重连的时候落后的部分还在里面就能增量同步 字节
+    pub repl_backlog_size: u64,
+    // 客户端请求里单个参数的最大长度 和 redis 的 proto-max-bulk-len 一样 超过的直接断开
+    pub proto_max_bulk_len: u64,
+    // DEBUG 命令能往任意路径写文件 和 redis 的 enable-debug-command 一样默认关掉
+    pub enable_debug_command: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    // 每批写入都 fsync 客户端的回复等落盘之后才发
+    Always,
+    // 每秒在后台 fsync 一次 最多丢一秒的数据
+    EverySec,
+    // 只写到操作系统 什么时候落盘由系统决定
+    No,
+}
+
+// 客户端证书要求 和 redis 的 tls-auth-clients 一样 默认必须带证书
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsAuthClients {
+    No,
+    Optional,
+    Yes,
 }
+
 pub enum EvictionType {
     LRU,
     LFU,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            eviction_type: EvictionType::LRU, // 这里可以根据需要加载不同的配置
+            aclfile: "users.acl".to_string(),
+            bind: vec!["127.0.0.1".to_string()],
+            port: 6379,
+            unixsocket: None,
+            unixsocketperm: None,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::Yes,
+            tls_auth_clients_user: false,
+            memcached_port: None,
+            http_port: None,
+            appendfsync: AppendFsync::EverySec,
+            appenddirname: "appendonlydir".to_string(),
+            appendfilename: "database.aof".to_string(),
+            aof_load_truncated: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            dbfilename: "dump.kvdb".to_string(),
+            import_rdb: None,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            replicaof: None,
+            masteruser: None,
+            masterauth: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            enable_debug_command: false,
+        }
+    }
+}
+
+impl Config {
+    /*
+       和 redis-server 一样的启动方式
+       1.kv /path/to/kv.conf  第一个参数不是 -- 开头就当配置文件 一行一个 "名字 值..." # 开头是注释
+       2.kv --port 0 --bind 127.0.0.1 ::1 --unixsocket /tmp/kv.sock
+       命令行的配置在文件之后生效 可以覆盖文件里的值
+    */
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut args = args.into_iter().peekable();
+        let mut config = Config::default();
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| format!("读取配置文件 {} 失败: {}", path, e))?;
+            for (line_no, line) in content.lines().enumerate() {
+                let mut parts = line.split_whitespace();
+                let Some(name) = parts.next().filter(|name| !name.starts_with('#')) else {
+                    continue;
+                };
+                let values: Vec<String> = parts.map(String::from).collect();
+                config
+                    .apply(name, &values)
+                    .map_err(|e| format!("配置文件第 {} 行 '{}': {}", line_no + 1, line, e))?;
+            }
+        }
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(format!("无法识别的参数 '{}'", arg));
+            };
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|value| !value.starts_with("--")) {
+                values.push(value);
+            }
+            config
+                .apply(name, &values)
+                .map_err(|e| format!("参数 --{}: {}", name, e))?;
+        }
+        Ok(config)
+    }
+
+    fn apply(&mut self, name: &str, values: &[String]) -> Result<(), String> {
+        let single = || match values {
+            [value] => Ok(value.as_str()),
+            _ => Err("需要且只能有一个值".to_string()),
+        };
+        match name.to_lowercase().as_str() {
+            "bind" => {
+                if values.is_empty() {
+                    return Err("至少需要一个地址".into());
+                }
+                self.bind = values.to_vec();
+            }
+            "port" => self.port = single()?.parse().map_err(|_| "端口不合法")?,
+            "unixsocket" => self.unixsocket = Some(single()?.to_string()),
+            "unixsocketperm" => {
+                let perm = u32::from_str_radix(single()?, 8).map_err(|_| "权限必须是八进制数字")?;
+                self.unixsocketperm = Some(perm);
+            }
+            "tls-port" => self.tls_port = Some(single()?.parse().map_err(|_| "端口不合法")?),
+            "tls-cert-file" => self.tls_cert_file = Some(single()?.to_string()),
+            "tls-key-file" => self.tls_key_file = Some(single()?.to_string()),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = Some(single()?.to_string()),
+            "tls-auth-clients" => {
+                self.tls_auth_clients = match single()?.to_lowercase().as_str() {
+                    "yes" => TlsAuthClients::Yes,
+                    "optional" => TlsAuthClients::Optional,
+                    "no" => TlsAuthClients::No,
+                    _ => return Err("只能是 yes / optional / no".into()),
+                }
+            }
+            "tls-auth-clients-user" => {
+                self.tls_auth_clients_user = match single()?.to_lowercase().as_str() {
+                    "cn" => true,
+                    "off" => false,
+                    _ => return Err("只能是 CN / off".into()),
+                }
+            }
+            "memcached-port" => {
+                self.memcached_port = Some(single()?.parse().map_err(|_| "端口不合法")?)
+            }
+            "http-port" => self.http_port = Some(single()?.parse().map_err(|_| "端口不合法")?),
+            "appendfsync" => {
+                self.appendfsync = match single()?.to_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => return Err("只能是 always / everysec / no".into()),
+                }
+            }
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage =
+                    single()?.parse().map_err(|_| "必须是非负整数")?
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(single()?)?
+            }
+            "appenddirname" => self.appenddirname = single()?.to_string(),
+            "appendfilename" => {
+                let name = single()?;
+                // 清单里只记文件名 不能带路径
+                if name.contains('/') {
+                    return Err("只能是文件名 不能带路径".into());
+                }
+                self.appendfilename = name.to_string()
+            }
+            "aof-load-truncated" => {
+                self.aof_load_truncated = match single()?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("只能是 yes / no".into()),
+                }
+            }
+            "dbfilename" => self.dbfilename = single()?.to_string(),
+            "import-rdb" => self.import_rdb = Some(single()?.to_string()),
+            "save" => self.save = parse_save_rules(values)?,
+            "replicaof" | "slaveof" => {
+                self.replicaof = match values {
+                    [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => None,
+                    [host, port] => Some((host.clone(), port.parse().map_err(|_| "端口不合法")?)),
+                    _ => return Err("需要 <host> <port> 或者 no one".into()),
+                }
+            }
+            "masteruser" => self.masteruser = Some(single()?.to_string()),
+            "masterauth" => self.masterauth = Some(single()?.to_string()),
+            "enable-debug-command" => {
+                self.enable_debug_command = match single()?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("只能是 yes / no".into()),
+                }
+            }
+            "replica-read-only" | "slave-read-only" => {
+                self.replica_read_only = match single()?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("只能是 yes / no".into()),
+                }
+            }
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_memory(single()?)?;
+                if self.repl_backlog_size == 0 {
+                    return Err("不能是 0".into());
+                }
+            }
+            "proto-max-bulk-len" => {
+                self.proto_max_bulk_len = parse_memory(single()?)?;
+                // 和 redis 一样不能小于 1mb 不然正常的命令都发不进来
+                if self.proto_max_bulk_len < 1024 * 1024 {
+                    return Err("不能小于 1mb".into());
+                }
+            }
+            "aclfile" => self.aclfile = single()?.to_string(),
+            "maxmemory-policy" => {
+                self.eviction_type = match single()?.to_lowercase().as_str() {
+                    "allkeys-lru" => EvictionType::LRU,
+                    "allkeys-lfu" => EvictionType::LFU,
+                    _ => return Err("只支持 allkeys-lru / allkeys-lfu".into()),
+                }
+            }
+            _ => return Err("未知的配置项".into()),
+        }
+        Ok(())
+    }
+}
+
+// save 3600 1 300 100 一行写多条 空字符串表示不自动保存
+fn parse_save_rules(values: &[String]) -> Result<Vec<(u64, u64)>, String> {
+    match values {
+        [] => return Err("至少需要一个值".into()),
+        [value] if value.is_empty() || value == "\"\"" => return Ok(Vec::new()),
+        _ => {}
+    }
+    if !values.len().is_multiple_of(2) {
+        return Err("必须是成对的 <秒数> <改动次数>".into());
+    }
+    values
+        .chunks(2)
+        .map(|pair| match (pair[0].parse(), pair[1].parse()) {
+            (Ok(seconds), Ok(changes)) => Ok((seconds, changes)),
+            _ => Err(format!("'{} {}' 不是合法的规则", pair[0], pair[1])),
+        })
+        .collect()
+}
+
+// 和 redis 一样的大小写法 k/m/g 是 1000 进制 kb/mb/gb 是 1024 进制 不分大小写
+fn parse_memory(value: &str) -> Result<u64, String> {
+    let lower = value.to_lowercase();
+    let units: [(&str, u64); 7] = [
+        ("kb", 1024),
+        ("mb", 1024 * 1024),
+        ("gb", 1024 * 1024 * 1024),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+        ("b", 1),
+    ];
+    let (number, unit) = units
+        .iter()
+        .find_map(|(suffix, unit)| lower.strip_suffix(suffix).map(|number| (number, *unit)))
+        .unwrap_or((lower.as_str(), 1));
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("'{}' 不是合法的大小", value))
+}
 
 // 注意 `pub` 关键字，这样其他模块才能访问它
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     println!("--- Loading configuration ---");
-    Config {
-        eviction_type: EvictionType::LRU, // 这里可以根据需要加载不同的配置
-    }
+    // 配置有问题直接拒绝启动 不要带着默认值悄悄跑起来
+    Config::load(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("配置加载失败: {}", e);
+        std::process::exit(1);
+    })
 });
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_load_args() {
+        let config = Config::load(args("--port 0 --bind 127.0.0.1 ::1 --unixsocket /tmp/kv.sock --unixsocketperm 770")).unwrap();
+        assert_eq!(config.port, 0);
+        assert_eq!(config.bind, vec!["127.0.0.1", "::1"]);
+        assert_eq!(config.unixsocket.as_deref(), Some("/tmp/kv.sock"));
+        assert_eq!(config.unixsocketperm, Some(0o770));
+
+        let config = Config::load(Vec::new()).unwrap();
+        assert_eq!(config.port, 6379);
+        assert_eq!(config.bind, vec!["127.0.0.1"]);
+
+        assert!(Config::load(args("--port")).is_err());
+        assert!(Config::load(args("--port 70000")).is_err());
+        assert!(Config::load(args("--unixsocketperm 9")).is_err());
+        assert!(Config::load(args("--nosuch 1")).is_err());
+        assert_eq!(Config::load(Vec::new()).unwrap().appendfsync, AppendFsync::EverySec);
+        assert_eq!(
+            Config::load(args("--appendfsync ALWAYS")).unwrap().appendfsync,
+            AppendFsync::Always
+        );
+        assert!(Config::load(args("--appendfsync sometimes")).is_err());
+        let config = Config::load(args("--auto-aof-rewrite-percentage 50 --auto-aof-rewrite-min-size 1mb")).unwrap();
+        assert_eq!(config.auto_aof_rewrite_percentage, 50);
+        assert_eq!(config.auto_aof_rewrite_min_size, 1024 * 1024);
+        assert_eq!(parse_memory("2K"), Ok(2000));
+        assert_eq!(parse_memory("4096"), Ok(4096));
+        assert!(parse_memory("1tb").is_err());
+        assert_eq!(Config::load(Vec::new()).unwrap().save.len(), 3);
+        let config = Config::load(args("--save 900 1 60 5 --dbfilename snap.kvdb")).unwrap();
+        assert_eq!(config.save, vec![(900, 1), (60, 5)]);
+        assert_eq!(config.dbfilename, "snap.kvdb");
+        assert_eq!(Config::load(args("--import-rdb dump.rdb")).unwrap().import_rdb.as_deref(), Some("dump.rdb"));
+        assert!(Config::load(args("--save \"\"")).unwrap().save.is_empty());
+        assert!(Config::load(args("--save 900")).is_err());
+        assert!(Config::load(args("--appendfilename dir/a.aof")).is_err());
+        assert!(Config::load(Vec::new()).unwrap().aof_load_truncated);
+        assert!(!Config::load(args("--aof-load-truncated no")).unwrap().aof_load_truncated);
+        assert!(Config::load(args("--aof-load-truncated maybe")).is_err());
+        assert!(!Config::load(Vec::new()).unwrap().enable_debug_command);
+        assert!(Config::load(args("--enable-debug-command yes")).unwrap().enable_debug_command);
+        assert!(Config::load(args("--enable-debug-command local")).is_err());
+        assert!(Config::load(args("/no/such/file.conf")).is_err());
+        let config = Config::load(args("--replicaof 127.0.0.1 6380 --repl-backlog-size 10mb")).unwrap();
+        assert_eq!(config.replicaof, Some(("127.0.0.1".to_string(), 6380)));
+        assert_eq!(config.repl_backlog_size, 10 * 1024 * 1024);
+        assert!(Config::load(args("--replicaof no one")).unwrap().replicaof.is_none());
+        assert!(Config::load(args("--replicaof 127.0.0.1")).is_err());
+        assert!(Config::load(Vec::new()).unwrap().replica_read_only);
+        assert!(Config::load(args("--repl-backlog-size 0")).is_err());
+        assert_eq!(Config::load(Vec::new()).unwrap().proto_max_bulk_len, 512 * 1024 * 1024);
+        assert_eq!(Config::load(args("--proto-max-bulk-len 2mb")).unwrap().proto_max_bulk_len, 2 * 1024 * 1024);
+        assert!(Config::load(args("--proto-max-bulk-len 1000")).is_err());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path = std::env::temp_dir().join(format!("kv-config-{}.conf", std::process::id()));
+        std::fs::write(&path, "# 注释\n\nport 7000\nbind 0.0.0.0 ::\n").unwrap();
+        let mut argv = vec![path.to_string_lossy().to_string()];
+        argv.extend(args("--port 7001"));
+        let config = Config::load(argv).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // 命令行覆盖文件
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.bind, vec!["0.0.0.0", "::"]);
+    }
+}
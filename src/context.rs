@@ -1,3 +1,7 @@
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
 use flume::Receiver;
 use mlua::Lua;
 use tokio::{
@@ -5,18 +9,65 @@ use tokio::{
     task_local,
 };
 
+use crate::core_aof::{AofAck, AofMessage};
+use crate::error::ClientReply;
 use crate::lua::lua_work::LuaTask;
 
 // 定义我们想为每个任务独立存储的状态
 #[derive(Clone, Debug)]
 pub struct ConnectionState {
-    pub selected_db: usize,
+    // 当前选中的库 SELECT 切换 AOF 和复制流里记的也是它
+    pub selected_db: Cell<usize>,
     pub client_address: Option<String>,
+    // 连接的唯一编号 CLIENT ID / REDIRECT 都靠它定位连接
+    pub client_id: u64,
+    // 协议版本 2 或者 3 由 HELLO 切换 回复按这个版本序列化
+    pub protocol: Cell<u8>,
+    // HELLO SETNAME 设置的连接名
+    pub client_name: RefCell<Option<String>>,
+    // 当前登录的 ACL 用户 None 表示还没有认证 只能执行 AUTH/HELLO
+    pub user: RefCell<Option<String>>,
+    // CLIENT REPLY 设置的回复模式
+    pub reply: Cell<ClientReply>,
+    // appendfsync always 的时候 最近一条写入落盘的回执 回复发出去之前要等它
+    // lua worker 拿到的是克隆 用 Arc 共享 脚本里的写入连接这边也能等到
+    pub pending_aof: Arc<Mutex<Option<AofAck>>>,
+    // 最后一条写入在 AOF 里的偏移量 WAITAOF 等它落盘 脚本里的写入也要算进来 所以也用 Arc 共享
+    pub aof_offset: Arc<AtomicU64>,
+    // replica 上执行主节点同步过来的命令用的连接 只读限制不管它 写入也不再往下游转发
+    pub master_link: bool,
+    // 对面是 replica 的话 REPLCONF listening-port 报上来的端口
+    pub replica_port: Cell<Option<u16>>,
+}
+
+impl ConnectionState {
+    pub fn new(selected_db: usize, client_address: Option<String>, client_id: u64) -> Self {
+        ConnectionState {
+            selected_db: Cell::new(selected_db),
+            client_address,
+            client_id,
+            protocol: Cell::new(2),
+            client_name: RefCell::new(None),
+            user: RefCell::new(None),
+            reply: Cell::new(ClientReply::On),
+            pending_aof: Arc::new(Mutex::new(None)),
+            aof_offset: Arc::new(AtomicU64::new(0)),
+            master_link: false,
+            replica_port: Cell::new(None),
+        }
+    }
+}
+
+// 客户端编号从 1 开始递增 0 留给 AOF 恢复这种“内部连接”
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_client_id() -> u64 {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
 }
 // 定义我们想为每个任务独立存储的状态
 #[derive(Clone, Debug)]
 pub struct ConnectionContent {
-    pub aof_tx: Sender<Vec<u8>>,
+    pub aof_tx: Sender<AofMessage>,
     pub shutdown_tx: tokio::sync::broadcast::Sender<()>,
     pub lua_sender: Sender<LuaTask>,
     pub receivce_lua: Receiver<Lua>,
@@ -1,136 +1,1574 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-use std::io::Read;
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 use tokio::sync::broadcast::Sender;
-use tokio::sync::mpsc::Receiver;
-use tokio::time::{self, Duration};
+use tokio::sync::mpsc::{Permit, Receiver};
+use tokio::sync::{Notify, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
 
-use crate::core_execute::{ execute_command};
+use bytes::{Buf, Bytes, BytesMut};
+use once_cell::sync::Lazy;
+
+use crate::command_execute::calculate_expiration_timestamp_ms;
+use crate::config::{AppendFsync, CONFIG};
+use crate::core_aof_manifest::{AofFile, AofManifest};
+use crate::core_aof_rewrite::{AOF_REWRITE, RewriteRequest};
+use crate::context::{CONN_STATE, ConnectionState};
+use crate::core_execute::{execute_command, execute_command_hook};
 use crate::core_explain::parse_frame;
-use crate::error::{Command};
+use crate::core_keyspec::CommandFlag;
+use crate::core_loading::LOADING;
+use crate::core_rdb::{RdbImport, decode_rdb_preamble, restore_entries};
+use crate::core_replication::REPLICATION;
+use crate::core_snapshot::load_snapshot;
+use crate::core_time::get_cached_time_ms;
+use crate::db::lock_plan::LockPlan;
+use crate::error::{Command, DelCommand, Expiration, Frame, SetCondition};
 use crate::Db;
 
 
-// 定义管道里传递的消息类型，这里就是序列化后的命令
-pub type AofMessage = Vec<u8>;
+// 定义管道里传递的消息类型，这里就是序列化后的命令 和它是在哪个库上执行的
+pub struct AofMessage {
+    pub db: usize,
+    pub payload: Vec<u8>,
+    // appendfsync always 的时候带上 落盘 (或者失败) 之后通知发送方
+    pub ack: Option<oneshot::Sender<Result<(), String>>>,
+    // 要不要转发给 replica 从主节点同步过来的写入已经直接进了复制流 这里就不能再发一遍
+    pub replicate: bool,
+    // 到这条为止一共发了多少字节 放进通道的时候才编 见 AofOffset
+    pub offset: u64,
+    // 执行的时候进入的快照纪元 AOF 重写要在哪个纪元切开 见 db::capture
+    pub epoch: u64,
+}
+
+pub type AofAck = oneshot::Receiver<Result<(), String>>;
+
+/*
+   AOF 偏移量 (WAITAOF 用)
+   1.每条写入放进通道的时候编一个偏移量 就是到这条为止一共发了多少字节 (不算写入任务补的 SELECT)
+     编号和放进通道在同一把锁里做 通道里的顺序和偏移量的大小一定一致
+   2.连接记下自己最后一条写入的偏移量 写入任务每次 fsync 成功就公布落盘到了哪个偏移量
+   3.WAITAOF 等公布的偏移量追上自己的 等的时候让写入任务马上 fsync 不用等 everysec 的下一秒 no 策略也能等到
+*/
+pub static AOF_OFFSET: Lazy<AofOffset> = Lazy::new(AofOffset::new);
+
+pub struct AofOffset {
+    sent: Mutex<u64>,
+    fsynced: watch::Sender<u64>,
+    // 有 WAITAOF 在等的最大偏移量 写入任务看到还没落盘就马上 fsync
+    wanted: AtomicU64,
+    sync_request: Notify,
+    // 写 AOF 出错之后就一直是坏的 文件尾巴上可能有半条命令 不能再往后追加了
+    poisoned: Mutex<Option<String>>,
+    // INFO persistence 用的 fsync 统计
+    last_fsync_ok: AtomicBool,
+    delayed_fsync: AtomicU64,
+    fsyncs: AtomicU64,
+    fsync_errors: AtomicU64,
+    last_fsync_usec: AtomicU64,
+    max_fsync_usec: AtomicU64,
+}
+
+/// INFO persistence 里的写入和 fsync 状态
+pub struct AofStatus {
+    pub last_write_ok: bool,
+    pub delayed_fsync: u64,
+    pub fsyncs: u64,
+    pub fsync_errors: u64,
+    pub last_fsync_usec: u64,
+    pub max_fsync_usec: u64,
+}
+
+impl AofOffset {
+    fn new() -> Self {
+        let (fsynced, _) = watch::channel(0);
+        AofOffset {
+            sent: Mutex::new(0),
+            fsynced,
+            wanted: AtomicU64::new(0),
+            sync_request: Notify::new(),
+            poisoned: Mutex::new(None),
+            last_fsync_ok: AtomicBool::new(true),
+            delayed_fsync: AtomicU64::new(0),
+            fsyncs: AtomicU64::new(0),
+            fsync_errors: AtomicU64::new(0),
+            last_fsync_usec: AtomicU64::new(0),
+            max_fsync_usec: AtomicU64::new(0),
+        }
+    }
+
+    /// 写坏了或者最近一次 fsync 失败了 last_write_status 都是 err
+    pub fn status(&self) -> AofStatus {
+        AofStatus {
+            last_write_ok: self.poisoned().is_none() && self.last_fsync_ok.load(Ordering::SeqCst),
+            delayed_fsync: self.delayed_fsync.load(Ordering::SeqCst),
+            fsyncs: self.fsyncs.load(Ordering::SeqCst),
+            fsync_errors: self.fsync_errors.load(Ordering::SeqCst),
+            last_fsync_usec: self.last_fsync_usec.load(Ordering::SeqCst),
+            max_fsync_usec: self.max_fsync_usec.load(Ordering::SeqCst),
+        }
+    }
+
+    // 每次 fsync 完记一笔 慢了或者失败了打日志
+    fn fsync_done(&self, elapsed: Duration, result: &Result<(), String>) {
+        match result {
+            Err(e) => tracing::error!("AOF fsync 失败: {} (耗时 {:?})", e, elapsed),
+            Ok(()) if elapsed >= SLOW_FSYNC => tracing::warn!("AOF fsync 太慢了 耗时 {:?}", elapsed),
+            Ok(()) => tracing::debug!("AOF fsync 耗时 {:?}", elapsed),
+        }
+        let usec = elapsed.as_micros() as u64;
+        self.fsyncs.fetch_add(1, Ordering::SeqCst);
+        self.last_fsync_usec.store(usec, Ordering::SeqCst);
+        self.max_fsync_usec.fetch_max(usec, Ordering::SeqCst);
+        self.last_fsync_ok.store(result.is_ok(), Ordering::SeqCst);
+        if result.is_err() {
+            self.fsync_errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// 编好偏移量放进通道 返回这条的偏移量
+    pub fn send(&self, permit: Permit<'_, AofMessage>, mut message: AofMessage) -> u64 {
+        let mut sent = self.sent.lock().unwrap();
+        *sent += message.payload.len() as u64;
+        message.offset = *sent;
+        permit.send(message);
+        *sent
+    }
+
+    pub fn fsynced(&self) -> u64 {
+        *self.fsynced.borrow()
+    }
+
+    // 后台线程的 fsync 可能比后面写入任务自己做的还晚完成 只往大了更新
+    fn fsynced_to(&self, offset: u64) {
+        self.fsynced.send_if_modified(|fsynced| {
+            let advanced = offset > *fsynced;
+            if advanced {
+                *fsynced = offset;
+            }
+            advanced
+        });
+    }
+
+    pub fn poisoned(&self) -> Option<String> {
+        self.poisoned.lock().unwrap().clone()
+    }
+
+    /// 写坏之后和 redis 一样拒绝写命令 读照常
+    pub fn check_command(&self, command: &Command) -> Result<(), String> {
+        match (command.spec(), self.poisoned()) {
+            (Some(spec), Some(msg)) if spec.has_flag(CommandFlag::Write) => {
+                Err(format!("MISCONF Errors writing to the AOF file: {}", msg))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 叫醒所有在等落盘的 让它们看到出错了
+    fn poison(&self, msg: String) {
+        *self.poisoned.lock().unwrap() = Some(msg);
+        self.fsynced.send_modify(|_| {});
+    }
+
+    /// 等 offset 之前的写入都落盘 超时返回 false timeout 是 None 就一直等
+    /// AOF 写坏了的话再也等不到了 返回错误
+    pub async fn wait_fsynced(&self, offset: u64, timeout: Option<Duration>) -> Result<bool, String> {
+        let mut fsynced = self.fsynced.subscribe();
+        if *fsynced.borrow_and_update() >= offset {
+            return Ok(true);
+        }
+        self.wanted.fetch_max(offset, Ordering::SeqCst);
+        self.sync_request.notify_one();
+        let wait = fsynced.wait_for(|fsynced| *fsynced >= offset || self.poisoned.lock().unwrap().is_some());
+        match timeout {
+            Some(timeout) => {
+                let _ = time::timeout(timeout, wait).await;
+            }
+            None => {
+                let _ = wait.await;
+            }
+        }
+        if self.fsynced() >= offset {
+            return Ok(true);
+        }
+        match self.poisoned() {
+            Some(msg) => Err(msg),
+            None => Ok(false),
+        }
+    }
+}
+
+// fsync 超过这么久打一条警告 一般是磁盘忙不过来了
+const SLOW_FSYNC: Duration = Duration::from_millis(500);
+// everysec 的后台 fsync 连着失败这么多次就当 AOF 写坏了
+const FSYNC_FAILURE_LIMIT: u32 = 3;
+
+/*
+   AOF 写入和刷盘
+   1.每次从通道里把积压的消息一次性取完 写成一批 写完 flush 到操作系统
+   2.always：每批写完马上 fsync 落盘之后才通知这一批里的所有发送方
+     fsync 期间新来的写入在通道里攒着 下一批再一起 fsync 这就是组提交 并发越高一次 fsync 覆盖的写入越多
+   3.everysec：每秒在后台线程 fsync 一次 不挡住写入 上一次还没做完就跳过这一次
+     失败了数据还算没落盘 下一秒接着试 连着失败几次就和写失败一样 之后的写命令回 MISCONF
+   4.no：只 flush 到操作系统 什么时候落盘交给系统
+   停机的时候不管哪种策略 都把剩下的写完再 fsync 一次
+   写的是清单里最后一个 incr 文件 AOF 重写要换 incr、装新 base 也都在这个任务里做
+   AOF 重写定了切分的纪元之后 通道里这个纪元的写入可能排在上一个纪元的前面 (不在同一个分片上)
+   先攒着不写 等切分请求来了 把上一个纪元的都写完、换好 incr 再写它们
+*/
+struct AofWriter {
+    manifest: AofManifest,
+    file: BufWriter<tokio::fs::File>,
+    // 同一个文件的另一个句柄 给后台线程 fsync 用
+    sync_file: Arc<File>,
+    policy: AppendFsync,
+    // 文件里最后一次 SELECT 的库 追加写的时候不知道文件尾巴上是哪个库 所以第一条一定补 SELECT
+    current_db: Option<usize>,
+    // 写进了操作系统但还没有 fsync
+    dirty: bool,
+    // 已经写进操作系统的最后一条的偏移量 fsync 完就公布它
+    written: u64,
+    background_sync: Option<JoinHandle<Result<(), String>>>,
+    // everysec 的后台 fsync 连着失败了几次
+    fsync_failures: u32,
+    // 就是 AOF_OFFSET 测试里换成自己的 免得把全局的写坏
+    offsets: &'static AofOffset,
+    // 切分点之后的写入 切分完之前不能写
+    held: Vec<AofMessage>,
+}
+
+impl AofWriter {
+    async fn open(manifest: AofManifest, policy: AppendFsync) -> std::io::Result<Self> {
+        let file = open_append(&manifest.path(manifest.last_incr())).await?;
+        let sync_file = Arc::new(file.try_clone().await?.into_std().await);
+        AOF_REWRITE.opened(manifest.temp_base_path(), manifest.total_size());
+        Ok(AofWriter {
+            manifest,
+            file: BufWriter::new(file),
+            sync_file,
+            policy,
+            current_db: None,
+            dirty: false,
+            written: AOF_OFFSET.fsynced(),
+            background_sync: None,
+            fsync_failures: 0,
+            offsets: &AOF_OFFSET,
+            held: Vec::new(),
+        })
+    }
+
+    // **核心的批量获取逻辑**
+    // 尽最大努力，一次性从管道中取出所有等待的消息 一批只 fsync 一次
+    async fn receive(&mut self, first: AofMessage, rx: &mut Receiver<AofMessage>) {
+        let mut batch = vec![first];
+        while let Ok(msg) = rx.try_recv() {
+            batch.push(msg);
+        }
+        self.accept(batch).await;
+        self.sync_if_wanted().await;
+    }
+
+    // 有切分在等的话 切分点之后的先攒着 其他的照常写
+    async fn accept(&mut self, batch: Vec<AofMessage>) {
+        let split = AOF_REWRITE.split_epoch();
+        let batch: Vec<AofMessage> = if split == 0 {
+            batch
+        } else {
+            let (after, before) = batch.into_iter().partition(|msg| msg.epoch >= split);
+            self.held.extend(after);
+            before
+        };
+        if !batch.is_empty() {
+            self.write_batch(batch).await;
+        }
+    }
+
+    // 切分完了 攒着的接着写
+    async fn release_held(&mut self) {
+        AOF_REWRITE.split_done();
+        let held = std::mem::take(&mut self.held);
+        if !held.is_empty() {
+            self.write_batch(held).await;
+        }
+    }
 
+    /*
+       写一批
+       1.写坏过一次之后什么都不写了 所有发送方都收到错误 要重启 (加载的时候按 aof-load-truncated 处理尾巴)
+       2.这一批全部写进操作系统之后才发给 replica 复制流里不会有 AOF 里没有的东西
+       3.中途出错后面的就不写了 写坏的这一批不算 WAITAOF 等不到 只会收到错误
+    */
+    async fn write_batch(&mut self, batch: Vec<AofMessage>) {
+        let mut acks = Vec::new();
+        let mut replicated = Vec::new();
+        let mut result = self.offsets.poisoned().map_or(Ok(()), Err);
+        let mut written = 0;
+        let mut last_offset = batch.last().map_or(self.written, |msg| msg.offset);
+        // 攒着没写的之前的才算写完了
+        if let Some(first) = self.held.first() {
+            last_offset = last_offset.min(first.offset - first.payload.len() as u64);
+        }
+        for msg in batch {
+            acks.extend(msg.ack);
+            if result.is_err() {
+                continue;
+            }
+            match self.write_message(msg.db, &msg.payload).await {
+                Ok(size) => written += size,
+                Err(e) => {
+                    result = Err(e.to_string());
+                    continue;
+                }
+            }
+            if msg.replicate {
+                replicated.push((msg.db, msg.payload));
+            }
+        }
+        AOF_REWRITE.grow(written as u64);
+        if result.is_ok()
+            && let Err(e) = self.file.flush().await
+        {
+            result = Err(e.to_string());
+        }
+        if result.is_ok() {
+            self.written = last_offset;
+            self.dirty = true;
+            // 复制流和 AOF 是同一条路 写 AOF 的顺序就是发给 replica 的顺序
+            for (db, payload) in replicated {
+                REPLICATION.feed(db, &payload);
+            }
+            if self.policy == AppendFsync::Always {
+                result = self.fsync().await;
+            }
+        }
+        if let Err(msg) = &result
+            && self.offsets.poisoned().is_none()
+        {
+            tracing::error!("AOF 写入失败 之后的写入都会被拒绝 需要重启: {}", msg);
+            self.offsets.poison(msg.clone());
+        }
+        for ack in acks {
+            let _ = ack.send(result.clone());
+        }
+    }
 
-pub async fn aof_writer_task(mut rx: Receiver<AofMessage>, path: &str, sender:Sender<()>) {
+    // 库变了先补一条 SELECT 返回一共写了多少字节
+    async fn write_message(&mut self, db: usize, payload: &[u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        if self.current_db != Some(db) {
+            let select = select_command(db);
+            self.file.write_all(&select).await?;
+            self.current_db = Some(db);
+            written += select.len();
+        }
+        self.file.write_all(payload).await?;
+        Ok(written + payload.len())
+    }
+
+    // 可能攒了好几个请求 (通知只留一个) 一次处理完
+    async fn handle_rewrite(&mut self, rx: &mut Receiver<AofMessage>) {
+        while let Some(pending) = AOF_REWRITE.take_request() {
+            let result = match pending.request {
+                RewriteRequest::SyncPoint => {
+                    self.drain(rx).await;
+                    let offset = REPLICATION.sync_point();
+                    self.release_held().await;
+                    Ok(offset)
+                }
+                RewriteRequest::Rotate => {
+                    self.drain(rx).await;
+                    let seq = self.rotate().await;
+                    self.release_held().await;
+                    seq
+                }
+                RewriteRequest::Install {
+                    temp_path,
+                    first_incr,
+                } => self.install_base(&temp_path, first_incr),
+            };
+            let _ = pending.done.send(result.map_err(|e| e.to_string()));
+        }
+    }
+
+    // 把通道里已经发出来的都写掉 (切分点之后的攒着)
+    // 重写和全量同步发请求之前等上一个纪元的写入都退出了 这时候通道里有切分点之前的全部写入
+    async fn drain(&mut self, rx: &mut Receiver<AofMessage>) {
+        let mut batch = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            batch.push(msg);
+        }
+        self.accept(batch).await;
+    }
+
+    // 旧 incr 落盘之后换到新的 incr 上 返回新 incr 的 seq
+    async fn rotate(&mut self) -> std::io::Result<u64> {
+        self.file.flush().await?;
+        if let Some(handle) = self.background_sync.take() {
+            let _ = handle.await;
+        }
+        self.fsync().await.map_err(std::io::Error::other)?;
+        let incr = self.manifest.add_incr()?.clone();
+        let file = open_append(&self.manifest.path(&incr)).await?;
+        self.sync_file = Arc::new(file.try_clone().await?.into_std().await);
+        self.file = BufWriter::new(file);
+        self.current_db = None;
+        Ok(incr.seq)
+    }
+
+    fn install_base(&mut self, temp_path: &Path, first_incr: u64) -> std::io::Result<u64> {
+        self.manifest.install_base(temp_path, first_incr)?;
+        AOF_REWRITE.rewritten(self.manifest.total_size());
+        Ok(self.manifest.base.as_ref().map_or(0, |base| base.seq))
+    }
+
+    async fn fsync(&mut self) -> Result<(), String> {
+        let start = Instant::now();
+        let written = self.written;
+        let result = self.file.get_ref().sync_data().await.map_err(|e| e.to_string());
+        self.offsets.fsync_done(start.elapsed(), &result);
+        if result.is_ok() {
+            self.dirty = false;
+            self.offsets.fsynced_to(written);
+        }
+        result
+    }
+
+    // 有 WAITAOF 在等还没落盘的写入 马上 fsync 一次
+    async fn sync_if_wanted(&mut self) {
+        let fsynced = self.offsets.fsynced();
+        if self.offsets.wanted.load(Ordering::SeqCst) > fsynced && self.written > fsynced {
+            let _ = self.fsync().await;
+        }
+    }
+
+    // everysec 的定时器 后台线程里 fsync
+    async fn tick(&mut self) {
+        if self.policy != AppendFsync::EverySec {
+            return;
+        }
+        if let Some(handle) = self.background_sync.take_if(|handle| handle.is_finished()) {
+            let result = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+            self.background_synced(result);
+        }
+        if !self.dirty {
+            return;
+        }
+        if self.background_sync.is_some() {
+            // redis 的 aof_delayed_fsync
+            self.offsets.delayed_fsync.fetch_add(1, Ordering::SeqCst);
+            tracing::warn!("上一次 AOF fsync 还没有完成 磁盘可能太忙了");
+            return;
+        }
+        self.dirty = false;
+        let file = self.sync_file.clone();
+        let written = self.written;
+        let offsets = self.offsets;
+        self.background_sync = Some(tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let result = file.sync_data().map_err(|e| e.to_string());
+            offsets.fsync_done(start.elapsed(), &result);
+            if result.is_ok() {
+                offsets.fsynced_to(written);
+            }
+            result
+        }));
+    }
+
+    // 上一次后台 fsync 的结果 失败了下一秒重试 一直失败就写坏
+    fn background_synced(&mut self, result: Result<(), String>) {
+        let Err(msg) = result else {
+            self.fsync_failures = 0;
+            return;
+        };
+        self.dirty = true;
+        self.fsync_failures += 1;
+        if self.fsync_failures >= FSYNC_FAILURE_LIMIT && self.offsets.poisoned().is_none() {
+            tracing::error!(
+                "AOF fsync 连着失败了 {} 次 之后的写入都会被拒绝 需要重启: {}",
+                self.fsync_failures,
+                msg
+            );
+            self.offsets.poison(msg);
+        }
+    }
+
+    async fn close(&mut self) {
+        if let Err(e) = self.file.flush().await {
+            tracing::error!("AOF 写入失败: {}", e);
+        }
+        if let Some(handle) = self.background_sync.take() {
+            let _ = handle.await;
+        }
+        let _ = self.fsync().await;
+    }
+}
+
+pub async fn aof_writer_task(mut rx: Receiver<AofMessage>, manifest: AofManifest, sender:Sender<()>) {
     // 打开 AOF 文件
-    let mut file = BufWriter::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .await
-            .unwrap(),
-    );
+    let mut writer = AofWriter::open(manifest, CONFIG.appendfsync).await.unwrap();
 
-    // 创建一个每秒触发一次的定时器
+    // 创建一个每秒触发一次的定时器 everysec 用
     let mut interval: time::Interval = time::interval(Duration::from_secs(1));
-    let mut buffer: Vec<AofMessage> = Vec::with_capacity(128);
     let mut receiver = sender.subscribe();
     loop {
         tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                writer.receive(msg, &mut rx).await;
+            }
+            _ = AOF_OFFSET.sync_request.notified() => {
+                writer.sync_if_wanted().await;
+            }
             _= interval.tick() =>{
-
+                writer.tick().await;
             },
+            _ = AOF_REWRITE.wait_request() => {
+                writer.handle_rewrite(&mut rx).await;
+            }
             _= receiver.recv() =>{
                     break;
                 }
         }
-        // 清空上次的缓冲区
-        buffer.clear();
+    }
+    // 停机前把通道里剩下的也写掉
+    writer.drain(&mut rx).await;
+    writer.release_held().await;
+    writer.close().await;
+}
 
-        // **核心的批量获取逻辑**
-        // 尽最大努力，一次性从管道中取出所有等待的消息
-        // 较少通道占用时间 
-        while let Ok(msg) = rx.try_recv() {
-            buffer.push(msg);
+/// 等当前连接最近一条写入落盘 只有 appendfsync always 才会真的等 其他策略直接返回
+pub async fn wait_aof_durable() -> Result<(), String> {
+    let ack = CONN_STATE
+        .try_with(|state| state.pending_aof.lock().unwrap().take())
+        .ok()
+        .flatten();
+    match ack {
+        Some(ack) => ack
+            .await
+            .unwrap_or_else(|_| Err("AOF 写入任务已经退出".into())),
+        None => Ok(()),
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<tokio::fs::File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+/// rename 本身也要落盘 不然掉电之后目录里可能还是旧文件
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => ".".into(),
+    };
+    File::open(dir)?.sync_all()
+}
+
+pub fn select_command(db: usize) -> Vec<u8> {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"SELECT")),
+        Frame::Bulk(Bytes::from(db.to_string())),
+    ])
+    .serialize()
+}
+
+/*
+   重放时的状态
+   1.SELECT 切换后面命令执行的库
+   2.MULTI 和 EXEC 之间的命令连同当时选中的库先攒着 EXEC 的时候把整段要的锁一次拿齐再执行
+     文件在中间截断的话这一段整个不执行
+   3.kv 执行不了的命令 (还没实现的、参数不支持的、执行报错的) 不让加载失败 记下来按命令名汇总
+     从 redis 迁移过来的 AOF 加载完把这些报出来 让人决定后面怎么处理
+*/
+#[derive(Default)]
+struct AofReplay {
+    db_index: usize,
+    multi: Option<Vec<(usize, Command)>>,
+    // 命令名 -> (条数, 第一次的原因)
+    rejected: BTreeMap<String, (u64, String)>,
+}
+
+impl AofReplay {
+    async fn apply(&mut self, frame: Frame, db: &Db) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let name = frame_command_name(&frame).unwrap_or_default();
+        match name.as_str() {
+            "SELECT" => {
+                self.db_index = select_index(&frame, db.store.store.len())
+                    .ok_or("AOF 里的 SELECT 参数不对")?;
+                return Ok(());
+            }
+            "MULTI" => {
+                self.multi = Some(Vec::new());
+                return Ok(());
+            }
+            "EXEC" => {
+                let queued = self.multi.take().ok_or("AOF 里的 EXEC 没有对应的 MULTI")?;
+                return self.execute_multi(queued, db).await;
+            }
+            _ => {}
+        }
+        let command = match Command::from_replay(frame) {
+            Ok(command) => command,
+            Err(e) => {
+                self.reject(&name, e.to_string());
+                return Ok(());
+            }
+        };
+        if let Err(reason) = replayable(&command) {
+            self.reject(command.name(), reason.into());
+            return Ok(());
         }
+        let Some(command) = skip_expired(legacy_exat(command), get_cached_time_ms()) else {
+            return Ok(());
+        };
+        match &mut self.multi {
+            Some(queued) => queued.push((self.db_index, command)),
+            None => self.execute(command, db).await?,
+        }
+        Ok(())
+    }
+
+    async fn execute(&mut self, command: Command, db: &Db) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let state = ConnectionState::new(self.db_index, None, 0);
+        let frame = CONN_STATE.scope(state, execute_command(&command, db)).await?;
+        self.check_reply(&command, frame);
+        Ok(())
+    }
 
-        if buffer.is_empty() {
-            continue; // 这一秒没任务，直接开始下一次等待
+    // 和 lua 脚本一样先按合起来的计划把锁拿齐 中间不会有别的读写插进来
+    // 攒进来的都是写命令 执行器只用传进去的视图 不会自己再去加锁
+    async fn execute_multi(
+        &mut self,
+        queued: Vec<(usize, Command)>,
+        db: &Db,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let plan = LockPlan::merge(
+            queued
+                .iter()
+                .map(|(db_index, command)| LockPlan::new(*db_index, &command.keys())),
+        );
+        let mut view = db.store.lock_plan(&plan).await;
+        for (db_index, command) in queued {
+            let state = ConnectionState::new(db_index, None, 0);
+            let frame = CONN_STATE
+                .scope(
+                    state,
+                    execute_command_hook(&command, Some(db.clone()), None, Some(&mut view)),
+                )
+                .await?;
+            self.check_reply(&command, frame);
         }
+        Ok(())
+    }
+
+    fn check_reply(&mut self, command: &Command, frame: Frame) {
+        if let Frame::Error(msg) = frame {
+            self.reject(command.name(), msg);
+        }
+    }
 
-        // 批量写入文件
-        for msg in &buffer {
-            if let Err(e) = file.write_all(msg).await {
-                tracing::error!("AOF 写入失败: {}", e);
+    fn reject(&mut self, name: &str, reason: String) {
+        let rejected = self
+            .rejected
+            .entry(name.to_lowercase())
+            .or_insert((0, reason));
+        rejected.0 += 1;
+    }
+
+    /// 一个命令一行 没有执行不了的就是空的
+    fn report(&self, path: &Path) -> Vec<String> {
+        self.rejected
+            .iter()
+            .map(|(name, (count, reason))| {
+                format!("{}: {} 有 {} 条没有执行 ({})", path.display(), name, count, reason)
+            })
+            .collect()
+    }
+}
+
+// AOF 里只会有写命令 kv 认识但不是写命令的 (比如 EVAL 脚本) 也不能拿来重放
+fn replayable(command: &Command) -> Result<(), &'static str> {
+    match command {
+        Command::Unimplement(_) => Err("kv 还不支持这个命令"),
+        command if command.spec().is_some_and(|spec| spec.has_flag(CommandFlag::Write)) => Ok(()),
+        _ => Err("不是写命令 不能在 AOF 里重放"),
+    }
+}
+
+// 老版本把 EX 换算成毫秒之后写成了 EXAT 秒数不可能这么大 (这已经是 5000 多年以后了)
+// 只在重放 AOF 的时候把这么大的 EXAT 当毫秒 客户端发的 EXAT 永远是秒
+const LEGACY_EXAT_MS: u64 = 100_000_000_000;
+
+fn legacy_exat(command: Command) -> Command {
+    match command {
+        Command::Set(mut set) => {
+            if let Some(Expiration::EXAT(ms)) = set.expiration
+                && ms >= LEGACY_EXAT_MS
+            {
+                set.expiration = Some(Expiration::PXAT(ms));
             }
+            Command::Set(set)
         }
+        command => command,
+    }
+}
+
+// 重放到的时候已经过期了的 SET 不用真的写进去再等着过期
+// 覆盖写的结果就是这个 key 没了 换成 DEL NX 的话原来有没有都不变 直接跳过
+fn skip_expired(command: Command, now: u64) -> Option<Command> {
+    let Command::Set(set) = &command else {
+        return Some(command);
+    };
+    match set.expiration.as_ref().map(calculate_expiration_timestamp_ms) {
+        Some(expires_at) if expires_at < now => {}
+        _ => return Some(command),
+    }
+    if matches!(set.condition, Some(SetCondition::NX)) {
+        return None;
+    }
+    Some(Command::Del(DelCommand {
+        keys: vec![set.key.clone()],
+    }))
+}
+
+// 命令名 大写
+pub fn frame_command_name(frame: &Frame) -> Option<String> {
+    match frame {
+        Frame::Array(items) => match items.first() {
+            Some(Frame::Bulk(name)) => Some(String::from_utf8_lossy(name).to_uppercase()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
-        // 强制将系统缓冲区的数据刷到磁盘
-        if let Err(e) = file.flush().await {
-            tracing::error!("AOF 刷盘失败: {}", e);
+/// SELECT 的库号 参数不对或者超出范围返回 None 复制流里的 SELECT 也用它
+pub fn select_index(frame: &Frame, databases: usize) -> Option<usize> {
+    let Frame::Array(items) = frame else {
+        return None;
+    };
+    match items.get(1) {
+        Some(Frame::Bulk(index)) => std::str::from_utf8(index)
+            .ok()
+            .and_then(|index| index.parse::<usize>().ok())
+            .filter(|index| *index < databases),
+        _ => None,
+    }
+}
+
+// SELECT/MULTI/EXEC 只在 AOF 里出现 不走普通命令的解析
+fn aof_directive(frame: &Frame) -> Option<String> {
+    frame_command_name(frame).filter(|name| matches!(name.as_str(), "SELECT" | "MULTI" | "EXEC"))
+}
+
+/// 启动的时候按清单加载 先加载 base 再按顺序重放每个 incr
+/// allow_truncated 是 aof-load-truncated 离线工具不能改文件 传 false
+/// 返回 kv 执行不了、跳过了的东西 一项一行
+pub async fn load_aof(
+    manifest: &AofManifest,
+    db: &Db,
+    allow_truncated: bool,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let files: Vec<&AofFile> = manifest.base.iter().chain(&manifest.incrs).collect();
+    let mut report = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        let path = manifest.path(file);
+        if file.is_snapshot() {
+            let loaded = load_snapshot(db, &path)
+                .await?
+                .ok_or_else(|| format!("{} 不存在", file.name))?;
+            tracing::info!("AOF base {} 加载了 {} 个 key", file.name, loaded);
+            continue;
         }
+        // 后面的文件都是空的 这个文件的结尾就是最后一次写入 断电的时候可能只写了一半
+        let is_tail = files[index + 1..]
+            .iter()
+            .all(|later| std::fs::metadata(manifest.path(later)).is_ok_and(|meta| meta.len() == 0));
+        report.extend(explain_execute_aofcommand(&path, db, is_tail && allow_truncated).await?);
     }
+    Ok(report)
 }
 
+/// 重放一个 RESP 格式的 AOF 文件 一边读一边执行 内存里只放还没解析完的那一段
+/// 结尾的命令不完整 (包括 MULTI 了没有 EXEC) 的时候 allow_truncated 就把文件截到最后一条完整的命令
+/// 开头是 RDB 的 (redis 的 RDB 前缀 或者 redis 7 的 .base.rdb) 先把 RDB 部分加载了再接着重放
 pub async fn explain_execute_aofcommand(
-    path: &str,
-    db: & mut Db,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut file = File::open(path)?;
-    //单线程恢复可以很大
-    let mut file_data: Vec<u8> = vec![0; 1024 * 1024 * 512];
-    //这个是应该写的区域 会随着不断变化
-    let mut file_data_ref = &mut file_data[..];
-    let mut exec_time;
-    let mut tail_file_length = 0;
-    //这个默认恢复从0 开始 
-    //let mut conn_state = ConnectionState { selected_db: 0 ,client_address: None};
+    path: &Path,
+    db: &Db,
+    allow_truncated: bool,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let (mut stream, preamble) = open_aof_stream(path).await.map_err(|e| match e {
+        AofError::Corrupted(msg) => format!("{} 开头的 RDB 部分加载失败: {}", path.display(), msg).into(),
+        e => Box::<dyn Error + Send + Sync>::from(e),
+    })?;
+    let mut report = Vec::new();
+    if let Some(import) = preamble {
+        let loaded = restore_entries(db, import.entries).await;
+        LOADING.progress(stream.offset);
+        tracing::info!("{} 开头的 RDB 部分 (版本 {}) 加载了 {} 个 key", path.display(), import.version, loaded);
+        report.extend(
+            import
+                .skipped
+                .iter()
+                .map(|skipped| format!("{}: RDB 部分跳过 {}", path.display(), skipped)),
+        );
+    }
+    let mut replay = AofReplay::default();
+    let mut progress = LoadProgress::new(path);
+    // 到这里为止都是完整的命令 而且不在 MULTI 里面
+    let mut valid_len = stream.offset;
+    // 进度按文件偏移算 跳过的 #TS 注释行也要算上 不然加载完了也到不了 100%
+    let mut loaded = stream.offset;
     loop {
-        let size: usize = file.read(file_data_ref)? + tail_file_length;
-        let mut tail_size: usize = 0;
-        let mut data: &[u8] = &file_data[0..size];
-        
-        exec_time = 0;
-        if size == 0 {
-            break;
+        match stream.next_frame().await {
+            Ok(Some((frame, _))) => {
+                replay.apply(frame, db).await?;
+                progress.advance((stream.offset - loaded) as usize);
+                loaded = stream.offset;
+                if replay.multi.is_none() {
+                    valid_len = stream.offset;
+                }
+            }
+            Ok(None) if replay.multi.is_none() => {
+                LOADING.progress(stream.offset - loaded);
+                break;
+            }
+            Ok(None) | Err(AofError::Truncated) => {
+                if !allow_truncated {
+                    return Err(format!(
+                        "{} 在 {} 字节处被截断了 可以用 kv-check-aof --fix 修复 或者打开 aof-load-truncated",
+                        path.display(),
+                        valid_len
+                    )
+                    .into());
+                }
+                tracing::warn!(
+                    "{} 结尾的命令不完整 截到最后一条完整命令的位置 {} 丢掉了 {} 字节",
+                    path.display(),
+                    valid_len,
+                    stream.offset + stream.buf.len() as u64 - valid_len
+                );
+                truncate_file(path, valid_len)?;
+                break;
+            }
+            Err(AofError::Corrupted(msg)) => {
+                return Err(format!(
+                    "{} 在 {} 字节处格式错误: {} 可以用 kv-check-aof 检查",
+                    path.display(),
+                    stream.offset,
+                    msg
+                )
+                .into());
+            }
+            Err(e) => return Err(e.into()),
         }
+    }
+    progress.done();
+    report.extend(replay.report(path));
+    Ok(report)
+}
+
+pub fn truncate_file(path: &Path, len: u64) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AofError {
+    #[error("IO 错误: {0}")]
+    Io(#[from] std::io::Error),
+    // 结尾的命令不完整 一般是写到一半断电了 截掉就行
+    #[error("结尾的命令不完整")]
+    Truncated,
+    // 中间的数据坏了 截掉的话后面的数据都没了 要人来决定
+    #[error("格式错误: {0}")]
+    Corrupted(String),
+}
 
+/// kv-check-aof 的检查结果
+#[derive(Debug)]
+pub struct AofCheck {
+    pub size: u64,
+    // 完整的命令条数
+    pub commands: u64,
+    // 到这里为止都是完整的命令 而且不在 MULTI 里面 --fix 就截到这里
+    pub valid_len: u64,
+    // 开头 RDB 部分的字节数和 key 数 没有 RDB 前缀是 None
+    pub preamble: Option<(u64, usize)>,
+    // 第一个出问题的位置
+    pub problem: Option<(u64, AofError)>,
+}
+
+/// 只检查格式不执行 每条都得是 RESP 数组 MULTI/EXEC 要配对
+pub async fn check_aof_file(path: &Path) -> std::io::Result<AofCheck> {
+    let size = tokio::fs::metadata(path).await?.len();
+    let mut check = AofCheck {
+        size,
+        commands: 0,
+        valid_len: 0,
+        preamble: None,
+        problem: None,
+    };
+    let mut stream = match open_aof_stream(path).await {
+        Ok((stream, preamble)) => {
+            check.preamble = preamble.map(|import| (stream.offset, import.entries.len()));
+            check.valid_len = stream.offset;
+            stream
+        }
+        Err(AofError::Io(e)) => return Err(e),
+        // RDB 部分坏了 后面的命令也没法找到开头 整个文件都算坏的
+        Err(e) => {
+            check.problem = Some((0, e));
+            return Ok(check);
+        }
+    };
+    let mut multi_start = None;
+    loop {
+        let offset = stream.offset;
+        match stream.next_frame().await {
+            Ok(Some((frame, _))) => {
+                if let Err(msg) = check_command_frame(&frame, &mut multi_start, offset) {
+                    check.problem = Some((offset, AofError::Corrupted(msg)));
+                    break;
+                }
+                check.commands += 1;
+                if multi_start.is_none() {
+                    check.valid_len = stream.offset;
+                }
+            }
+            Ok(None) => {
+                if let Some(start) = multi_start {
+                    check.problem = Some((start, AofError::Truncated));
+                }
+                break;
+            }
+            Err(AofError::Io(e)) => return Err(e),
+            Err(e) => {
+                check.problem = Some((offset, e));
+                break;
+            }
+        }
+    }
+    Ok(check)
+}
+
+fn check_command_frame(frame: &Frame, multi_start: &mut Option<u64>, offset: u64) -> Result<(), String> {
+    let Frame::Array(items) = frame else {
+        return Err("不是命令数组".into());
+    };
+    if items.is_empty() || !items.iter().all(|item| matches!(item, Frame::Bulk(_))) {
+        return Err("命令必须是非空的 bulk string 数组".into());
+    }
+    match aof_directive(frame).as_deref() {
+        Some("MULTI") if multi_start.is_some() => Err("MULTI 里面又有 MULTI".into()),
+        Some("MULTI") => {
+            *multi_start = Some(offset);
+            Ok(())
+        }
+        Some("EXEC") if multi_start.is_none() => Err("EXEC 没有对应的 MULTI".into()),
+        Some("EXEC") => {
+            *multi_start = None;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// redis 开了 aof-use-rdb-preamble 的 AOF 开头是一整个 RDB redis 7 的 base 文件干脆整个就是 RDB
+// RDB 部分要整个读进内存才能解 解完了文件跳到 RDB 后面 剩下的 RESP 照常一条一条读
+async fn open_aof_stream(
+    path: &Path,
+) -> Result<(AofStream<tokio::fs::File>, Option<RdbImport>), AofError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut magic = Vec::with_capacity(RDB_MAGIC.len());
+    (&mut file).take(RDB_MAGIC.len() as u64).read_to_end(&mut magic).await?;
+    if magic != RDB_MAGIC {
+        file.rewind().await?;
+        return Ok((AofStream::new(file, LOAD_CHUNK), None));
+    }
+    let data = tokio::fs::read(path).await?;
+    let (import, consumed) = decode_rdb_preamble(&data).map_err(AofError::Corrupted)?;
+    drop(data);
+    file.seek(SeekFrom::Start(consumed as u64)).await?;
+    let mut stream = AofStream::new(file, LOAD_CHUNK);
+    stream.offset = consumed as u64;
+    Ok((stream, Some(import)))
+}
+
+const RDB_MAGIC: &[u8] = b"REDIS";
+
+// 每次从文件里读这么多 一条命令比这个大的话缓冲区跟着涨 读完这条再缩回来
+const LOAD_CHUNK: usize = 64 * 1024;
+
+/*
+   从 AOF 文件里一条一条地读命令
+   1.缓冲区里解析不出完整的命令就再读一块 每次至少把缓冲区读成两倍
+     不然一条几百 MB 的命令每读 64K 就要从头解析一遍
+   2.文件读完了缓冲区里还有剩的 就是结尾的命令不完整
+*/
+struct AofStream<R> {
+    reader: R,
+    buf: BytesMut,
+    chunk: usize,
+    eof: bool,
+    // 已经解析出来的命令在文件里一共占了多少字节
+    offset: u64,
+}
+
+impl<R: AsyncRead + Unpin> AofStream<R> {
+    fn new(reader: R, chunk: usize) -> Self {
+        AofStream {
+            reader,
+            buf: BytesMut::with_capacity(chunk),
+            chunk,
+            eof: false,
+            offset: 0,
+        }
+    }
+
+    /// 返回下一条命令和它在文件里占的字节数 文件读完了返回 None
+    async fn next_frame(&mut self) -> Result<Option<(Frame, usize)>, AofError> {
         loop {
-            if data.len() == 0 {
-                return Ok(())
-            }
-            match parse_frame(data) {
-                Ok(frame) => match frame {
-                    //这个分支只有不可变
-                    Some((frame, size)) => {
-                        match Command::try_from(frame) {
-                            Ok(frame) => {
-                                execute_command(frame, db).await?;
-                                data = &data[size..];
-                                exec_time += 1;
-                            }
-                            Err(e) => {
-                                return Err(e.into());
-                            }
-                        };
-                        tail_size = size;
-                    }
-                    //这个分支有可变的复制 但是并没有使用可变 但是直接使用了对象  对象 不可变 可变三者交叉使用
-                    //只要每个域都没有问题 就可以交叉使用 没有问题
-                    None => {
-                        file_data.copy_within(tail_size..size, 0);
-                        // 5. 将剩下的数据移动到缓冲区头部
-                        file_data_ref = &mut file_data[(size - tail_size)..];
-                        tail_file_length = size - tail_size;
-                        if exec_time == 0 {
-                            return Err("字符串文件过大 大于512M".into());
-                        }
-                        break;
+            // redis 开了 aof-timestamp-enabled 会写 #TS:时间戳 这样的注释行 跳过去
+            if self.buf.first() == Some(&b'#') {
+                if let Some(end) = self.buf.iter().position(|byte| *byte == b'\n') {
+                    self.buf.advance(end + 1);
+                    self.offset += end as u64 + 1;
+                    continue;
+                }
+                if self.eof {
+                    return Err(AofError::Truncated);
+                }
+                self.fill().await?;
+                continue;
+            }
+            let parsed = parse_frame(&self.buf).map_err(|e| AofError::Corrupted(e.to_string()))?;
+            if let Some((frame, size)) = parsed {
+                self.buf.advance(size);
+                self.offset += size as u64;
+                // 刚读完一条特别大的命令 把大缓冲区还回去
+                if self.buf.capacity() > self.chunk * 16 && self.buf.len() < self.chunk {
+                    self.buf = BytesMut::from(&self.buf[..]);
+                }
+                return Ok(Some((frame, size)));
+            }
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(AofError::Truncated);
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> std::io::Result<()> {
+        let target = self.buf.len() + self.buf.len().max(self.chunk);
+        while self.buf.len() < target {
+            self.buf.reserve(target - self.buf.len());
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                self.eof = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 加载进度 每秒打一行日志 顺便更新 INFO 里的 loading_loaded_bytes
+struct LoadProgress<'a> {
+    path: &'a Path,
+    started: Instant,
+    last_report: Instant,
+    bytes: u64,
+    commands: u64,
+    total_commands: u64,
+}
+
+impl<'a> LoadProgress<'a> {
+    fn new(path: &'a Path) -> Self {
+        let now = Instant::now();
+        LoadProgress {
+            path,
+            started: now,
+            last_report: now,
+            bytes: 0,
+            commands: 0,
+            total_commands: 0,
+        }
+    }
+
+    fn advance(&mut self, size: usize) {
+        LOADING.progress(size as u64);
+        self.bytes += size as u64;
+        self.commands += 1;
+        self.total_commands += 1;
+        let elapsed = self.last_report.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        tracing::info!(
+            "正在加载 {}: 已完成 {:.1}% 每秒 {:.0} 字节 {:.0} 条命令",
+            self.path.display(),
+            LOADING.status().loaded_perc(),
+            self.bytes as f64 / secs,
+            self.commands as f64 / secs
+        );
+        self.last_report = Instant::now();
+        self.bytes = 0;
+        self.commands = 0;
+    }
+
+    fn done(&self) {
+        tracing::info!(
+            "{} 加载完成 {} 条命令 耗时 {:?}",
+            self.path.display(),
+            self.total_commands,
+            self.started.elapsed()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aof_exchange::argv_to_frame;
+    use crate::types::{Element, Value, ValueEntry};
+
+    #[tokio::test]
+    async fn test_aof_stream() {
+        // 中间那条命令比每次读的块大得多
+        let big = "x".repeat(1000);
+        let mut data = select_command(1);
+        data.extend(argv_frame(&["SET", "a", &big]));
+        data.extend(argv_frame(&["DEL", "a"]));
+        let mut stream = AofStream::new(&data[..], 16);
+        let mut sizes = Vec::new();
+        while let Some((_, size)) = stream.next_frame().await.unwrap() {
+            sizes.push(size);
+        }
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        assert!(stream.buf.capacity() < 1000);
+
+        // 结尾的命令不完整
+        let mut stream = AofStream::new(&data[..data.len() - 3], 16);
+        for _ in 0..2 {
+            assert!(stream.next_frame().await.unwrap().is_some());
+        }
+        assert!(stream.next_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_aof_file() {
+        let path = std::env::temp_dir().join(format!("kv-check-{}.aof", std::process::id()));
+        let mut data = argv_frame(&["SET", "a", "1"]);
+        let complete = data.len() as u64;
+        data.extend(argv_frame(&["MULTI"]));
+        data.extend(argv_frame(&["SET", "b", "2"]));
+        let check_bytes = |bytes: Vec<u8>| {
+            let path = path.clone();
+            async move {
+                std::fs::write(&path, bytes).unwrap();
+                check_aof_file(&path).await.unwrap()
+            }
+        };
+
+        // MULTI 没有 EXEC 就结束了 整段都不算
+        let check = check_bytes(data.clone()).await;
+        assert!(matches!(check.problem, Some((offset, AofError::Truncated)) if offset == complete));
+        assert_eq!((check.commands, check.valid_len), (3, complete));
+
+        let mut full = data.clone();
+        full.extend(argv_frame(&["EXEC"]));
+        let check = check_bytes(full.clone()).await;
+        assert!(check.problem.is_none());
+        assert_eq!(check.valid_len, full.len() as u64);
+
+        // 最后一条只写了一半
+        let check = check_bytes(full[..full.len() - 2].to_vec()).await;
+        assert!(matches!(check.problem, Some((_, AofError::Truncated))));
+        assert_eq!(check.valid_len, complete);
+
+        // 中间坏了
+        let mut corrupted = argv_frame(&["SET", "a", "1"]);
+        corrupted.extend(b"garbage\r\n");
+        corrupted.extend(argv_frame(&["SET", "b", "2"]));
+        let check = check_bytes(corrupted).await;
+        assert!(matches!(check.problem, Some((offset, AofError::Corrupted(_))) if offset == complete));
+
+        truncate_file(&path, complete).unwrap();
+        assert!(check_aof_file(&path).await.unwrap().problem.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_skip_expired() {
+        let set = |args: &[&str]| {
+            let argv = args.iter().map(|arg| Bytes::from(arg.to_string())).collect();
+            Command::try_from(argv_to_frame(argv)).unwrap()
+        };
+        let now = 1_700_000_000_000;
+        // 没过期的原样执行
+        let command = skip_expired(set(&["SET", "k", "v", "PXAT", "1700000000001"]), now).unwrap();
+        assert!(matches!(command, Command::Set(_)));
+        assert!(matches!(skip_expired(set(&["SET", "k", "v"]), now), Some(Command::Set(_))));
+        // 过期了的覆盖写等于删掉
+        let command = skip_expired(set(&["SET", "k", "v", "PXAT", "1699999999999"]), now).unwrap();
+        assert!(matches!(command, Command::Del(del) if del.keys[0].as_str() == "k"));
+        // EXAT 是秒
+        let command = skip_expired(set(&["SET", "k", "v", "EXAT", "1699999999"]), now).unwrap();
+        assert!(matches!(command, Command::Del(_)));
+        assert!(skip_expired(set(&["SET", "k", "v", "PXAT", "1", "NX"]), now).is_none());
+    }
+
+    #[test]
+    fn test_legacy_exat() {
+        let set = |args: &[&str]| {
+            let argv = args.iter().map(|arg| Bytes::from(arg.to_string())).collect();
+            Command::try_from(argv_to_frame(argv)).unwrap()
+        };
+        let expiration = |command: Command| match command {
+            Command::Set(set) => set.expiration.as_ref().map(calculate_expiration_timestamp_ms),
+            _ => None,
+        };
+        // 老版本写下的毫秒数 重放的时候按 PXAT 算
+        let command = legacy_exat(set(&["SET", "k", "v", "EXAT", "4102444800000"]));
+        assert_eq!(expiration(command), Some(4102444800000));
+        // 正常的秒数不动
+        let command = legacy_exat(set(&["SET", "k", "v", "EXAT", "4102444800"]));
+        assert_eq!(expiration(command), Some(4102444800000));
+        // 客户端发的 EXAT 不管多大都是秒
+        let command = set(&["SET", "k", "v", "EXAT", "100000000000"]);
+        assert_eq!(expiration(command), Some(100_000_000_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_aof_offset() {
+        let offsets = AofOffset::new();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let message = |payload: &'static [u8]| AofMessage {
+            db: 0,
+            payload: payload.to_vec(),
+            ack: None,
+            replicate: true,
+            offset: 0,
+            epoch: 0,
+        };
+        assert_eq!(offsets.send(tx.reserve().await.unwrap(), message(b"abc")), 3);
+        assert_eq!(offsets.send(tx.reserve().await.unwrap(), message(b"de")), 5);
+        assert_eq!(rx.recv().await.unwrap().offset, 3);
+        assert_eq!(rx.recv().await.unwrap().offset, 5);
+        // 还没写到 5 等不到
+        offsets.fsynced_to(3);
+        assert_eq!(offsets.wait_fsynced(5, Some(Duration::from_millis(10))).await, Ok(false));
+        assert_eq!(offsets.wanted.load(Ordering::SeqCst), 5);
+        offsets.fsynced_to(5);
+        assert_eq!(offsets.wait_fsynced(5, None).await, Ok(true));
+        // 晚到的后台 fsync 不会让偏移量往回走
+        offsets.fsynced_to(3);
+        assert_eq!(offsets.fsynced(), 5);
+    }
+
+    // /dev/full 上 flush 一定失败 写坏之后所有人都要收到错误 也不能发给 replica
+    #[tokio::test]
+    async fn test_write_failure_poisons() {
+        let offsets: &'static AofOffset = Box::leak(Box::new(AofOffset::new()));
+        let file = OpenOptions::new().write(true).open("/dev/full").await.unwrap();
+        let sync_file = Arc::new(file.try_clone().await.unwrap().into_std().await);
+        let mut writer = AofWriter {
+            manifest: AofManifest::new(std::env::temp_dir(), "poison.aof"),
+            file: BufWriter::new(file),
+            sync_file,
+            policy: AppendFsync::Always,
+            current_db: None,
+            dirty: false,
+            written: 0,
+            background_sync: None,
+            fsync_failures: 0,
+            offsets,
+            held: Vec::new(),
+        };
+        let message = |offset: u64| {
+            let (ack, ack_rx) = oneshot::channel();
+            let message = AofMessage {
+                db: 0,
+                payload: argv_frame(&["SET", "a", "1"]),
+                ack: Some(ack),
+                // 走到 REPLICATION.feed 的话测试进程会去读配置 这里也顺便确认了没有发给 replica
+                replicate: true,
+                offset,
+                epoch: 0,
+            };
+            (message, ack_rx)
+        };
+        let waiter = tokio::spawn(offsets.wait_fsynced(10, None));
+        let (first, first_ack) = message(10);
+        writer.write_batch(vec![first]).await;
+        assert!(first_ack.await.unwrap().is_err());
+        assert_eq!(writer.written, 0);
+        assert!(offsets.poisoned().is_some());
+        // 之前在等的 WAITAOF 也要收到错误
+        assert!(waiter.await.unwrap().is_err());
+        assert!(offsets.wait_fsynced(20, Some(Duration::from_millis(10))).await.is_err());
+
+        // 写坏之后的批次直接失败
+        let (second, second_ack) = message(20);
+        writer.write_batch(vec![second]).await;
+        assert!(second_ack.await.unwrap().is_err());
+        assert_eq!(offsets.fsynced(), 0);
+    }
+
+    // appendfsync always 的组提交 同时等着的写入一起 fsync 一次 落盘之前谁都回复不了
+    #[tokio::test]
+    async fn test_always_group_commit() {
+        let offsets: &'static AofOffset = Box::leak(Box::new(AofOffset::new()));
+        let path = std::env::temp_dir().join(format!("group-commit-{}.aof", std::process::id()));
+        let file = open_append(&path).await.unwrap();
+        let sync_file = Arc::new(file.try_clone().await.unwrap().into_std().await);
+        let mut writer = AofWriter {
+            manifest: AofManifest::new(std::env::temp_dir(), "group-commit.aof"),
+            file: BufWriter::new(file),
+            sync_file,
+            policy: AppendFsync::Always,
+            // 不用补 SELECT 文件大小就是偏移量
+            current_db: Some(0),
+            dirty: false,
+            written: 0,
+            background_sync: None,
+            fsync_failures: 0,
+            offsets,
+            held: Vec::new(),
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        // 四个连接各写一条 回复之前都在等自己那条落盘
+        let mut replies = Vec::new();
+        for client_id in 0..4 {
+            let (ack, ack_rx) = oneshot::channel();
+            let message = AofMessage {
+                db: 0,
+                payload: argv_frame(&["SET", &format!("k{}", client_id), "v"]),
+                ack: Some(ack),
+                replicate: false,
+                offset: 0,
+                epoch: 0,
+            };
+            let offset = offsets.send(tx.reserve().await.unwrap(), message);
+            let state = ConnectionState::new(0, None, client_id);
+            *state.pending_aof.lock().unwrap() = Some(ack_rx);
+            let reply = CONN_STATE.scope(state, async move {
+                let result = wait_aof_durable().await;
+                (result, offsets.status().fsyncs)
+            });
+            replies.push((offset, tokio::spawn(reply)));
+        }
+        tokio::task::yield_now().await;
+        assert!(replies.iter().all(|(_, reply)| !reply.is_finished()));
+
+        let first = rx.recv().await.unwrap();
+        writer.receive(first, &mut rx).await;
+        let total = replies.last().unwrap().0;
+        assert_eq!(offsets.fsynced(), total);
+        for (offset, reply) in replies {
+            let (result, fsyncs) = reply.await.unwrap();
+            assert_eq!(result, Ok(()));
+            // 拿到回复的时候已经落盘了 而且四条共用一次 fsync
+            assert_eq!(fsyncs, 1);
+            assert!(offsets.fsynced() >= offset);
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), total);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // everysec 的后台 fsync 失败了下一秒接着试 成功了就清零 一直失败就写坏
+    #[tokio::test]
+    async fn test_background_fsync_retry() {
+        let offsets: &'static AofOffset = Box::leak(Box::new(AofOffset::new()));
+        // /dev/null 写得进去 fsync 一定失败
+        let file = OpenOptions::new().write(true).open("/dev/null").await.unwrap();
+        let sync_file = Arc::new(file.try_clone().await.unwrap().into_std().await);
+        let mut writer = AofWriter {
+            manifest: AofManifest::new(std::env::temp_dir(), "fsync-retry.aof"),
+            file: BufWriter::new(file),
+            sync_file,
+            policy: AppendFsync::EverySec,
+            current_db: None,
+            dirty: true,
+            written: 5,
+            background_sync: None,
+            fsync_failures: 0,
+            offsets,
+            held: Vec::new(),
+        };
+        // 下一秒的 tick 拿到上一次的结果
+        async fn next_second(writer: &mut AofWriter) {
+            while writer.background_sync.as_ref().is_some_and(|handle| !handle.is_finished()) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            writer.tick().await;
+        }
+        writer.tick().await;
+        next_second(&mut writer).await;
+        assert_eq!(writer.fsync_failures, 1);
+        assert!(!offsets.status().last_write_ok);
+        // 没有落盘 还要再 fsync 一次
+        assert!(writer.background_sync.is_some());
+        assert_eq!(offsets.fsynced(), 0);
+
+        // 换成能 fsync 的文件 重试成功了 之前的写入都算落盘
+        let path = std::env::temp_dir().join(format!("fsync-retry-{}.aof", std::process::id()));
+        writer.sync_file = Arc::new(std::fs::File::create(&path).unwrap());
+        next_second(&mut writer).await;
+        next_second(&mut writer).await;
+        assert_eq!(writer.fsync_failures, 0);
+        assert_eq!(offsets.fsynced(), 5);
+        let status = offsets.status();
+        assert!(status.last_write_ok);
+        assert_eq!((status.fsyncs, status.fsync_errors), (3, 2));
+        assert!(writer.background_sync.is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        // 一直失败 到了次数就写坏 写命令回 MISCONF
+        writer.sync_file = Arc::new(std::fs::File::options().write(true).open("/dev/null").unwrap());
+        writer.written = 8;
+        writer.dirty = true;
+        writer.tick().await;
+        for failures in 1..FSYNC_FAILURE_LIMIT {
+            next_second(&mut writer).await;
+            assert_eq!(writer.fsync_failures, failures);
+            assert!(offsets.poisoned().is_none());
+        }
+        next_second(&mut writer).await;
+        assert!(offsets.poisoned().is_some());
+        assert!(offsets.wait_fsynced(8, None).await.is_err());
+        let command = |args: &[&str]| {
+            let argv = args.iter().map(|arg| Bytes::from(arg.to_string())).collect();
+            Command::try_from(argv_to_frame(argv)).unwrap()
+        };
+        let refused = offsets.check_command(&command(&["SET", "a", "1"])).unwrap_err();
+        assert!(refused.starts_with("MISCONF"), "{}", refused);
+        assert!(offsets.check_command(&command(&["GET", "a"])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_replay_redis_aof() {
+        crate::core_time::refresh_cached_time();
+        // RDB 前缀 库 0 里一个 p=1 校验和是 0 表示不校验
+        let mut data = b"REDIS0011\xfe\x00\x00\x01p\x011\xff".to_vec();
+        data.extend([0; 8]);
+        let preamble = data.len() as u64;
+        data.extend(b"#TS:1700000000\r\n");
+        for argv in [
+            &["SELECT", "1"][..],
+            &["SET", "a", "1"],
+            &["PEXPIREAT", "a", "4102444800000"],
+            &["PEXPIREAT", "missing", "4102444800000"],
+            &["SELECT", "0"],
+            &["MULTI"],
+            &["SET", "b", "2"],
+            &["SELECT", "2"],
+            &["HSET", "h", "f", "v"],
+            &["LPUSH", "l", "x"],
+            &["EXEC"],
+            &["LPUSH", "l", "y"],
+            &["GET", "a"],
+            &["SET", "c"],
+            &["SET", "d", "1"],
+            &["PEXPIREAT", "d", "1"],
+        ] {
+            data.extend(argv_frame(argv));
+        }
+        let path = std::env::temp_dir().join(format!("kv-redis-{}.aof", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let check = check_aof_file(&path).await.unwrap();
+        assert!(check.problem.is_none());
+        assert_eq!(check.preamble, Some((preamble, 1)));
+        assert_eq!((check.commands, check.valid_len), (16, data.len() as u64));
+
+        let db = Db::new(&crate::config::EvictionType::LRU);
+        let loaded_before = LOADING.status().loaded_bytes;
+        let report = explain_execute_aofcommand(&path, &db, false).await.unwrap();
+        // RDB 前缀 注释行和每条命令都算进加载进度 别的测试也在加 所以只能是不少于
+        assert!(LOADING.status().loaded_bytes >= loaded_before + data.len() as u64);
+        std::fs::remove_file(&path).unwrap();
+        let report: Vec<_> = report
+            .iter()
+            .map(|line| line.split_once(": ").unwrap().1.split(" (").next().unwrap())
+            .collect();
+        assert_eq!(report, ["get 有 1 条没有执行", "lpush 有 2 条没有执行", "set 有 1 条没有执行"]);
+
+        let keys = |db_index: usize| {
+            let db = db.clone();
+            async move {
+                let mut keys = Vec::new();
+                for shard_index in 0..crate::db::eviction::NUM_SHARDS {
+                    for (key, entry) in db.store.shard_entries(db_index, shard_index).await {
+                        keys.push((key.to_string(), entry.expires_at));
                     }
-                },
-                Err(e) => {
-                    return Err(e.into());
                 }
+                keys.sort();
+                keys
             }
+        };
+        // SELECT 在 MULTI 里面也算数 PEXPIREAT 过去的时间等于删掉
+        assert_eq!(keys(0).await, [("b".to_string(), None), ("p".to_string(), None)]);
+        assert_eq!(keys(1).await, [("a".to_string(), Some(4102444800000))]);
+        assert_eq!(keys(2).await, [("h".to_string(), None)]);
+    }
+
+    // 脚本改过的列表/集合/有序集合 记下来的效果命令重放出来要和原来一样
+    #[tokio::test]
+    async fn test_replay_collection_effects() {
+        crate::core_time::refresh_cached_time();
+        let str = |s: &'static str| Element::String(Bytes::from_static(s.as_bytes()));
+        let entries = [
+            ("l", Value::List([str("a"), Element::Int(5)].into()), Some(4102444800000)),
+            ("s", Value::Set([str("m"), Element::Int(-2)].into_iter().collect()), None),
+            ("z", Value::ZSet([(str("a"), 1.5), (Element::Int(2), f64::NEG_INFINITY)].into_iter().collect()), None),
+        ];
+        let mut commands = Vec::new();
+        for (key, value, expires_at) in &entries {
+            let entry = ValueEntry::new(value.clone(), *expires_at);
+            commands.extend(crate::aof_exchange::entry_effect_argv(key, Some(&entry)));
+        }
+        let mut data = argv_to_frame(vec![Bytes::from_static(b"MULTI")]).serialize();
+        for argv in commands {
+            data.extend(argv_to_frame(argv).serialize());
         }
+        data.extend(argv_to_frame(vec![Bytes::from_static(b"EXEC")]).serialize());
+        let path = std::env::temp_dir().join(format!("kv-effects-{}.aof", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let db = Db::new(&crate::config::EvictionType::LRU);
+        let report = explain_execute_aofcommand(&path, &db, false).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+        for (key, value, expires_at) in entries {
+            let key = Arc::new(key.to_string());
+            let shard_index = crate::db::eviction::MemoryCache::get_shard_index(&key);
+            let entry = db.store.shard_entries(0, shard_index).await.into_iter().find(|(k, _)| *k == key).unwrap().1;
+            assert_eq!((entry.data, entry.expires_at), (value, expires_at));
+        }
+    }
+
+    fn argv_frame(argv: &[&str]) -> Vec<u8> {
+        Frame::Array(
+            argv.iter()
+                .map(|arg| Frame::Bulk(Bytes::from(arg.to_string())))
+                .collect(),
+        )
+        .serialize()
     }
-    Ok(())
 }
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use crate::core_aof::{AofError, check_aof_file, truncate_file};
+use crate::core_aof_manifest::AofManifest;
+use crate::core_snapshot::decode_snapshot;
+
+/*
+   kv-check-aof 检查 (和修复) AOF 文件
+   1.可以给单个 RESP 格式的 AOF 文件 也可以给清单 给清单就按顺序检查里面的每个文件
+     快照格式的 base 只检查能不能完整解出来 (包括校验和) redis 带 RDB 前缀的 AOF 也是先解 RDB 部分
+   2.--fix 把出问题的文件截到最后一条完整的命令 只有后面的文件都是空的才能修
+     不然中间截掉一段 后面的命令就是在缺了数据的基础上重放的
+   3.格式错误 (不只是结尾不完整) 也可以截 但是会丢掉出错位置之后的所有数据 所以要看清楚提示再用
+*/
+
+const USAGE: &str = "用法: kv-check-aof [--fix] <file.aof | file.manifest | file.kvdb>";
+
+/// 返回进程的退出码 文件没问题或者修好了是 0
+pub async fn check_aof(args: impl IntoIterator<Item = String>) -> i32 {
+    let mut fix = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                return 1;
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [path] = paths.as_slice() else {
+        eprintln!("{}", USAGE);
+        return 1;
+    };
+    let files = match list_files(path) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut ok = true;
+    for (index, file) in files.iter().enumerate() {
+        // 和启动加载的规则一样 后面的文件都是空的 这个文件才能截
+        let is_tail = files[index + 1..]
+            .iter()
+            .all(|later| std::fs::metadata(later).is_ok_and(|meta| meta.len() == 0));
+        ok &= check_file(file, fix, is_tail).await;
+    }
+    if ok { 0 } else { 1 }
+}
+
+// 清单展开成里面的文件 按加载顺序
+fn list_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if path.extension().is_some_and(|ext| ext == "manifest") {
+        let manifest = AofManifest::open(path)?;
+        return Ok(manifest
+            .base
+            .iter()
+            .chain(&manifest.incrs)
+            .map(|file| manifest.path(file))
+            .collect());
+    }
+    Ok(vec![path.to_path_buf()])
+}
+
+async fn check_file(path: &Path, fix: bool, is_tail: bool) -> bool {
+    if path.extension().is_some_and(|ext| ext == "kvdb") {
+        return check_snapshot(path).await;
+    }
+    let check = match check_aof_file(path).await {
+        Ok(check) => check,
+        Err(e) => {
+            println!("{}: 读取失败 {}", path.display(), e);
+            return false;
+        }
+    };
+    if let Some((len, keys)) = check.preamble {
+        println!("{}: 开头是 {} 字节的 RDB {} 个 key", path.display(), len, keys);
+    }
+    println!("{}: {} 字节 {} 条完整的命令", path.display(), check.size, check.commands);
+    let Some((offset, problem)) = check.problem else {
+        println!("{}: 没有问题", path.display());
+        return true;
+    };
+    match &problem {
+        AofError::Truncated => println!("{}: 从 {} 字节开始的命令不完整", path.display(), offset),
+        _ => println!("{}: 在 {} 字节处{}", path.display(), offset, problem),
+    }
+    let lost = check.size - check.valid_len;
+    if !is_tail {
+        println!("{}: 后面的文件里还有数据 不能截断 只能手动处理", path.display());
+        return false;
+    }
+    if !fix {
+        println!(
+            "{}: 最后一条完整的命令到 {} 字节 --fix 可以截到这里 会丢掉 {} 字节",
+            path.display(),
+            check.valid_len,
+            lost
+        );
+        return false;
+    }
+    match truncate_file(path, check.valid_len) {
+        Ok(()) => {
+            println!("{}: 已经截到 {} 字节 丢掉了 {} 字节", path.display(), check.valid_len, lost);
+            true
+        }
+        Err(e) => {
+            println!("{}: 截断失败 {}", path.display(), e);
+            false
+        }
+    }
+}
+
+async fn check_snapshot(path: &Path) -> bool {
+    let result = tokio::fs::read(path)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|data| decode_snapshot(&data));
+    match result {
+        Ok(entries) => {
+            println!("{}: 快照没有问题 {} 个 key", path.display(), entries.len());
+            true
+        }
+        Err(e) => {
+            println!("{}: 快照损坏 {} 快照没法截断修复", path.display(), e);
+            false
+        }
+    }
+}
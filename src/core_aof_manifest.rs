@@ -0,0 +1,306 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::core_aof::sync_parent_dir;
+
+/*
+   多文件 AOF (和 redis 7 的 multi part AOF 一个思路)
+   1.appenddirname 目录下一个 base 文件加若干个 incr 文件 清单文件记录有哪些 按什么顺序加载
+     清单一行一个文件：file database.aof.2.base.kvdb seq 2 type b
+   2.base 是重写时的全量数据 平时是二进制快照 (.kvdb) 从老的单文件 AOF 升级上来的是 RESP (.aof)
+     incr 是 RESP 格式的增量写入 写入任务只往最后一个 incr 里追加
+     从 redis 迁移的时候 appenddirname/appendfilename 直接指向 redis 的目录和 appendonly.aof
+     redis 7 的清单和 .base.rdb 都能加载 老版本 redis 的单文件 AOF (包括 RDB 前缀的) 也一样挪进来
+   3.清单总是先写临时文件 fsync 之后 rename 过去 文件要先建好再写进清单
+     所以任何时候崩溃 清单里列的文件都是齐的 要么是旧的一套要么是新的一套
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AofFileKind {
+    Base,
+    Incr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub kind: AofFileKind,
+}
+
+impl AofFile {
+    // base 看扩展名决定按快照还是按 RESP 加载
+    pub fn is_snapshot(&self) -> bool {
+        self.name.ends_with(".kvdb")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AofManifest {
+    dir: PathBuf,
+    // 文件名前缀 就是 appendfilename
+    prefix: String,
+    pub base: Option<AofFile>,
+    pub incrs: Vec<AofFile>,
+}
+
+impl AofManifest {
+    pub(crate) fn new(dir: PathBuf, prefix: &str) -> Self {
+        AofManifest {
+            dir,
+            prefix: prefix.to_string(),
+            base: None,
+            incrs: Vec::new(),
+        }
+    }
+
+    /// 启动的时候调用 返回清单和是不是全新启动 (没有任何 AOF 数据)
+    /// 1.目录里有清单就直接用
+    /// 2.没有清单但是老的单文件 AOF 还在 挪进目录当 base
+    /// 3.都没有就是全新启动 只建一个空的 incr
+    pub fn prepare(config: &Config) -> Result<(Self, bool), String> {
+        let dir = PathBuf::from(&config.appenddirname);
+        let mut manifest = AofManifest::new(dir.clone(), &config.appendfilename);
+        let manifest_path = manifest.manifest_path();
+        if manifest_path.exists() {
+            let mut manifest = AofManifest::open(&manifest_path)?;
+            if manifest.incrs.is_empty() {
+                manifest.add_incr().map_err(|e| e.to_string())?;
+            }
+            return Ok((manifest, false));
+        }
+        fs::create_dir_all(&dir).map_err(|e| format!("创建 {} 失败: {}", dir.display(), e))?;
+        let legacy = PathBuf::from(&config.appendfilename);
+        let fresh = fs::metadata(&legacy).map_or(true, |meta| meta.len() == 0);
+        if !fresh {
+            let base = AofFile {
+                name: format!("{}.1.base.aof", config.appendfilename),
+                seq: 1,
+                kind: AofFileKind::Base,
+            };
+            fs::rename(&legacy, manifest.path(&base))
+                .map_err(|e| format!("把 {} 挪进 {} 失败: {}", legacy.display(), dir.display(), e))?;
+            tracing::info!("老的 AOF 文件 {} 挪成了 {}", legacy.display(), base.name);
+            manifest.base = Some(base);
+        }
+        manifest.add_incr().map_err(|e| e.to_string())?;
+        Ok((manifest, fresh))
+    }
+
+    /// 按清单文件的路径读进来 文件名前缀就是清单名去掉 .manifest kv-check-aof 也用这个
+    pub fn open(manifest_path: &Path) -> Result<Self, String> {
+        let prefix = manifest_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".manifest"))
+            .ok_or_else(|| format!("{} 不是 .manifest 文件", manifest_path.display()))?;
+        let dir = manifest_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut manifest = AofManifest::new(dir, prefix);
+        let content = fs::read_to_string(manifest_path)
+            .map_err(|e| format!("读取 {} 失败: {}", manifest_path.display(), e))?;
+        let (base, incrs) = parse_manifest(&content)
+            .map_err(|e| format!("清单 {} 格式不对: {}", manifest_path.display(), e))?;
+        manifest.base = base;
+        manifest.incrs = incrs;
+        for file in manifest.base.iter().chain(&manifest.incrs) {
+            if !manifest.path(file).exists() {
+                return Err(format!("清单里的 {} 不存在", file.name));
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn path(&self, file: &AofFile) -> PathBuf {
+        self.dir.join(&file.name)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.prefix))
+    }
+
+    /// 重写的时候新 base 先写到这里 装上去的时候再改名
+    pub fn temp_base_path(&self) -> PathBuf {
+        self.dir.join(format!("temp-{}.rewrite.kvdb", self.prefix))
+    }
+
+    pub fn last_incr(&self) -> &AofFile {
+        self.incrs.last().expect("清单里至少有一个 incr 文件")
+    }
+
+    /// 新建下一个 incr 文件并写进清单 写入任务之后往新文件里追加
+    pub fn add_incr(&mut self) -> std::io::Result<&AofFile> {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        let incr = AofFile {
+            name: format!("{}.{}.incr.aof", self.prefix, seq),
+            seq,
+            kind: AofFileKind::Incr,
+        };
+        fs::File::create(self.path(&incr))?.sync_all()?;
+        self.incrs.push(incr);
+        self.persist()?;
+        Ok(self.last_incr())
+    }
+
+    /// 把重写好的快照装成新的 base seq 比 first_incr 小的 incr 里的东西都已经在快照里了
+    /// 清单换好之后再删旧文件 返回删掉的文件
+    pub fn install_base(&mut self, temp_path: &Path, first_incr: u64) -> std::io::Result<Vec<AofFile>> {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        let base = AofFile {
+            name: format!("{}.{}.base.kvdb", self.prefix, seq),
+            seq,
+            kind: AofFileKind::Base,
+        };
+        fs::rename(temp_path, self.path(&base))?;
+        let mut obsolete: Vec<AofFile> = self.base.replace(base).into_iter().collect();
+        let (kept, merged): (Vec<_>, Vec<_>) =
+            self.incrs.drain(..).partition(|incr| incr.seq >= first_incr);
+        self.incrs = kept;
+        obsolete.extend(merged);
+        self.persist()?;
+        for file in &obsolete {
+            if let Err(e) = fs::remove_file(self.path(file)) {
+                tracing::warn!("删除旧的 AOF 文件 {} 失败: {}", file.name, e);
+            }
+        }
+        Ok(obsolete)
+    }
+
+    /// base 和所有 incr 加起来的大小 自动重写按它算
+    pub fn total_size(&self) -> u64 {
+        self.base
+            .iter()
+            .chain(&self.incrs)
+            .filter_map(|file| fs::metadata(self.path(file)).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    fn persist(&self) -> std::io::Result<()> {
+        let path = self.manifest_path();
+        let temp_path = self.dir.join(format!("temp-{}.manifest", self.prefix));
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(self.serialize().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        sync_parent_dir(&path)
+    }
+
+    fn serialize(&self) -> String {
+        self.base
+            .iter()
+            .chain(&self.incrs)
+            .map(|file| {
+                let kind = match file.kind {
+                    AofFileKind::Base => "b",
+                    AofFileKind::Incr => "i",
+                };
+                format!("file {} seq {} type {}\n", file.name, file.seq, kind)
+            })
+            .collect()
+    }
+}
+
+// 每行是 file <name> seq <seq> type <b|i> 这样的键值对 顺序无所谓 不认识的键跳过
+// redis 的 h (history) 文件已经没用了 直接忽略
+fn parse_manifest(content: &str) -> Result<(Option<AofFile>, Vec<AofFile>), String> {
+    let mut base = None;
+    let mut incrs: Vec<AofFile> = Vec::new();
+    for (line_no, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if !parts.len().is_multiple_of(2) {
+            return Err(format!("第 {} 行不是成对的键值", line_no + 1));
+        }
+        let (mut name, mut seq, mut kind) = (None, None, None);
+        for pair in parts.chunks(2) {
+            match pair[0] {
+                "file" => name = Some(pair[1].to_string()),
+                "seq" => seq = pair[1].parse::<u64>().ok(),
+                "type" => kind = Some(pair[1]),
+                _ => {}
+            }
+        }
+        let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+            return Err(format!("第 {} 行缺少 file/seq/type", line_no + 1));
+        };
+        if name.contains('/') {
+            return Err(format!("第 {} 行的文件名不能带路径", line_no + 1));
+        }
+        match kind {
+            "b" if base.is_none() => {
+                base = Some(AofFile { name, seq, kind: AofFileKind::Base })
+            }
+            "b" => return Err("有不止一个 base 文件".into()),
+            "i" => {
+                if incrs.last().is_some_and(|last| last.seq >= seq) {
+                    return Err(format!("第 {} 行 incr 的 seq 不是递增的", line_no + 1));
+                }
+                incrs.push(AofFile { name, seq, kind: AofFileKind::Incr })
+            }
+            "h" => {}
+            _ => return Err(format!("第 {} 行的 type 不认识", line_no + 1)),
+        }
+    }
+    Ok((base, incrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_parse() {
+        let content = "file database.aof.2.base.kvdb seq 2 type b\n\
+                       file database.aof.1.incr.aof seq 1 type h\n\
+                       file database.aof.3.incr.aof seq 3 type i\n\
+                       file database.aof.4.incr.aof seq 4 type i\n";
+        let (base, incrs) = parse_manifest(content).unwrap();
+        let base = base.unwrap();
+        assert_eq!((base.name.as_str(), base.seq), ("database.aof.2.base.kvdb", 2));
+        assert!(base.is_snapshot());
+        assert_eq!(incrs.iter().map(|incr| incr.seq).collect::<Vec<_>>(), vec![3, 4]);
+
+        let mut manifest = AofManifest::new(PathBuf::from("dir"), "database.aof");
+        manifest.base = Some(base);
+        manifest.incrs = incrs;
+        assert_eq!(
+            manifest.serialize(),
+            "file database.aof.2.base.kvdb seq 2 type b\n\
+             file database.aof.3.incr.aof seq 3 type i\n\
+             file database.aof.4.incr.aof seq 4 type i\n"
+        );
+
+        assert!(parse_manifest("file a seq 1 type i\nfile b seq 1 type i\n").is_err());
+        assert!(parse_manifest("file a seq 1 type b\nfile b seq 2 type b\n").is_err());
+        assert!(parse_manifest("file ../a seq 1 type i\n").is_err());
+        assert!(parse_manifest("file a seq x type i\n").is_err());
+    }
+
+    #[test]
+    fn test_manifest_rotate_and_install() {
+        let dir = std::env::temp_dir().join(format!("kv-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut manifest = AofManifest::new(dir.clone(), "database.aof");
+        manifest.add_incr().unwrap();
+        let first_incr = manifest.add_incr().unwrap().seq;
+        assert_eq!(first_incr, 2);
+
+        let temp_path = manifest.temp_base_path();
+        fs::write(&temp_path, b"snapshot").unwrap();
+        let obsolete = manifest.install_base(&temp_path, first_incr).unwrap();
+        assert_eq!(obsolete.len(), 1);
+        assert!(!dir.join("database.aof.1.incr.aof").exists());
+        assert_eq!(manifest.total_size(), 8);
+
+        // 崩溃重启之后读出来的和内存里的一样
+        let content = fs::read_to_string(dir.join("database.aof.manifest")).unwrap();
+        assert_eq!(content, manifest.serialize());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
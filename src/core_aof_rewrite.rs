@@ -0,0 +1,271 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{Notify, oneshot};
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::core_snapshot::write_snapshot_file;
+use crate::db::Db;
+
+/*
+   AOF 重写 (BGREWRITEAOF 和自动压缩)
+   1.不拿所有分片的锁 开一个时间点快照 (db::capture) 告诉写入任务在这个纪元切开
+     等开始之前的写入都退出 让写入任务把它们写完再换一个新的 incr 文件 之后纪元的写入都进新 incr
+     旧 incr 里正好是快照里的那些写入 新 incr 里正好是之后的 两边不重不漏
+     RPUSH、RENAME 这种重放两遍结果不一样的命令也没问题
+   2.一个分片一个分片地拿读锁把时间点上的数据编码成二进制快照 作为新的 base
+     已经编码过的分片照常写 还没轮到的分片被写之前先复制一份留给快照
+   3.快照写完交给写入任务装上去：改名成新 base、清单里去掉旧 base 和换文件之前的 incr、删旧文件
+     清单是原子替换的 中途失败或者崩溃 旧的清单和文件都还在
+   4.文件比上次重写之后大了 auto-aof-rewrite-percentage 并且超过 auto-aof-rewrite-min-size 就自动重写
+*/
+
+pub static AOF_REWRITE: Lazy<AofRewrite> = Lazy::new(AofRewrite::default);
+
+#[derive(Default)]
+pub struct AofRewrite {
+    state: Mutex<RewriteState>,
+    // 有请求了 叫写入任务来处理
+    ready: Notify,
+    current_size: AtomicU64,
+    // 上一次重写 (或者启动) 之后的文件大小 自动重写按它算增长
+    base_size: AtomicU64,
+    // 要在哪个快照纪元切开 0 是没有 写入任务切完清掉
+    split: AtomicU64,
+}
+
+#[derive(Default)]
+struct RewriteState {
+    // 写入任务打开文件之后才有 新 base 先写到这里
+    temp_path: Option<PathBuf>,
+    started: Option<Instant>,
+    // 等写入任务打开文件之后再开始 (比如启动时加载完快照要马上重写一次)
+    scheduled: bool,
+    // 重写和全量同步可能同时在等 按顺序处理
+    pending: VecDeque<PendingRequest>,
+    last_failed: bool,
+    last_duration: Option<Duration>,
+    rewrites: u64,
+}
+
+/// 要写入任务做的事 都要在写入任务里做 才不会和追加写入交错
+pub enum RewriteRequest {
+    // 在切分的纪元记下复制积压缓冲区的偏移量 全量同步用 回复这个偏移量
+    SyncPoint,
+    // 在切分的纪元换一个新的 incr 文件 回复新文件的 seq
+    Rotate,
+    // 把快照装成新的 base 回复新 base 的 seq
+    Install { temp_path: PathBuf, first_incr: u64 },
+}
+
+pub struct PendingRequest {
+    pub request: RewriteRequest,
+    pub done: oneshot::Sender<Result<u64, String>>,
+}
+
+/// INFO persistence 里的重写状态
+pub struct RewriteStatus {
+    pub in_progress: bool,
+    pub scheduled: bool,
+    pub current_time: Option<Duration>,
+    pub last_time: Option<Duration>,
+    pub last_ok: bool,
+    pub rewrites: u64,
+    pub current_size: u64,
+    pub base_size: u64,
+}
+
+impl AofRewrite {
+    /// 写入任务打开文件的时候调用
+    pub fn opened(&self, temp_path: PathBuf, size: u64) {
+        self.state.lock().unwrap().temp_path = Some(temp_path);
+        self.current_size.store(size, Ordering::SeqCst);
+        self.base_size.store(size, Ordering::SeqCst);
+    }
+
+    pub fn start(&'static self, db: Db) -> Result<(), String> {
+        let temp_path = {
+            let mut state = self.state.lock().unwrap();
+            if state.started.is_some() {
+                return Err("ERR Background append only file rewriting already in progress".into());
+            }
+            let Some(temp_path) = state.temp_path.clone() else {
+                return Err("ERR AOF 文件还没有打开".into());
+            };
+            state.started = Some(Instant::now());
+            state.scheduled = false;
+            temp_path
+        };
+        tokio::spawn(self.rewrite(db, temp_path));
+        Ok(())
+    }
+
+    async fn rewrite(&self, db: Db, temp_path: PathBuf) {
+        tracing::info!("后台 AOF 重写开始");
+        let started = Instant::now();
+        let result = async {
+            let turn = db.store.capture_turn().await;
+            self.split_at(turn.epoch());
+            let point = turn.start();
+            point.settle().await;
+            let first_incr = self.request(RewriteRequest::Rotate).await?;
+            if let Err(e) = write_snapshot_file(&point, &temp_path).await {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e.to_string());
+            }
+            self.request(RewriteRequest::Install {
+                temp_path: temp_path.clone(),
+                first_incr,
+            })
+            .await
+        }
+        .await;
+        let mut state = self.state.lock().unwrap();
+        state.started = None;
+        state.last_duration = Some(started.elapsed());
+        state.last_failed = result.is_err();
+        match result {
+            Ok(seq) => {
+                state.rewrites += 1;
+                tracing::info!("后台 AOF 重写完成 新的 base 是第 {} 个 耗时 {:?}", seq, started.elapsed());
+            }
+            Err(e) => tracing::error!("后台 AOF 重写失败: {}", e),
+        }
+    }
+
+    async fn request(&self, request: RewriteRequest) -> Result<u64, String> {
+        let (done, done_rx) = oneshot::channel();
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .push_back(PendingRequest { request, done });
+        self.ready.notify_one();
+        done_rx
+            .await
+            .unwrap_or_else(|_| Err("AOF 写入任务已经退出".into()))
+    }
+
+    /// 切分点之前的写入都进了复制积压缓冲区之后的偏移量
+    pub async fn sync_point(&self) -> Result<u64, String> {
+        self.request(RewriteRequest::SyncPoint).await
+    }
+
+    /// 这个纪元的写入先别写 等切分请求
+    pub fn split_at(&self, epoch: u64) {
+        self.split.store(epoch, Ordering::SeqCst);
+    }
+
+    pub fn split_epoch(&self) -> u64 {
+        self.split.load(Ordering::SeqCst)
+    }
+
+    pub fn split_done(&self) {
+        self.split.store(0, Ordering::SeqCst);
+    }
+
+    /// 由定时任务在下一秒开始
+    pub fn schedule(&self) {
+        self.state.lock().unwrap().scheduled = true;
+    }
+
+    pub async fn wait_request(&self) {
+        self.ready.notified().await
+    }
+
+    pub fn take_request(&self) -> Option<PendingRequest> {
+        self.state.lock().unwrap().pending.pop_front()
+    }
+
+    pub fn grow(&self, bytes: u64) {
+        self.current_size.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// 新 base 装上去了
+    pub fn rewritten(&self, size: u64) {
+        self.current_size.store(size, Ordering::SeqCst);
+        self.base_size.store(size, Ordering::SeqCst);
+    }
+
+    pub fn status(&self) -> RewriteStatus {
+        let state = self.state.lock().unwrap();
+        RewriteStatus {
+            in_progress: state.started.is_some(),
+            scheduled: state.scheduled,
+            current_time: state.started.map(|started| started.elapsed()),
+            last_time: state.last_duration,
+            last_ok: !state.last_failed,
+            rewrites: state.rewrites,
+            current_size: self.current_size.load(Ordering::SeqCst),
+            base_size: self.base_size.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// 和 redis 一样 比上次重写之后大了 percentage% 才算 太小的文件不值得重写
+fn should_rewrite(current: u64, base: u64, percentage: u64, min_size: u64) -> bool {
+    if percentage == 0 || current < min_size {
+        return false;
+    }
+    let base = base.max(1);
+    current.saturating_mul(100) / base >= 100 + percentage
+}
+
+/// 每秒检查一次要不要自动重写
+pub async fn aof_rewrite_cron(db: Db, shutdown: Sender<()>) {
+    let mut receiver = shutdown.subscribe();
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let status = AOF_REWRITE.status();
+                if status.in_progress {
+                    continue;
+                }
+                if status.scheduled {
+                    let _ = AOF_REWRITE.start(db.clone());
+                    continue;
+                }
+                if !should_rewrite(
+                    status.current_size,
+                    status.base_size,
+                    CONFIG.auto_aof_rewrite_percentage,
+                    CONFIG.auto_aof_rewrite_min_size,
+                ) {
+                    continue;
+                }
+                tracing::info!(
+                    "AOF 从 {} 字节涨到了 {} 字节 自动开始重写",
+                    status.base_size,
+                    status.current_size
+                );
+                let _ = AOF_REWRITE.start(db.clone());
+            }
+            _ = receiver.recv() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_rewrite() {
+        let mb = 1024 * 1024;
+        // 没到最小大小
+        assert!(!should_rewrite(10 * mb, mb, 100, 64 * mb));
+        // 刚好翻倍
+        assert!(should_rewrite(128 * mb, 64 * mb, 100, 64 * mb));
+        assert!(!should_rewrite(127 * mb, 64 * mb, 100, 64 * mb));
+        // 启动的时候文件是空的
+        assert!(should_rewrite(64 * mb, 0, 100, 64 * mb));
+        // 0 表示关掉自动重写
+        assert!(!should_rewrite(u64::MAX, 1, 0, 0));
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tokio::sync::{Notify, watch};
+
+use crate::context::CONN_STATE;
+use crate::core_tracking::TRACKING;
+use crate::error::{ClientKillFilter, Command};
+
+/*
+   在线连接的登记表 (CLIENT LIST/INFO/KILL/PAUSE 都靠它)
+   1.每个 RESP 连接进来登记一条 断开的时候删掉 别的连接只能通过这里看到它
+   2.连接名、登录用户这些本来在各自的 CONN_STATE 里 每条命令执行前同步一次到这里
+   3.KILL 只是给目标连接发一个信号 真正关 socket 是目标连接自己的循环收到信号之后退出
+     这样不会在执行命令的半路把连接掐断
+     连接会卡住的地方 (回复写不出去、PAUSE、WAITAOF、给 replica 发数据) 都用 unless_killed 包一层
+     卡在那里也能马上收到信号退出 两条命令之间也会检查一次
+   4.PAUSE 是全局的 命令执行前检查一下 暂停期间就等着 到期或者 UNPAUSE 再继续
+*/
+
+pub static CLIENTS: Lazy<ClientRegistry> = Lazy::new(ClientRegistry::default);
+
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: Mutex<HashMap<u64, ClientEntry>>,
+    pause: Mutex<Option<Pause>>,
+    // UNPAUSE 的时候叫醒所有在等的连接
+    unpaused: Notify,
+}
+
+struct ClientEntry {
+    addr: String,
+    name: Option<String>,
+    user: Option<String>,
+    lib_name: Option<String>,
+    lib_ver: Option<String>,
+    created: Instant,
+    last_interaction: Instant,
+    // 最近一条命令 子命令写成 client|list 这种
+    last_command: String,
+    db: usize,
+    protocol: u8,
+    // 还没解析的请求字节数 和 还没写出去的回复字节数
+    qbuf: usize,
+    obuf: usize,
+    no_evict: bool,
+    // 被 KILL 之后就是 true 了
+    kill_tx: watch::Sender<bool>,
+}
+
+#[derive(Clone, Copy)]
+struct Pause {
+    until: Instant,
+    // false 表示只暂停写命令
+    all: bool,
+}
+
+impl ClientRegistry {
+    /// 在连接自己的 CONN_STATE 里调用 返回的通道收到信号就该断开了
+    pub fn register(&self) -> watch::Receiver<bool> {
+        let (kill_tx, kill_rx) = watch::channel(false);
+        let now = Instant::now();
+        let (client_id, entry) = CONN_STATE.with(|state| {
+            (
+                state.client_id,
+                ClientEntry {
+                    addr: state.client_address.clone().unwrap_or_default(),
+                    name: state.client_name.borrow().clone(),
+                    user: state.user.borrow().clone(),
+                    lib_name: None,
+                    lib_ver: None,
+                    created: now,
+                    last_interaction: now,
+                    last_command: "NULL".into(),
+                    db: state.selected_db.get(),
+                    protocol: state.protocol.get(),
+                    qbuf: 0,
+                    obuf: 0,
+                    no_evict: false,
+                    kill_tx,
+                },
+            )
+        });
+        self.clients.lock().unwrap().insert(client_id, entry);
+        kill_rx
+    }
+
+    pub fn unregister(&self, client_id: u64) {
+        self.clients.lock().unwrap().remove(&client_id);
+    }
+
+    /// 没登记过的连接 (HTTP/memcached) 返回 None 它们不会被 KILL
+    pub fn kill_signal(&self, client_id: u64) -> Option<watch::Receiver<bool>> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&client_id).map(|entry| entry.kill_tx.subscribe())
+    }
+
+    pub fn is_killed(&self, client_id: u64) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&client_id).is_some_and(|entry| *entry.kill_tx.borrow())
+    }
+
+    /// 每条命令执行前调用 记下命令和缓冲区大小 顺便同步连接名和用户
+    pub fn touch(&self, command: &str, qbuf: usize, obuf: usize) {
+        let client_id = CONN_STATE.with(|state| state.client_id);
+        self.update(client_id, |entry| {
+            entry.last_interaction = Instant::now();
+            entry.last_command = command.to_string();
+            entry.qbuf = qbuf;
+            entry.obuf = obuf;
+        });
+        self.refresh();
+    }
+
+    /// SETNAME、AUTH、HELLO、SELECT 改了连接状态之后马上同步过来 别的连接立刻就能看到
+    pub fn refresh(&self) {
+        let Ok((client_id, name, user, protocol, db)) = CONN_STATE.try_with(|state| {
+            (
+                state.client_id,
+                state.client_name.borrow().clone(),
+                state.user.borrow().clone(),
+                state.protocol.get(),
+                state.selected_db.get(),
+            )
+        }) else {
+            return;
+        };
+        self.update(client_id, |entry| {
+            entry.name = name;
+            entry.user = user;
+            entry.protocol = protocol;
+            entry.db = db;
+        });
+    }
+
+    pub fn set_lib_info(&self, client_id: u64, lib_name: Option<String>, lib_ver: Option<String>) {
+        self.update(client_id, |entry| {
+            if lib_name.is_some() {
+                entry.lib_name = lib_name;
+            }
+            if lib_ver.is_some() {
+                entry.lib_ver = lib_ver;
+            }
+        });
+    }
+
+    pub fn set_no_evict(&self, client_id: u64, on: bool) {
+        self.update(client_id, |entry| entry.no_evict = on);
+    }
+
+    fn update(&self, client_id: u64, f: impl FnOnce(&mut ClientEntry)) {
+        if let Some(entry) = self.clients.lock().unwrap().get_mut(&client_id) {
+            f(entry);
+        }
+    }
+
+    /// CLIENT INFO 的一行 连接不在登记表里 (比如 AOF 恢复) 返回 None
+    pub fn info(&self, client_id: u64) -> Option<String> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&client_id).map(|entry| entry.describe(client_id))
+    }
+
+    /// CLIENT LIST 按 id 排序 ids 为空表示全部
+    pub fn list(&self, ids: &[u64]) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut selected: Vec<(&u64, &ClientEntry)> = clients
+            .iter()
+            .filter(|(id, _)| ids.is_empty() || ids.contains(id))
+            .collect();
+        selected.sort_by_key(|(id, _)| **id);
+        selected
+            .into_iter()
+            .map(|(id, entry)| entry.describe(*id) + "\n")
+            .collect()
+    }
+
+    /// 按过滤条件关掉连接 返回关掉了几个
+    pub fn kill(&self, filter: &ClientKillFilter, me: u64) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        let mut killed = 0;
+        for (id, entry) in clients.iter_mut() {
+            let matched = filter.id.is_none_or(|target| target == *id)
+                && filter.addr.as_ref().is_none_or(|addr| *addr == entry.addr)
+                && filter
+                    .user
+                    .as_ref()
+                    .is_none_or(|user| entry.user.as_ref() == Some(user))
+                && filter.idle.is_none_or(|idle| {
+                    now.duration_since(entry.last_interaction).as_secs() >= idle
+                })
+                && filter
+                    .maxage
+                    .is_none_or(|age| now.duration_since(entry.created).as_secs() >= age);
+            if !matched || (filter.skipme && *id == me) {
+                continue;
+            }
+            // 已经被杀的不重复计数
+            if !entry.kill_tx.send_replace(true) {
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    pub fn pause(&self, timeout: Duration, all: bool) {
+        let until = Instant::now() + timeout;
+        let mut pause = self.pause.lock().unwrap();
+        // 已经在暂停中的话 时间取更晚的那个 模式取更严的那个
+        *pause = Some(match *pause {
+            Some(old) if old.until > Instant::now() => Pause {
+                until: until.max(old.until),
+                all: all || old.all,
+            },
+            _ => Pause { until, all },
+        });
+    }
+
+    pub fn unpause(&self) {
+        *self.pause.lock().unwrap() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    /// 暂停期间在这里等着 CLIENT 命令自己不受影响 不然就没法 UNPAUSE 了
+    /// WRITE 模式只拦会写数据的命令 脚本也算写
+    pub async fn wait_unpaused(&self, command: &Command) {
+        if matches!(command, Command::Client(_)) {
+            return;
+        }
+        let is_write = command.spec().is_some_and(|spec| {
+            spec.in_category("write") || spec.in_category("scripting")
+        });
+        loop {
+            // 先挂上通知再检查状态 免得检查完、等待前 UNPAUSE 的通知丢了
+            let notified = self.unpaused.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let until = match *self.pause.lock().unwrap() {
+                Some(pause) if (pause.all || is_write) && pause.until > Instant::now() => {
+                    pause.until
+                }
+                _ => return,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(until.into()) => {}
+                _ = notified => {}
+            }
+        }
+    }
+}
+
+/// 收到 KILL 信号之前一直挂着 发送端没了 (已经从登记表里删掉) 也算
+pub async fn killed(mut signal: watch::Receiver<bool>) {
+    let _ = signal.wait_for(|killed| *killed).await;
+}
+
+/// 当前连接等 fut 的时候被 CLIENT KILL 了就放弃等待 返回 None
+pub async fn unless_killed<F: Future>(fut: F) -> Option<F::Output> {
+    let signal = CONN_STATE
+        .try_with(|state| state.client_id)
+        .ok()
+        .and_then(|client_id| CLIENTS.kill_signal(client_id));
+    let Some(signal) = signal else {
+        return Some(fut.await);
+    };
+    // 已经被杀了就不再等 fut 哪怕它马上就能完成
+    tokio::select! {
+        biased;
+        _ = killed(signal) => None,
+        output = fut => Some(output),
+    }
+}
+
+impl ClientEntry {
+    // 字段顺序和 redis 的 CLIENT LIST 保持一致 没有的功能填默认值 方便现成的客户端解析
+    fn describe(&self, client_id: u64) -> String {
+        let now = Instant::now();
+        let mut flags = String::new();
+        if *self.kill_tx.borrow() {
+            flags.push('A');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        format!(
+            "id={} addr={} name={} age={} idle={} flags={} db={} sub=0 psub=0 ssub=0 multi=-1 qbuf={} obl={} cmd={} user={} redir={} resp={} lib-name={} lib-ver={}",
+            client_id,
+            self.addr,
+            self.name.as_deref().unwrap_or(""),
+            now.duration_since(self.created).as_secs(),
+            now.duration_since(self.last_interaction).as_secs(),
+            flags,
+            self.db,
+            self.qbuf,
+            self.obuf,
+            self.last_command,
+            self.user.as_deref().unwrap_or(""),
+            TRACKING.get_redirect(client_id),
+            self.protocol,
+            self.lib_name.as_deref().unwrap_or(""),
+            self.lib_ver.as_deref().unwrap_or(""),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EvictionType;
+    use crate::context::ConnectionState;
+    use crate::db::Db;
+    use crate::server::tests::{TestConnection, request};
+    use tokio::io::AsyncWriteExt;
+
+    fn register(registry: &ClientRegistry, id: u64, addr: &str, user: &str) -> watch::Receiver<bool> {
+        let state = ConnectionState::new(0, Some(addr.into()), id);
+        *state.user.borrow_mut() = Some(user.into());
+        CONN_STATE.sync_scope(state, || registry.register())
+    }
+
+    #[test]
+    fn test_kill_filter() {
+        let registry = ClientRegistry::default();
+        let first = register(&registry, 1, "127.0.0.1:1000", "alice");
+        let second = register(&registry, 2, "127.0.0.1:2000", "bob");
+        let third = register(&registry, 3, "127.0.0.1:3000", "bob");
+
+        // 条件要同时满足
+        let filter = ClientKillFilter {
+            user: Some("bob".into()),
+            addr: Some("127.0.0.1:3000".into()),
+            skipme: true,
+            ..Default::default()
+        };
+        assert_eq!(registry.kill(&filter, 1), 1);
+        assert!(*third.borrow());
+        // 已经被杀的不重复计数
+        assert_eq!(registry.kill(&filter, 1), 0);
+
+        // SKIPME 跳过自己
+        let filter = ClientKillFilter {
+            user: Some("bob".into()),
+            skipme: true,
+            ..Default::default()
+        };
+        assert_eq!(registry.kill(&filter, 2), 0);
+        assert!(!*second.borrow());
+        let filter = ClientKillFilter {
+            id: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(registry.kill(&filter, 1), 1);
+        assert!(*first.borrow() && registry.is_killed(1));
+
+        registry.unregister(3);
+        let list = registry.list(&[]);
+        assert_eq!(list.lines().count(), 2);
+        assert!(list.starts_with("id=1 addr=127.0.0.1:1000 "));
+        assert!(list.contains("flags=A"));
+        assert!(registry.list(&[2]).contains("user=bob"));
+    }
+
+    // 卡在等待上的连接被 KILL 也要马上醒过来
+    #[tokio::test]
+    async fn test_kill_blocked_client() {
+        let state = ConnectionState::new(0, Some("127.0.0.1:4000".into()), 9_000_001);
+        CONN_STATE
+            .scope(state, async {
+                let _signal = CLIENTS.register();
+                let blocked = tokio::spawn(CONN_STATE.scope(
+                    ConnectionState::new(0, None, 9_000_001),
+                    unless_killed(std::future::pending::<()>()),
+                ));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                assert!(!blocked.is_finished());
+                let filter = ClientKillFilter {
+                    id: Some(9_000_001),
+                    ..Default::default()
+                };
+                assert_eq!(CLIENTS.kill(&filter, 0), 1);
+                let result = tokio::time::timeout(Duration::from_secs(1), blocked).await;
+                assert_eq!(result.unwrap().unwrap(), None);
+                // 已经被杀了 之后再等什么都直接返回 None
+                assert_eq!(unless_killed(async { 1 }).await, None);
+                CLIENTS.unregister(9_000_001);
+                // 没被杀的照常等到结果
+                assert_eq!(unless_killed(async { 1 }).await, Some(1));
+            })
+            .await;
+    }
+
+    // 字段要和连接现在的状态对得上 改了名字、换了库别的连接马上就能看到
+    #[tokio::test]
+    async fn test_client_list_and_info() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut alpha = TestConnection::open(&db, 9_200_001);
+        let mut other = TestConnection::open(&db, 9_200_002);
+        alpha.call(&["CLIENT", "GETNAME"], "$-1\r\n").await;
+        alpha.call(&["CLIENT", "SETNAME", "alpha"], "+OK\r\n").await;
+        alpha.call(&["CLIENT", "GETNAME"], "$5\r\nalpha\r\n").await;
+        alpha.client.write_all(&request(&["CLIENT", "SETNAME", "a b"])).await.unwrap();
+        let bad_name = alpha.read_line().await;
+        assert!(bad_name.starts_with('-'), "{}", bad_name);
+        alpha.call(&["SELECT", "3"], "+OK\r\n").await;
+        let info = alpha.call_bulk(&["CLIENT", "INFO"]).await;
+        assert!(
+            info.starts_with("id=9200001 addr=127.0.0.1:9200001 name=alpha "),
+            "{}",
+            info
+        );
+        for field in ["flags=N", "db=3", "cmd=client|info", "user=default", "resp=2"] {
+            assert!(info.split_whitespace().any(|f| f == field), "{} 不在 {}", field, info);
+        }
+
+        let list = other.call_bulk(&["CLIENT", "LIST", "ID", "9200001", "9200002"]).await;
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", list);
+        let alpha_line = lines.iter().find(|line| line.starts_with("id=9200001 ")).unwrap();
+        let other_line = lines.iter().find(|line| line.starts_with("id=9200002 ")).unwrap();
+        assert!(alpha_line.contains(" name=alpha ") && alpha_line.contains(" db=3 "), "{}", alpha_line);
+        assert!(other_line.contains(" name= ") && other_line.contains(" db=0 "), "{}", other_line);
+        assert!(other_line.contains(" cmd=client|list "), "{}", other_line);
+
+        // 换回去 名字清掉 LIST 里跟着变
+        alpha.call(&["SELECT", "0"], "+OK\r\n").await;
+        alpha.call(&["CLIENT", "SETNAME", ""], "+OK\r\n").await;
+        alpha.call(&["CLIENT", "GETNAME"], "$-1\r\n").await;
+        let list = other.call_bulk(&["CLIENT", "LIST", "ID", "9200001"]).await;
+        assert!(list.contains(" name= ") && list.contains(" db=0 "), "{}", list);
+    }
+
+    // 换了库之后读写的都是新库 范围不对的库号不切换
+    #[tokio::test]
+    async fn test_select() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut conn = TestConnection::open(&db, 9_200_003);
+        conn.call(&["SELECT", "16"], "-ERR DB index is out of range\r\n").await;
+        conn.call(&["SELECT", "-1"], "-ERR DB index is out of range\r\n").await;
+        conn.call(&["SELECT", "x"], "-ERR value is not an integer or out of range\r\n").await;
+        conn.call(&["SET", "k", "db0"], "+OK\r\n").await;
+        conn.call(&["SELECT", "5"], "+OK\r\n").await;
+        conn.call(&["GET", "k"], "$-1\r\n").await;
+        conn.call(&["SET", "k", "db5"], "+OK\r\n").await;
+        let mut other = TestConnection::open(&db, 9_200_004);
+        other.call(&["GET", "k"], "$3\r\ndb0\r\n").await;
+        conn.call(&["SELECT", "0"], "+OK\r\n").await;
+        conn.call(&["GET", "k"], "$3\r\ndb0\r\n").await;
+    }
+
+    // WRITE 模式只挡写命令 读照常 UNPAUSE 之后挡住的写命令接着执行
+    #[tokio::test]
+    async fn test_client_pause_write() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut admin = TestConnection::open(&db, 9_200_005);
+        let mut conn = TestConnection::open(&db, 9_200_006);
+        admin.call(&["CLIENT", "PAUSE", "10000", "WRITE"], "+OK\r\n").await;
+        conn.call(&["GET", "paused"], "$-1\r\n").await;
+        conn.client.write_all(&request(&["SET", "paused", "1"])).await.unwrap();
+        assert!(conn.silent_for(Duration::from_millis(100)).await);
+        admin.call(&["CLIENT", "UNPAUSE"], "+OK\r\n").await;
+        assert_eq!(conn.read(5).await, "+OK\r\n");
+        conn.call(&["GET", "paused"], "$1\r\n1\r\n").await;
+    }
+
+    // OFF 连自己的 OK 都不回 直到 ON SKIP 只跳过下一条
+    #[tokio::test]
+    async fn test_client_reply() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut conn = TestConnection::open(&db, 9_200_007);
+        let mut pipeline = request(&["CLIENT", "REPLY", "OFF"]);
+        pipeline.extend(request(&["SET", "quiet", "1"]));
+        pipeline.extend(request(&["GET", "quiet"]));
+        pipeline.extend(request(&["CLIENT", "REPLY", "ON"]));
+        pipeline.extend(request(&["GET", "quiet"]));
+        let expected = "+OK\r\n$1\r\n1\r\n";
+        assert_eq!(conn.exchange(&pipeline, expected.len()).await, expected);
+
+        let mut pipeline = request(&["CLIENT", "REPLY", "SKIP"]);
+        pipeline.extend(request(&["GET", "quiet"]));
+        pipeline.extend(request(&["PING"]));
+        assert_eq!(conn.exchange(&pipeline, 7).await, "+PONG\r\n");
+        // 后面没有漏出来的回复
+        conn.call(&["PING", "last"], "$4\r\nlast\r\n").await;
+        assert!(conn.silent_for(Duration::from_millis(20)).await);
+    }
+}
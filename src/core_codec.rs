@@ -0,0 +1,120 @@
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    core_aof::wait_aof_durable,
+    core_explain::{ProtoLimits, parse_request},
+    error::{Frame, KvError},
+};
+
+/*
+   连接上的编解码
+   1.读：数据进 read_buf 每次从头切一条完整请求出来 不完整就留着等下次读
+   2.写：回复先攒在 write_buf 里 一批管道命令处理完统一 flush 一次 而不是每条回复一次系统调用
+   3.背压：write_buf 攒到上限就先写出去再继续解析 客户端不读回复的话 write_all 会卡住
+     这时候也不会再去读新的请求 内存不会被一个只发不收的客户端撑爆
+*/
+
+// 回复攒到这么多就先刷出去 和 redis 的 PROTO_REPLY_CHUNK_BYTES 一个量级
+const WRITE_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+pub struct FrameCodec<S> {
+    stream: S,
+    read_buf: BytesMut,
+    write_buf: Vec<u8>,
+    limits: ProtoLimits,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> FrameCodec<S> {
+    pub fn new(stream: S) -> Self {
+        FrameCodec {
+            stream,
+            read_buf: BytesMut::with_capacity(1024),
+            write_buf: Vec::with_capacity(1024),
+            limits: ProtoLimits::UNLIMITED,
+        }
+    }
+
+    /// 客户端连接要检查请求的长度上限
+    pub fn with_limits(mut self, limits: ProtoLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 从连接里再读一批数据 返回 0 说明客户端关闭了
+    pub async fn read_more(&mut self) -> std::io::Result<usize> {
+        self.stream.read_buf(&mut self.read_buf).await
+    }
+
+    /// 切出下一条完整请求 Ok(None) 表示剩下的数据还不完整
+    /// 出错说明数据已经没法按帧对齐了 调用方回完错误应该断开连接
+    pub fn next_request(&mut self) -> Result<Option<Frame>, KvError> {
+        match parse_request(&self.read_buf, &self.limits)? {
+            Some((frame, size)) => {
+                self.read_buf.advance(size);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 直接拿底层的连接 全量同步的快照不经过回复缓冲区 边编码边写
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// 还没解析的原始数据 memcached 这种文本协议自己解析
+    pub fn buffered(&self) -> &[u8] {
+        &self.read_buf
+    }
+
+    pub fn consume(&mut self, size: usize) {
+        self.read_buf.advance(size);
+    }
+
+    /// 已经编码好的回复直接放进缓冲区
+    pub fn write_raw(&mut self, data: &[u8]) {
+        self.write_buf.extend_from_slice(data);
+    }
+
+    /// 回复先放进缓冲区 按连接当前的协议版本序列化
+    pub fn write_frame(&mut self, frame: &Frame, protocol: u8) {
+        self.write_buf
+            .extend_from_slice(&frame.serialize_with(protocol));
+    }
+
+    /// 还没写出去的回复字节数 CLIENT LIST 的 obl
+    pub fn pending_output(&self) -> usize {
+        self.write_buf.len()
+    }
+
+    /// 缓冲区攒得太多了 该先刷一次
+    pub fn should_flush(&self) -> bool {
+        self.write_buf.len() >= WRITE_FLUSH_THRESHOLD
+    }
+
+    /// 回复写出去之前先等这个连接最近的写入落盘 (appendfsync always 才会真的等)
+    /// 落盘失败的话攒着的回复不能再发了 不然客户端会以为写成功了
+    /// 换成 on_error 给的一条错误 返回 false 调用方应该断开连接
+    pub async fn flush_durable(
+        &mut self,
+        on_error: impl FnOnce(&str) -> Vec<u8>,
+    ) -> std::io::Result<bool> {
+        let durable = wait_aof_durable().await;
+        if let Err(msg) = &durable {
+            self.write_buf.clear();
+            self.write_buf.extend_from_slice(&on_error(msg));
+        }
+        self.flush().await?;
+        Ok(durable.is_ok())
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(&self.write_buf).await?;
+        self.write_buf.clear();
+        self.stream.flush().await
+    }
+}
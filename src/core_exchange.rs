@@ -1,8 +1,13 @@
+use bytes::Bytes;
 
-use crate::command_exchange::CommandExchange;
+use crate::command_exchange::{CommandArgv, CommandExchange};
+use crate::core_keyspec::lookup_command;
 use crate::error::KvError::ProtocolError;
 use crate::error::{
-    Command, EvalCommand, Frame, GetCommand, KvError, PingCommand, SetCommand, UnimplementCommand
+    AclCommand, AuthCommand, BgRewriteAofCommand, BgSaveCommand, ClientCommand, Command, DebugCommand, DelCommand, EvalCommand, Frame, GetCommand, HGetAllCommand,
+    HGetCommand, HSetCommand, HelloCommand, InfoCommand, KvError, LastSaveCommand, MGetCommand, MSetCommand, PExpireAtCommand, PingCommand,
+    PsyncCommand, RenameCommand, ReplconfCommand, ReplicaOfCommand, SaveCommand, SelectCommand, SetCommand, UnimplementCommand,
+    RPushCommand, SAddCommand, SubscribeCommand, UnsubscribeCommand, WaitAofCommand, ZAddCommand, ZRangeCommand,
 };
 
 impl TryFrom<Frame> for Command {
@@ -24,22 +29,51 @@ impl TryFrom<Frame> for Command {
                 let command_name = String::from_utf8(start_str.to_vec())
                     .map_err(|_| ProtocolError("zhuan huan yi chang ".into()))?
                     .to_uppercase();
+                // 参数个数统一按命令表里的声明校验
+                if let Some(spec) = lookup_command(&command_name)
+                    && !spec.check_arity(length)
+                {
+                    return Err(ProtocolError(format!(
+                        "wrong number of arguments for '{}' command",
+                        spec.name
+                    )));
+                }
                 match command_name.as_str() {
-                    "GET" => {
-                        if length != 2 {
-                            return Err(ProtocolError("GET 命令需要 1 个参数".into()));
-                        }
-                        GetCommand::exchange(iter, command_name)
-                    }
-                    "SET" => {
-                        if length < 3 {
-                            return Err(ProtocolError("frame is too short".into()));
-                        }
-                        SetCommand::exchange(iter, command_name)
-                    }
+                    "GET" => GetCommand::exchange(iter, command_name),
+                    "SET" => SetCommand::exchange(iter, command_name),
+                    "MGET" => MGetCommand::exchange(iter, command_name),
+                    "MSET" => MSetCommand::exchange(iter, command_name),
+                    "DEL" => DelCommand::exchange(iter, command_name),
+                    "RENAME" => RenameCommand::exchange(iter, command_name),
+                    "PEXPIREAT" => PExpireAtCommand::exchange(iter, command_name),
                     "PING" => PingCommand::exchange(iter, command_name),
                     //lua 脚本
                     "EVAL" => EvalCommand::exchange(iter, command_name),
+                    "CLIENT" => ClientCommand::exchange(iter, command_name),
+                    "HELLO" => HelloCommand::exchange(iter, command_name),
+                    "SUBSCRIBE" => SubscribeCommand::exchange(iter, command_name),
+                    "UNSUBSCRIBE" => UnsubscribeCommand::exchange(iter, command_name),
+                    "AUTH" => AuthCommand::exchange(iter, command_name),
+                    "SELECT" => SelectCommand::exchange(iter, command_name),
+                    "ACL" => AclCommand::exchange(iter, command_name),
+                    "HSET" => HSetCommand::exchange(iter, command_name),
+                    "HGET" => HGetCommand::exchange(iter, command_name),
+                    "HGETALL" => HGetAllCommand::exchange(iter, command_name),
+                    "ZRANGE" => ZRangeCommand::exchange(iter, command_name),
+                    "RPUSH" => RPushCommand::exchange(iter, command_name),
+                    "SADD" => SAddCommand::exchange(iter, command_name),
+                    "ZADD" => ZAddCommand::exchange(iter, command_name),
+                    "BGREWRITEAOF" => BgRewriteAofCommand::exchange(iter, command_name),
+                    "INFO" => InfoCommand::exchange(iter, command_name),
+                    "SAVE" => SaveCommand::exchange(iter, command_name),
+                    "BGSAVE" => BgSaveCommand::exchange(iter, command_name),
+                    "LASTSAVE" => LastSaveCommand::exchange(iter, command_name),
+                    "DEBUG" => DebugCommand::exchange(iter, command_name),
+                    // 主从复制 SLAVEOF/SYNC 是老名字
+                    "REPLICAOF" | "SLAVEOF" => ReplicaOfCommand::exchange(iter, command_name),
+                    "REPLCONF" => ReplconfCommand::exchange(iter, command_name),
+                    "PSYNC" | "SYNC" => PsyncCommand::exchange(iter, command_name),
+                    "WAITAOF" => WaitAofCommand::exchange(iter, command_name),
 
                     // 4. 所有其他不认识的命令，都匹配到这里
                     _ => UnimplementCommand::exchange(iter, command_name),
@@ -49,3 +83,75 @@ impl TryFrom<Frame> for Command {
         }
     }
 }
+
+impl Command {
+    /// AOF 重放和复制流里的命令 SET 可以多带一个 MCFLAGS flags (memcached 的 flags)
+    /// 只有这两条路上认 客户端、脚本、HTTP 发来的 SET 不认这个参数
+    pub fn from_replay(frame: Frame) -> Result<Command, KvError> {
+        let Frame::Array(mut frames) = frame else {
+            return Command::try_from(frame);
+        };
+        let is_set = matches!(frames.first(), Some(Frame::Bulk(name)) if name.eq_ignore_ascii_case(b"SET"));
+        // key 和 value 可能正好叫 MCFLAGS 从选项开始找 选项自己的参数都是数字
+        let position = frames.iter().skip(3).position(|frame| {
+            matches!(frame, Frame::Bulk(bytes) if bytes.eq_ignore_ascii_case(b"MCFLAGS"))
+        });
+        let (true, Some(position)) = (is_set, position) else {
+            return Command::try_from(Frame::Array(frames));
+        };
+        let at = position + 3;
+        let flags = match frames.get(at + 1) {
+            Some(Frame::Bulk(bytes)) => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|flags| flags.parse::<u32>().ok())
+                .ok_or_else(|| ProtocolError("MCFLAGS 必须是 32 位无符号整数".into()))?,
+            _ => return Err(ProtocolError("MCFLAGS 需要一个参数".into())),
+        };
+        frames.drain(at..at + 2);
+        let mut command = Command::try_from(Frame::Array(frames))?;
+        if let Command::Set(set) = &mut command {
+            set.flags = flags;
+        }
+        Ok(command)
+    }
+
+    /// 把命令还原成 argv (argv[0] 是命令名)
+    pub fn argv(&self) -> Vec<Bytes> {
+        match self {
+            Command::Set(set) => set.to_argv(),
+            Command::Get(get) => get.to_argv(),
+            Command::MGet(mget) => mget.to_argv(),
+            Command::MSet(mset) => mset.to_argv(),
+            Command::Del(del) => del.to_argv(),
+            Command::Rename(rename) => rename.to_argv(),
+            Command::PExpireAt(pexpireat) => pexpireat.to_argv(),
+            Command::Ping(ping) => ping.to_argv(),
+            Command::Unimplement(unimplement) => unimplement.to_argv(),
+            Command::EvalCommand(eval) => eval.to_argv(),
+            Command::Client(client) => client.to_argv(),
+            Command::Hello(hello) => hello.to_argv(),
+            Command::Subscribe(subscribe) => subscribe.to_argv(),
+            Command::Unsubscribe(unsubscribe) => unsubscribe.to_argv(),
+            Command::HSet(hset) => hset.to_argv(),
+            Command::HGet(hget) => hget.to_argv(),
+            Command::HGetAll(hgetall) => hgetall.to_argv(),
+            Command::ZRange(zrange) => zrange.to_argv(),
+            Command::RPush(rpush) => rpush.to_argv(),
+            Command::SAdd(sadd) => sadd.to_argv(),
+            Command::ZAdd(zadd) => zadd.to_argv(),
+            Command::Auth(auth) => auth.to_argv(),
+            Command::Select(select) => select.to_argv(),
+            Command::Acl(acl) => acl.to_argv(),
+            Command::BgRewriteAof(bgrewriteaof) => bgrewriteaof.to_argv(),
+            Command::Info(info) => info.to_argv(),
+            Command::Save(save) => save.to_argv(),
+            Command::BgSave(bgsave) => bgsave.to_argv(),
+            Command::LastSave(lastsave) => lastsave.to_argv(),
+            Command::Debug(debug) => debug.to_argv(),
+            Command::ReplicaOf(replicaof) => replicaof.to_argv(),
+            Command::Replconf(replconf) => replconf.to_argv(),
+            Command::Psync(psync) => psync.to_argv(),
+            Command::WaitAof(waitaof) => waitaof.to_argv(),
+        }
+    }
+}
@@ -1,77 +1,80 @@
 use crate::aof_exchange::AofContent;
 use crate::command_execute::{CommandContext, CommandExecutor};
-use crate::context::ConnectionContent;
-use crate::db::LockedDb;
-use crate::error::{Command, Frame, KvError};
+use crate::context::{CONN_STATE, ConnectionContent};
+use crate::core_tracking::TRACKING;
+use crate::db::lock_plan::{LockPlan, LockedShards};
+use crate::error::{ClientCommand, ClientSubCommand, Command, Frame, KvError};
 use crate::Db;
+use bytes::Bytes;
 
 // 假定：Command: Clone
-pub async fn execute_command(command: Command, db: &Db) -> Result<Frame, KvError> {
-    // 在调用时直接转换 None 的类型
-    // 这个调用现在是完全正确的，因为 `HookFn` 的定义和 `execute_command_hook` 的要求完美匹配
-    let result = execute_command_hook(&command, Some(db.clone()), None, None).await;
-    result
+// AOF 恢复走这里 没有连接上下文 但是锁规划和正常执行是一样的
+pub async fn execute_command(command: &Command, db: &Db) -> Result<Frame, KvError> {
+    let mut view = db.store.lock_plan(&LockPlan::for_command(command)).await;
+    execute_command_hook(command, Some(db.clone()), None, Some(&mut view)).await
 }
 
-pub async fn execute_command_hook<'a>(
+pub async fn execute_command_hook(
     command: &Command,
     db: Option<Db>, // post_write_hook 是一个可选的闭包
     connect_content: Option<ConnectionContent>,
-    db_lock: Option<&mut LockedDb>,
+    db_lock: Option<&mut LockedShards>,
 ) -> Result<Frame, KvError> {
+    let ctx = CommandContext {
+        db: None,
+        connect_content,
+    };
     match command {
-        Command::Get(get) => {
-            get.execute(
-                CommandContext {
-                    db: None,
-                    connect_content,
-                },
-                db_lock,
-            )
-            .await
-        }
-        Command::Set(set) => {
-            set.execute(
-                CommandContext {
-                    db: None,
-                    connect_content,
-                },
-                db_lock,
-            )
-            .await
-        }
-        Command::Ping(ping) => {
-            ping.execute(
-                CommandContext {
-                    db: None,
-                    connect_content,
-                },
-                None,
-            )
-            .await
+        Command::Get(get) => get.execute(ctx, db_lock).await,
+        Command::Set(set) => set.execute(ctx, db_lock).await,
+        Command::MGet(mget) => mget.execute(ctx, db_lock).await,
+        Command::MSet(mset) => mset.execute(ctx, db_lock).await,
+        Command::Del(del) => del.execute(ctx, db_lock).await,
+        Command::Rename(rename) => rename.execute(ctx, db_lock).await,
+        Command::PExpireAt(pexpireat) => pexpireat.execute(ctx, db_lock).await,
+        Command::Ping(ping) => ping.execute(ctx, None).await,
+        Command::Unimplement(unimplement) => unimplement.execute(ctx, None).await,
+        // lua 需要 db 自己去按计划加锁
+        Command::EvalCommand(eval_command) => {
+            eval_command
+                .execute(CommandContext { db, ..ctx }, None)
+                .await
         }
-        Command::Unimplement(unimplement) => {
-            unimplement
-                .execute(
-                    CommandContext {
-                        db: None,
-                        connect_content,
-                    },
-                    None,
-                )
+        Command::Client(client) => client.execute(ctx, None).await,
+        Command::Hello(hello) => hello.execute(ctx, None).await,
+        Command::Subscribe(subscribe) => subscribe.execute(ctx, None).await,
+        Command::Unsubscribe(unsubscribe) => unsubscribe.execute(ctx, None).await,
+        Command::HSet(hset) => hset.execute(ctx, db_lock).await,
+        Command::HGet(hget) => hget.execute(ctx, db_lock).await,
+        Command::HGetAll(hgetall) => hgetall.execute(ctx, db_lock).await,
+        Command::ZRange(zrange) => zrange.execute(ctx, db_lock).await,
+        Command::RPush(rpush) => rpush.execute(ctx, db_lock).await,
+        Command::SAdd(sadd) => sadd.execute(ctx, db_lock).await,
+        Command::ZAdd(zadd) => zadd.execute(ctx, db_lock).await,
+        Command::Auth(auth) => auth.execute(ctx, None).await,
+        // 要知道一共有几个库
+        Command::Select(select) => select.execute(CommandContext { db, ..ctx }, None).await,
+        Command::Acl(acl) => acl.execute(ctx, None).await,
+        // 重写和快照要自己一个分片一个分片地去拿锁
+        Command::BgRewriteAof(bgrewriteaof) => {
+            bgrewriteaof
+                .execute(CommandContext { db, ..ctx }, None)
                 .await
         }
-        Command::EvalCommand(eval_command) => {
-            eval_command
-                .execute(
-                    CommandContext {
-                        db: db,
-                        connect_content,
-                    },
-                    None,
-                )
+        Command::Info(info) => info.execute(ctx, None).await,
+        Command::Save(save) => save.execute(CommandContext { db, ..ctx }, None).await,
+        Command::BgSave(bgsave) => bgsave.execute(CommandContext { db, ..ctx }, None).await,
+        Command::LastSave(lastsave) => lastsave.execute(ctx, None).await,
+        Command::Debug(debug) => debug.execute(CommandContext { db, ..ctx }, None).await,
+        // 连主节点的后台任务要用 db 和连接上下文
+        Command::ReplicaOf(replicaof) => {
+            replicaof
+                .execute(CommandContext { db, ..ctx }, None)
                 .await
         }
+        Command::Replconf(replconf) => replconf.execute(ctx, None).await,
+        Command::Psync(psync) => psync.execute(ctx, None).await,
+        Command::WaitAof(waitaof) => waitaof.execute(ctx, None).await,
     }
 }
 
@@ -82,52 +85,158 @@ pub async fn execute_command_normal(
     connect_content: ConnectionContent,
 ) -> Result<Frame, KvError> {
     //这里已经是脱离所有权了 开始独立拿出来用了
-    let mut lock = get_command_lock(&command, db).await;
+    // 相对过期时间先换成绝对时间 执行和 AOF 用同一个
+    let command = command.pin_expiration();
+    // EVAL 的锁由 lua worker 按同一套计划去拿(外面还要包一层事务节点) 这里不能重复加锁
+    let mut view = match command {
+        Command::EvalCommand(_) => None,
+        _ => Some(db.store.lock_plan(&LockPlan::for_command(&command)).await),
+    };
     let frame: Frame = execute_command_hook(
         &command,
         Some(db.clone()),
         Some(connect_content.clone()),
-        lock.as_mut(),
+        view.as_mut(),
     )
     .await?;
+    // CLIENT CACHING yes/no 只管下一条命令 执行完就作废
+    if !matches!(
+        command,
+        Command::Client(ClientCommand {
+            sub: ClientSubCommand::Caching(_)
+        })
+    ) {
+        TRACKING.clear_caching(CONN_STATE.with(|state| state.client_id));
+    }
     //在这里同意执行aof 正常情况下的限定执行
-    command.exe_aof_command(AofContent {
-        aof_tx: &connect_content.aof_tx,
-        shutdown_tx: &connect_content.shutdown_tx,
-    }).await;
-    Ok(frame)
-}
-
-pub async fn get_command_lock<'a>(command: &Command, db: &'a Db) -> Option<LockedDb> {
-    match command {
-        Command::Set(set_command) => db.store.lock_write(&set_command.key).await.into(),
-        Command::Get(get_command) => db.store.lock_read(&get_command.key).await.into(),
-        Command::Ping(_) => None,
-        Command::Unimplement(_) => None,
-        Command::EvalCommand(_) => None,
+    //锁要拿到 AOF 发送完才放 同一个 key 的写入顺序和 AOF 里的顺序才能一致
+    //执行报错的命令什么都没改 不用写
+    if !matches!(frame, Frame::Error(_)) {
+        command.exe_aof_command(AofContent {
+            aof_tx: &connect_content.aof_tx,
+            shutdown_tx: &connect_content.shutdown_tx,
+            epoch: view.as_ref().map_or(0, LockedShards::epoch),
+        }).await;
     }
+    drop(view);
+    Ok(frame)
 }
 
 impl Frame {
+    /// 按 RESP2 序列化 AOF 和没有 HELLO 3 的连接都走这个
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(2)
+    }
+
+    /// 按连接协商好的协议版本序列化
+    pub fn serialize_with(&self, protocol: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf, protocol >= 3);
+        buf
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>, resp3: bool) {
         match self {
-            Frame::Simple(s) => format!("+{}\r\n", s).into_bytes(),
-            Frame::Error(s) => format!("-{}\r\n", s).into_bytes(),
-            Frame::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-            Frame::Null => b"$-1\r\n".to_vec(),
-            Frame::Bulk(bytes) => {
-                let mut buf = format!("${}\r\n", bytes.len()).into_bytes();
-                buf.extend_from_slice(bytes);
-                buf.extend_from_slice(b"\r\n");
-                buf
+            Frame::Simple(s) => write_line(buf, b'+', s),
+            // 错误里带换行会把协议打乱 (比如 lua 的 traceback) 和 redis 一样换成空格
+            Frame::Error(s) => write_line(buf, b'-', &s.replace(['\r', '\n'], " ")),
+            Frame::Integer(i) => write_line(buf, b':', &i.to_string()),
+            Frame::Null if resp3 => buf.extend_from_slice(b"_\r\n"),
+            Frame::Null => buf.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(bytes) => write_bulk(buf, b'$', bytes),
+            Frame::Array(frames) => write_aggregate(buf, b'*', frames, resp3),
+            // 下面是 RESP3 的类型 RESP2 连接按 redis 的规则降级
+            Frame::Map(pairs) if resp3 => write_map(buf, b'%', pairs),
+            Frame::Map(pairs) => {
+                // 降级成 key value key value ... 的平铺数组
+                write_line(buf, b'*', &(pairs.len() * 2).to_string());
+                for (key, value) in pairs {
+                    key.write_to(buf, false);
+                    value.write_to(buf, false);
+                }
             }
-            Frame::Array(frames) => {
-                let mut buf = format!("*{}\r\n", frames.len()).into_bytes();
-                for frame in frames {
-                    buf.extend_from_slice(&frame.serialize());
+            Frame::Set(frames) => write_aggregate(buf, if resp3 { b'~' } else { b'*' }, frames, resp3),
+            Frame::Push(frames) => write_aggregate(buf, if resp3 { b'>' } else { b'*' }, frames, resp3),
+            Frame::Double(d) if resp3 => write_line(buf, b',', &format_double(*d)),
+            Frame::Double(d) => write_bulk(buf, b'$', format_double(*d).as_bytes()),
+            Frame::Boolean(b) if resp3 => write_line(buf, b'#', if *b { "t" } else { "f" }),
+            Frame::Boolean(b) => write_line(buf, b':', if *b { "1" } else { "0" }),
+            Frame::BigNumber(n) if resp3 => write_line(buf, b'(', n),
+            Frame::BigNumber(n) => write_bulk(buf, b'$', n.as_bytes()),
+            Frame::Verbatim(format, data) if resp3 => {
+                let mut content = Vec::with_capacity(format.len() + 1 + data.len());
+                content.extend_from_slice(format.as_bytes());
+                content.push(b':');
+                content.extend_from_slice(data);
+                write_bulk(buf, b'=', &content);
+            }
+            Frame::Verbatim(_, data) => write_bulk(buf, b'$', data),
+            Frame::Attribute(attrs, frame) => {
+                // RESP2 没有属性 直接丢掉只留真正的回复
+                if resp3 {
+                    write_map(buf, b'|', attrs);
                 }
-                buf
+                frame.write_to(buf, resp3);
+            }
+        }
+    }
+
+    /// 把 RESP3 的类型转成 RESP2 里对应的类型 (lua 脚本里看到的就是降级后的结果)
+    pub fn into_resp2(self) -> Frame {
+        match self {
+            Frame::Map(pairs) => Frame::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(key, value)| [key.into_resp2(), value.into_resp2()])
+                    .collect(),
+            ),
+            Frame::Array(frames) | Frame::Set(frames) | Frame::Push(frames) => {
+                Frame::Array(frames.into_iter().map(Frame::into_resp2).collect())
             }
+            Frame::Double(d) => Frame::Bulk(Bytes::from(format_double(d))),
+            Frame::Boolean(b) => Frame::Integer(b as i64),
+            Frame::BigNumber(n) => Frame::Bulk(Bytes::from(n)),
+            Frame::Verbatim(_, data) => Frame::Bulk(data),
+            Frame::Attribute(_, frame) => frame.into_resp2(),
+            other => other,
         }
     }
 }
+
+fn write_line(buf: &mut Vec<u8>, prefix: u8, line: &str) {
+    buf.push(prefix);
+    buf.extend_from_slice(line.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn write_bulk(buf: &mut Vec<u8>, prefix: u8, data: &[u8]) {
+    write_line(buf, prefix, &data.len().to_string());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn write_aggregate(buf: &mut Vec<u8>, prefix: u8, frames: &[Frame], resp3: bool) {
+    write_line(buf, prefix, &frames.len().to_string());
+    for frame in frames {
+        frame.write_to(buf, resp3);
+    }
+}
+
+fn write_map(buf: &mut Vec<u8>, prefix: u8, pairs: &[(Frame, Frame)]) {
+    write_line(buf, prefix, &pairs.len().to_string());
+    for (key, value) in pairs {
+        key.write_to(buf, true);
+        value.write_to(buf, true);
+    }
+}
+
+// RESP3 规定的写法 inf / -inf / nan 其他按最短能还原的十进制输出
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".into()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf".into() } else { "-inf".into() }
+    } else {
+        d.to_string()
+    }
+}
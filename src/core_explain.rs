@@ -1,144 +1,585 @@
-use crate::error::Frame::Bulk;
-use crate::error::KvError::ProtocolError;
-use crate::error::{Frame, KvError};
-use bytes::{Buf, Bytes};
-use memchr::memmem;
-use std::io::Cursor;
-/// --- 2. 核心解析逻辑 ---
-
-/// 总调度函数：尝试从可变的 BytesMut 缓冲区解析一个 Frame。
-/// 这是暴露给外部的唯一入口。
-pub fn parse_frame(buf: &[u8]) -> Result<Option<(Frame,usize)>, KvError> {
-    // 1. 创建一个 Cursor 来进行安全的“只读预演”。
-    //    Cursor 包裹的是一个对 buf 数据的只读切片。
-    let mut cursor = Cursor::new(&buf[..]);
-
-    // 2. 在 Cursor 上进行递归解析。
-    //    这个过程不会修改原始的 buf。
-    match parse_frame_from_cursor(&mut cursor)? {
-        Some(frame) => {
-            // 3. 如果“预演”成功，我们通过 cursor.position() 知道了总共消耗了多少字节。
-            let consumed = cursor.position() as usize;
-            // 4. 才进行唯一一次的、破坏性的操作：从原始 buf 中消耗掉这些字节。
-            Ok(Some((frame,consumed)))
-        }
-        // 如果预演时发现数据不完整 (Ok(None)) 或格式错误 (Err)，
-        _ => Ok(None)
-    }
-}
-
-/// 在 Cursor 上进行递归解析的“真正”核心函数。
-/// 它只在只读的数据上操作，不修改任何东西。
-fn parse_frame_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Option<Frame>, KvError> {
-    // 检查游标后面是否还有数据可读
-    if !cursor.has_remaining() {
-        return Ok(None);
-    }
-    println!("{:?}",std::str::from_utf8(cursor.get_ref()));
-    // 根据游标当前位置的第一个字节来决定如何解析
-    match cursor.get_ref()[cursor.position() as usize] {
-        b'*' => parse_array_from_cursor(cursor),
-        b'$' => parse_bulk_string_from_cursor(cursor),
-        _ => Err(ProtocolError("无效的 Frame 类型前缀".into()))
-    }
-}
-
-/// 在 Cursor 上解析数组
-fn parse_array_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Option<Frame>, KvError> {
-    // 1. 从 Cursor 当前位置读取一行元数据 (e.g., "*2\r\n")
-    if let Some(line_bytes) = read_line_from_cursor(cursor)? {
-        if line_bytes[0] != b'*' {
-            return Err(ProtocolError("期望是数组 '*'".into()));
-        }
-
-        let num_elements = parse_decimal(&line_bytes[1..])?;
-
-        let mut elements = Vec::with_capacity(num_elements);
-        // 2. 循环 N 次，递归地在 Cursor 上解析子元素
-        for _ in 0..num_elements {
-            if let Some(child_frame) = parse_frame_from_cursor(cursor)? {
-                elements.push(child_frame);
-            } else {
-                // 如果任何一个子元素不完整，则整个数组都不完整
-                return Ok(None);
-            }
-        }
-        Ok(Some(Frame::Array(elements)))
-    } else {
-        // 元数据行都不完整
-        Ok(None)
-    }
-}
-
-/// 在 Cursor 上解析批量字符串
-fn parse_bulk_string_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Option<Frame>, KvError> {
-    if let Some(line_bytes) = read_line_from_cursor(cursor)? {
-        if line_bytes[0] != b'$' {
-            return Err(ProtocolError("期望是批量字符串 '$'".into()));
-        }
-        let data_len = parse_decimal(&line_bytes[1..])?;
-
-        // 检查游标后面“剩下”的数据是否足够
-        if cursor.remaining() < data_len + 2 - 1 {
-            return Ok(None);
-        }
-
-        // 提取数据
-        let data_start = cursor.position() as usize;
-        let data_end = data_start + data_len;
-        let data = Bytes::copy_from_slice(&cursor.get_ref()[data_start..data_end]);
-        // 移动游标，跳过数据
-        cursor.advance(data_len);
-
-        // 检查并消耗结尾的 \r\n
-        if &cursor.get_ref()[cursor.position() as usize .. cursor.position() as usize + 2] != b"\r\n" {
-            return Err(ProtocolError("批量字符串结尾缺少 \\r\\n".into()));
-        }
-        cursor.advance(2);
-
-        Ok(Some(Bulk(data)))
-    } else {
-        // 元数据行都不完整
-        Ok(None)
-    }
-}
-
-// --- 3. 辅助函数 ---
-
-/// 核心辅助函数：从 Cursor 当前位置读取一行，并移动 Cursor 的位置指针
-fn read_line_from_cursor<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<Option<&'a [u8]>, KvError> {
-    let start = cursor.position() as usize;
-    let end = cursor.get_ref().len();
-    let remaining_buf = &cursor.get_ref()[start..end];
-    if let Some(crlf_pos) = find_crlf(remaining_buf) {
-        let line_bytes = &remaining_buf[..crlf_pos];
-        cursor.advance(crlf_pos + 2);
-        Ok(Some(line_bytes))
-    } else {
-        Ok(None)
-    }
-}
-
-/// 在字节切片中查找 CRLF (`\r\n`)
-// fn find_crlf(buf: &[u8]) -> Option<usize> {
-//     buf.windows(2).position(|window| window == b"\r\n")
-// }
-
-
-/// 在字节切片中查找 CRLF (`\r\n`)，使用 memchr 进行 SIMD 优化 指令集可以一次读取较长 并行比较
-fn find_crlf(buf: &[u8]) -> Option<usize> {
-    // 创建一个针对 b"\r\n" 的专用查找器
-    // Finder::new 的开销很小，可以在循环中重复创建
-    let finder = memmem::Finder::new(b"\r\n");
-    finder.find(buf)
-}
-
-
-/// 将字节切片解析成一个 usize 类型的十进制数
-fn parse_decimal(bytes: &[u8]) -> Result<usize, KvError> {
-    let s = std::str::from_utf8(bytes)
-        .map_err(|_| ProtocolError("无效的 UTF-8 数字序列".into()))?;
-    s.parse::<usize>()
-        .map_err(|_| ProtocolError("无效的十进制格式".into()))
-}
-
+use crate::config::CONFIG;
+use crate::error::Frame::Bulk;
+use crate::error::KvError::ProtocolError;
+use crate::error::{Frame, KvError};
+use bytes::{Buf, Bytes};
+use memchr::memmem;
+use std::io::Cursor;
+
+/*
+   长度上限
+   1.头里的长度是对面说了算的 不能照着它提前分配内存 *9999999999999 一下就能把进程打挂
+     数组最多先分配 PREALLOC_MAX 个 后面的边解析边长 数据不够的时候根本走不到分配那一步
+   2.客户端请求还要检查长度本身 单个参数不能超过 proto-max-bulk-len 参数个数不能超过 MAX_MULTIBULK_LEN
+     超了直接回协议错误断开 不会一直等一个永远收不完的请求
+   3.AOF 和主从复制读的是自己写出来的数据 不检查长度 但是嵌套层数都有上限 免得递归把栈撑爆
+*/
+const PREALLOC_MAX: usize = 1024;
+// 一条命令最多这么多个参数 正常的 MSET/DEL 远远用不到
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const MAX_NESTING: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProtoLimits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+}
+
+impl ProtoLimits {
+    pub const UNLIMITED: ProtoLimits = ProtoLimits {
+        max_bulk_len: usize::MAX,
+        max_multibulk_len: usize::MAX,
+    };
+
+    /// 客户端连接用的上限
+    pub fn client() -> Self {
+        ProtoLimits {
+            max_bulk_len: usize::try_from(CONFIG.proto_max_bulk_len).unwrap_or(usize::MAX),
+            max_multibulk_len: MAX_MULTIBULK_LEN,
+        }
+    }
+}
+
+// --- 2. 核心解析逻辑 ---
+
+/// 总调度函数：尝试从可变的 BytesMut 缓冲区解析一个 Frame。
+/// 这是暴露给外部的唯一入口。
+pub fn parse_frame(buf: &[u8]) -> Result<Option<(Frame,usize)>, KvError> {
+    parse_frame_with(buf, &ProtoLimits::UNLIMITED)
+}
+
+fn parse_frame_with(buf: &[u8], limits: &ProtoLimits) -> Result<Option<(Frame,usize)>, KvError> {
+    // 1. 创建一个 Cursor 来进行安全的“只读预演”。
+    //    Cursor 包裹的是一个对 buf 数据的只读切片。
+    let mut cursor = Cursor::new(buf);
+
+    // 2. 在 Cursor 上进行递归解析。
+    //    这个过程不会修改原始的 buf。
+    match parse_frame_from_cursor(&mut cursor, limits, 0)? {
+        Some(frame) => {
+            // 3. 如果“预演”成功，我们通过 cursor.position() 知道了总共消耗了多少字节。
+            let consumed = cursor.position() as usize;
+            // 4. 才进行唯一一次的、破坏性的操作：从原始 buf 中消耗掉这些字节。
+            Ok(Some((frame,consumed)))
+        }
+        // 预演时发现数据不完整 就等下一次读到更多数据再来
+        // 格式错误的话上面的 ? 已经直接返回 Err 了 交给调用方决定怎么处理
+        None => Ok(None)
+    }
+}
+
+// 单条 inline 命令最长 64K 和 redis 的 PROTO_INLINE_MAX_SIZE 一样 防止一直收不到换行把内存撑爆
+const INLINE_MAX_SIZE: usize = 64 * 1024;
+
+/// 客户端请求的入口：看第一个字节决定走哪种协议
+/// '*' 开头是标准的 RESP 数组 其他的都当成 inline 命令 (nc/telnet 直接敲的 "PING\r\n")
+pub fn parse_request(buf: &[u8], limits: &ProtoLimits) -> Result<Option<(Frame, usize)>, KvError> {
+    match buf.first() {
+        None => Ok(None),
+        Some(b'*') => parse_frame_with(buf, limits),
+        Some(_) => parse_inline(buf),
+    }
+}
+
+/// 解析一行 inline 命令 转换成和 RESP 一样的 Bulk 数组 后面的流程完全复用
+fn parse_inline(buf: &[u8]) -> Result<Option<(Frame, usize)>, KvError> {
+    // 空行直接跳过 telnet 里多敲一个回车不算错
+    let mut start = 0;
+    loop {
+        let Some(newline) = memchr::memchr(b'\n', &buf[start..]) else {
+            if buf.len() - start > INLINE_MAX_SIZE {
+                return Err(ProtocolError("too big inline request".into()));
+            }
+            return Ok(None);
+        };
+        let end = start + newline;
+        // 兼容只发 \n 的客户端
+        let line = match buf[start..end].last() {
+            Some(b'\r') => &buf[start..end - 1],
+            _ => &buf[start..end],
+        };
+        let args = split_inline_args(line)?;
+        if args.is_empty() {
+            start = end + 1;
+            continue;
+        }
+        let frame = Frame::Array(args.into_iter().map(Bulk).collect());
+        return Ok(Some((frame, end + 1)));
+    }
+}
+
+/// 按 redis-cli 的规则切分参数 (对应 redis 的 sdssplitargs)
+/// 1.空白分隔
+/// 2.双引号里支持 \n \r \t \b \a \\ \" 以及 \xHH 转义
+/// 3.单引号里只认 \' 其他原样保留
+/// 4.引号闭合之后必须紧跟空白或者行尾 否则就是 unbalanced quotes
+fn split_inline_args(line: &[u8]) -> Result<Vec<Bytes>, KvError> {
+    let unbalanced = || ProtocolError("unbalanced quotes in request".into());
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut current = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+        loop {
+            if in_double {
+                match line.get(i) {
+                    None => return Err(unbalanced()),
+                    Some(b'\\')
+                        if i + 3 < line.len()
+                            && line[i + 1] == b'x'
+                            && line[i + 2].is_ascii_hexdigit()
+                            && line[i + 3].is_ascii_hexdigit() =>
+                    {
+                        let hex = std::str::from_utf8(&line[i + 2..i + 4]).unwrap();
+                        current.push(u8::from_str_radix(hex, 16).unwrap());
+                        i += 3;
+                    }
+                    Some(b'\\') if i + 1 < line.len() => {
+                        i += 1;
+                        current.push(match line[i] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    Some(b'"') => {
+                        if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        in_double = false;
+                    }
+                    Some(&c) => current.push(c),
+                }
+            } else if in_single {
+                match line.get(i) {
+                    None => return Err(unbalanced()),
+                    Some(b'\\') if line.get(i + 1) == Some(&b'\'') => {
+                        i += 1;
+                        current.push(b'\'');
+                    }
+                    Some(b'\'') => {
+                        if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        in_single = false;
+                    }
+                    Some(&c) => current.push(c),
+                }
+            } else {
+                match line.get(i) {
+                    None => break,
+                    Some(c) if c.is_ascii_whitespace() || *c == 0 => break,
+                    Some(b'"') => in_double = true,
+                    Some(b'\'') => in_single = true,
+                    Some(&c) => current.push(c),
+                }
+            }
+            i += 1;
+        }
+        args.push(Bytes::from(current));
+    }
+}
+
+/// 在 Cursor 上进行递归解析的“真正”核心函数。
+/// 它只在只读的数据上操作，不修改任何东西。
+fn parse_frame_from_cursor(
+    cursor: &mut Cursor<&[u8]>,
+    limits: &ProtoLimits,
+    depth: usize,
+) -> Result<Option<Frame>, KvError> {
+    // 检查游标后面是否还有数据可读
+    if !cursor.has_remaining() {
+        return Ok(None);
+    }
+    if depth > MAX_NESTING {
+        return Err(ProtocolError("too deeply nested".into()));
+    }
+    // 根据游标当前位置的第一个字节来决定如何解析
+    match cursor.get_ref()[cursor.position() as usize] {
+        b'*' | b'~' | b'>' => parse_array_from_cursor(cursor, limits, depth),
+        b'%' | b'|' => parse_map_from_cursor(cursor, limits, depth),
+        b'$' | b'=' | b'!' => parse_bulk_string_from_cursor(cursor, limits),
+        b'+' | b'-' | b':' | b'_' | b',' | b'#' | b'(' => parse_line_from_cursor(cursor),
+        _ => Err(ProtocolError("无效的 Frame 类型前缀".into()))
+    }
+}
+
+/// 在 Cursor 上解析单行类型：简单字符串 "+OK"、错误 "-ERR xxx"、整数 ":100"
+/// 以及 RESP3 的空值 "_"、浮点数 ",1.5"、布尔 "#t"、大数 "(123"
+/// 这些都是一行就结束 内容里不允许出现 \r\n
+fn parse_line_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Option<Frame>, KvError> {
+    let Some(line_bytes) = read_line_from_cursor(cursor)? else {
+        return Ok(None);
+    };
+    let content = std::str::from_utf8(&line_bytes[1..])
+        .map_err(|_| ProtocolError("无效的 UTF-8 字符串".into()))?;
+    let frame = match line_bytes[0] {
+        b'+' => Frame::Simple(content.to_string()),
+        b'-' => Frame::Error(content.to_string()),
+        b'_' => Frame::Null,
+        b',' => Frame::Double(match content {
+            "inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            _ => content
+                .parse::<f64>()
+                .map_err(|_| ProtocolError("无效的浮点数格式".into()))?,
+        }),
+        b'#' => match content {
+            "t" => Frame::Boolean(true),
+            "f" => Frame::Boolean(false),
+            _ => return Err(ProtocolError("无效的布尔值".into())),
+        },
+        b'(' => Frame::BigNumber(content.to_string()),
+        _ => Frame::Integer(
+            content
+                .parse::<i64>()
+                .map_err(|_| ProtocolError("无效的整数格式".into()))?,
+        ),
+    };
+    Ok(Some(frame))
+}
+
+/// 在 Cursor 上解析数组 RESP3 的集合 "~" 和推送 ">" 结构一样 只是类型不同
+fn parse_array_from_cursor(
+    cursor: &mut Cursor<&[u8]>,
+    limits: &ProtoLimits,
+    depth: usize,
+) -> Result<Option<Frame>, KvError> {
+    // 1. 从 Cursor 当前位置读取一行元数据 (e.g., "*2\r\n")
+    if let Some(line_bytes) = read_line_from_cursor(cursor)? {
+
+        // *-1 是空数组 (比如 BLPOP 超时) 我们统一用 Null 表示
+        if &line_bytes[1..] == b"-1" {
+            return Ok(Some(Frame::Null));
+        }
+        let num_elements = parse_decimal(&line_bytes[1..])?;
+        if num_elements > limits.max_multibulk_len {
+            return Err(ProtocolError("invalid multibulk length".into()));
+        }
+
+        let mut elements = Vec::with_capacity(num_elements.min(PREALLOC_MAX));
+        // 2. 循环 N 次，递归地在 Cursor 上解析子元素
+        for _ in 0..num_elements {
+            if let Some(child_frame) = parse_frame_from_cursor(cursor, limits, depth + 1)? {
+                elements.push(child_frame);
+            } else {
+                // 如果任何一个子元素不完整，则整个数组都不完整
+                return Ok(None);
+            }
+        }
+        Ok(Some(match line_bytes[0] {
+            b'~' => Frame::Set(elements),
+            b'>' => Frame::Push(elements),
+            _ => Frame::Array(elements),
+        }))
+    } else {
+        // 元数据行都不完整
+        Ok(None)
+    }
+}
+
+/// 在 Cursor 上解析 RESP3 的 Map "%" 和属性 "|"
+/// 属性后面紧跟着它所修饰的那个回复 要一起解析出来
+fn parse_map_from_cursor(
+    cursor: &mut Cursor<&[u8]>,
+    limits: &ProtoLimits,
+    depth: usize,
+) -> Result<Option<Frame>, KvError> {
+    let Some(line_bytes) = read_line_from_cursor(cursor)? else {
+        return Ok(None);
+    };
+    let num_pairs = parse_decimal(&line_bytes[1..])?;
+    if num_pairs > limits.max_multibulk_len / 2 {
+        return Err(ProtocolError("invalid multibulk length".into()));
+    }
+    let mut pairs = Vec::with_capacity(num_pairs.min(PREALLOC_MAX));
+    for _ in 0..num_pairs {
+        let Some(key) = parse_frame_from_cursor(cursor, limits, depth + 1)? else {
+            return Ok(None);
+        };
+        let Some(value) = parse_frame_from_cursor(cursor, limits, depth + 1)? else {
+            return Ok(None);
+        };
+        pairs.push((key, value));
+    }
+    if line_bytes[0] == b'%' {
+        return Ok(Some(Frame::Map(pairs)));
+    }
+    match parse_frame_from_cursor(cursor, limits, depth + 1)? {
+        Some(frame) => Ok(Some(Frame::Attribute(pairs, Box::new(frame)))),
+        None => Ok(None),
+    }
+}
+
+/// 在 Cursor 上解析批量字符串 RESP3 的原样字符串 "=" 和批量错误 "!" 也是带长度的 格式一样
+fn parse_bulk_string_from_cursor(
+    cursor: &mut Cursor<&[u8]>,
+    limits: &ProtoLimits,
+) -> Result<Option<Frame>, KvError> {
+    if let Some(line_bytes) = read_line_from_cursor(cursor)? {
+        // $-1 是空的批量字符串 GET 不存在的 key 返回的就是这个
+        if &line_bytes[1..] == b"-1" {
+            return Ok(Some(Frame::Null));
+        }
+        let data_len = parse_decimal(&line_bytes[1..])?;
+        if data_len > limits.max_bulk_len {
+            return Err(ProtocolError("invalid bulk length".into()));
+        }
+
+        // 检查游标后面“剩下”的数据是否足够 数据本身加上结尾的 \r\n
+        // 长度是对面给的 加 2 也可能溢出 溢出了肯定不够
+        match data_len.checked_add(2) {
+            Some(needed) if cursor.remaining() >= needed => {}
+            _ => return Ok(None),
+        }
+
+        // 提取数据
+        let data_start = cursor.position() as usize;
+        let data_end = data_start + data_len;
+        let data = Bytes::copy_from_slice(&cursor.get_ref()[data_start..data_end]);
+        // 移动游标，跳过数据
+        cursor.advance(data_len);
+
+        // 检查并消耗结尾的 \r\n
+        if &cursor.get_ref()[cursor.position() as usize .. cursor.position() as usize + 2] != b"\r\n" {
+            return Err(ProtocolError("批量字符串结尾缺少 \\r\\n".into()));
+        }
+        cursor.advance(2);
+
+        match line_bytes[0] {
+            // 原样字符串前 4 个字节是 "txt:" 这样的格式说明
+            b'=' => {
+                if data.len() < 4 || data[3] != b':' {
+                    return Err(ProtocolError("原样字符串缺少格式前缀".into()));
+                }
+                let format = String::from_utf8_lossy(&data[..3]).into_owned();
+                Ok(Some(Frame::Verbatim(format, data.slice(4..))))
+            }
+            b'!' => Ok(Some(Frame::Error(String::from_utf8_lossy(&data).into_owned()))),
+            _ => Ok(Some(Bulk(data))),
+        }
+    } else {
+        // 元数据行都不完整
+        Ok(None)
+    }
+}
+
+// --- 3. 辅助函数 ---
+
+/// 核心辅助函数：从 Cursor 当前位置读取一行，并移动 Cursor 的位置指针
+fn read_line_from_cursor<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<Option<&'a [u8]>, KvError> {
+    let start = cursor.position() as usize;
+    let end = cursor.get_ref().len();
+    let remaining_buf = &cursor.get_ref()[start..end];
+    if let Some(crlf_pos) = find_crlf(remaining_buf) {
+        let line_bytes = &remaining_buf[..crlf_pos];
+        cursor.advance(crlf_pos + 2);
+        Ok(Some(line_bytes))
+    } else {
+        Ok(None)
+    }
+}
+
+// 在字节切片中查找 CRLF (`\r\n`)
+// fn find_crlf(buf: &[u8]) -> Option<usize> {
+//     buf.windows(2).position(|window| window == b"\r\n")
+// }
+
+
+/// 在字节切片中查找 CRLF (`\r\n`)，使用 memchr 进行 SIMD 优化 指令集可以一次读取较长 并行比较
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    // 创建一个针对 b"\r\n" 的专用查找器
+    // Finder::new 的开销很小，可以在循环中重复创建
+    let finder = memmem::Finder::new(b"\r\n");
+    finder.find(buf)
+}
+
+
+/// 将字节切片解析成一个 usize 类型的十进制数
+fn parse_decimal(bytes: &[u8]) -> Result<usize, KvError> {
+    let s = std::str::from_utf8(bytes)
+        .map_err(|_| ProtocolError("无效的 UTF-8 数字序列".into()))?;
+    s.parse::<usize>()
+        .map_err(|_| ProtocolError("无效的十进制格式".into()))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 自己序列化出去的东西 必须能原样解析回来
+    #[test]
+    fn test_round_trip_all_frame_types() {
+        let frames = vec![
+            Frame::Simple("OK".into()),
+            Frame::Error("ERR unknown command".into()),
+            Frame::Integer(-42),
+            Frame::Null,
+            Frame::Bulk(Bytes::from_static(b"hello\r\nworld")),
+            Frame::Bulk(Bytes::new()),
+            Frame::Array(vec![]),
+            Frame::Array(vec![
+                Frame::Integer(1),
+                Frame::Null,
+                Frame::Array(vec![Frame::Simple("nested".into())]),
+            ]),
+        ];
+        for frame in frames {
+            let data = frame.serialize();
+            let (parsed, consumed) = parse_frame(&data).unwrap().unwrap();
+            assert_eq!(parsed, frame);
+            assert_eq!(consumed, data.len());
+        }
+    }
+
+    #[test]
+    fn test_round_trip_resp3() {
+        let frames = vec![
+            Frame::Null,
+            Frame::Double(1.5),
+            Frame::Double(f64::NEG_INFINITY),
+            Frame::Boolean(true),
+            Frame::BigNumber("3492890328409238509324850943850943825024385".into()),
+            Frame::Verbatim("txt".into(), Bytes::from_static(b"Some string")),
+            Frame::Set(vec![Frame::Integer(1), Frame::Simple("a".into())]),
+            Frame::Push(vec![
+                Frame::Bulk(Bytes::from_static(b"invalidate")),
+                Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"k"))]),
+            ]),
+            Frame::Map(vec![(
+                Frame::Bulk(Bytes::from_static(b"proto")),
+                Frame::Integer(3),
+            )]),
+            Frame::Attribute(
+                vec![(Frame::Simple("ttl".into()), Frame::Integer(10))],
+                Box::new(Frame::Bulk(Bytes::from_static(b"v"))),
+            ),
+        ];
+        for frame in frames {
+            let data = frame.serialize_with(3);
+            let (parsed, consumed) = parse_frame(&data).unwrap().unwrap();
+            assert_eq!(parsed, frame);
+            assert_eq!(consumed, data.len());
+        }
+    }
+
+    // RESP2 的连接拿到的是降级之后的结果 序列化直接降级和先 into_resp2 再序列化要一致
+    #[test]
+    fn test_resp2_downgrade() {
+        let frame = Frame::Attribute(
+            vec![],
+            Box::new(Frame::Map(vec![
+                (Frame::Bulk(Bytes::from_static(b"f")), Frame::Double(2.5)),
+                (Frame::Bulk(Bytes::from_static(b"ok")), Frame::Boolean(true)),
+            ])),
+        );
+        let data = frame.serialize();
+        assert_eq!(data, frame.clone().into_resp2().serialize());
+        assert_eq!(
+            data,
+            b"*4\r\n$1\r\nf\r\n$3\r\n2.5\r\n$2\r\nok\r\n:1\r\n".to_vec()
+        );
+        assert_eq!(Frame::Null.serialize(), b"$-1\r\n".to_vec());
+        assert_eq!(Frame::Null.serialize_with(3), b"_\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_null_array() {
+        let (frame, consumed) = parse_frame(b"*-1\r\n+OK\r\n").unwrap().unwrap();
+        assert_eq!(frame, Frame::Null);
+        assert_eq!(consumed, 5);
+    }
+
+    #[test]
+    fn test_incomplete_frames() {
+        // 每一个前缀都不完整 都应该等更多数据 而不是报错或者越界
+        let data = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"foo")),
+            Frame::Integer(7),
+        ])
+        .serialize();
+        for end in 0..data.len() {
+            assert!(parse_frame(&data[..end]).unwrap().is_none(), "prefix {}", end);
+        }
+    }
+
+    fn parse_request_unlimited(buf: &[u8]) -> Result<Option<(Frame, usize)>, KvError> {
+        parse_request(buf, &ProtoLimits::UNLIMITED)
+    }
+
+    fn bulk_array(args: &[&[u8]]) -> Frame {
+        Frame::Array(args.iter().map(|a| Frame::Bulk(Bytes::copy_from_slice(a))).collect())
+    }
+
+    #[test]
+    fn test_inline_request() {
+        let (frame, consumed) = parse_request_unlimited(b"PING\r\n").unwrap().unwrap();
+        assert_eq!(frame, bulk_array(&[b"PING"]));
+        assert_eq!(consumed, 6);
+
+        // 只有 \n 结尾 前面还有空行
+        let (frame, consumed) = parse_request_unlimited(b"\r\n\nset  k   v\nGET k\n").unwrap().unwrap();
+        assert_eq!(frame, bulk_array(&[b"set", b"k", b"v"]));
+        assert_eq!(consumed, 14);
+
+        assert!(parse_request_unlimited(b"PING").unwrap().is_none());
+        assert!(parse_request_unlimited(b"\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_inline_quoting() {
+        let (frame, _) = parse_request_unlimited(b"SET \"a b\" 'c \\'d' \"\\x41\\n\\\"\" x\"y z\"\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame, bulk_array(&[b"SET", b"a b", b"c 'd", b"A\n\"", b"xy z"]));
+
+        let (frame, _) = parse_request_unlimited(b"SET k ''\r\n").unwrap().unwrap();
+        assert_eq!(frame, bulk_array(&[b"SET", b"k", b""]));
+
+        assert!(parse_request_unlimited(b"SET \"abc\r\n").is_err());
+        assert!(parse_request_unlimited(b"SET \"a\"b\r\n").is_err());
+        assert!(parse_request_unlimited(b"SET 'a\r\n").is_err());
+    }
+
+    // 头里的长度是客户端随便写的 不能照着它分配内存 也不能溢出
+    #[test]
+    fn test_malicious_headers() {
+        let limits = ProtoLimits {
+            max_bulk_len: 1024 * 1024,
+            max_multibulk_len: MAX_MULTIBULK_LEN,
+        };
+        // 数据还没到 只是等着 不会提前分配
+        assert!(parse_frame(b"*9999999999999\r\n").unwrap().is_none());
+        assert!(parse_frame(b"%9999999999999\r\n").unwrap().is_none());
+        assert!(parse_frame(b"*2\r\n$18446744073709551615\r\nab").unwrap().is_none());
+        assert!(parse_frame(b"*99999999999999999999999\r\n").is_err());
+        // 客户端请求超过上限直接报错
+        assert!(parse_request(b"*9999999999999\r\n", &limits).is_err());
+        assert!(parse_request(b"*2\r\n$1048577\r\n", &limits).is_err());
+        assert!(parse_request(b"*1\r\n%9999999999999\r\n", &limits).is_err());
+        assert!(parse_request(b"*1\r\n$1048576\r\n", &limits).unwrap().is_none());
+        // 嵌套太深
+        assert!(parse_frame(&b"*1\r\n".repeat(1000)).is_err());
+        assert!(parse_frame(&b"*1\r\n".repeat(10)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        assert!(parse_frame(b"!oops\r\n").is_err());
+        assert!(parse_frame(b":abc\r\n").is_err());
+        assert!(parse_frame(b"$3\r\nfooXY").is_err());
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+
+use crate::error::Command;
+
+/*
+   命令的声明式描述 (和 redis 的 command table 一个思路)
+   1.arity：参数个数 正数表示固定个数 负数表示“至少”这么多个 都包含命令名本身
+   2.key_specs：key 在 argv 里的位置以及读写属性
+   3.acl_categories：ACL 里 +@string 这种规则按分类授权
+   锁规划、权限检查、AOF 这些都只看这张表 不再为每个命令单独写 match
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAccess {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum KeySearch {
+    // argv[first..=last] 每 step 个取一个 last 为负数表示从末尾往回数 -1 就是最后一个
+    Range { first: usize, last: isize, step: usize },
+    // key 的个数写在参数里 比如 EVAL script numkeys key [key ...]
+    KeyNum {
+        numkeys_index: usize,
+        first: usize,
+        step: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeySpec {
+    pub search: KeySearch,
+    pub access: KeyAccess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandFlag {
+    Write,
+    ReadOnly,
+    // 不能在 lua 脚本里调用
+    NoScript,
+    // 没有登录也能执行 (AUTH/HELLO)
+    NoAuth,
+    // 启动加载数据的时候也能执行
+    Loading,
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: &'static [CommandFlag],
+    // ACL 的命令分类 @read/@write 由 flags 推出来 不用重复写
+    pub acl_categories: &'static [&'static str],
+    pub key_specs: &'static [KeySpec],
+}
+
+// 一条命令里解析出来的一个 key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRef {
+    pub key: String,
+    pub access: KeyAccess,
+}
+
+const fn range(first: usize, last: isize, step: usize, access: KeyAccess) -> KeySpec {
+    KeySpec {
+        search: KeySearch::Range { first, last, step },
+        access,
+    }
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &[CommandFlag::ReadOnly],
+        acl_categories: &["string", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Read)],
+    },
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["string", "slow"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "mget",
+        arity: -2,
+        flags: &[CommandFlag::ReadOnly],
+        acl_categories: &["string", "fast"],
+        key_specs: &[range(1, -1, 1, KeyAccess::Read)],
+    },
+    CommandSpec {
+        name: "mset",
+        arity: -3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["string", "slow"],
+        key_specs: &[range(1, -1, 2, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "del",
+        arity: -2,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["keyspace", "slow"],
+        key_specs: &[range(1, -1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "rename",
+        arity: 3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["keyspace", "slow"],
+        key_specs: &[range(1, 2, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "pexpireat",
+        arity: 3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["keyspace", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &[CommandFlag::Loading],
+        acl_categories: &["fast", "connection"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "eval",
+        arity: -3,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["slow", "scripting"],
+        key_specs: &[KeySpec {
+            search: KeySearch::KeyNum {
+                numkeys_index: 2,
+                first: 3,
+                step: 1,
+            },
+            access: KeyAccess::Write,
+        }],
+    },
+    CommandSpec {
+        name: "client",
+        arity: -2,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["slow", "connection"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["pubsub", "slow"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["pubsub", "slow"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &[CommandFlag::NoScript, CommandFlag::NoAuth, CommandFlag::Loading],
+        acl_categories: &["fast", "connection"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "auth",
+        arity: -2,
+        flags: &[CommandFlag::NoScript, CommandFlag::NoAuth, CommandFlag::Loading],
+        acl_categories: &["fast", "connection"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["fast", "connection"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "acl",
+        arity: -2,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "hset",
+        arity: -4,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["hash", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "hget",
+        arity: 3,
+        flags: &[CommandFlag::ReadOnly],
+        acl_categories: &["hash", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Read)],
+    },
+    CommandSpec {
+        name: "hgetall",
+        arity: 2,
+        flags: &[CommandFlag::ReadOnly],
+        acl_categories: &["hash", "slow"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Read)],
+    },
+    CommandSpec {
+        name: "rpush",
+        arity: -3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["list", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "sadd",
+        arity: -3,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["set", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "zadd",
+        arity: -4,
+        flags: &[CommandFlag::Write],
+        acl_categories: &["sortedset", "fast"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Write)],
+    },
+    CommandSpec {
+        name: "zrange",
+        arity: -4,
+        flags: &[CommandFlag::ReadOnly],
+        acl_categories: &["sortedset", "slow"],
+        key_specs: &[range(1, 1, 1, KeyAccess::Read)],
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        arity: 1,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "save",
+        arity: 1,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "bgsave",
+        arity: -1,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "lastsave",
+        arity: 1,
+        flags: &[CommandFlag::Loading],
+        acl_categories: &["admin", "fast", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "debug",
+        arity: -2,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &[CommandFlag::Loading],
+        acl_categories: &["slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "replicaof",
+        arity: 3,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "slaveof",
+        arity: 3,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    // replica 在主节点那边全量同步的时候也要能回 ACK
+    CommandSpec {
+        name: "replconf",
+        arity: -1,
+        flags: &[CommandFlag::NoScript, CommandFlag::Loading],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "psync",
+        arity: -3,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    CommandSpec {
+        name: "sync",
+        arity: 1,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["admin", "slow", "dangerous"],
+        key_specs: &[],
+    },
+    // 会一直阻塞到落盘 脚本里不能用
+    CommandSpec {
+        name: "waitaof",
+        arity: 4,
+        flags: &[CommandFlag::NoScript],
+        acl_categories: &["slow", "connection", "blocking"],
+        key_specs: &[],
+    },
+];
+
+// ACL CAT 列出来的所有分类
+pub const ACL_CATEGORIES: &[&str] = &[
+    "keyspace", "read", "write", "set", "sortedset", "list", "hash", "string", "bitmap",
+    "hyperloglog", "geo", "stream", "pubsub", "admin", "fast", "slow", "blocking", "dangerous",
+    "connection", "transaction", "scripting",
+];
+
+pub static COMMAND_TABLE: Lazy<HashMap<&'static str, &'static CommandSpec>> =
+    Lazy::new(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect());
+
+pub fn lookup_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.get(name.to_lowercase().as_str()).copied()
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: CommandFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    pub fn has_subcommands(&self) -> bool {
+        matches!(self.name, "client" | "acl")
+    }
+
+    pub fn in_category(&self, category: &str) -> bool {
+        match category {
+            "all" => true,
+            "read" => self.has_flag(CommandFlag::ReadOnly),
+            "write" => self.has_flag(CommandFlag::Write),
+            _ => self.acl_categories.contains(&category),
+        }
+    }
+
+    // argc 包含命令名本身
+    pub fn check_arity(&self, argc: usize) -> bool {
+        if self.arity >= 0 {
+            argc == self.arity as usize
+        } else {
+            argc >= (-self.arity) as usize
+        }
+    }
+
+    /// 按照 key_specs 从 argv 里把 key 挑出来
+    pub fn extract_keys(&self, argv: &[Bytes]) -> Vec<KeyRef> {
+        let mut keys = Vec::new();
+        for spec in self.key_specs {
+            let (first, last, step) = match spec.search {
+                KeySearch::Range { first, last, step } => {
+                    let last = if last < 0 {
+                        argv.len() as isize + last
+                    } else {
+                        last
+                    };
+                    (first, last, step)
+                }
+                KeySearch::KeyNum {
+                    numkeys_index,
+                    first,
+                    step,
+                } => {
+                    let numkeys = argv
+                        .get(numkeys_index)
+                        .and_then(|n| std::str::from_utf8(n).ok())
+                        .and_then(|n| n.parse::<isize>().ok())
+                        .unwrap_or(0);
+                    (first, first as isize + (numkeys - 1) * step as isize, step)
+                }
+            };
+            let mut index = first as isize;
+            while index <= last && (index as usize) < argv.len() {
+                keys.push(KeyRef {
+                    key: String::from_utf8_lossy(&argv[index as usize]).into_owned(),
+                    access: spec.access,
+                });
+                index += step as isize;
+            }
+        }
+        keys
+    }
+}
+
+impl Command {
+    pub fn spec(&self) -> Option<&'static CommandSpec> {
+        lookup_command(self.name())
+    }
+
+    /// 这条命令会访问到的 pub/sub 频道 ACL 按这个检查 退订不用检查
+    pub fn channels(&self) -> Vec<String> {
+        match self {
+            Command::Subscribe(subscribe) => subscribe.channels.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// 这条命令会访问到的所有 key 以及读写属性
+    pub fn keys(&self) -> Vec<KeyRef> {
+        match self.spec() {
+            Some(spec) if !spec.key_specs.is_empty() => spec.extract_keys(&self.argv()),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::error::Frame;
+
+    fn command(args: &[&str]) -> Command {
+        let frames = args
+            .iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect();
+        Command::try_from(Frame::Array(frames)).unwrap()
+    }
+
+    fn keys(args: &[&str]) -> Vec<(String, KeyAccess)> {
+        command(args)
+            .keys()
+            .into_iter()
+            .map(|key| (key.key, key.access))
+            .collect()
+    }
+
+    #[test]
+    fn test_command_table() {
+        let names: HashSet<_> = COMMANDS.iter().map(|spec| spec.name).collect();
+        assert_eq!(names.len(), COMMANDS.len(), "命令表里有重名的");
+        assert!(COMMANDS.iter().all(|spec| spec.name == spec.name.to_lowercase()));
+        assert_eq!(lookup_command("GeT").unwrap().name, "get");
+        assert!(lookup_command("nosuch").is_none());
+        // 分类都要在 ACL CAT 里列出来
+        for spec in COMMANDS {
+            for category in spec.acl_categories {
+                assert!(ACL_CATEGORIES.contains(category), "{} 的分类 {} 不存在", spec.name, category);
+            }
+        }
+
+        let get = lookup_command("get").unwrap();
+        assert!(get.in_category("read") && get.in_category("string") && get.in_category("all"));
+        assert!(!get.in_category("write") && !get.in_category("hash"));
+    }
+
+    #[test]
+    fn test_check_arity() {
+        let get = lookup_command("get").unwrap();
+        assert!(get.check_arity(2) && !get.check_arity(1) && !get.check_arity(3));
+        let set = lookup_command("set").unwrap();
+        assert!(!set.check_arity(2) && set.check_arity(3) && set.check_arity(6));
+    }
+
+    #[test]
+    fn test_extract_keys() {
+        use KeyAccess::{Read, Write};
+        let key = |k: &str, access| (k.to_string(), access);
+        assert_eq!(keys(&["GET", "a"]), vec![key("a", Read)]);
+        assert_eq!(keys(&["SET", "a", "1", "PX", "100"]), vec![key("a", Write)]);
+        assert_eq!(keys(&["MGET", "a", "b", "c"]), vec![key("a", Read), key("b", Read), key("c", Read)]);
+        // 每隔一个是 key
+        assert_eq!(keys(&["MSET", "a", "1", "b", "2"]), vec![key("a", Write), key("b", Write)]);
+        assert_eq!(keys(&["RENAME", "a", "b"]), vec![key("a", Write), key("b", Write)]);
+        // key 的个数写在参数里 后面的是 ARGV
+        assert_eq!(
+            keys(&["EVAL", "return 1", "2", "a", "b", "arg"]),
+            vec![key("a", Write), key("b", Write)]
+        );
+        assert!(keys(&["EVAL", "return 1", "0", "arg"]).is_empty());
+        assert!(keys(&["PING"]).is_empty());
+    }
+}
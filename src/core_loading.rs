@@ -0,0 +1,173 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use tokio::time::Instant;
+
+use crate::core_keyspec::CommandFlag;
+use crate::error::Command;
+
+/*
+   启动加载数据期间的状态
+   1.监听先起来 数据在后台加载 这期间连上来的客户端大部分命令都回 -LOADING
+     PING/INFO/AUTH 这些带 Loading 标记的命令照常执行 客户端可以轮询什么时候加载完
+   2.加载进度 (总字节数、已经加载的字节数) 放在这里 INFO persistence 里能看到
+*/
+
+pub static LOADING: Lazy<Loading> = Lazy::new(Loading::default);
+
+const LOADING_ERROR: &str = "LOADING kv is loading the dataset in memory";
+
+#[derive(Default)]
+pub struct Loading {
+    loading: AtomicBool,
+    // 开始加载的时刻 和对应的 unix 时间 (秒)
+    started: Mutex<Option<(Instant, u64)>>,
+    total_bytes: AtomicU64,
+    loaded_bytes: AtomicU64,
+}
+
+/// INFO persistence 里的加载状态
+pub struct LoadingStatus {
+    pub loading: bool,
+    pub start_time: u64,
+    pub total_bytes: u64,
+    pub loaded_bytes: u64,
+    pub elapsed_secs: f64,
+}
+
+impl Loading {
+    pub fn start(&self, total_bytes: u64) {
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        *self.started.lock().unwrap() = Some((Instant::now(), unix_secs));
+        self.total_bytes.store(total_bytes, Ordering::SeqCst);
+        self.loaded_bytes.store(0, Ordering::SeqCst);
+        self.loading.store(true, Ordering::SeqCst);
+    }
+
+    pub fn progress(&self, bytes: u64) {
+        self.loaded_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self) {
+        self.loading.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::SeqCst)
+    }
+
+    /// 执行前检查 加载期间只放行带 Loading 标记的命令
+    pub fn check_command(&self, command: &Command) -> Result<(), String> {
+        if !self.is_loading() {
+            return Ok(());
+        }
+        match command.spec() {
+            Some(spec) if spec.has_flag(CommandFlag::Loading) => Ok(()),
+            _ => Err(LOADING_ERROR.into()),
+        }
+    }
+
+    pub fn status(&self) -> LoadingStatus {
+        let (elapsed_secs, start_time) = self
+            .started
+            .lock()
+            .unwrap()
+            .map_or((0.0, 0), |(started, unix_secs)| {
+                (started.elapsed().as_secs_f64(), unix_secs)
+            });
+        LoadingStatus {
+            loading: self.is_loading(),
+            start_time,
+            total_bytes: self.total_bytes.load(Ordering::SeqCst),
+            loaded_bytes: self.loaded_bytes.load(Ordering::SeqCst),
+            elapsed_secs,
+        }
+    }
+}
+
+impl LoadingStatus {
+    pub fn loaded_perc(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        self.loaded_bytes as f64 * 100.0 / self.total_bytes as f64
+    }
+
+    // 按目前的速度估一下还要多久 还没加载任何东西的时候给 1
+    pub fn eta_secs(&self) -> u64 {
+        if self.loaded_bytes == 0 || self.elapsed_secs <= 0.0 {
+            return 1;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.loaded_bytes) as f64;
+        (remaining * self.elapsed_secs / self.loaded_bytes as f64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::error::Frame;
+
+    fn command(argv: &[&str]) -> Command {
+        let frames = argv
+            .iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect();
+        Command::try_from(Frame::Array(frames)).unwrap()
+    }
+
+    #[test]
+    fn test_check_command() {
+        // 全局的 LOADING 别的测试也会碰 这里用自己的
+        let loading = Loading::default();
+        assert!(loading.check_command(&command(&["GET", "a"])).is_ok());
+
+        loading.start(100);
+        for argv in [&["PING"][..], &["INFO"], &["AUTH", "pw"], &["HELLO"], &["LASTSAVE"]] {
+            assert!(loading.check_command(&command(argv)).is_ok(), "{:?}", argv);
+        }
+        for argv in [&["GET", "a"][..], &["SET", "a", "1"], &["KEYS", "*"], &["FLUSHALL"]] {
+            let err = loading.check_command(&command(argv)).unwrap_err();
+            assert!(err.starts_with("LOADING "), "{:?}", argv);
+        }
+
+        loading.finish();
+        assert!(loading.check_command(&command(&["SET", "a", "1"])).is_ok());
+    }
+
+    #[test]
+    fn test_progress() {
+        let loading = Loading::default();
+        let status = loading.status();
+        assert!(!status.loading);
+        assert_eq!((status.start_time, status.loaded_perc(), status.eta_secs()), (0, 100.0, 1));
+
+        loading.start(200);
+        assert_eq!(loading.status().eta_secs(), 1);
+        loading.progress(50);
+        let status = loading.status();
+        assert!(status.loading);
+        assert!(status.start_time > 0);
+        assert_eq!((status.total_bytes, status.loaded_bytes), (200, 50));
+        assert_eq!(status.loaded_perc(), 25.0);
+
+        // 剩下的是已经加载的 3 倍 用的时间也该是 3 倍
+        let status = LoadingStatus { elapsed_secs: 2.0, ..status };
+        assert_eq!(status.eta_secs(), 6);
+
+        // 重新开始加载 进度从头算
+        loading.start(10);
+        assert_eq!(loading.status().loaded_bytes, 0);
+        loading.progress(10);
+        loading.finish();
+        let status = loading.status();
+        assert!(!status.loading);
+        assert_eq!(status.loaded_perc(), 100.0);
+    }
+}
@@ -0,0 +1,1104 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::core_aof::sync_parent_dir;
+use crate::core_loading::LOADING;
+use crate::core_snapshot::{CHECKSUM, SnapshotEntry};
+use crate::core_time::get_cached_time_ms;
+use crate::db::eviction::NUM_SHARDS;
+use crate::db::{Db, bytes_to_i64_fast};
+use crate::types::{Element, Value, ValueEntry};
+
+/*
+   导入 redis 的 RDB 文件 (版本 9 到 12 也就是 redis 5.0 到 7.4)
+   1.文件格式：REDIS + 4 位版本号 后面是一串操作码 0xFF 结尾 最后 8 字节是 CRC64 (0 表示没开校验)
+   2.字符串、列表、哈希、集合、有序集合和过期时间都能转成 kv 的 Value
+     包括各种紧凑编码 ziplist / listpack / quicklist / intset 和 LZF 压缩的字符串
+   3.kv 没有的类型 (stream、模块、带字段过期时间的哈希) 按格式整个跳过 记到 skipped 里报告出来
+     key 或者哈希字段不是 UTF-8 的 库号超过 16 的也一样 文件本身坏了才直接报错
+   4.数字都是按 redis 的规则 长度编码是大端 其他 (过期时间、ziplist 里的整数) 是小端
+   5.导出正好反过来 为了 redis 5.0 以后的版本都能读 只写版本 9 和最基本的类型
+     (字符串、列表、集合、哈希、二进制分数的有序集合) 不压缩 memcached 的 flags redis 里没有 导出就丢了
+*/
+
+const MAGIC: &[u8] = b"REDIS";
+const MIN_VERSION: u32 = 9;
+const MAX_VERSION: u32 = 12;
+const EXPORT_VERSION: u32 = 9;
+// 和 Db::new 建的库数量一样
+const DATABASES: usize = 16;
+
+const OP_SLOT_INFO: u8 = 0xF4;
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_FUNCTION_PRE_GA: u8 = 0xF6;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// 长度的最高两位是 11 的时候 后面是特殊编码的字符串
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+#[derive(Debug)]
+pub struct RdbImport {
+    pub version: u32,
+    pub entries: Vec<SnapshotEntry>,
+    // 没导入的东西 一条一句说明
+    pub skipped: Vec<String>,
+}
+
+/// 整个文件校验过之后再解码 过期的 key 也原样留着 由调用的地方决定要不要
+pub fn decode_rdb(data: &[u8]) -> Result<RdbImport, String> {
+    if data.len() < MAGIC.len() + 4 + 1 + 8 || !data.starts_with(MAGIC) {
+        return Err("不是 RDB 文件".into());
+    }
+    rdb_version(data)?;
+    let (body, checksum) = data.split_at(data.len() - 8);
+    let checksum = u64::from_le_bytes(checksum.try_into().unwrap());
+    if checksum != 0 && CHECKSUM.checksum(body) != checksum {
+        return Err("RDB 文件校验和不对 文件可能损坏了".into());
+    }
+    let (import, end) = decode_rdb_body(data)?;
+    if end != body.len() {
+        return Err("RDB 文件结尾后面还有多余的数据".into());
+    }
+    Ok(import)
+}
+
+/// redis 的 AOF 开了 aof-use-rdb-preamble 的时候 开头是一整个 RDB 后面才是 RESP 命令
+/// 返回 RDB 部分的内容和它占了多少字节 (包括校验和)
+pub fn decode_rdb_preamble(data: &[u8]) -> Result<(RdbImport, usize), String> {
+    let (import, end) = decode_rdb_body(data)?;
+    let checksum = data
+        .get(end..end + 8)
+        .ok_or("AOF 开头的 RDB 部分被截断了")?;
+    let checksum = u64::from_le_bytes(checksum.try_into().unwrap());
+    if checksum != 0 && CHECKSUM.checksum(&data[..end]) != checksum {
+        return Err("AOF 开头的 RDB 部分校验和不对 文件可能损坏了".into());
+    }
+    Ok((import, end + 8))
+}
+
+fn rdb_version(data: &[u8]) -> Result<u32, String> {
+    if data.len() < MAGIC.len() + 4 || !data.starts_with(MAGIC) {
+        return Err("不是 RDB 文件".into());
+    }
+    let version = std::str::from_utf8(&data[MAGIC.len()..MAGIC.len() + 4])
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or("RDB 文件头里的版本号不对")?;
+    if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+        return Err(format!(
+            "不支持 RDB 版本 {} 只支持 {} 到 {}",
+            version, MIN_VERSION, MAX_VERSION
+        ));
+    }
+    Ok(version)
+}
+
+// 从文件头解到 EOF 返回 EOF 后面的位置 校验和由调用的地方检查
+fn decode_rdb_body(data: &[u8]) -> Result<(RdbImport, usize), String> {
+    let version = rdb_version(data)?;
+    let mut reader = RdbReader { data, pos: MAGIC.len() + 4 };
+    let mut import = RdbImport { version, entries: Vec::new(), skipped: Vec::new() };
+    let mut db_index = 0;
+    let mut expires_at = None;
+    loop {
+        match reader.u8()? {
+            OP_EOF => break,
+            OP_SELECTDB => db_index = reader.length()? as usize,
+            OP_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OP_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OP_EXPIRETIME_MS => expires_at = Some(u64::from_le_bytes(reader.array()?)),
+            OP_EXPIRETIME => expires_at = Some(u32::from_le_bytes(reader.array()?) as u64 * 1000),
+            OP_FREQ => {
+                reader.u8()?;
+            }
+            OP_IDLE => {
+                reader.length()?;
+            }
+            OP_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OP_MODULE_AUX => {
+                let module_id = reader.length()?;
+                // when_opcode 和 when
+                reader.length()?;
+                reader.length()?;
+                reader.skip_module_value()?;
+                import.skipped.push(format!("模块 {} 的辅助数据", module_name(module_id)));
+            }
+            OP_FUNCTION2 => {
+                reader.string()?;
+                import.skipped.push("一个函数库 (FUNCTION LOAD 加载的) kv 不支持".into());
+            }
+            OP_FUNCTION_PRE_GA => return Err("不支持 redis 7.0 正式版之前的函数格式".into()),
+            value_type => {
+                let key = reader.string()?;
+                let value = reader.value(value_type)?;
+                let expires_at = expires_at.take();
+                let problem = match (std::str::from_utf8(&key), value) {
+                    (_, Err(reason)) => reason,
+                    (Err(_), _) => "key 不是 UTF-8".into(),
+                    (Ok(_), _) if db_index >= DATABASES => {
+                        format!("库号超出了 kv 的 {} 个库", DATABASES)
+                    }
+                    (Ok(name), Ok(value)) => {
+                        let entry = ValueEntry::new(value, expires_at);
+                        import.entries.push((db_index, Arc::new(name.to_string()), entry));
+                        continue;
+                    }
+                };
+                import.skipped.push(format!(
+                    "db{} 的 key '{}': {}",
+                    db_index,
+                    String::from_utf8_lossy(&key),
+                    problem
+                ));
+            }
+        }
+    }
+    Ok((import, reader.pos))
+}
+
+/// 启动的时候用 没过期的 key 直接放进库里 返回加载的 key 数和没导入的说明
+pub async fn load_rdb(db: &Db, path: &Path) -> Result<(usize, Vec<String>), String> {
+    let data = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+    let import = decode_rdb(&data)?;
+    let loaded = restore_entries(db, import.entries).await;
+    LOADING.progress(data.len() as u64);
+    Ok((loaded, import.skipped))
+}
+
+/// 解出来的 key 放进库里 已经过期的不要 返回放进去的个数 AOF 的 RDB 前缀也走这里
+pub async fn restore_entries(db: &Db, entries: Vec<SnapshotEntry>) -> usize {
+    let now = get_cached_time_ms();
+    let mut loaded = 0;
+    for (db_index, key, entry) in entries {
+        if entry.expires_at.is_some_and(|at| at < now) {
+            continue;
+        }
+        db.store.restore(db_index, key, entry).await;
+        loaded += 1;
+    }
+    loaded
+}
+
+/// DEBUG EXPORT-RDB 用 先写临时文件 fsync 之后 rename 过去 返回导出的 key 数
+pub async fn write_rdb(db: &Db, path: &Path) -> std::io::Result<usize> {
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let result = async {
+        let exported = write_rdb_file(db, &temp_path).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+        sync_parent_dir(path)?;
+        Ok(exported)
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+// 和写快照一样一个分片一个分片地复制 RESIZEDB 只是给 redis 预先分配用的 不知道总数就不写
+async fn write_rdb_file(db: &Db, temp_path: &Path) -> std::io::Result<usize> {
+    let mut file = BufWriter::new(tokio::fs::File::create(temp_path).await?);
+    let mut digest = CHECKSUM.digest();
+    let mut chunk = Vec::new();
+    let mut exported = 0;
+    encode_rdb_header(&mut chunk, get_cached_time_ms());
+    for index in 0..db.store.store.len() {
+        let mut selected = false;
+        for shard_index in 0..NUM_SHARDS {
+            let entries = db.store.shard_entries(index, shard_index).await;
+            if entries.is_empty() {
+                continue;
+            }
+            if !selected {
+                chunk.push(OP_SELECTDB);
+                encode_length(&mut chunk, index as u64);
+                selected = true;
+            }
+            for (key, entry) in &entries {
+                encode_rdb_entry(&mut chunk, key, entry);
+            }
+            exported += entries.len();
+            digest.update(&chunk);
+            file.write_all(&chunk).await?;
+            chunk.clear();
+        }
+    }
+    chunk.push(OP_EOF);
+    digest.update(&chunk);
+    chunk.extend_from_slice(&digest.finalize().to_le_bytes());
+    file.write_all(&chunk).await?;
+    file.flush().await?;
+    file.get_ref().sync_data().await?;
+    Ok(exported)
+}
+
+fn encode_rdb_header(buf: &mut Vec<u8>, created_ms: u64) {
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(format!("{:04}", EXPORT_VERSION).as_bytes());
+    for (name, value) in [("redis-bits", "64".to_string()), ("ctime", (created_ms / 1000).to_string())] {
+        buf.push(OP_AUX);
+        encode_string(buf, name.as_bytes());
+        encode_string(buf, value.as_bytes());
+    }
+}
+
+fn encode_rdb_entry(buf: &mut Vec<u8>, key: &str, entry: &ValueEntry) {
+    if let Some(expires_at) = entry.expires_at {
+        buf.push(OP_EXPIRETIME_MS);
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    let value_type = match &entry.data {
+        Value::Simple(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET_2,
+    };
+    buf.push(value_type);
+    encode_string(buf, key.as_bytes());
+    match &entry.data {
+        Value::Simple(element) => encode_element(buf, element),
+        Value::List(list) => {
+            encode_length(buf, list.len() as u64);
+            list.iter().for_each(|element| encode_element(buf, element));
+        }
+        Value::Hash(map) => {
+            encode_length(buf, map.len() as u64);
+            for (field, element) in map {
+                encode_string(buf, field.as_bytes());
+                encode_element(buf, element);
+            }
+        }
+        Value::Set(set) => {
+            encode_length(buf, set.len() as u64);
+            set.iter().for_each(|element| encode_element(buf, element));
+        }
+        Value::ZSet(zset) => {
+            encode_length(buf, zset.len() as u64);
+            for (member, score) in zset {
+                encode_element(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn encode_length(buf: &mut Vec<u8>, len: u64) {
+    match len {
+        0..0x40 => buf.push(len as u8),
+        0x40..0x4000 => buf.extend_from_slice(&(0x4000 | len as u16).to_be_bytes()),
+        0x4000..0x1_0000_0000 => {
+            buf.push(0x80);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0x81);
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+}
+
+fn encode_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    encode_length(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// 整数放得进 32 位就用整数编码 和 redis 自己存的一样
+fn encode_element(buf: &mut Vec<u8>, element: &Element) {
+    match *element {
+        Element::String(ref bytes) => encode_string(buf, bytes),
+        Element::Int(i) => {
+            if let Ok(i) = i8::try_from(i) {
+                buf.push(0xC0 | ENC_INT8);
+                buf.extend_from_slice(&i.to_le_bytes());
+            } else if let Ok(i) = i16::try_from(i) {
+                buf.push(0xC0 | ENC_INT16);
+                buf.extend_from_slice(&i.to_le_bytes());
+            } else if let Ok(i) = i32::try_from(i) {
+                buf.push(0xC0 | ENC_INT32);
+                buf.extend_from_slice(&i.to_le_bytes());
+            } else {
+                encode_string(buf, i.to_string().as_bytes());
+            }
+        }
+    }
+}
+
+// 模块 id 的高 54 位是 9 个字符的类型名 低 10 位是版本
+fn module_name(module_id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut id = module_id >> 10;
+    let mut name = [0u8; 9];
+    for c in name.iter_mut().rev() {
+        *c = CHARSET[(id & 63) as usize];
+        id >>= 6;
+    }
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+// 字符串能当整数存的就当整数存 和 SET 写进来的一样
+fn element(bytes: Vec<u8>) -> Element {
+    let bytes = Bytes::from(bytes);
+    match bytes_to_i64_fast(&bytes) {
+        Some(i) => Element::Int(i),
+        None => Element::String(bytes),
+    }
+}
+
+fn hash_value(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Value, String> {
+    let mut map = HashMap::new();
+    for (field, value) in pairs {
+        let field = String::from_utf8(field).map_err(|_| "哈希里有不是 UTF-8 的字段")?;
+        map.insert(field, element(value));
+    }
+    Ok(Value::Hash(map))
+}
+
+fn parse_score(bytes: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|score| score.parse().ok())
+        .ok_or_else(|| format!("有序集合的分数 '{}' 不是数字", String::from_utf8_lossy(bytes)))
+}
+
+// ziplist / listpack / intset 里的一个元素 整数就直接是整数
+enum Packed<'a> {
+    Str(&'a [u8]),
+    Int(i64),
+}
+
+impl Packed<'_> {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Packed::Str(bytes) => bytes.to_vec(),
+            Packed::Int(i) => i.to_string().into_bytes(),
+        }
+    }
+
+    fn into_element(self) -> Element {
+        match self {
+            Packed::Str(bytes) => element(bytes.to_vec()),
+            Packed::Int(i) => Element::Int(i),
+        }
+    }
+
+    fn score(&self) -> Result<f64, String> {
+        match self {
+            Packed::Str(bytes) => parse_score(bytes),
+            Packed::Int(i) => Ok(*i as f64),
+        }
+    }
+}
+
+// 两两一组 (哈希的字段和值 有序集合的成员和分数)
+fn pairs(entries: Vec<Packed<'_>>) -> Result<Vec<(Packed<'_>, Packed<'_>)>, String> {
+    if !entries.len().is_multiple_of(2) {
+        return Err("压缩列表里的元素个数不是偶数".into());
+    }
+    let mut entries = entries.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(first), Some(second)) = (entries.next(), entries.next()) {
+        pairs.push((first, second));
+    }
+    Ok(pairs)
+}
+
+fn zset_value(entries: Vec<Packed<'_>>) -> Result<Value, String> {
+    let mut zset = HashMap::new();
+    for (member, score) in pairs(entries)? {
+        let score = score.score()?;
+        zset.insert(member.into_element(), score);
+    }
+    Ok(Value::ZSet(zset))
+}
+
+fn i24(bytes: [u8; 3]) -> i64 {
+    (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as i64
+}
+
+// ziplist：头 10 字节 每个元素是 前一个元素的长度 + 编码 + 内容 0xFF 结尾
+fn parse_ziplist(blob: &[u8]) -> Result<Vec<Packed<'_>>, String> {
+    let mut reader = RdbReader { data: blob, pos: 10 };
+    let mut entries = Vec::new();
+    loop {
+        match reader.u8()? {
+            0xFF => break,
+            0xFE => {
+                reader.take(4)?;
+            }
+            _ => {}
+        }
+        let encoding = reader.u8()?;
+        let entry = match encoding >> 6 {
+            0 => Packed::Str(reader.take((encoding & 0x3F) as usize)?),
+            1 => {
+                let len = ((encoding as usize & 0x3F) << 8) | reader.u8()? as usize;
+                Packed::Str(reader.take(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                Packed::Str(reader.take(len)?)
+            }
+            _ => Packed::Int(match encoding {
+                0xC0 => i16::from_le_bytes(reader.array()?) as i64,
+                0xD0 => i32::from_le_bytes(reader.array()?) as i64,
+                0xE0 => i64::from_le_bytes(reader.array()?),
+                0xF0 => i24(reader.array()?),
+                0xFE => reader.u8()? as i8 as i64,
+                0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                other => return Err(format!("ziplist 里有不认识的编码 {:#x}", other)),
+            }),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// listpack：头 6 字节 每个元素是 编码 + 内容 + 反向长度 0xFF 结尾
+fn parse_listpack(blob: &[u8]) -> Result<Vec<Packed<'_>>, String> {
+    let mut reader = RdbReader { data: blob, pos: 6 };
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let encoding = reader.u8()?;
+        let entry = match encoding {
+            0xFF => break,
+            0x00..=0x7F => Packed::Int(encoding as i64),
+            0x80..=0xBF => Packed::Str(reader.take((encoding & 0x3F) as usize)?),
+            0xC0..=0xDF => {
+                let value = ((encoding as i64 & 0x1F) << 8) | reader.u8()? as i64;
+                // 13 位有符号数
+                Packed::Int(if value >= 1 << 12 { value - (1 << 13) } else { value })
+            }
+            0xE0..=0xEF => {
+                let len = ((encoding as usize & 0x0F) << 8) | reader.u8()? as usize;
+                Packed::Str(reader.take(len)?)
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                Packed::Str(reader.take(len)?)
+            }
+            0xF1 => Packed::Int(i16::from_le_bytes(reader.array()?) as i64),
+            0xF2 => Packed::Int(i24(reader.array()?)),
+            0xF3 => Packed::Int(i32::from_le_bytes(reader.array()?) as i64),
+            0xF4 => Packed::Int(i64::from_le_bytes(reader.array()?)),
+            other => return Err(format!("listpack 里有不认识的编码 {:#x}", other)),
+        };
+        let backlen = match reader.pos - start {
+            0..=127 => 1,
+            128..16383 => 2,
+            16383..2097151 => 3,
+            2097151..268435455 => 4,
+            _ => 5,
+        };
+        reader.take(backlen)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_intset(blob: &[u8]) -> Result<Vec<Packed<'_>>, String> {
+    let mut reader = RdbReader { data: blob, pos: 0 };
+    let encoding = u32::from_le_bytes(reader.array()?);
+    let len = u32::from_le_bytes(reader.array()?);
+    let mut entries = Vec::new();
+    for _ in 0..len {
+        let value = match encoding {
+            2 => i16::from_le_bytes(reader.array()?) as i64,
+            4 => i32::from_le_bytes(reader.array()?) as i64,
+            8 => i64::from_le_bytes(reader.array()?),
+            other => return Err(format!("intset 的整数宽度 {} 不对", other)),
+        };
+        entries.push(Packed::Int(value));
+    }
+    Ok(entries)
+}
+
+// LZF 解压 控制字节小于 32 是一段原样的字节 否则是往回引用已经解出来的内容
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let corrupted = || "LZF 压缩的字符串损坏了".to_string();
+    let mut output = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+        if ctrl < 32 {
+            let literal = input.get(pos..pos + ctrl + 1).ok_or_else(corrupted)?;
+            output.extend_from_slice(literal);
+            pos += ctrl + 1;
+        } else {
+            let mut copy = ctrl >> 5;
+            if copy == 7 {
+                copy += *input.get(pos).ok_or_else(corrupted)? as usize;
+                pos += 1;
+            }
+            let back = ((ctrl & 0x1F) << 8) + *input.get(pos).ok_or_else(corrupted)? as usize + 1;
+            pos += 1;
+            let from = output.len().checked_sub(back).ok_or_else(corrupted)?;
+            // 引用的范围可能和正在写的部分重叠 只能一个字节一个字节地复制
+            for i in from..from + copy + 2 {
+                output.push(output[i]);
+            }
+        }
+        if output.len() > len {
+            return Err(corrupted());
+        }
+    }
+    if output.len() != len {
+        return Err(corrupted());
+    }
+    Ok(output)
+}
+
+struct RdbReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let data = self.data;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| format!("RDB 数据在偏移 {} 处被截断", self.pos))?;
+        let bytes = &data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    // 返回 (长度, 是不是特殊编码) 特殊编码的时候长度就是编码类型
+    fn length_or_encoding(&mut self) -> Result<(u64, bool), String> {
+        let first = self.u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3F) as u64, false)),
+            1 => Ok(((((first & 0x3F) as u64) << 8) | self.u8()? as u64, false)),
+            3 => Ok(((first & 0x3F) as u64, true)),
+            _ => match first {
+                0x80 => Ok((u32::from_be_bytes(self.array()?) as u64, false)),
+                0x81 => Ok((u64::from_be_bytes(self.array()?), false)),
+                other => Err(format!("偏移 {} 处的长度编码 {:#x} 不对", self.pos - 1, other)),
+            },
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        match self.length_or_encoding()? {
+            (len, false) => Ok(len),
+            _ => Err(format!("偏移 {} 处应该是长度", self.pos - 1)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        let (len, encoded) = self.length_or_encoding()?;
+        if !encoded {
+            return Ok(self.take(len as usize)?.to_vec());
+        }
+        let value = match len as u8 {
+            ENC_INT8 => self.u8()? as i8 as i64,
+            ENC_INT16 => i16::from_le_bytes(self.array()?) as i64,
+            ENC_INT32 => i32::from_le_bytes(self.array()?) as i64,
+            ENC_LZF => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                return lzf_decompress(self.take(compressed_len)?, len);
+            }
+            other => return Err(format!("偏移 {} 处的字符串编码 {} 不对", self.pos - 1, other)),
+        };
+        Ok(value.to_string().into_bytes())
+    }
+
+    // 老格式有序集合的分数 长度 + 文本 253/254/255 分别是 nan/inf/-inf
+    fn string_score(&mut self) -> Result<f64, String> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as usize)?),
+        }
+    }
+
+    /// 外层的错误是文件坏了 里层的错误是这个值 kv 不支持 已经按格式跳过了
+    fn value(&mut self, value_type: u8) -> Result<Result<Value, String>, String> {
+        let value = match value_type {
+            TYPE_STRING => Value::Simple(element(self.string()?)),
+            TYPE_LIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    list.push_back(element(self.string()?));
+                }
+                Value::List(list)
+            }
+            TYPE_SET => {
+                let mut set = HashSet::new();
+                for _ in 0..self.length()? {
+                    set.insert(element(self.string()?));
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut zset = HashMap::new();
+                for _ in 0..self.length()? {
+                    let member = element(self.string()?);
+                    let score = match value_type {
+                        TYPE_ZSET_2 => f64::from_le_bytes(self.array()?),
+                        _ => self.string_score()?,
+                    };
+                    zset.insert(member, score);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_HASH => {
+                let mut fields = Vec::new();
+                for _ in 0..self.length()? {
+                    fields.push((self.string()?, self.string()?));
+                }
+                return Ok(hash_value(fields));
+            }
+            TYPE_LIST_ZIPLIST => {
+                let blob = self.string()?;
+                Value::List(parse_ziplist(&blob)?.into_iter().map(Packed::into_element).collect())
+            }
+            TYPE_LIST_QUICKLIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    let blob = self.string()?;
+                    list.extend(parse_ziplist(&blob)?.into_iter().map(Packed::into_element));
+                }
+                Value::List(list)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    let container = self.length()?;
+                    let blob = self.string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => list.push_back(element(blob)),
+                        QUICKLIST_NODE_PACKED => {
+                            list.extend(parse_listpack(&blob)?.into_iter().map(Packed::into_element))
+                        }
+                        other => return Err(format!("quicklist 的节点类型 {} 不对", other)),
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_SET_INTSET => {
+                let blob = self.string()?;
+                Value::Set(parse_intset(&blob)?.into_iter().map(Packed::into_element).collect())
+            }
+            TYPE_SET_LISTPACK => {
+                let blob = self.string()?;
+                Value::Set(parse_listpack(&blob)?.into_iter().map(Packed::into_element).collect())
+            }
+            TYPE_ZSET_ZIPLIST => zset_value(parse_ziplist(&self.string()?)?)?,
+            TYPE_ZSET_LISTPACK => zset_value(parse_listpack(&self.string()?)?)?,
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.string()?;
+                let entries = match value_type {
+                    TYPE_HASH_ZIPLIST => parse_ziplist(&blob)?,
+                    _ => parse_listpack(&blob)?,
+                };
+                let fields = pairs(entries)?
+                    .into_iter()
+                    .map(|(field, value)| (field.into_bytes(), value.into_bytes()))
+                    .collect();
+                return Ok(hash_value(fields));
+            }
+            TYPE_HASH_ZIPMAP => {
+                self.string()?;
+                return Ok(Err("zipmap 编码的哈希 (redis 2.6 之前的格式) 不支持".into()));
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(value_type)?;
+                return Ok(Err("kv 没有 stream 类型".into()));
+            }
+            TYPE_MODULE_2 => {
+                let module_id = self.length()?;
+                self.skip_module_value()?;
+                return Ok(Err(format!("模块类型 {} kv 不支持", module_name(module_id))));
+            }
+            TYPE_HASH_METADATA => {
+                // 最小的过期时间 然后每个字段是 过期时间 + 字段 + 值
+                self.take(8)?;
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                    self.string()?;
+                }
+                return Ok(Err("kv 的哈希不支持字段过期时间".into()));
+            }
+            TYPE_HASH_LISTPACK_EX => {
+                self.take(8)?;
+                self.string()?;
+                return Ok(Err("kv 的哈希不支持字段过期时间".into()));
+            }
+            other => return Err(format!("不认识的值类型 {} (偏移 {})", other, self.pos - 1)),
+        };
+        Ok(Ok(value))
+    }
+
+    // 模块的值是一串带类型的字段 EOF 结尾 不用知道模块自己的格式也能跳过
+    fn skip_module_value(&mut self) -> Result<(), String> {
+        loop {
+            match self.length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.length()?;
+                }
+                MODULE_OPCODE_FLOAT => {
+                    self.take(4)?;
+                }
+                MODULE_OPCODE_DOUBLE => {
+                    self.take(8)?;
+                }
+                MODULE_OPCODE_STRING => {
+                    self.string()?;
+                }
+                other => return Err(format!("模块数据里有不认识的字段类型 {}", other)),
+            }
+        }
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<(), String> {
+        // 每个节点是 起始 id + listpack
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.string()?;
+        }
+        // 元素个数 最后一个 id 7.0 之后还有 第一个 id、最大删除的 id、一共加过多少
+        let fields = if value_type == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+        for _ in 0..fields {
+            self.length()?;
+        }
+        for _ in 0..self.length()? {
+            // 消费组的名字和 last id
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if value_type != TYPE_STREAM_LISTPACKS {
+                self.length()?;
+            }
+            // 待确认列表 每条是 id + 投递时间 + 投递次数
+            for _ in 0..self.length()? {
+                self.take(16 + 8)?;
+                self.length()?;
+            }
+            for _ in 0..self.length()? {
+                // 消费者名字 最后活跃时间 7.2 之后多一个时间 自己的待确认 id
+                self.string()?;
+                self.take(8)?;
+                if value_type == TYPE_STREAM_LISTPACKS_3 {
+                    self.take(8)?;
+                }
+                for _ in 0..self.length()? {
+                    self.take(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 头 + 内容 + EOF + 校验和
+    fn rdb(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(OP_EOF);
+        let checksum = CHECKSUM.checksum(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![value.len() as u8];
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    // listpack 里 6 位长度的字符串
+    fn lp_string(value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x80 | value.len() as u8];
+        buf.extend_from_slice(value);
+        buf.push(1 + value.len() as u8);
+        buf
+    }
+
+    fn listpack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![0; 6];
+        buf.extend(entries.concat());
+        buf.push(0xFF);
+        string(&buf)
+    }
+
+    fn key(value_type: u8, name: &[u8], value: &[u8]) -> Vec<u8> {
+        [vec![value_type], string(name), value.to_vec()].concat()
+    }
+
+    fn value<'a>(import: &'a RdbImport, name: &str) -> &'a ValueEntry {
+        &import.entries.iter().find(|(_, key, _)| key.as_str() == name).unwrap().2
+    }
+
+    fn str(value: &str) -> Element {
+        Element::String(Bytes::copy_from_slice(value.as_bytes()))
+    }
+
+    #[test]
+    fn test_decode_rdb() {
+        let ziplist = [
+            vec![0; 10],
+            vec![0x00, 0x02, b'a', b'b'],
+            vec![0x04, 0xF5],
+            vec![0x02, 0xC0],
+            1000i16.to_le_bytes().to_vec(),
+            vec![0xFF],
+        ]
+        .concat();
+        let intset = [2u32.to_le_bytes(), 2u32.to_le_bytes()].concat();
+        let intset = [intset, 1i16.to_le_bytes().to_vec(), (-2i16).to_le_bytes().to_vec()].concat();
+        let module_id = [vec![0x81], u64::MAX.to_be_bytes().to_vec()].concat();
+        let body = [
+            [vec![OP_AUX], string(b"redis-ver"), string(b"7.2.4")].concat(),
+            vec![OP_SELECTDB, 0, OP_RESIZEDB, 10, 1],
+            key(TYPE_STRING, b"s", &string(b"hello")),
+            key(TYPE_STRING, b"n", &[0xC0, 0x7B]),
+            // LZF 压缩的 10 个 a
+            key(TYPE_STRING, b"lzf", &[0xC3, 5, 10, 0x00, b'a', 0xE0, 0x00, 0x00]),
+            [vec![OP_EXPIRETIME_MS], 1700000000000u64.to_le_bytes().to_vec()].concat(),
+            key(TYPE_STRING, b"e", &string(b"x")),
+            key(TYPE_LIST_ZIPLIST, b"zl", &string(&ziplist)),
+            key(TYPE_SET_INTSET, b"is", &string(&intset)),
+            key(
+                TYPE_LIST_QUICKLIST_2,
+                b"ql",
+                &[
+                    vec![2, 2],
+                    listpack(&[lp_string(b"a"), vec![0x05, 1], vec![0xDF, 0xFF, 2]]),
+                    vec![1],
+                    string(b"big"),
+                ]
+                .concat(),
+            ),
+            key(TYPE_HASH_LISTPACK, b"h", &listpack(&[lp_string(b"f"), lp_string(b"v"), lp_string(b"g"), vec![7, 1]])),
+            key(TYPE_ZSET_LISTPACK, b"z", &listpack(&[lp_string(b"m"), lp_string(b"1.5"), lp_string(b"n"), vec![3, 1]])),
+            key(TYPE_ZSET_2, b"z2", &[vec![1], string(b"x"), 2.5f64.to_le_bytes().to_vec()].concat()),
+            key(TYPE_ZSET, b"z1", &[vec![2], string(b"y"), string(b"0.5"), string(b"z"), vec![254]].concat()),
+            key(TYPE_SET_LISTPACK, b"sl", &listpack(&[lp_string(b"a"), vec![0x05, 1]])),
+            // kv 不支持的 模块类型 和 不是 UTF-8 的 key
+            key(TYPE_MODULE_2, b"mod", &[module_id, vec![MODULE_OPCODE_UINT as u8, 5, MODULE_OPCODE_EOF as u8]].concat()),
+            key(TYPE_STRING, &[0xFF, 0xFE], &string(b"x")),
+            vec![OP_SELECTDB, 20],
+            key(TYPE_STRING, b"far", &string(b"x")),
+        ]
+        .concat();
+
+        let import = decode_rdb(&rdb(&body)).unwrap();
+        assert_eq!(import.version, 11);
+        assert_eq!(import.entries.len(), 12);
+        assert_eq!(import.skipped.len(), 3);
+        assert!(import.skipped[0].contains("mod"));
+        assert!(import.skipped[2].contains("db20"));
+
+        assert!(matches!(&value(&import, "s").data, Value::Simple(e) if *e == str("hello")));
+        assert!(matches!(&value(&import, "n").data, Value::Simple(Element::Int(123))));
+        assert!(matches!(&value(&import, "lzf").data, Value::Simple(e) if *e == str("aaaaaaaaaa")));
+        assert_eq!(value(&import, "e").expires_at, Some(1700000000000));
+        assert_eq!(value(&import, "s").expires_at, None);
+        assert!(matches!(&value(&import, "zl").data,
+            Value::List(list) if list.iter().cloned().eq([str("ab"), Element::Int(4), Element::Int(1000)])));
+        assert!(matches!(&value(&import, "is").data,
+            Value::Set(set) if set.contains(&Element::Int(1)) && set.contains(&Element::Int(-2))));
+        assert!(matches!(&value(&import, "ql").data,
+            Value::List(list) if list.iter().cloned().eq([str("a"), Element::Int(5), Element::Int(-1), str("big")])));
+        assert!(matches!(&value(&import, "h").data,
+            Value::Hash(map) if map["f"] == str("v") && map["g"] == Element::Int(7)));
+        assert!(matches!(&value(&import, "z").data,
+            Value::ZSet(zset) if zset[&str("m")] == 1.5 && zset[&str("n")] == 3.0 && zset.len() == 2));
+        assert!(matches!(&value(&import, "z2").data, Value::ZSet(zset) if zset[&str("x")] == 2.5));
+        assert!(matches!(&value(&import, "z1").data,
+            Value::ZSet(zset) if zset[&str("y")] == 0.5 && zset[&str("z")] == f64::INFINITY));
+        assert!(matches!(&value(&import, "sl").data, Value::Set(set) if set.len() == 2));
+    }
+
+    #[test]
+    fn test_decode_rdb_errors() {
+        let body = key(TYPE_STRING, b"s", &string(b"hello"));
+        let mut data = rdb(&body);
+        // 校验和是 0 表示没开校验
+        let len = data.len();
+        data[len - 8..].fill(0);
+        assert_eq!(decode_rdb(&data).unwrap().entries.len(), 1);
+
+        let mut data = rdb(&body);
+        data[12] = b'j';
+        assert!(decode_rdb(&data).unwrap_err().contains("校验和"));
+
+        let mut data = rdb(&body);
+        data[5..9].copy_from_slice(b"0008");
+        assert!(decode_rdb(&data).unwrap_err().contains("版本"));
+
+        let data = rdb(&key(TYPE_STRING, b"s", &[10, b'x']));
+        assert!(decode_rdb(&data).unwrap_err().contains("截断"));
+        assert!(decode_rdb(b"KVSNAP").is_err());
+
+        // AOF 的 RDB 前缀后面还跟着命令 整个当 RDB 解是不行的
+        let mut data = rdb(&body);
+        let preamble = data.len();
+        data.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        assert!(decode_rdb(&data).is_err());
+        let (import, consumed) = decode_rdb_preamble(&data).unwrap();
+        assert_eq!((import.entries.len(), consumed), (1, preamble));
+        // EOF 前面那个字节是值的最后一个字母
+        data[preamble - 10] = b'j';
+        assert!(decode_rdb_preamble(&data).unwrap_err().contains("校验和"));
+        assert!(decode_rdb_preamble(&data[..preamble - 3]).is_err());
+    }
+
+    // 不经过 Db 直接把一批 key 编码成完整的 RDB 文件
+    fn encode_rdb(entries: &[SnapshotEntry], created_ms: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_rdb_header(&mut buf, created_ms);
+        let mut current = None;
+        for (db_index, key, entry) in entries {
+            if current != Some(*db_index) {
+                buf.push(OP_SELECTDB);
+                encode_length(&mut buf, *db_index as u64);
+                current = Some(*db_index);
+            }
+            encode_rdb_entry(&mut buf, key, entry);
+        }
+        buf.push(OP_EOF);
+        let checksum = CHECKSUM.checksum(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    fn export_entries() -> Vec<SnapshotEntry> {
+        let list: VecDeque<Element> = (0..100).map(|i| Element::Int(i * 1000)).chain([str("x")]).collect();
+        let hash = [("f".to_string(), str("v")), ("n".to_string(), Element::Int(-70000))].into_iter().collect();
+        let set = [Element::Int(i64::MAX), str("m")].into_iter().collect();
+        let zset = [(str("a"), 1.5), (Element::Int(2), f64::NEG_INFINITY)].into_iter().collect();
+        let values = [
+            ("small", Value::Simple(Element::Int(-5)), None),
+            ("medium", Value::Simple(Element::Int(300)), Some(4102444800000)),
+            ("large", Value::Simple(Element::Int(1 << 40)), None),
+            ("long", Value::Simple(Element::String(Bytes::from(vec![b'x'; 20000]))), None),
+            ("list", Value::List(list), Some(4102444800123)),
+            ("hash", Value::Hash(hash), None),
+            ("set", Value::Set(set), None),
+            ("zset", Value::ZSet(zset), None),
+        ];
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, (key, data, expires_at))| (i % 3 * 7, Arc::new(key.to_string()), ValueEntry::new(data, expires_at)))
+            .collect()
+    }
+
+    // 逐个 key 比较库号、类型、值和过期时间
+    fn assert_same(mut expected: Vec<SnapshotEntry>, mut actual: Vec<SnapshotEntry>) {
+        expected.sort_by(|a, b| a.1.cmp(&b.1));
+        actual.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(expected.len(), actual.len());
+        for ((db, key, entry), (actual_db, actual_key, actual_entry)) in expected.iter().zip(&actual) {
+            assert_eq!((db, key), (actual_db, actual_key));
+            assert_eq!(entry.data, actual_entry.data, "{}", key);
+            assert_eq!(entry.expires_at, actual_entry.expires_at, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_rdb_roundtrip() {
+        let entries = export_entries();
+        let data = encode_rdb(&entries, 1700000000000);
+        assert!(data.starts_with(b"REDIS0009"));
+        let import = decode_rdb(&data).unwrap();
+        assert_eq!(import.version, 9);
+        assert!(import.skipped.is_empty());
+        assert_same(entries, import.entries);
+    }
+
+    #[tokio::test]
+    async fn test_write_rdb() {
+        let db = Db::new(&crate::config::EvictionType::LRU);
+        let entries = export_entries();
+        for (db_index, key, entry) in &entries {
+            db.store.restore(*db_index, key.clone(), entry.clone()).await;
+        }
+        let path = std::env::temp_dir().join(format!("kv-export-{}.rdb", std::process::id()));
+        assert_eq!(write_rdb(&db, &path).await.unwrap(), entries.len());
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_same(entries, decode_rdb(&data).unwrap().entries);
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::EvictionType;
+use crate::core_aof::{explain_execute_aofcommand, load_aof};
+use crate::core_aof_manifest::AofManifest;
+use crate::core_rdb::write_rdb;
+use crate::core_snapshot::load_snapshot;
+use crate::core_time::refresh_cached_time;
+use crate::db::Db;
+
+/*
+   kv-export-rdb 把 kv 的快照或者 AOF 离线转成 redis 能读的 RDB 文件
+   1.先和启动的时候一样加载到内存里 再和 DEBUG EXPORT-RDB 一样导出 两边导出来的内容一致
+   2.可以给快照 (.kvdb)、AOF 清单 (.manifest) 或者单个 RESP 格式的 AOF 文件
+     redis 的 AOF 也可以 kv 执行不了的命令一条条打印出来 导出本身还是算成功
+   3.不会改输入文件 AOF 结尾不完整就报错 先用 kv-check-aof --fix 修好再导出
+*/
+
+const USAGE: &str = "用法: kv-export-rdb [-o <dump.rdb>] <file.kvdb | file.manifest | file.aof>";
+const DEFAULT_OUTPUT: &str = "dump.rdb";
+
+/// 返回进程的退出码 导出成功是 0
+pub async fn export_rdb(args: impl IntoIterator<Item = String>) -> i32 {
+    let mut args = args.into_iter();
+    let mut output = PathBuf::from(DEFAULT_OUTPUT);
+    let mut inputs = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = PathBuf::from(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 1;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("{}", USAGE);
+                return 1;
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    let [input] = inputs.as_slice() else {
+        eprintln!("{}", USAGE);
+        return 1;
+    };
+    match convert(input, &output).await {
+        Ok(exported) => {
+            println!("{}: 导出 {} 个 key 到 {}", input.display(), exported, output.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: 导出失败 {}", input.display(), e);
+            1
+        }
+    }
+}
+
+async fn convert(input: &Path, output: &Path) -> Result<usize, String> {
+    // 加载的时候要用当前时间判断过期
+    refresh_cached_time();
+    let db = Db::new(&EvictionType::LRU);
+    let report = match input.extension().and_then(|ext| ext.to_str()) {
+        Some("kvdb") => {
+            load_snapshot(&db, input).await?.ok_or("文件不存在")?;
+            Vec::new()
+        }
+        Some("manifest") => {
+            let manifest = AofManifest::open(input)?;
+            load_aof(&manifest, &db, false).await.map_err(|e| e.to_string())?
+        }
+        _ => explain_execute_aofcommand(input, &db, false)
+            .await
+            .map_err(|e| e.to_string())?,
+    };
+    for line in &report {
+        println!("跳过 {}", line);
+    }
+    write_rdb(&db, output)
+        .await
+        .map_err(|e| format!("写 {} 失败 {}", output.display(), e))
+}
@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::core_aof::sync_parent_dir;
+use crate::core_rdb::decode_rdb;
+use crate::core_snapshot::encode_snapshot;
+
+/*
+   kv-import-rdb 把 redis 的 dump.rdb 转成 kv 的快照文件
+   1.转出来的文件放到 dbfilename 的位置 AOF 是空的时候启动就会加载它
+   2.已经过期的 key 直接丢掉 kv 不支持的东西一条一条打印出来 转换本身还是算成功
+   3.也可以不转 直接启动的时候用 --import-rdb dump.rdb 导入
+*/
+
+const USAGE: &str = "用法: kv-import-rdb [-o <file.kvdb>] <dump.rdb>";
+const DEFAULT_OUTPUT: &str = "dump.kvdb";
+
+/// 返回进程的退出码 转换成功是 0
+pub async fn import_rdb(args: impl IntoIterator<Item = String>) -> i32 {
+    let mut args = args.into_iter();
+    let mut output = PathBuf::from(DEFAULT_OUTPUT);
+    let mut inputs = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = PathBuf::from(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 1;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("{}", USAGE);
+                return 1;
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    let [input] = inputs.as_slice() else {
+        eprintln!("{}", USAGE);
+        return 1;
+    };
+    match convert(input, &output).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: 导入失败 {}", input.display(), e);
+            1
+        }
+    }
+}
+
+async fn convert(input: &Path, output: &Path) -> Result<(), String> {
+    let data = tokio::fs::read(input).await.map_err(|e| e.to_string())?;
+    let mut import = decode_rdb(&data)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64);
+    let total = import.entries.len();
+    import.entries.retain(|(_, _, entry)| entry.expires_at.is_none_or(|at| at >= now));
+    for skipped in &import.skipped {
+        println!("跳过 {}", skipped);
+    }
+    println!(
+        "{}: RDB 版本 {} 导入 {} 个 key 丢掉 {} 个已经过期的 跳过 {} 项不支持的",
+        input.display(),
+        import.version,
+        import.entries.len(),
+        total - import.entries.len(),
+        import.skipped.len()
+    );
+
+    let temp_path = output.with_extension("tmp");
+    let result = write_file(&temp_path, &encode_snapshot(&import.entries, now), output);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result.map_err(|e| format!("写 {} 失败 {}", output.display(), e))?;
+    println!("已经写到 {}", output.display());
+    Ok(())
+}
+
+// 先写临时文件 fsync 之后 rename 过去 和保存快照一样
+fn write_file(temp_path: &Path, data: &[u8], path: &Path) -> std::io::Result<()> {
+    std::fs::write(temp_path, data)?;
+    std::fs::File::open(temp_path)?.sync_all()?;
+    std::fs::rename(temp_path, path)?;
+    sync_parent_dir(path)
+}
@@ -0,0 +1,1232 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytes::{Buf, Bytes, BytesMut};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+use crate::aof_exchange::{AofContent, send_multi_aof};
+use crate::config::CONFIG;
+use crate::context::{CONN_STATE, ConnectionContent, ConnectionState, next_client_id};
+use crate::core_aof::{frame_command_name, select_command, select_index};
+use crate::core_aof_rewrite::AOF_REWRITE;
+use crate::core_client::unless_killed;
+use crate::core_codec::FrameCodec;
+use crate::core_execute::{execute_command_hook, execute_command_normal};
+use crate::core_explain::parse_frame;
+use crate::core_keyspec::CommandFlag;
+use crate::core_loading::LOADING;
+use crate::core_rdb::{decode_rdb, restore_entries};
+use crate::core_snapshot::{SnapshotEntry, decode_snapshot, write_snapshot_to};
+use crate::db::Db;
+use crate::db::lock_plan::LockPlan;
+use crate::error::{Command, Frame, PsyncCommand, ReplconfOption};
+
+/*
+   主从复制 (REPLICAOF/PSYNC)
+   1.主节点的写入和 AOF 走同一条路 写入任务写 AOF 的时候顺便追加到复制积压缓冲区
+     复制偏移量就是复制流一共写了多少字节 每个 replica 连接按自己的位置从积压缓冲区里取数据发出去
+     落后太多 (要的数据已经被挤出缓冲区) 就断开 让它重连之后重新全量同步
+   2.replica 连上来先 PSYNC replid offset 历史对得上并且这个位置还在积压缓冲区里就 +CONTINUE 只补后面的
+     否则 +FULLRESYNC 把快照边编码边发过去
+     和 AOF 重写一样开一个时间点快照 (db::capture) 在这个纪元切开 写入任务写完之前的、记下偏移量再写之后的
+     快照里正好是这个偏移量之前的写入 复制流从这里接上 RPUSH、RENAME 这种命令不会多执行也不会漏
+     不拿所有分片的锁 快照一个分片一个分片地边编码边发
+   3.replica 这边一个后台任务连主节点 全量同步就清空所有库加载快照 然后按顺序执行复制流里的命令
+     执行的结果照常写自己的 AOF 原始字节追加到自己的积压缓冲区 下面还能再挂 replica 偏移量和主节点是对齐的
+     每秒 REPLCONF ACK 报一次自己的偏移量 断开了每秒重连一次 重连的时候先试增量同步
+   4.replica 默认只读 只有主节点同步过来的命令能写
+   5.REPLICAOF NO ONE 提升成主节点 原来的 replid 留作 replid2 原来挂在同一个主节点下的 replica 换过来还能增量同步
+*/
+
+pub static REPLICATION: Lazy<Replication> = Lazy::new(Replication::new);
+
+const READONLY_ERROR: &str = "READONLY You can't write against a read only replica.";
+
+// 主节点隔这么久给 replica 发一次 PING replica 靠它判断连接是不是还活着
+const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+// 这么久没收到对面的数据就认为连接断了 和 redis 的 repl-timeout 一样
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+// replica 断开之后隔这么久重连
+const REPL_RETRY_DELAY: Duration = Duration::from_secs(1);
+// replica 报偏移量的间隔
+const REPL_ACK_PERIOD: Duration = Duration::from_secs(1);
+// 每次从积压缓冲区里取这么多发出去
+const REPL_CHUNK: usize = 64 * 1024;
+
+const REPLID_LEN: usize = 40;
+
+pub struct Replication {
+    state: Mutex<ReplState>,
+    // 现在是不是 replica 每条命令都要做只读检查 不想每次都拿锁
+    replica: AtomicBool,
+    // 复制流追加了数据就更新 叫醒给 replica 发数据的连接
+    appended: watch::Sender<u64>,
+    // REPLICAOF 一次只能有一个在切换
+    switching: tokio::sync::Mutex<()>,
+    // replica 执行主节点同步过来的命令到追加进积压缓冲区之间拿着 (MULTI 到 EXEC 整段)
+    // 下游 replica 全量同步定时间点的时候要等它 不然偏移量和数据对不上
+    applying: tokio::sync::Mutex<()>,
+}
+
+struct ReplState {
+    replid: String,
+    // 提升成主节点之前跟的那个主节点的 replid 偏移量不超过 second_replid_offset 的时候还认
+    replid2: String,
+    second_replid_offset: i64,
+    backlog: Backlog,
+    // 数据集整个换掉了 (全量同步、提升成主节点、主节点换了 replid) 就加一 下游的 replica 要断开重新同步
+    generation: u64,
+    // 复制流里最后一次 SELECT 的库 变了要先补一条 SELECT
+    stream_db: Option<usize>,
+    last_ping: Option<Instant>,
+    // 自己是 replica 的话 连主节点的后台任务
+    master: Option<MasterLink>,
+    // 挂在自己下面的 replica 按连接编号
+    replicas: BTreeMap<u64, ReplicaInfo>,
+}
+
+struct MasterLink {
+    host: String,
+    port: u16,
+    status: LinkStatus,
+    last_io: Instant,
+    stop: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkStatus {
+    Connecting,
+    // 正在全量同步
+    Sync,
+    Up,
+}
+
+struct ReplicaInfo {
+    ip: String,
+    port: u16,
+    // send_bulk 发快照 / online 正常同步 和 redis 的 INFO 一样
+    state: &'static str,
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+/// 复制积压缓冲区 固定容量 只留复制流最后的这么多字节
+struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    // 复制流一共写了多少字节 也就是 master_repl_offset
+    offset: u64,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Backlog {
+            buf: VecDeque::new(),
+            capacity,
+            offset: 0,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.offset += data.len() as u64;
+        // 一次比整个缓冲区还大 只留最后的部分
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+    }
+
+    // 缓冲区里第一个字节的偏移量 偏移量从 1 开始数 和 redis 一样
+    fn first_byte(&self) -> u64 {
+        self.offset - self.buf.len() as u64 + 1
+    }
+
+    /// pos 是下一个要发的字节 返回 None 说明它已经被挤出去了 (或者还没写到)
+    fn read_from(&self, pos: u64, max: usize) -> Option<Vec<u8>> {
+        if pos < self.first_byte() || pos > self.offset + 1 {
+            return None;
+        }
+        let start = (pos - self.first_byte()) as usize;
+        let end = (start + max).min(self.buf.len());
+        Some(self.buf.range(start..end).copied().collect())
+    }
+
+    fn reset(&mut self, offset: u64) {
+        self.buf.clear();
+        self.offset = offset;
+    }
+}
+
+impl ReplState {
+    /// PSYNC 带的 offset 是 replica 想要的下一个字节 能增量同步就返回从哪里开始发
+    fn continue_from(&self, replid: &str, offset: i64) -> Option<u64> {
+        if offset < 1 {
+            return None;
+        }
+        let same_history = replid == self.replid
+            || (replid == self.replid2 && offset <= self.second_replid_offset);
+        if !same_history {
+            return None;
+        }
+        let pos = offset as u64;
+        (pos >= self.backlog.first_byte() && pos <= self.backlog.offset + 1).then_some(pos)
+    }
+
+    // 换成一段新的历史 原来的 replid 还能认到现在这个位置
+    fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_replid_offset = self.backlog.offset as i64 + 1;
+        self.stream_db = None;
+        self.generation += 1;
+    }
+}
+
+/// 给 replica 的连接从哪里开始同步
+pub struct ReplicaSync {
+    full: bool,
+    replid: String,
+    // 下一个要发的字节
+    pos: u64,
+    generation: u64,
+}
+
+impl Replication {
+    fn new() -> Self {
+        let (appended, _) = watch::channel(0);
+        Replication {
+            state: Mutex::new(ReplState {
+                replid: random_id(),
+                replid2: "0".repeat(REPLID_LEN),
+                second_replid_offset: -1,
+                backlog: Backlog::new(CONFIG.repl_backlog_size as usize),
+                generation: 0,
+                stream_db: None,
+                last_ping: None,
+                master: None,
+                replicas: BTreeMap::new(),
+            }),
+            replica: AtomicBool::new(false),
+            appended,
+            switching: tokio::sync::Mutex::new(()),
+            applying: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::SeqCst)
+    }
+
+    /// 执行前检查 只读的 replica 上只有主节点同步过来的命令能写
+    pub fn check_command(&self, command: &Command) -> Result<(), String> {
+        match command.spec() {
+            Some(spec) if spec.has_flag(CommandFlag::Write) && self.read_only() => {
+                Err(READONLY_ERROR.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 当前连接能不能写 memcached 这种不走命令表的自己判断写命令
+    pub fn read_only(&self) -> bool {
+        self.is_replica()
+            && CONFIG.replica_read_only
+            && !CONN_STATE.try_with(|state| state.master_link).unwrap_or(false)
+    }
+
+    /// AOF 写入任务写一条就调一次 自己是 replica 的时候不转发 (主节点的数据走 feed_stream)
+    pub fn feed(&self, db: usize, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.master.is_some() {
+            return;
+        }
+        if state.stream_db != Some(db) {
+            state.backlog.append(&select_command(db));
+            state.stream_db = Some(db);
+        }
+        state.backlog.append(payload);
+        let offset = state.backlog.offset;
+        drop(state);
+        self.appended.send_replace(offset);
+    }
+
+    /// replica 把主节点发过来的原始数据原样追加 偏移量和主节点保持一致
+    fn feed_stream(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.backlog.append(data);
+        let offset = state.backlog.offset;
+        drop(state);
+        self.appended.send_replace(offset);
+    }
+
+    fn ping_replicas(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.master.is_some() || state.replicas.is_empty() {
+            return;
+        }
+        if state
+            .last_ping
+            .is_some_and(|last| last.elapsed() < REPL_PING_PERIOD)
+        {
+            return;
+        }
+        state.last_ping = Some(Instant::now());
+        let ping = Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"PING"))]).serialize();
+        state.backlog.append(&ping);
+        let offset = state.backlog.offset;
+        drop(state);
+        self.appended.send_replace(offset);
+    }
+
+    /// 收到 PSYNC 决定全量还是增量 并且登记这个 replica
+    pub fn begin_sync(
+        &self,
+        psync: &PsyncCommand,
+        client_id: u64,
+        ip: String,
+        port: u16,
+    ) -> Result<ReplicaSync, String> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .master
+            .as_ref()
+            .is_some_and(|link| link.status != LinkStatus::Up)
+        {
+            return Err("NOMASTERLINK Can't SYNC while not connected with my master".into());
+        }
+        let (full, pos) = match state.continue_from(&psync.replid, psync.offset) {
+            Some(pos) => (false, pos),
+            // 真正的位置等快照定下来之后由 full_sync_point 定
+            None => (true, state.backlog.offset + 1),
+        };
+        state.replicas.insert(
+            client_id,
+            ReplicaInfo {
+                ip,
+                port,
+                state: if full { "send_bulk" } else { "online" },
+                ack_offset: pos - 1,
+                last_ack: Instant::now(),
+            },
+        );
+        Ok(ReplicaSync {
+            full,
+            replid: state.replid.clone(),
+            pos,
+            generation: state.generation,
+        })
+    }
+
+    /// 写入任务在切分点上调用 这时候积压缓冲区里正好是快照之前的写入
+    pub fn sync_point(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        // 新来的 replica 不知道之前选的是哪个库 下一条写入先补 SELECT
+        state.stream_db = None;
+        state.backlog.offset
+    }
+
+    /// 全量同步的快照从 sync_point 这个偏移量接上复制流 中间数据集换掉了就要重新同步
+    fn full_sync_point(&self, client_id: u64, sync: &mut ReplicaSync, offset: u64) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != sync.generation {
+            return Err("数据集换掉了 要重新同步".into());
+        }
+        sync.pos = offset + 1;
+        sync.replid = state.replid.clone();
+        if let Some(replica) = state.replicas.get_mut(&client_id) {
+            replica.ack_offset = offset;
+        }
+        Ok(())
+    }
+
+    fn read_backlog(&self, pos: u64, generation: u64) -> Result<Vec<u8>, String> {
+        let state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return Err("数据集换掉了 要重新同步".into());
+        }
+        state.backlog.read_from(pos, REPL_CHUNK).ok_or_else(|| {
+            "落后太多 要的数据已经被挤出复制积压缓冲区了 (可以调大 repl-backlog-size)".into()
+        })
+    }
+
+    fn replica_acked(&self, client_id: u64, offset: u64) {
+        if let Some(replica) = self.state.lock().unwrap().replicas.get_mut(&client_id) {
+            replica.state = "online";
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    fn remove_replica(&self, client_id: u64) {
+        self.state.lock().unwrap().replicas.remove(&client_id);
+    }
+
+    /// REPLICAOF host port / NO ONE
+    pub async fn replica_of(
+        &self,
+        master: Option<(String, u16)>,
+        db: Db,
+        content: ConnectionContent,
+    ) -> String {
+        let _switching = self.switching.lock().await;
+        let old = {
+            let mut state = self.state.lock().unwrap();
+            let current = state.master.as_ref().map(|link| (link.host.clone(), link.port));
+            if master.is_some() && current == master {
+                return "OK Already connected to specified master".into();
+            }
+            state.master.take()
+        };
+        // 先停掉原来的连接 等它把手上正在执行的命令做完
+        if let Some(mut link) = old {
+            if let Some(stop) = link.stop.take() {
+                let _ = stop.send(());
+            }
+            let _ = link.handle.await;
+            if master.is_none() {
+                let mut state = self.state.lock().unwrap();
+                state.shift_replid(random_id());
+                self.replica.store(false, Ordering::SeqCst);
+                tracing::info!("提升成主节点 新的 replid 是 {}", state.replid);
+            }
+        }
+        if let Some((host, port)) = master {
+            self.start_link(host, port, db, content);
+        }
+        "OK".into()
+    }
+
+    /// 开一个后台任务去连主节点 启动的时候配了 replicaof 也走这里
+    pub fn start_link(&self, host: String, port: u16, db: Db, content: ConnectionContent) {
+        tracing::info!("作为 {}:{} 的 replica 开始同步", host, port);
+        let (stop, stop_rx) = oneshot::channel();
+        // 拿着锁 spawn 任务里更新状态的时候 master 一定已经登记好了
+        let mut state = self.state.lock().unwrap();
+        let handle = tokio::spawn(master_link_task(host.clone(), port, db, content, stop_rx));
+        state.master = Some(MasterLink {
+            host,
+            port,
+            status: LinkStatus::Connecting,
+            last_io: Instant::now(),
+            stop: Some(stop),
+            handle,
+        });
+        self.replica.store(true, Ordering::SeqCst);
+    }
+
+    fn set_link_status(&self, status: LinkStatus) {
+        if let Some(link) = &mut self.state.lock().unwrap().master {
+            link.status = status;
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn touch_link(&self) {
+        if let Some(link) = &mut self.state.lock().unwrap().master {
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().backlog.offset
+    }
+
+    // 重连的时候拿自己的 replid 和偏移量去试增量同步
+    fn psync_position(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.backlog.offset + 1)
+    }
+
+    // 全量同步完 历史从主节点给的偏移量重新开始
+    fn full_synced(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = "0".repeat(REPLID_LEN);
+        state.second_replid_offset = -1;
+        state.backlog.reset(offset);
+        state.stream_db = None;
+        state.generation += 1;
+        drop(state);
+        self.appended.send_replace(offset);
+    }
+
+    // 主节点换了 replid (它被提升过) 跟着换
+    fn continued(&self, replid: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replid) = replid
+            && replid != state.replid
+        {
+            state.shift_replid(replid);
+        }
+    }
+
+    /// INFO replication
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut lines = vec!["# Replication".to_string()];
+        match &state.master {
+            None => lines.push("role:master".into()),
+            Some(link) => lines.extend([
+                "role:slave".to_string(),
+                format!("master_host:{}", link.host),
+                format!("master_port:{}", link.port),
+                format!(
+                    "master_link_status:{}",
+                    if link.status == LinkStatus::Up { "up" } else { "down" }
+                ),
+                format!("master_last_io_seconds_ago:{}", link.last_io.elapsed().as_secs()),
+                format!("master_sync_in_progress:{}", (link.status == LinkStatus::Sync) as u8),
+                format!("slave_repl_offset:{}", state.backlog.offset),
+                format!("slave_read_only:{}", CONFIG.replica_read_only as u8),
+            ]),
+        }
+        lines.push(format!("connected_slaves:{}", state.replicas.len()));
+        for (index, replica) in state.replicas.values().enumerate() {
+            lines.push(format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}",
+                index,
+                replica.ip,
+                replica.port,
+                replica.state,
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            ));
+        }
+        lines.extend([
+            format!("master_replid:{}", state.replid),
+            format!("master_replid2:{}", state.replid2),
+            format!("master_repl_offset:{}", state.backlog.offset),
+            format!("second_repl_offset:{}", state.second_replid_offset),
+            "repl_backlog_active:1".to_string(),
+            format!("repl_backlog_size:{}", state.backlog.capacity),
+            format!("repl_backlog_first_byte_offset:{}", state.backlog.first_byte()),
+            format!("repl_backlog_histlen:{}", state.backlog.buf.len()),
+        ]);
+        lines.iter().map(|line| format!("{}\r\n", line)).collect()
+    }
+}
+
+fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..REPLID_LEN)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// 给 replica 定时发 PING
+pub async fn replication_cron(shutdown: broadcast::Sender<()>) {
+    let mut receiver = shutdown.subscribe();
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => REPLICATION.ping_replicas(),
+            _ = receiver.recv() => break,
+        }
+    }
+}
+
+/*
+   主节点这边 一个连接发了 PSYNC 之后就变成复制流 不再当普通客户端用
+   1.全量同步先回 +FULLRESYNC replid offset 再发 $EOF:<标记> 快照 标记
+     不知道快照多大 所以和 redis 的无盘复制一样用随机标记表示结束
+     发完等 replica 加载好回第一个 ACK 才开始发复制流 不然快照后面紧跟着的数据没法和标记分开
+   2.之后从积压缓冲区里按位置取数据发 对面只会发 REPLCONF ACK 过来
+*/
+pub async fn serve_replica<S: AsyncRead + AsyncWrite + Unpin>(
+    codec: &mut FrameCodec<S>,
+    db: &Db,
+    psync: PsyncCommand,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> std::io::Result<()> {
+    let (client_id, address, port) = CONN_STATE.with(|state| {
+        (
+            state.client_id,
+            state.client_address.clone().unwrap_or_default(),
+            state.replica_port.get().unwrap_or(0),
+        )
+    });
+    let ip = address
+        .rsplit_once(':')
+        .map_or(address.clone(), |(ip, _)| ip.to_string());
+    let sync = match REPLICATION.begin_sync(&psync, client_id, ip, port) {
+        Ok(sync) => sync,
+        Err(msg) => {
+            codec.write_frame(&Frame::Error(msg), 2);
+            return codec.flush().await;
+        }
+    };
+    // 发快照和复制流的时候都可能卡在写上 被 CLIENT KILL 了直接断开
+    let result = unless_killed(stream_to_replica(codec, db, sync, &address, shutdown))
+        .await
+        .unwrap_or(Ok(()));
+    REPLICATION.remove_replica(client_id);
+    tracing::info!("replica {} 断开了", address);
+    result
+}
+
+async fn stream_to_replica<S: AsyncRead + AsyncWrite + Unpin>(
+    codec: &mut FrameCodec<S>,
+    db: &Db,
+    mut sync: ReplicaSync,
+    address: &str,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> std::io::Result<()> {
+    let client_id = CONN_STATE.with(|state| state.client_id);
+    // 先订阅 后面取数据的时候漏掉的追加也会把它叫醒
+    let mut appended = REPLICATION.appended.subscribe();
+    // 偏移量和快照的时间点一起定下来 之后一个分片一个分片地编码发送
+    let point = if sync.full {
+        // 切开之后被 CLIENT KILL 打断的话 写入任务会一直攒着 放到单独的任务里做完
+        let store = db.store.clone();
+        let cut = tokio::spawn(async move {
+            let turn = store.capture_turn().await;
+            // replica 上的复制流是执行完才追加的 不能停在一段的中间
+            let _applying = REPLICATION.applying.lock().await;
+            AOF_REWRITE.split_at(turn.epoch());
+            let point = turn.start();
+            point.settle().await;
+            AOF_REWRITE.sync_point().await.map(|offset| (point, offset))
+        });
+        let (point, offset) = cut
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(std::io::Error::other)?;
+        if let Err(msg) = REPLICATION.full_sync_point(client_id, &mut sync, offset) {
+            tracing::warn!("断开 replica {}: {}", address, msg);
+            return Ok(());
+        }
+        Some(point)
+    } else {
+        None
+    };
+    let mut pos = sync.pos;
+    let mut online = !sync.full;
+    if let Some(point) = point {
+        tracing::info!("replica {} 全量同步 从偏移量 {} 开始", address, pos - 1);
+        let mark = random_id();
+        codec.write_raw(format!("+FULLRESYNC {} {}\r\n$EOF:{}\r\n", sync.replid, pos - 1, mark).as_bytes());
+        codec.flush().await?;
+        let started = Instant::now();
+        let mut writer = BufWriter::new(codec.get_mut());
+        write_snapshot_to(&point, &mut writer).await?;
+        writer.write_all(mark.as_bytes()).await?;
+        writer.flush().await?;
+        tracing::info!("给 replica {} 发完了快照 耗时 {:?}", address, started.elapsed());
+    } else {
+        tracing::info!("replica {} 增量同步 从偏移量 {} 开始", address, pos);
+        codec.write_raw(format!("+CONTINUE {}\r\n", sync.replid).as_bytes());
+    }
+    let mut check = time::interval(Duration::from_secs(1));
+    let mut last_ack = Instant::now();
+    loop {
+        if online {
+            loop {
+                let chunk = match REPLICATION.read_backlog(pos, sync.generation) {
+                    Ok(chunk) => chunk,
+                    Err(msg) => {
+                        tracing::warn!("断开 replica {}: {}", address, msg);
+                        codec.flush().await?;
+                        return Ok(());
+                    }
+                };
+                if chunk.is_empty() {
+                    break;
+                }
+                pos += chunk.len() as u64;
+                codec.write_raw(&chunk);
+                if codec.should_flush() {
+                    codec.flush().await?;
+                }
+            }
+        }
+        codec.flush().await?;
+        tokio::select! {
+            changed = appended.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+            res = codec.read_more() => {
+                if res? == 0 {
+                    return Ok(());
+                }
+                loop {
+                    let frame = match codec.next_request() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::warn!("replica {} 发来的数据不对: {}", address, e);
+                            return Ok(());
+                        }
+                    };
+                    let Ok(Command::Replconf(replconf)) = Command::try_from(frame) else {
+                        continue;
+                    };
+                    for option in replconf.options {
+                        if let ReplconfOption::Ack(offset) = option {
+                            last_ack = Instant::now();
+                            REPLICATION.replica_acked(client_id, offset);
+                            if !online {
+                                tracing::info!("replica {} 加载完快照 开始发复制流", address);
+                                online = true;
+                            }
+                        }
+                    }
+                }
+            }
+            _ = check.tick() => {
+                if last_ack.elapsed() > REPL_TIMEOUT {
+                    tracing::warn!("replica {} 太久没有回 ACK 断开", address);
+                    return Ok(());
+                }
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/*
+   replica 这边连主节点的后台任务
+   1.断开了每秒重连一次 REPLICAOF 换主节点或者 NO ONE 的时候通过 stop 叫它退出
+     只在等数据的时候检查 stop 不会在执行命令的半路停下来
+   2.执行复制流的状态 (选中的库、MULTI 攒了一半的命令) 跨重连保留
+     偏移量已经算上了这些字节 增量同步的时候主节点从后面接着发
+*/
+async fn master_link_task(
+    host: String,
+    port: u16,
+    db: Db,
+    content: ConnectionContent,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut shutdown = content.shutdown_tx.subscribe();
+    let mut applier = StreamApplier::new(format!("{}:{}", host, port));
+    loop {
+        REPLICATION.set_link_status(LinkStatus::Connecting);
+        let mut link = MasterLinkContext {
+            db: &db,
+            content: &content,
+            stop: &mut stop,
+            shutdown: &mut shutdown,
+        };
+        match link.sync_with_master(&host, port, &mut applier).await {
+            Ok(()) => return,
+            Err(e) => tracing::warn!("和主节点 {}:{} 的同步断开了: {}", host, port, e),
+        }
+        tokio::select! {
+            _ = time::sleep(REPL_RETRY_DELAY) => {}
+            _ = &mut stop => return,
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+struct MasterLinkContext<'a> {
+    db: &'a Db,
+    content: &'a ConnectionContent,
+    stop: &'a mut oneshot::Receiver<()>,
+    shutdown: &'a mut broadcast::Receiver<()>,
+}
+
+impl MasterLinkContext<'_> {
+    /// 返回 Ok 表示被叫停了 Err 是连接出了问题 要重连
+    async fn sync_with_master(
+        &mut self,
+        host: &str,
+        port: u16,
+        applier: &mut StreamApplier,
+    ) -> Result<(), String> {
+        let stream = time::timeout(REPL_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| "连接超时".to_string())?
+            .map_err(|e| e.to_string())?;
+        let mut conn = MasterConnection::new(stream);
+        // 要求认证的主节点回 NOAUTH 也说明连接是通的
+        match conn.call(&["PING"]).await? {
+            Frame::Error(msg) if !msg.starts_with("NOAUTH") => return Err(msg),
+            _ => {}
+        }
+        if let Some(password) = &CONFIG.masterauth {
+            let mut argv = vec!["AUTH"];
+            argv.extend(CONFIG.masteruser.as_deref());
+            argv.push(password);
+            if let Frame::Error(msg) = conn.call(&argv).await? {
+                return Err(format!("主节点认证失败: {}", msg));
+            }
+        }
+        // 这两个主节点不认也不影响同步
+        let listening_port = CONFIG.port.to_string();
+        for argv in [
+            vec!["REPLCONF", "listening-port", listening_port.as_str()],
+            vec!["REPLCONF", "capa", "eof", "capa", "psync2"],
+        ] {
+            if let Frame::Error(msg) = conn.call(&argv).await? {
+                tracing::warn!("主节点不认 {}: {}", argv.join(" "), msg);
+            }
+        }
+        let (replid, offset) = REPLICATION.psync_position();
+        let reply = conn.call(&["PSYNC", &replid, &offset.to_string()]).await?;
+        let line = match reply {
+            Frame::Simple(line) => line,
+            Frame::Error(msg) => return Err(format!("主节点拒绝了 PSYNC: {}", msg)),
+            other => return Err(format!("PSYNC 的回复不对: {:?}", other)),
+        };
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("FULLRESYNC") => {
+                let (Some(replid), Some(offset)) = (words.next(), words.next()) else {
+                    return Err(format!("FULLRESYNC 的格式不对: {}", line));
+                };
+                let offset = offset
+                    .parse::<u64>()
+                    .map_err(|_| format!("FULLRESYNC 的格式不对: {}", line))?;
+                REPLICATION.set_link_status(LinkStatus::Sync);
+                tracing::info!("从主节点 {}:{} 全量同步 偏移量 {}", host, port, offset);
+                let payload = conn.read_snapshot().await?;
+                let loaded = load_full_sync(&payload, self.db).await?;
+                REPLICATION.full_synced(replid.to_string(), offset);
+                *applier = StreamApplier::new(applier.address.clone());
+                tracing::info!("全量同步完成 加载了 {} 个 key", loaded);
+            }
+            Some("CONTINUE") => {
+                REPLICATION.continued(words.next().map(str::to_string));
+                tracing::info!("和主节点 {}:{} 增量同步 从偏移量 {} 接着来", host, port, offset);
+            }
+            _ => return Err(format!("PSYNC 的回复不对: {}", line)),
+        }
+        REPLICATION.set_link_status(LinkStatus::Up);
+        // 全量同步的话主节点等这第一个 ACK 才开始发复制流
+        conn.send_ack().await?;
+        self.apply_stream(&mut conn, applier).await
+    }
+
+    async fn apply_stream(
+        &mut self,
+        conn: &mut MasterConnection,
+        applier: &mut StreamApplier,
+    ) -> Result<(), String> {
+        let mut ack = time::interval(REPL_ACK_PERIOD);
+        let mut last_io = Instant::now();
+        // MULTI 还没等到 EXEC 的时候一直拿着 下游全量同步不会停在事务中间
+        let mut applying = None;
+        loop {
+            let mut ack_now = false;
+            while let Some((frame, size)) = parse_frame(&conn.buf).map_err(|e| e.to_string())? {
+                let raw = conn.buf.split_to(size);
+                if applying.is_none() {
+                    applying = Some(REPLICATION.applying.lock().await);
+                }
+                ack_now |= applier.apply(frame, self.db, self.content).await;
+                // 执行完再算进偏移量 下游的 replica 拿到的也是执行过的
+                REPLICATION.feed_stream(&raw);
+                if applier.multi.is_none() {
+                    applying = None;
+                }
+            }
+            if ack_now {
+                conn.send_ack().await?;
+            }
+            tokio::select! {
+                res = conn.stream.read_buf(&mut conn.buf) => {
+                    if res.map_err(|e| e.to_string())? == 0 {
+                        return Err("主节点关闭了连接".into());
+                    }
+                    last_io = Instant::now();
+                    REPLICATION.touch_link();
+                }
+                _ = ack.tick() => {
+                    if last_io.elapsed() > REPL_TIMEOUT {
+                        return Err("主节点太久没有发数据了".into());
+                    }
+                    conn.send_ack().await?;
+                }
+                _ = &mut *self.stop => return Ok(()),
+                _ = self.shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+}
+
+// replica 到主节点的连接 握手阶段一问一答 之后就是一直收复制流
+struct MasterConnection {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl MasterConnection {
+    fn new(stream: TcpStream) -> Self {
+        MasterConnection {
+            stream,
+            buf: BytesMut::with_capacity(16 * 1024),
+        }
+    }
+
+    async fn send(&mut self, argv: &[&str]) -> Result<(), String> {
+        let frame = Frame::Array(
+            argv.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        self.stream
+            .write_all(&frame.serialize())
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn send_ack(&mut self) -> Result<(), String> {
+        let offset = REPLICATION.offset().to_string();
+        self.send(&["REPLCONF", "ACK", &offset]).await
+    }
+
+    async fn call(&mut self, argv: &[&str]) -> Result<Frame, String> {
+        self.send(argv).await?;
+        loop {
+            // redis 的主节点准备快照的时候会发空行保活
+            while self.buf.first() == Some(&b'\n') {
+                self.buf.advance(1);
+            }
+            if let Some((frame, size)) = parse_frame(&self.buf).map_err(|e| e.to_string())? {
+                self.buf.advance(size);
+                return Ok(frame);
+            }
+            self.read_more().await?;
+        }
+    }
+
+    async fn read_more(&mut self) -> Result<(), String> {
+        let n = time::timeout(REPL_TIMEOUT, self.stream.read_buf(&mut self.buf))
+            .await
+            .map_err(|_| "等主节点的回复超时了".to_string())?
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("主节点关闭了连接".into());
+        }
+        REPLICATION.touch_link();
+        Ok(())
+    }
+
+    /// 全量同步的数据 $EOF:<标记> ... <标记> 或者 $<长度> ...
+    async fn read_snapshot(&mut self) -> Result<Bytes, String> {
+        let header = loop {
+            while self.buf.first() == Some(&b'\n') {
+                self.buf.advance(1);
+            }
+            if let Some(end) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                self.buf.advance(end + 2);
+                break line;
+            }
+            self.read_more().await?;
+        };
+        let header = header
+            .strip_prefix('$')
+            .ok_or_else(|| format!("全量同步的数据格式不对: {}", header))?;
+        if let Some(mark) = header.strip_prefix("EOF:") {
+            // 标记后面可能紧跟着复制流 (redis 不等 ACK 就开始发) 所以不能只看结尾
+            // 找到标记之后剩下的留在 buf 里 接着当复制流执行
+            if mark.is_empty() {
+                return Err("全量同步的 EOF 标记是空的".into());
+            }
+            let mut searched = 0;
+            loop {
+                if let Some(payload) = split_eof_payload(&mut self.buf, mark.as_bytes(), &mut searched) {
+                    return Ok(payload);
+                }
+                self.read_more().await?;
+            }
+        }
+        let len = header
+            .parse::<usize>()
+            .map_err(|_| format!("全量同步的数据格式不对: ${}", header))?;
+        while self.buf.len() < len {
+            self.read_more().await?;
+        }
+        Ok(self.buf.split_to(len).freeze())
+    }
+}
+
+/// 在 buf 里找 EOF 标记 找到了就把标记前面的快照切出来 标记也去掉 后面的数据留在 buf 里
+/// searched 记着已经找过的位置 快照很大的时候不用每次都从头找
+fn split_eof_payload(buf: &mut BytesMut, mark: &[u8], searched: &mut usize) -> Option<Bytes> {
+    match buf[*searched..].windows(mark.len()).position(|window| window == mark) {
+        Some(pos) => {
+            let payload = buf.split_to(*searched + pos).freeze();
+            buf.advance(mark.len());
+            Some(payload)
+        }
+        None => {
+            // 标记可能被拆在两次读里 往回留一点再接着找
+            *searched = buf.len().saturating_sub(mark.len() - 1);
+            None
+        }
+    }
+}
+
+/// 全量同步收到的快照 清空所有库再加载 主节点是 redis 的话发过来的是 RDB
+/// 加载完重写一次 AOF 旧数据就不在 AOF 里了 重写完之前崩了的话重启之后反正也要重新全量同步
+async fn load_full_sync(payload: &[u8], db: &Db) -> Result<usize, String> {
+    let entries: Vec<SnapshotEntry> = if payload.starts_with(b"REDIS") {
+        let import = decode_rdb(payload)?;
+        for skipped in &import.skipped {
+            tracing::warn!("全量同步跳过 {}", skipped);
+        }
+        import.entries
+    } else {
+        decode_snapshot(payload)?
+    };
+    if let Some((index, _, _)) = entries
+        .iter()
+        .find(|(index, _, _)| *index >= db.store.store.len())
+    {
+        return Err(format!("快照里的库号 {} 超出范围", index));
+    }
+    LOADING.start(payload.len() as u64);
+    for index in 0..db.store.store.len() {
+        let mut view = db.store.lock_plan(&LockPlan::whole_db(index)).await;
+        for operator in view.operators() {
+            for key in operator.keys() {
+                operator.delete(&key).await;
+            }
+        }
+    }
+    let loaded = restore_entries(db, entries).await;
+    LOADING.progress(payload.len() as u64);
+    LOADING.finish();
+    AOF_REWRITE.schedule();
+    Ok(loaded)
+}
+
+/*
+   replica 执行复制流 和 AOF 重放差不多
+   1.SELECT 切库 PING 只是保活 MULTI/EXEC 之间的命令攒着 EXEC 的时候把锁一次拿齐再执行
+   2.执行的时候带上 master_link 标记 只读检查放行 写 AOF 的时候也不会再转发一遍
+   3.kv 执行不了的命令 (一般是主节点是 redis) 不断开同步 每种命令警告一次
+*/
+struct StreamApplier {
+    address: String,
+    client_id: u64,
+    db_index: usize,
+    multi: Option<Vec<(usize, Command)>>,
+    warned: HashSet<String>,
+}
+
+impl StreamApplier {
+    fn new(address: String) -> Self {
+        StreamApplier {
+            address,
+            client_id: next_client_id(),
+            db_index: 0,
+            multi: None,
+            warned: HashSet::new(),
+        }
+    }
+
+    fn state(&self, db_index: usize) -> ConnectionState {
+        let mut state = ConnectionState::new(db_index, Some(self.address.clone()), self.client_id);
+        state.master_link = true;
+        state
+    }
+
+    /// 返回 true 表示主节点要马上回一次 ACK
+    async fn apply(&mut self, frame: Frame, db: &Db, content: &ConnectionContent) -> bool {
+        let name = frame_command_name(&frame).unwrap_or_default();
+        match name.as_str() {
+            "SELECT" => {
+                match select_index(&frame, db.store.store.len()) {
+                    Some(index) => self.db_index = index,
+                    None => self.warn("select", "库号不对".into()),
+                }
+                return false;
+            }
+            "PING" => return false,
+            "MULTI" => {
+                self.multi = Some(Vec::new());
+                return false;
+            }
+            "EXEC" => {
+                if let Some(queued) = self.multi.take() {
+                    self.execute_multi(queued, db, content).await;
+                }
+                return false;
+            }
+            _ => {}
+        }
+        let command = match Command::from_replay(frame) {
+            Ok(command) => command,
+            Err(e) => {
+                self.warn(&name, e.to_string());
+                return false;
+            }
+        };
+        match &command {
+            Command::Replconf(replconf) => {
+                return replconf
+                    .options
+                    .iter()
+                    .any(|option| matches!(option, ReplconfOption::GetAck));
+            }
+            Command::Unimplement(_) => {
+                self.warn(command.name(), "kv 还不支持这个命令".into());
+                return false;
+            }
+            _ => {}
+        }
+        match &mut self.multi {
+            Some(queued) => queued.push((self.db_index, command.pin_expiration())),
+            None => {
+                let name = command.name().to_string();
+                let result = CONN_STATE
+                    .scope(
+                        self.state(self.db_index),
+                        execute_command_normal(command, db, content.clone()),
+                    )
+                    .await;
+                match result {
+                    Ok(Frame::Error(msg)) => self.warn(&name, msg),
+                    Err(e) => self.warn(&name, e.to_string()),
+                    Ok(_) => {}
+                }
+            }
+        }
+        false
+    }
+
+    // 和 AOF 重放一样按合起来的计划把锁拿齐 AOF 也在锁还拿着的时候写 写进去还是一个整段
+    async fn execute_multi(&mut self, queued: Vec<(usize, Command)>, db: &Db, content: &ConnectionContent) {
+        let plan = LockPlan::merge(
+            queued
+                .iter()
+                .map(|(db_index, command)| LockPlan::new(*db_index, &command.keys())),
+        );
+        let mut view = db.store.lock_plan(&plan).await;
+        // 按库分段 每段一个 MULTI/EXEC
+        let mut effects: Vec<(usize, Vec<Vec<Bytes>>)> = Vec::new();
+        for (db_index, command) in queued {
+            let result = CONN_STATE
+                .scope(
+                    self.state(db_index),
+                    execute_command_hook(&command, Some(db.clone()), None, Some(&mut view)),
+                )
+                .await;
+            match result {
+                Ok(Frame::Error(msg)) => self.warn(command.name(), msg),
+                Err(e) => self.warn(command.name(), e.to_string()),
+                Ok(_) => match effects.last_mut() {
+                    Some((index, argvs)) if *index == db_index => argvs.push(command.argv()),
+                    _ => effects.push((db_index, vec![command.argv()])),
+                },
+            }
+        }
+        for (db_index, argvs) in effects {
+            let ctx = AofContent {
+                aof_tx: &content.aof_tx,
+                shutdown_tx: &content.shutdown_tx,
+                epoch: view.epoch(),
+            };
+            CONN_STATE
+                .scope(self.state(db_index), send_multi_aof(ctx, argvs))
+                .await;
+        }
+        drop(view);
+    }
+
+    fn warn(&mut self, name: &str, reason: String) {
+        if self.warned.insert(name.to_lowercase()) {
+            tracing::warn!("主节点同步过来的 {} 执行不了 ({}) 同样的命令之后不再提示", name, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl_state(capacity: usize) -> ReplState {
+        ReplState {
+            replid: "a".repeat(REPLID_LEN),
+            replid2: "0".repeat(REPLID_LEN),
+            second_replid_offset: -1,
+            backlog: Backlog::new(capacity),
+            generation: 0,
+            stream_db: None,
+            last_ping: None,
+            master: None,
+            replicas: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_split_eof_payload() {
+        let mark = b"0123456789";
+        let mut buf = BytesMut::new();
+        let mut searched = 0;
+        buf.extend_from_slice(b"snapshot-data0123");
+        assert_eq!(split_eof_payload(&mut buf, mark, &mut searched), None);
+        // 标记被拆开了 下次要从它的开头接着找
+        assert_eq!(searched, 8);
+        buf.extend_from_slice(b"456789*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            split_eof_payload(&mut buf, mark, &mut searched),
+            Some(Bytes::from_static(b"snapshot-data"))
+        );
+        // 紧跟在标记后面的复制流还留着
+        assert_eq!(&buf[..], b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8);
+        assert_eq!(backlog.first_byte(), 1);
+        assert_eq!(backlog.read_from(1, 100), Some(Vec::new()));
+        backlog.append(b"hello");
+        assert_eq!(backlog.read_from(1, 100), Some(b"hello".to_vec()));
+        assert_eq!(backlog.read_from(3, 2), Some(b"ll".to_vec()));
+        assert_eq!(backlog.read_from(6, 100), Some(Vec::new()));
+        assert_eq!(backlog.read_from(7, 100), None);
+        // 超出容量 前面的被挤出去
+        backlog.append(b"world");
+        assert_eq!(backlog.offset, 10);
+        assert_eq!(backlog.first_byte(), 3);
+        assert_eq!(backlog.read_from(2, 100), None);
+        assert_eq!(backlog.read_from(3, 100), Some(b"lloworld".to_vec()));
+        // 一次写进来的比容量还大
+        backlog.append(b"0123456789");
+        assert_eq!(backlog.offset, 20);
+        assert_eq!(backlog.read_from(13, 100), Some(b"23456789".to_vec()));
+        backlog.reset(100);
+        assert_eq!(backlog.first_byte(), 101);
+        assert_eq!(backlog.read_from(101, 100), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_continue_from() {
+        let mut state = repl_state(8);
+        let replid = state.replid.clone();
+        // 第一次连上来 偏移量是 -1 一定全量
+        assert_eq!(state.continue_from("?", -1), None);
+        assert_eq!(state.continue_from(&replid, 1), Some(1));
+        state.backlog.append(b"0123456789");
+        assert_eq!(state.continue_from(&replid, 11), Some(11));
+        assert_eq!(state.continue_from(&replid, 3), Some(3));
+        // 已经被挤出去了 或者比主节点还超前
+        assert_eq!(state.continue_from(&replid, 2), None);
+        assert_eq!(state.continue_from(&replid, 12), None);
+        assert_eq!(state.continue_from(&"b".repeat(REPLID_LEN), 11), None);
+
+        // 提升成主节点 老的 replid 只认到提升的那个位置
+        state.shift_replid("c".repeat(REPLID_LEN));
+        assert_eq!(state.generation, 1);
+        assert_eq!(state.second_replid_offset, 11);
+        state.backlog.append(b"xy");
+        assert_eq!(state.continue_from(&replid, 11), Some(11));
+        assert_eq!(state.continue_from(&replid, 12), None);
+        assert_eq!(state.continue_from(&"c".repeat(REPLID_LEN), 13), Some(13));
+    }
+}
@@ -0,0 +1,685 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use crc::{CRC_64_REDIS, Crc};
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast::Sender;
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::core_aof::sync_parent_dir;
+use crate::core_loading::LOADING;
+use crate::core_time::get_cached_time_ms;
+use crate::db::Db;
+use crate::db::capture::PointInTime;
+use crate::db::eviction::NUM_SHARDS;
+use crate::types::{Element, Value, ValueEntry};
+
+/*
+   二进制快照 (SAVE/BGSAVE)
+   文件格式 数字都是小端
+   1.头：KVSNAP + 版本号 u16 + 生成时间 (毫秒) u64
+   2.每个非空的库：0xFE + 库号 u32 后面跟这个库的所有 key
+   3.每个 key：[0xFD + 过期时间点 u64] [0xFC + memcached flags u32] 类型 + key + 值
+     字符串都是 长度 u32 + 内容 有序集合的分数是 f64
+   4.尾：0xFF + 前面所有字节的 CRC64 (和 RDB 用的是同一个)
+   保存的是开始时的时间点 一个分片一个分片地拿读锁编码 不会同时挡住所有分片的写入 见 db::capture
+   先写临时文件 fsync 之后 rename 过去
+*/
+
+const MAGIC: &[u8] = b"KVSNAP";
+const VERSION: u16 = 1;
+
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EXPIRE_MS: u8 = 0xFD;
+const OP_FLAGS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_SIMPLE: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+
+const ELEMENT_STRING: u8 = 0;
+const ELEMENT_INT: u8 = 1;
+
+// 编码的时候攒这么多字节写一次
+const WRITE_CHUNK: usize = 64 * 1024;
+
+// 保存失败之后隔这么久才按规则重试 免得磁盘满了一直刷失败
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub const CHECKSUM: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+pub static SNAPSHOT: Lazy<Snapshot> = Lazy::new(Snapshot::default);
+
+pub struct Snapshot {
+    state: Mutex<SnapshotState>,
+    // 上次成功保存之后的写入次数 save 规则看它
+    dirty: AtomicU64,
+    // 上次成功保存的 unix 时间 (秒) LASTSAVE 返回它 启动的时候算一次
+    lastsave: AtomicU64,
+}
+
+#[derive(Default)]
+struct SnapshotState {
+    started: Option<Instant>,
+    // BGSAVE SCHEDULE 的时候正好有保存在跑 跑完再来一次
+    scheduled: bool,
+    last_try: Option<Instant>,
+    last_failed: bool,
+    last_duration: Option<Duration>,
+    saves: u64,
+}
+
+/// INFO persistence 里的快照状态
+pub struct SnapshotStatus {
+    pub in_progress: bool,
+    pub current_time: Option<Duration>,
+    pub last_time: Option<Duration>,
+    pub last_ok: bool,
+    pub saves: u64,
+    pub changes: u64,
+    pub lastsave: u64,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            state: Mutex::default(),
+            dirty: AtomicU64::new(0),
+            lastsave: AtomicU64::new(unix_time_secs()),
+        }
+    }
+}
+
+impl Snapshot {
+    /// 每条写命令记一次
+    pub fn changed(&self) {
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.lastsave.load(Ordering::SeqCst)
+    }
+
+    /// SAVE 在当前连接里做完再回复 别的连接照常读写
+    pub async fn save(&self, db: &Db) -> Result<(), String> {
+        let dirty_before = self.begin()?;
+        let started = Instant::now();
+        let result = write_snapshot(db, &CONFIG.dbfilename).await;
+        self.finish(started, dirty_before, result)
+    }
+
+    /// BGSAVE schedule 为 true 的时候 正在保存就排到它后面 返回 false
+    pub fn start_background(&'static self, db: Db, schedule: bool) -> Result<bool, String> {
+        let dirty_before = match self.begin() {
+            Ok(dirty_before) => dirty_before,
+            Err(_) if schedule => {
+                self.state.lock().unwrap().scheduled = true;
+                return Ok(false);
+            }
+            Err(msg) => return Err(msg),
+        };
+        tokio::spawn(async move {
+            tracing::info!("后台保存快照开始");
+            let started = Instant::now();
+            let result = write_snapshot(&db, &CONFIG.dbfilename).await;
+            if self.finish(started, dirty_before, result).is_ok() {
+                tracing::info!("后台保存快照完成 耗时 {:?}", started.elapsed());
+            }
+        });
+        Ok(true)
+    }
+
+    // 标记开始 返回开始时的写入次数 保存成功之后减掉这部分 保存期间的写入还算没保存
+    fn begin(&self) -> Result<u64, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.started.is_some() {
+            return Err("ERR Background save already in progress".into());
+        }
+        state.started = Some(Instant::now());
+        state.last_try = state.started;
+        Ok(self.dirty.load(Ordering::SeqCst))
+    }
+
+    fn finish(
+        &self,
+        started: Instant,
+        dirty_before: u64,
+        result: std::io::Result<()>,
+    ) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.started = None;
+        state.last_duration = Some(started.elapsed());
+        state.last_failed = result.is_err();
+        match result {
+            Ok(()) => {
+                state.saves += 1;
+                self.dirty.fetch_sub(dirty_before, Ordering::SeqCst);
+                self.lastsave.store(unix_time_secs(), Ordering::SeqCst);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("保存快照失败: {}", e);
+                Err(format!("ERR {}", e))
+            }
+        }
+    }
+
+    pub fn status(&self) -> SnapshotStatus {
+        let state = self.state.lock().unwrap();
+        SnapshotStatus {
+            in_progress: state.started.is_some(),
+            current_time: state.started.map(|started| started.elapsed()),
+            last_time: state.last_duration,
+            last_ok: !state.last_failed,
+            saves: state.saves,
+            changes: self.dirty.load(Ordering::SeqCst),
+            lastsave: self.lastsave(),
+        }
+    }
+
+    // 排着的 BGSAVE 或者满足了某条 save 规则
+    fn due(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.started.is_some() {
+            return false;
+        }
+        if state.scheduled {
+            state.scheduled = false;
+            return true;
+        }
+        if state.last_failed
+            && state
+                .last_try
+                .is_some_and(|last_try| last_try.elapsed() < SAVE_RETRY_DELAY)
+        {
+            return false;
+        }
+        let changes = self.dirty.load(Ordering::SeqCst);
+        let elapsed = unix_time_secs().saturating_sub(self.lastsave());
+        CONFIG
+            .save
+            .iter()
+            .any(|(seconds, min_changes)| changes >= *min_changes && elapsed >= *seconds)
+    }
+}
+
+/// 每秒检查一次 save 规则
+pub async fn snapshot_cron(db: Db, shutdown: Sender<()>) {
+    let mut receiver = shutdown.subscribe();
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if SNAPSHOT.due() {
+                    let _ = SNAPSHOT.start_background(db.clone(), false);
+                }
+            }
+            _ = receiver.recv() => break,
+        }
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+async fn write_snapshot(db: &Db, path: &str) -> std::io::Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    let point = db.store.point_in_time().await;
+    let result = async {
+        write_snapshot_file(&point, Path::new(&temp_path)).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+        sync_parent_dir(Path::new(path))
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// 写完会 fsync AOF 重写也用它来生成新的 base
+pub async fn write_snapshot_file(point: &PointInTime, temp_path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(tokio::fs::File::create(temp_path).await?);
+    write_snapshot_to(point, &mut file).await?;
+    file.flush().await?;
+    file.get_ref().sync_data().await
+}
+
+/// 把时间点上的数据编码成快照写进 writer 全量同步的时候直接写到 replica 的连接上
+pub async fn write_snapshot_to<W: AsyncWrite + Unpin>(
+    point: &PointInTime,
+    writer: &mut W,
+) -> std::io::Result<()> {
+    let mut digest = CHECKSUM.digest();
+    let mut chunk = Vec::new();
+    encode_header(&mut chunk, get_cached_time_ms());
+    for index in 0..point.databases() {
+        let mut selected = false;
+        for shard_index in 0..NUM_SHARDS {
+            point
+                .visit_shard(index, shard_index, |key, entry| {
+                    if !selected {
+                        chunk.push(OP_SELECT_DB);
+                        chunk.extend_from_slice(&(index as u32).to_le_bytes());
+                        selected = true;
+                    }
+                    encode_entry(&mut chunk, key, entry);
+                })
+                .await;
+            // 一个分片编码完 放开锁之后再写 不用把整个库攒在内存里
+            if chunk.len() >= WRITE_CHUNK {
+                digest.update(&chunk);
+                writer.write_all(&chunk).await?;
+                chunk.clear();
+            }
+        }
+    }
+    chunk.push(OP_EOF);
+    digest.update(&chunk);
+    chunk.extend_from_slice(&digest.finalize().to_le_bytes());
+    writer.write_all(&chunk).await
+}
+
+/// 不经过 Db 直接把一批 key 编码成完整的快照文件 kv-import-rdb 用
+pub fn encode_snapshot(entries: &[SnapshotEntry], created_ms: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_header(&mut buf, created_ms);
+    let mut current = None;
+    for (db_index, key, entry) in entries {
+        if current != Some(*db_index) {
+            buf.push(OP_SELECT_DB);
+            buf.extend_from_slice(&(*db_index as u32).to_le_bytes());
+            current = Some(*db_index);
+        }
+        encode_entry(&mut buf, key, entry);
+    }
+    buf.push(OP_EOF);
+    let checksum = CHECKSUM.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn encode_header(buf: &mut Vec<u8>, created_ms: u64) {
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&created_ms.to_le_bytes());
+}
+
+fn encode_entry(buf: &mut Vec<u8>, key: &str, entry: &ValueEntry) {
+    if let Some(expires_at) = entry.expires_at {
+        buf.push(OP_EXPIRE_MS);
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if entry.flags != 0 {
+        buf.push(OP_FLAGS);
+        buf.extend_from_slice(&entry.flags.to_le_bytes());
+    }
+    let value_type = match &entry.data {
+        Value::Simple(_) => TYPE_SIMPLE,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+    };
+    buf.push(value_type);
+    encode_bytes(buf, key.as_bytes());
+    match &entry.data {
+        Value::Simple(element) => encode_element(buf, element),
+        Value::List(list) => {
+            buf.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            list.iter().for_each(|element| encode_element(buf, element));
+        }
+        Value::Hash(map) => {
+            buf.extend_from_slice(&(map.len() as u32).to_le_bytes());
+            for (field, element) in map {
+                encode_bytes(buf, field.as_bytes());
+                encode_element(buf, element);
+            }
+        }
+        Value::Set(set) => {
+            buf.extend_from_slice(&(set.len() as u32).to_le_bytes());
+            set.iter().for_each(|element| encode_element(buf, element));
+        }
+        Value::ZSet(zset) => {
+            buf.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+            for (member, score) in zset {
+                encode_element(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_element(buf: &mut Vec<u8>, element: &Element) {
+    match element {
+        Element::String(bytes) => {
+            buf.push(ELEMENT_STRING);
+            encode_bytes(buf, bytes);
+        }
+        Element::Int(i) => {
+            buf.push(ELEMENT_INT);
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+    }
+}
+
+/// 读出来的一个 key (库号, key, 值)
+pub type SnapshotEntry = (usize, Arc<String>, ValueEntry);
+
+/// 整个文件校验过之后再解码 校验和不对一个 key 都不加载
+pub fn decode_snapshot(data: &[u8]) -> Result<Vec<SnapshotEntry>, String> {
+    if data.len() < MAGIC.len() + 2 + 8 + 1 + 8 || !data.starts_with(MAGIC) {
+        return Err("不是快照文件".into());
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if CHECKSUM.checksum(body) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("快照文件校验和不对 文件可能损坏了".into());
+    }
+    let mut reader = SnapshotReader { data: body, pos: MAGIC.len() };
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(format!("不支持的快照版本 {}", version));
+    }
+    reader.u64()?;
+    let mut entries = Vec::new();
+    let mut db_index = 0;
+    loop {
+        let mut expires_at = None;
+        let mut flags = 0;
+        let mut op = reader.u8()?;
+        match op {
+            OP_EOF => break,
+            OP_SELECT_DB => {
+                db_index = reader.u32()? as usize;
+                continue;
+            }
+            _ => {}
+        }
+        if op == OP_EXPIRE_MS {
+            expires_at = Some(reader.u64()?);
+            op = reader.u8()?;
+        }
+        if op == OP_FLAGS {
+            flags = reader.u32()?;
+            op = reader.u8()?;
+        }
+        let key = reader.string()?;
+        let data = match op {
+            TYPE_SIMPLE => Value::Simple(reader.element()?),
+            TYPE_LIST => {
+                let len = reader.u32()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(reader.element()?);
+                }
+                Value::List(list)
+            }
+            TYPE_HASH => {
+                let len = reader.u32()?;
+                let mut map = HashMap::new();
+                for _ in 0..len {
+                    let field = reader.string()?;
+                    map.insert(field, reader.element()?);
+                }
+                Value::Hash(map)
+            }
+            TYPE_SET => {
+                let len = reader.u32()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(reader.element()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET => {
+                let len = reader.u32()?;
+                let mut zset = HashMap::new();
+                for _ in 0..len {
+                    let member = reader.element()?;
+                    zset.insert(member, f64::from_le_bytes(reader.array()?));
+                }
+                Value::ZSet(zset)
+            }
+            other => return Err(format!("快照里有不认识的类型 {} (偏移 {})", other, reader.pos - 1)),
+        };
+        entries.push((db_index, Arc::new(key), ValueEntry::new(data, expires_at).with_flags(flags)));
+    }
+    if reader.pos != body.len() {
+        return Err("快照文件结尾后面还有多余的数据".into());
+    }
+    Ok(entries)
+}
+
+struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl SnapshotReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| format!("快照文件在偏移 {} 处被截断", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn bytes(&mut self) -> Result<Bytes, String> {
+        let len = self.u32()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let pos = self.pos;
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| format!("快照文件偏移 {} 处的字符串不是 UTF-8", pos))
+    }
+
+    fn element(&mut self) -> Result<Element, String> {
+        match self.u8()? {
+            ELEMENT_STRING => Ok(Element::String(self.bytes()?)),
+            ELEMENT_INT => Ok(Element::Int(i64::from_le_bytes(self.array()?))),
+            other => Err(format!("快照里有不认识的元素类型 {} (偏移 {})", other, self.pos - 1)),
+        }
+    }
+}
+
+/// 启动的时候加载快照 文件不存在返回 None 已经过期的 key 不加载
+pub async fn load_snapshot(db: &Db, path: &Path) -> Result<Option<usize>, String> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let now = get_cached_time_ms();
+    let mut loaded = 0;
+    for (db_index, key, entry) in decode_snapshot(&data)? {
+        if db_index >= db.store.store.len() {
+            return Err(format!("快照里的库号 {} 超出范围", db_index));
+        }
+        if entry.expires_at.is_some_and(|at| at < now) {
+            continue;
+        }
+        db.store.restore(db_index, key, entry).await;
+        loaded += 1;
+    }
+    LOADING.progress(data.len() as u64);
+    Ok(Some(loaded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EvictionType;
+    use crate::context::{CONN_STATE, ConnectionState};
+    use crate::core_execute::execute_command;
+    use crate::error::{Command, Frame};
+
+    fn snapshot_bytes(entries: &[(u32, &str, ValueEntry)]) -> Vec<u8> {
+        let entries: Vec<SnapshotEntry> = entries
+            .iter()
+            .map(|(db_index, key, entry)| (*db_index as usize, Arc::new(key.to_string()), entry.clone()))
+            .collect();
+        encode_snapshot(&entries, 0)
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let string = ValueEntry::new(
+            Value::Simple(Element::String(Bytes::from_static(b"v"))),
+            Some(1700000000000),
+        )
+        .with_flags(42);
+        let int = ValueEntry::new(Value::Simple(Element::Int(-7)), None);
+        let list = ValueEntry::new(
+            Value::List([Element::Int(1), Element::String(Bytes::from_static(b"x"))].into()),
+            None,
+        );
+        let hash = ValueEntry::new(
+            Value::Hash([("f".to_string(), Element::Int(2))].into_iter().collect()),
+            None,
+        );
+        let set = ValueEntry::new(
+            Value::Set([Element::Int(3), Element::Int(4)].into_iter().collect()),
+            None,
+        );
+        let zset = ValueEntry::new(
+            Value::ZSet([(Element::String(Bytes::from_static(b"m")), 1.5)].into_iter().collect()),
+            None,
+        );
+        let data = snapshot_bytes(&[
+            (0, "s", string),
+            (0, "i", int),
+            (3, "l", list),
+            (15, "h", hash),
+            (15, "set", set),
+            (15, "z", zset),
+        ]);
+
+        let entries = decode_snapshot(&data).unwrap();
+        assert_eq!(entries.len(), 6);
+        let (db_index, key, entry) = &entries[0];
+        assert_eq!((*db_index, key.as_str()), (0, "s"));
+        assert_eq!(entry.expires_at, Some(1700000000000));
+        assert_eq!(entry.flags, 42);
+        assert!(matches!(&entry.data, Value::Simple(Element::String(v)) if v == "v"));
+        assert!(matches!(entries[1].2.data, Value::Simple(Element::Int(-7))));
+        let (db_index, _, entry) = &entries[2];
+        assert_eq!(*db_index, 3);
+        assert!(matches!(&entry.data, Value::List(list) if list.len() == 2 && list[0] == Element::Int(1)));
+        let (db_index, _, entry) = &entries[3];
+        assert_eq!(*db_index, 15);
+        assert!(matches!(&entry.data, Value::Hash(map) if map["f"] == Element::Int(2)));
+        assert!(matches!(&entries[4].2.data, Value::Set(set) if set.contains(&Element::Int(4))));
+        assert!(matches!(&entries[5].2.data, Value::ZSet(zset) if zset[&Element::String(Bytes::from_static(b"m"))] == 1.5));
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let entry = ValueEntry::new(Value::Simple(Element::Int(1)), None);
+        let data = snapshot_bytes(&[(0, "k", entry)]);
+        // 改掉一个字节 校验和对不上
+        let mut corrupted = data.clone();
+        corrupted[MAGIC.len() + 12] ^= 0xFF;
+        assert!(decode_snapshot(&corrupted).unwrap_err().contains("校验和"));
+        // 截断
+        assert!(decode_snapshot(&data[..data.len() - 3]).is_err());
+        assert!(decode_snapshot(b"REDIS0011").is_err());
+    }
+
+    fn command(args: &[&str]) -> Command {
+        let frames = args
+            .iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect();
+        Command::try_from(Frame::Array(frames)).unwrap()
+    }
+
+    // 开始之后的写入不拿所有分片的锁也进不了快照 跨分片的 RENAME 也是
+    #[tokio::test]
+    async fn test_snapshot_point_in_time() {
+        let db = Db::new(&EvictionType::LRU);
+        CONN_STATE
+            .scope(ConnectionState::new(0, None, 1), async {
+                for args in [["SET", "a", "1"], ["SET", "b", "2"], ["SET", "c", "3"]] {
+                    execute_command(&command(&args), &db).await.unwrap();
+                }
+                let point = db.store.point_in_time().await;
+                // 快照还没开始编码 写入照常执行 不会等它
+                for args in [
+                    vec!["SET", "a", "changed"],
+                    vec!["RENAME", "b", "renamed"],
+                    vec!["DEL", "c"],
+                    vec!["SET", "d", "new"],
+                ] {
+                    execute_command(&command(&args), &db).await.unwrap();
+                }
+                let mut data = Vec::new();
+                write_snapshot_to(&point, &mut data).await.unwrap();
+                drop(point);
+                let mut keys: Vec<(String, Value)> = decode_snapshot(&data)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, key, entry)| (key.to_string(), entry.data))
+                    .collect();
+                keys.sort_by(|a, b| a.0.cmp(&b.0));
+                let simple = |value: &Value| match value {
+                    Value::Simple(Element::String(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+                    Value::Simple(Element::Int(i)) => i.to_string(),
+                    _ => panic!("不是字符串"),
+                };
+                let keys: Vec<(&str, String)> = keys.iter().map(|(key, value)| (key.as_str(), simple(value))).collect();
+                assert_eq!(keys, [("a", "1".into()), ("b", "2".into()), ("c", "3".into())]);
+
+                // 快照结束之后的时间点就是现在的数据
+                let mut data = Vec::new();
+                write_snapshot_to(&db.store.point_in_time().await, &mut data).await.unwrap();
+                let mut keys: Vec<String> = decode_snapshot(&data)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, key, _)| key.to_string())
+                    .collect();
+                keys.sort();
+                assert_eq!(keys, ["a", "d", "renamed"]);
+            })
+            .await;
+    }
+}
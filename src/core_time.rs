@@ -14,10 +14,15 @@ fn system_time_ms() -> u64 {
         .as_millis() as u64
 }
 
+// 马上更新一次缓存的时间 启动的时候等不及定时任务第一次跑
+pub fn refresh_cached_time() {
+    CACHED_TIME_MS.store(system_time_ms(), Ordering::Relaxed);
+}
+
 // 在您的服务器启动时，只执行一次
 pub async fn start_time_caching_task(sender: Sender<()>) {
     // 初始化第一次的时间
-    CACHED_TIME_MS.store(system_time_ms(), Ordering::Relaxed);
+    refresh_cached_time();
     // 启动一个独立的后台任务
     tokio::spawn(async move {
         let mut receiver = sender.subscribe();
@@ -31,7 +36,7 @@ pub async fn start_time_caching_task(sender: Sender<()>) {
                 }
             }
             // 每 10 毫秒更新一次全局时间
-            CACHED_TIME_MS.store(system_time_ms(), Ordering::Relaxed);
+            refresh_cached_time();
         }
     });
 }
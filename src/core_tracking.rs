@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::context::CONN_STATE;
+use crate::error::{Command, Frame, TrackingMode, TrackingOptions};
+
+/*
+   服务端辅助的客户端缓存 (CLIENT TRACKING)
+   1.默认模式：记录每个连接读过哪些 key，key 被修改/过期/淘汰时给读过它的连接推送失效消息，
+     推送一次之后这条记录就作废了，客户端需要重新读才会重新被追踪
+   2.BCAST 模式：不记录读，只要被修改的 key 匹配注册的前缀就推送
+   3.OPTIN/OPTOUT：由 CLIENT CACHING yes/no 决定下一条命令读到的 key 要不要追踪
+   推送是走每个连接自己的无界通道，写路径上只是 send 一下，不会被慢客户端拖住
+   RESP3 的连接收到的是 push 类型 RESP2 的连接在发送前转成 pub/sub 的 message
+   4.RESP2 的回复流里不能夹推送 所以 RESP2 要 REDIRECT 到别的连接才收得到
+     而且那个连接要 SUBSCRIBE 了 __redis__:invalidate 才发 没订阅的直接丢掉
+     和 redis 一样 RESP2 不带 REDIRECT 也能开 只是失效消息都丢掉 HELLO 3 之后才开始收
+     没有 PUBLISH 订阅表只是给失效通知用的 也放在这里
+*/
+
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+// 开启追踪的连接数量 为 0 的时候写路径直接跳过 不去碰全局锁
+static TRACKING_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+// 订阅了频道的连接数量 为 0 的时候不用检查订阅状态下的命令限制
+static SUBSCRIBED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub static TRACKING: Lazy<TrackingTable> = Lazy::new(TrackingTable::default);
+
+#[derive(Default)]
+pub struct TrackingTable {
+    inner: Mutex<TrackingInner>,
+}
+
+#[derive(Default)]
+struct TrackingInner {
+    // 所有在线连接的推送通道 REDIRECT 的目标不一定自己开了追踪
+    push_channels: HashMap<u64, PushTarget>,
+    // 开启了追踪的连接
+    clients: HashMap<u64, ClientTracking>,
+    // 默认模式下 key -> 读过它的连接
+    keys: HashMap<String, HashSet<u64>>,
+    // BCAST 模式下 前缀 -> 注册了它的连接 空字符串表示所有 key
+    prefixes: HashMap<String, HashSet<u64>>,
+}
+
+struct PushTarget {
+    push_tx: UnboundedSender<Frame>,
+    // HELLO 切协议的时候同步过来 推送的时候要看目标是不是 RESP3
+    protocol: u8,
+    channels: HashSet<String>,
+}
+
+impl PushTarget {
+    // RESP3 随时能收推送 RESP2 要订阅了失效频道才行
+    fn accepts_invalidation(&self, redirected: bool) -> bool {
+        self.protocol >= 3 || (redirected && self.channels.contains(INVALIDATE_CHANNEL))
+    }
+}
+
+struct ClientTracking {
+    options: TrackingOptions,
+    // CLIENT CACHING yes/no 只对下一条命令生效
+    caching: Option<bool>,
+    // 默认模式下这个连接读过 还没失效的 key 关追踪的时候只清这些 不用扫整个 key 表
+    keys: HashSet<String>,
+}
+
+// CLIENT TRACKINGINFO 需要的快照
+pub struct TrackingInfo {
+    pub options: Option<TrackingOptions>,
+    pub caching: Option<bool>,
+    pub redirect_broken: bool,
+}
+
+impl TrackingTable {
+    pub fn register_client(&self, client_id: u64, push_tx: UnboundedSender<Frame>) {
+        self.inner.lock().unwrap().push_channels.insert(
+            client_id,
+            PushTarget {
+                push_tx,
+                protocol: 2,
+                channels: HashSet::new(),
+            },
+        );
+    }
+
+    pub fn set_protocol(&self, client_id: u64, protocol: u8) {
+        if let Some(target) = self.inner.lock().unwrap().push_channels.get_mut(&client_id) {
+            target.protocol = protocol;
+        }
+    }
+
+    // 连接关闭的时候调用 把这个连接的所有追踪记录都清掉
+    pub fn unregister_client(&self, client_id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(target) = inner.push_channels.remove(&client_id)
+            && !target.channels.is_empty()
+        {
+            SUBSCRIBED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+        }
+        inner.disable(client_id);
+    }
+
+    pub fn enable(&self, client_id: u64, options: TrackingOptions) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(redirect) = options.redirect
+            && !inner.push_channels.contains_key(&redirect)
+        {
+            return Err("The client ID you want redirect to does not exist".into());
+        }
+        // 重新开启等于覆盖旧的配置
+        inner.disable(client_id);
+        if options.mode == TrackingMode::Bcast {
+            if options.prefixes.is_empty() {
+                inner.prefixes.entry(String::new()).or_default().insert(client_id);
+            }
+            for prefix in &options.prefixes {
+                inner.prefixes.entry(prefix.clone()).or_default().insert(client_id);
+            }
+        }
+        inner.clients.insert(
+            client_id,
+            ClientTracking {
+                options,
+                caching: None,
+                keys: HashSet::new(),
+            },
+        );
+        TRACKING_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn disable(&self, client_id: u64) {
+        self.inner.lock().unwrap().disable(client_id);
+    }
+
+    pub fn set_caching(&self, client_id: u64, yes: bool) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.clients.get_mut(&client_id) {
+            Some(client) => match (client.options.mode, yes) {
+                (TrackingMode::OptIn, true) | (TrackingMode::OptOut, false) => {
+                    client.caching = Some(yes);
+                    Ok(())
+                }
+                (TrackingMode::OptIn, false) | (TrackingMode::OptOut, true) => Err(
+                    "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode, NO in OPTOUT mode."
+                        .into(),
+                ),
+                _ => Err(
+                    "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"
+                        .into(),
+                ),
+            },
+            None => Err(
+                "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"
+                    .into(),
+            ),
+        }
+    }
+
+    // 每条命令执行完都要把 CACHING 标记清掉
+    pub fn clear_caching(&self, client_id: u64) {
+        if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(client) = self.inner.lock().unwrap().clients.get_mut(&client_id) {
+            client.caching = None;
+        }
+    }
+
+    pub fn get_redirect(&self, client_id: u64) -> i64 {
+        match self.inner.lock().unwrap().clients.get(&client_id) {
+            Some(client) => client.options.redirect.map(|id| id as i64).unwrap_or(0),
+            None => -1,
+        }
+    }
+
+    pub fn info(&self, client_id: u64) -> TrackingInfo {
+        let inner = self.inner.lock().unwrap();
+        match inner.clients.get(&client_id) {
+            Some(client) => TrackingInfo {
+                options: Some(client.options.clone()),
+                caching: client.caching,
+                redirect_broken: client
+                    .options
+                    .redirect
+                    .is_some_and(|id| !inner.push_channels.contains_key(&id)),
+            },
+            None => TrackingInfo {
+                options: None,
+                caching: None,
+                redirect_broken: false,
+            },
+        }
+    }
+
+    /// 读路径调用：当前连接读到了 key，按照它的追踪模式决定是否记下来
+    pub fn track_read(&self, key: &str) {
+        if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let Ok(client_id) = CONN_STATE.try_with(|state| state.client_id) else {
+            return;
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let Some(client) = inner.clients.get_mut(&client_id) else {
+            return;
+        };
+        let should_track = match client.options.mode {
+            TrackingMode::Default => true,
+            TrackingMode::OptIn => client.caching == Some(true),
+            TrackingMode::OptOut => client.caching != Some(false),
+            TrackingMode::Bcast => false,
+        };
+        if should_track && client.keys.insert(key.to_string()) {
+            inner.keys.entry(key.to_string()).or_default().insert(client_id);
+        }
+    }
+
+    /// 写路径调用：key 被修改、删除、过期或者淘汰了
+    /// 这里可能是在后台淘汰任务里调用的 那时候没有 CONN_STATE，也就没有 NOLOOP 的说法
+    pub fn invalidate_key(&self, key: &str) {
+        if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let writer = CONN_STATE.try_with(|state| state.client_id).ok();
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let mut targets: HashSet<u64> = inner.keys.remove(key).unwrap_or_default();
+        // 推送一次记录就作废了 连接自己那份也要删掉
+        for client_id in &targets {
+            if let Some(client) = inner.clients.get_mut(client_id) {
+                client.keys.remove(key);
+            }
+        }
+        for (prefix, clients) in &inner.prefixes {
+            if key.starts_with(prefix.as_str()) {
+                targets.extend(clients.iter().copied());
+            }
+        }
+        for client_id in targets {
+            let Some(client) = inner.clients.get(&client_id) else {
+                continue;
+            };
+            if client.options.noloop && writer == Some(client_id) {
+                continue;
+            }
+            let target = client.options.redirect.unwrap_or(client_id);
+            if let Some(target) = inner.push_channels.get(&target)
+                && target.accepts_invalidation(client.options.redirect.is_some())
+            {
+                let _ = target.push_tx.send(invalidate_message(key));
+            }
+        }
+    }
+
+    /// 返回每个频道订阅之后这个连接一共订阅了几个
+    pub fn subscribe(&self, client_id: u64, channels: &[String]) -> Vec<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(target) = inner.push_channels.get_mut(&client_id) else {
+            return vec![0; channels.len()];
+        };
+        let was_subscribed = !target.channels.is_empty();
+        let counts = channels
+            .iter()
+            .map(|channel| {
+                target.channels.insert(channel.clone());
+                target.channels.len()
+            })
+            .collect();
+        if !was_subscribed && !target.channels.is_empty() {
+            SUBSCRIBED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// 不带频道就是全部退订 返回退订的频道和退订之后还剩几个
+    pub fn unsubscribe(&self, client_id: u64, channels: &[String]) -> Vec<(Option<String>, usize)> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(target) = inner.push_channels.get_mut(&client_id) else {
+            return vec![(None, 0)];
+        };
+        let was_subscribed = !target.channels.is_empty();
+        let channels: Vec<String> = if channels.is_empty() {
+            let mut all: Vec<String> = target.channels.iter().cloned().collect();
+            all.sort();
+            all
+        } else {
+            channels.to_vec()
+        };
+        let mut result: Vec<(Option<String>, usize)> = channels
+            .into_iter()
+            .map(|channel| {
+                target.channels.remove(&channel);
+                (Some(channel), target.channels.len())
+            })
+            .collect();
+        // 什么都没订阅的时候全部退订也要回一条
+        if result.is_empty() {
+            result.push((None, 0));
+        }
+        if was_subscribed && target.channels.is_empty() {
+            SUBSCRIBED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// RESP2 的连接订阅了频道之后回复流就是 pub/sub 消息了 只能再执行订阅相关的命令
+    pub fn check_command(&self, command: &Command) -> Result<(), String> {
+        if SUBSCRIBED_CLIENTS.load(Ordering::Relaxed) == 0 {
+            return Ok(());
+        }
+        let Ok((client_id, protocol)) = CONN_STATE.try_with(|state| (state.client_id, state.protocol.get()))
+        else {
+            return Ok(());
+        };
+        if protocol >= 3 || matches!(command, Command::Subscribe(_) | Command::Unsubscribe(_) | Command::Ping(_)) {
+            return Ok(());
+        }
+        let inner = self.inner.lock().unwrap();
+        match inner.push_channels.get(&client_id) {
+            Some(target) if !target.channels.is_empty() => Err(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command.name().to_lowercase()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TrackingInner {
+    // 每个连接关闭都会走到这里 只动这个连接自己的记录 不能扫全表
+    fn disable(&mut self, client_id: u64) {
+        let Some(client) = self.clients.remove(&client_id) else {
+            return;
+        };
+        TRACKING_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+        let prefixes = match client.options.mode {
+            TrackingMode::Bcast if client.options.prefixes.is_empty() => vec![String::new()],
+            TrackingMode::Bcast => client.options.prefixes,
+            _ => Vec::new(),
+        };
+        for prefix in prefixes {
+            remove_client(&mut self.prefixes, prefix, client_id);
+        }
+        for key in client.keys {
+            remove_client(&mut self.keys, key, client_id);
+        }
+    }
+}
+
+fn remove_client(table: &mut HashMap<String, HashSet<u64>>, name: String, client_id: u64) {
+    if let Some(clients) = table.get_mut(&name) {
+        clients.remove(&client_id);
+        if clients.is_empty() {
+            table.remove(&name);
+        }
+    }
+}
+
+// RESP3 的失效通知 >2 invalidate [key]
+fn invalidate_message(key: &str) -> Frame {
+    Frame::Push(vec![
+        Frame::Bulk(Bytes::from_static(b"invalidate")),
+        Frame::Array(vec![Frame::Bulk(Bytes::copy_from_slice(key.as_bytes()))]),
+    ])
+}
+
+/// RESP2 没有推送类型 失效消息伪装成 __redis__:invalidate 频道的 pub/sub message
+pub fn push_to_resp2(frame: Frame) -> Frame {
+    match frame {
+        Frame::Push(mut items) if items.first() == Some(&Frame::Bulk(Bytes::from_static(b"invalidate"))) => {
+            let keys = items.pop().unwrap_or(Frame::Null);
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"message")),
+                Frame::Bulk(Bytes::from_static(INVALIDATE_CHANNEL.as_bytes())),
+                keys,
+            ])
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+    use super::*;
+    use crate::config::EvictionType;
+    use crate::context::ConnectionState;
+    use crate::db::Db;
+    use crate::server::tests::TestConnection;
+
+    fn connect(table: &TrackingTable, client_id: u64, protocol: u8) -> UnboundedReceiver<Frame> {
+        let (push_tx, push_rx) = unbounded_channel();
+        table.register_client(client_id, push_tx);
+        table.set_protocol(client_id, protocol);
+        push_rx
+    }
+
+    fn options(mode: TrackingMode, redirect: Option<u64>, prefixes: &[&str], noloop: bool) -> TrackingOptions {
+        TrackingOptions {
+            enable: true,
+            mode,
+            redirect,
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            noloop,
+        }
+    }
+
+    // 在某个连接里执行 读写都要知道是哪个连接干的
+    fn as_client(client_id: u64, f: impl FnOnce()) {
+        CONN_STATE.sync_scope(ConnectionState::new(0, None, client_id), f);
+    }
+
+    fn received(push_rx: &mut UnboundedReceiver<Frame>) -> Vec<String> {
+        let mut keys = Vec::new();
+        while let Ok(Frame::Push(items)) = push_rx.try_recv() {
+            if let Some(Frame::Array(frames)) = items.last() {
+                for frame in frames {
+                    if let Frame::Bulk(key) = frame {
+                        keys.push(String::from_utf8_lossy(key).to_string());
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_default_mode_once() {
+        let table = TrackingTable::default();
+        let mut rx = connect(&table, 1, 3);
+        table.enable(1, options(TrackingMode::Default, None, &[], false)).unwrap();
+        as_client(1, || table.track_read("a"));
+        table.invalidate_key("b");
+        table.invalidate_key("a");
+        // 推送一次之后就不再追踪了
+        table.invalidate_key("a");
+        assert_eq!(received(&mut rx), vec!["a"]);
+    }
+
+    #[test]
+    fn test_disable_cleans_own_records() {
+        let table = TrackingTable::default();
+        let _rx1 = connect(&table, 1, 3);
+        let mut rx2 = connect(&table, 2, 3);
+        let _rx3 = connect(&table, 3, 3);
+        table.enable(1, options(TrackingMode::Default, None, &[], false)).unwrap();
+        table.enable(2, options(TrackingMode::Default, None, &[], false)).unwrap();
+        table.enable(3, options(TrackingMode::Bcast, None, &["user:", "order:"], false)).unwrap();
+        as_client(1, || ["a", "b", "a"].into_iter().for_each(|key| table.track_read(key)));
+        as_client(2, || table.track_read("a"));
+        // 失效过的 key 连接自己那份也没了
+        table.invalidate_key("b");
+        assert_eq!(table.inner.lock().unwrap().clients[&1].keys, HashSet::from(["a".to_string()]));
+
+        table.unregister_client(1);
+        {
+            let inner = table.inner.lock().unwrap();
+            assert_eq!(inner.keys.len(), 1);
+            assert_eq!(inner.keys["a"], HashSet::from([2]));
+        }
+        table.unregister_client(3);
+        assert!(table.inner.lock().unwrap().prefixes.is_empty());
+        // 没开过追踪的连接关掉 什么都不动
+        table.unregister_client(99);
+        table.invalidate_key("a");
+        assert_eq!(received(&mut rx2), vec!["a"]);
+        assert!(table.inner.lock().unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn test_bcast_prefix() {
+        let table = TrackingTable::default();
+        let mut rx = connect(&table, 1, 3);
+        table.enable(1, options(TrackingMode::Bcast, None, &["user:"], false)).unwrap();
+        table.invalidate_key("user:1");
+        table.invalidate_key("order:1");
+        table.invalidate_key("user:1");
+        assert_eq!(received(&mut rx), vec!["user:1", "user:1"]);
+
+        // 不给前缀就是所有 key
+        let mut all = connect(&table, 2, 3);
+        table.enable(2, options(TrackingMode::Bcast, None, &[], false)).unwrap();
+        table.invalidate_key("order:1");
+        assert_eq!(received(&mut all), vec!["order:1"]);
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn test_optin_optout() {
+        let table = TrackingTable::default();
+        let mut optin = connect(&table, 1, 3);
+        let mut optout = connect(&table, 2, 3);
+        table.enable(1, options(TrackingMode::OptIn, None, &[], false)).unwrap();
+        table.enable(2, options(TrackingMode::OptOut, None, &[], false)).unwrap();
+        assert!(table.set_caching(1, false).is_err());
+        assert!(table.set_caching(2, true).is_err());
+
+        // OPTIN 不说 CACHING yes 就不追踪
+        as_client(1, || table.track_read("a"));
+        table.set_caching(1, true).unwrap();
+        as_client(1, || table.track_read("b"));
+        table.clear_caching(1);
+        as_client(1, || table.track_read("c"));
+
+        // OPTOUT 默认追踪 CACHING no 的那一条不追踪
+        table.set_caching(2, false).unwrap();
+        as_client(2, || table.track_read("a"));
+        table.clear_caching(2);
+        as_client(2, || table.track_read("b"));
+
+        for key in ["a", "b", "c"] {
+            table.invalidate_key(key);
+        }
+        assert_eq!(received(&mut optin), vec!["b"]);
+        assert_eq!(received(&mut optout), vec!["b"]);
+    }
+
+    #[test]
+    fn test_noloop() {
+        let table = TrackingTable::default();
+        let mut rx = connect(&table, 1, 3);
+        table.enable(1, options(TrackingMode::Bcast, None, &[], true)).unwrap();
+        // 自己改的不通知 别的连接改的照常通知 后台淘汰没有连接也通知
+        as_client(1, || table.invalidate_key("a"));
+        as_client(2, || table.invalidate_key("b"));
+        table.invalidate_key("c");
+        assert_eq!(received(&mut rx), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_resp2_delivery() {
+        let table = TrackingTable::default();
+        let _rx = connect(&table, 1, 2);
+        let mut target = connect(&table, 2, 2);
+        // RESP2 不 REDIRECT 也能开 和 redis 一样 只是失效消息没地方发 直接丢掉
+        let mut own = connect(&table, 3, 2);
+        table.enable(3, options(TrackingMode::Bcast, None, &[], false)).unwrap();
+        table.invalidate_key("a");
+        assert!(received(&mut own).is_empty());
+        // 切到 RESP3 之后就开始收了
+        table.set_protocol(3, 3);
+        table.invalidate_key("a");
+        assert_eq!(received(&mut own), vec!["a"]);
+        table.disable(3);
+
+        assert!(table.enable(1, options(TrackingMode::Bcast, Some(9), &[], false)).is_err());
+        table.enable(1, options(TrackingMode::Bcast, Some(2), &[], false)).unwrap();
+
+        // 目标没订阅失效频道 不能往它的回复流里塞消息
+        table.invalidate_key("a");
+        assert!(received(&mut target).is_empty());
+
+        assert_eq!(table.subscribe(2, &[INVALIDATE_CHANNEL.to_string()]), vec![1]);
+        table.invalidate_key("b");
+        let frame = target.try_recv().unwrap();
+        assert_eq!(
+            push_to_resp2(frame),
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"message")),
+                Frame::Bulk(Bytes::from_static(INVALIDATE_CHANNEL.as_bytes())),
+                Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"b"))]),
+            ])
+        );
+
+        // 订阅了之后 RESP2 只能执行订阅相关的命令
+        as_client(2, || {
+            let get = Command::try_from(Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"GET")),
+                Frame::Bulk(Bytes::from_static(b"a")),
+            ]))
+            .unwrap();
+            assert!(table.check_command(&get).is_err());
+        });
+
+        assert_eq!(
+            table.unsubscribe(2, &[]),
+            vec![(Some(INVALIDATE_CHANNEL.to_string()), 0)]
+        );
+        assert_eq!(table.unsubscribe(2, &[]), vec![(None, 0)]);
+        table.invalidate_key("c");
+        assert!(received(&mut target).is_empty());
+    }
+
+    #[test]
+    fn test_resp3_delivery() {
+        let table = TrackingTable::default();
+        let mut rx = connect(&table, 1, 3);
+        let mut target = connect(&table, 2, 3);
+        table.enable(1, options(TrackingMode::Bcast, None, &[], false)).unwrap();
+        table.invalidate_key("a");
+        assert_eq!(received(&mut rx), vec!["a"]);
+
+        // RESP3 的目标不用订阅也能收
+        table.enable(1, options(TrackingMode::Bcast, Some(2), &[], false)).unwrap();
+        table.invalidate_key("b");
+        assert_eq!(received(&mut target), vec!["b"]);
+        assert!(received(&mut rx).is_empty());
+
+        // 开着追踪切回 RESP2 就收不到了
+        table.enable(1, options(TrackingMode::Bcast, None, &[], false)).unwrap();
+        table.set_protocol(1, 2);
+        table.invalidate_key("c");
+        assert!(received(&mut rx).is_empty());
+    }
+
+    // RESP2 的连接直接 CLIENT TRACKING ON 回 OK 失效消息不会夹进它的回复里
+    #[tokio::test]
+    async fn test_resp2_tracking_without_redirect() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut tracked = TestConnection::open(&db, 9_300_001);
+        let mut writer = TestConnection::open(&db, 9_300_002);
+        tracked.call(&["CLIENT", "TRACKING", "ON"], "+OK\r\n").await;
+        tracked.call(&["GET", "tracked"], "$-1\r\n").await;
+        writer.call(&["SET", "tracked", "1"], "+OK\r\n").await;
+        tracked.call(&["PING"], "+PONG\r\n").await;
+        assert!(tracked.silent_for(std::time::Duration::from_millis(20)).await);
+        tracked.call(&["CLIENT", "TRACKING", "OFF"], "+OK\r\n").await;
+    }
+}
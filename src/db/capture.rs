@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use tokio::sync::{Notify, OwnedMutexGuard};
+
+use crate::{
+    core_time::get_cached_time_ms,
+    db::{
+        Storage,
+        eviction::{MemoryCache, MemoryCacheNode, NUM_SHARDS},
+    },
+    types::ValueEntry,
+};
+
+/*
+   时间点快照 SAVE/BGSAVE、AOF 重写、全量同步共用 不拿所有分片的锁
+   1.写命令拿齐自己的分片锁之后进入当前的纪元 放锁之前 (AOF 已经发出去了) 才退出
+     开始快照就是纪元加一 之前进入的写入都算在快照里 之后进入的都不算
+     进入纪元的时候已经拿着锁了 同一个分片上新纪元的写入一定排在旧纪元的后面
+   2.快照按顺序一个分片拿一次读锁 编码完就放开再去下一个
+     新纪元的写入要改还没轮到的分片 先把这个分片复制一份留给快照 (写时复制) 轮到它的时候直接用
+     只有快照期间真被写到的分片才会提前复制 编码完就扔
+   3.快照要和 AOF 对齐的话 (重写、全量同步) 等旧纪元的写入都退出 它们的 AOF 就都在通道里了
+     写入任务按消息带的纪元把它们分成前后两段 见 core_aof
+   淘汰删掉的 key 本来就不写 AOF 也不管它是哪个纪元的
+*/
+
+#[derive(Default)]
+pub struct Capture {
+    epoch: AtomicU64,
+    // 正在执行的写入 按纪元的奇偶分开数 快照开始之后只要等上一个纪元的清零
+    writing: [AtomicUsize; 2],
+    left: Notify,
+    // 正在做的快照 进入同一个纪元的写入要帮它写时复制
+    active: RwLock<Option<Arc<Session>>>,
+    // 同一时间只做一个快照 后来的排队
+    turn: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct Session {
+    epoch: u64,
+    // 下标是 db * NUM_SHARDS + shard
+    shards: Vec<Mutex<ShardCopy>>,
+}
+
+enum ShardCopy {
+    Pending,
+    // 写入抢在快照前面复制的
+    Copied(Vec<(Arc<String>, ValueEntry)>),
+    Done,
+}
+
+impl Capture {
+    /// 写命令拿齐锁之后进入
+    pub fn enter(self: &Arc<Self>) -> Writing {
+        // 读到纪元和计数加上之间 纪元可能刚好变了 那就退出来重新进
+        let epoch = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.writing[(epoch % 2) as usize].fetch_add(1, Ordering::SeqCst);
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break epoch;
+            }
+            self.leave(epoch);
+        };
+        let session = self
+            .active
+            .read()
+            .unwrap()
+            .clone()
+            .filter(|session| session.epoch == epoch);
+        Writing {
+            capture: self.clone(),
+            epoch,
+            session,
+        }
+    }
+
+    fn leave(&self, epoch: u64) {
+        if self.writing[(epoch % 2) as usize].fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.left.notify_waiters();
+        }
+    }
+}
+
+/// 写命令拿着锁的这段时间 drop 掉就退出纪元
+pub struct Writing {
+    capture: Arc<Capture>,
+    epoch: u64,
+    session: Option<Arc<Session>>,
+}
+
+impl Writing {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// 拿着写锁 改之前调用 快照还没轮到这个分片就先复制一份给它
+    pub fn copy_before_write(&self, db_index: usize, shard_index: usize, node: &MemoryCacheNode) {
+        let Some(session) = &self.session else {
+            return;
+        };
+        let mut slot = session.shards[db_index * NUM_SHARDS + shard_index].lock().unwrap();
+        if matches!(*slot, ShardCopy::Pending) {
+            *slot = ShardCopy::Copied(
+                node.db_store
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.clone()))
+                    .collect(),
+            );
+        }
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        self.capture.leave(self.epoch);
+    }
+}
+
+/// 轮到自己做快照了 还没开始
+pub struct CaptureTurn {
+    capture: Arc<Capture>,
+    store: Arc<Vec<Arc<MemoryCache>>>,
+    turn: OwnedMutexGuard<()>,
+}
+
+impl CaptureTurn {
+    /// 开始之后写入进入的纪元
+    pub fn epoch(&self) -> u64 {
+        self.capture.epoch.load(Ordering::SeqCst) + 1
+    }
+
+    /// 先登记快照再加纪元 进入新纪元的写入一定看得到它
+    pub fn start(self) -> PointInTime {
+        let session = Arc::new(Session {
+            epoch: self.epoch(),
+            shards: (0..self.store.len() * NUM_SHARDS)
+                .map(|_| Mutex::new(ShardCopy::Pending))
+                .collect(),
+        });
+        *self.capture.active.write().unwrap() = Some(session.clone());
+        self.capture.epoch.store(session.epoch, Ordering::SeqCst);
+        PointInTime {
+            capture: self.capture,
+            store: self.store,
+            session,
+            _turn: self.turn,
+        }
+    }
+}
+
+/// 定下来的时间点 drop 掉快照就结束了
+pub struct PointInTime {
+    capture: Arc<Capture>,
+    store: Arc<Vec<Arc<MemoryCache>>>,
+    session: Arc<Session>,
+    _turn: OwnedMutexGuard<()>,
+}
+
+impl PointInTime {
+    pub fn databases(&self) -> usize {
+        self.store.len()
+    }
+
+    /// 等开始之前进入的写入都退出 它们的 AOF 这时候都已经发出去了
+    pub async fn settle(&self) {
+        let previous = &self.capture.writing[((self.session.epoch - 1) % 2) as usize];
+        loop {
+            let left = self.capture.left.notified();
+            tokio::pin!(left);
+            left.as_mut().enable();
+            if previous.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            left.await;
+        }
+    }
+
+    /// 时间点上这个分片里还没过期的数据 一个个交给 visit 读锁只拿到这个分片看完
+    pub async fn visit_shard(
+        &self,
+        db_index: usize,
+        shard_index: usize,
+        mut visit: impl FnMut(&str, &ValueEntry),
+    ) {
+        let node = self.store[db_index].message[shard_index].read().await;
+        let slot = &self.session.shards[db_index * NUM_SHARDS + shard_index];
+        let copied = std::mem::replace(&mut *slot.lock().unwrap(), ShardCopy::Done);
+        let now = get_cached_time_ms();
+        let live = |entry: &ValueEntry| entry.expires_at.is_none_or(|at| at >= now);
+        match copied {
+            ShardCopy::Copied(entries) => {
+                drop(node);
+                for (key, entry) in entries.iter().filter(|(_, entry)| live(entry)) {
+                    visit(key, entry);
+                }
+            }
+            _ => {
+                for (key, entry) in node.db_store.iter().filter(|(_, entry)| live(entry)) {
+                    visit(key, entry);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PointInTime {
+    fn drop(&mut self) {
+        *self.capture.active.write().unwrap() = None;
+    }
+}
+
+impl Storage {
+    /// 排队等上一个快照做完
+    pub async fn capture_turn(&self) -> CaptureTurn {
+        let turn = self.capture.turn.clone().lock_owned().await;
+        CaptureTurn {
+            capture: self.capture.clone(),
+            store: self.store.clone(),
+            turn,
+        }
+    }
+
+    /// 不用和 AOF 对齐的快照 (SAVE/BGSAVE) 直接开始
+    pub async fn point_in_time(&self) -> PointInTime {
+        self.capture_turn().await.start()
+    }
+}
@@ -8,8 +8,7 @@ use tokio::{sync::Mutex, time::Instant};
 const EVICTION_MAX_NUMBER: usize = 5;
 
 use crate::{
-    core_time::get_cached_time_ms,
-    db::{Storage, eviction::{LockOwner, MemoryCache}},
+    db::{Storage, eviction::LockOwner},
 };
 
 impl Storage {
@@ -57,17 +56,9 @@ impl Storage {
                     continue;
                 }
                 let key = shard.get_eviction_policy().await.unwrap().get_random_sample_key().unwrap();
-                if let Some(value) = shard.select(&key).await {
-                    if let Some(expire_time) = value.expires_at {
-                        if get_cached_time_ms() > expire_time {
-                            //更新分片和整体内存数据
-                            let data_size = value.data_size;
-                            shard.add_memory(data_size);
-                            //调用方法删除
-                            let _ = shard.get_eviction_policy().await.unwrap().on_delete(key);
-                        }
-                    }
-                }
+                // 写锁下的 select 发现过期会直接删掉
+                // 内存账、淘汰策略、客户端缓存的失效通知都在里面一起处理了
+                let _ = shard.select(&key).await;
                 keys_check -= 1;
             }
         }
@@ -118,12 +109,9 @@ impl Storage {
                     //每次循环都需要克隆
                     let shutdown_clone = shutdown_tx.clone();
                     let (_, db_index, shard_index) = item.0;
-                    //先获取锁 然后执行指定的时间段
-                    let mut shard_lock = self.get_lock_read(db_index, shard_index).await;
-                    let store: Arc<Vec<Arc<MemoryCache>>> = self.store.clone();
+                    let storage = self.clone();
                     // 内存超了，开一个任务
                     let task_delete = tokio::spawn(async move {
-                        //let mut shard_lock = shard.await;
                         let mut processed_count = 0;
                         //设置开始时间
                         let start_stopwatch = Instant::now();
@@ -139,15 +127,15 @@ impl Storage {
 
                                 }
                             }
-                            //再次精确判断 锁内部判断就完全没有问题了
-                            if Storage::get_global_memory_not_move(store.clone(),target_memory).await {
+                            // 算全局内存要读所有分片 不能拿着自己分片的写锁去算
+                            // 不然读到自己的分片就死锁了 两个任务之间也会互相等
+                            if storage.get_global_memory_can_move(target_memory).await {
+                                // 要真正删数据 必须拿写锁 每删一个就放掉
+                                let mut shard_lock = storage.get_lock_write(db_index, shard_index).await;
                                 let key = shard_lock.get_eviction_policy().await.unwrap().pop_victim();
                                 if let Some(key) = key {
-                                    let data_size =
-                                        shard_lock.select(&key).await.unwrap().data_size;
-                                    shard_lock.get_eviction_policy().await.unwrap().on_delete(key);
-                                    //现在删除内存更新分片和全局内存数据
-                                    shard_lock.sub_memory(data_size);
+                                    //删除会同时更新分片内存 并通知客户端缓存失效
+                                    shard_lock.delete(&key).await;
                                     processed_count += 1;
                                 } else {
                                     break;
@@ -186,20 +174,81 @@ impl Storage {
         }
         return false;
     }
+}
 
-    //self 一定不能move 这个还是最基本的在循环里
-    pub async fn get_global_memory_not_move(store: Arc<Vec<Arc<MemoryCache>>>,max_size:usize) -> bool {
-        let mut global_memory = 0;
-        //这个是通过计算每个分片获取数据
-        for db_index in 0..16 {
-            for shard_index in 0..32 {
-                let shard_lock = store[db_index].message[shard_index].clone().read_owned().await;
-                global_memory += shard_lock.get_memory_usage();
-                if global_memory > max_size {
-                    return true;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::EvictionType,
+        db::eviction::MemoryCache,
+        types::{Element, Value, ValueEntry},
+    };
+
+    async fn fill(storage: &Storage, expires_at: Option<u64>) {
+        for index in 0..5 {
+            let value = Value::Simple(Element::String(bytes::Bytes::from_static(b"value")));
+            let key = Arc::new(format!("key:{}", index));
+            let shard_index = MemoryCache::get_shard_index(&key);
+            storage
+                .get_lock_write(0, shard_index)
+                .await
+                .insert(key, ValueEntry::new(value, expires_at))
+                .await;
+        }
+    }
+
+    async fn memory(storage: &Storage) -> usize {
+        let mut total = 0;
+        for shard_index in 0..32 {
+            total += storage.get_lock_read(0, shard_index).await.get_memory_usage();
+        }
+        total
+    }
+
+    async fn wait_empty(storage: &Storage) {
+        let started = Instant::now();
+        while memory(storage).await > 0 {
+            assert!(started.elapsed() < Duration::from_secs(10), "后台任务没有把数据清掉");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eviction_ttl_removes_expired() {
+        crate::core_time::refresh_cached_time();
+        let storage = Storage::new(&EvictionType::LRU);
+        fill(&storage, Some(1)).await;
+        assert!(memory(&storage).await > 0);
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        let task = tokio::spawn(storage.clone().eviction_ttl(shutdown_tx.clone()));
+        // 过期清理走写锁下的 select 内存账清干净了 分片才会从活跃列表里出去
+        wait_empty(&storage).await;
+        for shard_index in 0..32 {
+            let shard = storage.get_lock_write(0, shard_index).await;
+            assert!(shard.keys().is_empty());
+        }
+        shutdown_tx.send(()).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_eviction_memory_deletes_victims() {
+        let storage = Storage::new(&EvictionType::LRU);
+        fill(&storage, None).await;
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        let task = tokio::spawn(storage.clone().eviction_memory(0, shutdown_tx.clone()));
+        // 上限是 0 所有 key 都会被淘汰 数据真的删掉了 内存账也跟着减
+        wait_empty(&storage).await;
+        for shard_index in 0..32 {
+            let shard = storage.get_lock_write(0, shard_index).await;
+            assert!(shard.keys().is_empty());
+            assert_eq!(shard.get_eviction_policy().await.unwrap().pop_victim(), None);
+        }
+        shutdown_tx.send(()).unwrap();
+        let deletes = task.await.unwrap();
+        for handle in std::mem::take(&mut *deletes.lock().await) {
+            handle.await.unwrap();
         }
-        return false;
     }
 }
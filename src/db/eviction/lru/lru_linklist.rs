@@ -101,44 +101,12 @@ impl LruList {
     }
 
 
-    //删除头节点 并返回头节点的key
-    pub fn pop_front(&mut self) -> Option<Arc<String>> {
-        // 1. 使用 .take() 来“取出” head，这会自动把 self.head 设为 None
-        //    这比 if let 更安全，因为它处理了所有权
-        let head_ptr = self.head.take()?; // 如果是 None，直接 ? 退出返回 None
-
-        // 走到这里，说明 head_ptr 是 Some(ptr)，并且 self.head 已经是 None 了
-        self.len -= 1;
-
-        // 2. 把裸指针变回 Box，我们现在“拥有”了这个节点
-        //    这是整个函数里最关键的 unsafe 操作
-        let head_box: Box<Node> = unsafe { Box::from_raw(head_ptr.as_ptr()) };
-
-        // 3. 处理“新的头节点” (head_box.next)
-        match head_box.next {
-            Some(mut new_head) => {
-                // 3a. 【边界情况1：链表里还有节点】
-                // 新的头是 new_head
-                // 我们必须把它的 prev 设为 None
-                unsafe {
-                    new_head.as_mut().prev = None;
-                }
-                // 把 self.head 指向这个新头
-                self.head = Some(new_head);
-            }
-            None => {
-                // 3b. 【你刚意识到的那个边界！(head_box.next 是 None)】
-                // 这说明我们刚弹出了“最后一个”节点
-                // self.head 已经是 None (因为第1步的 take())
-                // 我们现在必须手动把 tail 也设为 None！
-                self.tail = None;
-            }
-        }
-
-        // 4. 返回我们“拥有”的那个被弹出的节点
-        Some(head_box.key)
+    //查看头节点的key 不删除
+    pub fn front(&self) -> Option<Arc<String>> {
+        self.head.map(|head| unsafe { (*head.as_ptr()).key.clone() })
     }
 
+
     //删除指定节点
     pub fn pop_node(&mut self, node_ptr: NonNull<Node>) {
         self.len -= 1;
@@ -98,6 +98,43 @@ impl EvictionPolicy for LruNode {
     }
 
     fn pop_victim(&mut self) -> Option<Arc<String>> {
-        self.list.pop_front()
+        // 走 on_delete 把链表、采样数组、索引表一起清掉
+        // 只弹链表的话 map_key 里会留下悬空指针
+        let key = self.list.front()?;
+        self.on_delete(key.clone());
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> Arc<String> {
+        Arc::new(name.to_string())
+    }
+
+    #[test]
+    fn test_pop_victim_cleans_index() {
+        let mut lru = LruNode::new();
+        for name in ["a", "b", "c"] {
+            lru.on_write(key(name));
+        }
+        lru.on_read(&key("a"));
+        // 最久没用的先出来 弹出来的 key 索引表和采样数组里也都没了
+        assert_eq!(lru.pop_victim(), Some(key("b")));
+        assert!(!lru.map_key.contains_key(&key("b")));
+        assert_eq!(lru.sample_keys.len(), 2);
+        for (index, sample) in lru.sample_keys.iter().enumerate() {
+            assert_eq!(lru.map_key[sample].sample_idx, index);
+        }
+        // 弹过的 key 再写一次是新节点 不会碰到已经释放的链表节点
+        lru.on_write(key("b"));
+        lru.on_read(&key("b"));
+        assert_eq!(lru.pop_victim(), Some(key("c")));
+        assert_eq!(lru.pop_victim(), Some(key("a")));
+        assert_eq!(lru.pop_victim(), Some(key("b")));
+        assert_eq!(lru.pop_victim(), None);
+        assert!(lru.map_key.is_empty() && lru.sample_keys.is_empty());
     }
 }
@@ -6,9 +6,12 @@ use std::{
     },
 };
 
+use crate::aof_exchange::entry_effect_argv;
 use crate::core_time::get_cached_time_ms;
+use crate::core_tracking::TRACKING;
 use crate::{config::EvictionType, db::eviction::lru::lru_struct::LruNode, types::ValueEntry};
 use async_trait::async_trait;
+use bytes::Bytes;
 use fxhash::FxHasher;
 use std::hash::{Hash, Hasher};
 use tokio::sync::{Mutex, MutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
@@ -76,6 +79,36 @@ impl KvOperator for LuaCacheNode {
         self.differ_map.insert(key.clone(), ChangeOp::Delete);
         self.local_memory_diff -= size_before as isize;
     }
+
+    // 底下分片的数据提交之前不能动 只能拷一份出来
+    async fn take(&mut self, key: &Arc<String>) -> Option<ValueEntry> {
+        let value = match self.differ_map.remove(key) {
+            Some(ChangeOp::Update(value)) => value,
+            Some(ChangeOp::Delete) => {
+                self.differ_map.insert(key.clone(), ChangeOp::Delete);
+                return None;
+            }
+            None => self.db_store.select(key).await?.clone(),
+        };
+        self.differ_map.insert(key.clone(), ChangeOp::Delete);
+        self.local_memory_diff -= value.data_size as isize;
+        Some(value)
+    }
+
+    // 底下的 key 加上事务里新插入的 去掉事务里删掉的
+    fn keys(&self) -> Vec<Arc<String>> {
+        let mut keys: Vec<Arc<String>> = self
+            .db_store
+            .keys()
+            .into_iter()
+            .filter(|key| !self.differ_map.contains_key(key))
+            .collect();
+        keys.extend(self.differ_map.iter().filter_map(|(key, change)| match change {
+            ChangeOp::Update(_) => Some(key.clone()),
+            ChangeOp::Delete => None,
+        }));
+        keys
+    }
     // 事务缓冲方法
     fn as_transactional(self: Box<Self>) -> Option<Box<dyn Transactional>> {
         Some(self)
@@ -84,22 +117,26 @@ impl KvOperator for LuaCacheNode {
 
 #[async_trait]
 impl Transactional for LuaCacheNode {
-    async fn commit(&mut self) {
+    async fn commit(&mut self) -> Vec<Vec<Bytes>> {
+        let mut effects = Vec::new();
         for (key, change) in self.differ_map.drain() {
             match change {
                 ChangeOp::Update(value_entry) => {
+                    effects.extend(entry_effect_argv(&key, Some(&value_entry)));
                     self.db_store.insert(key, value_entry).await;
                 }
                 ChangeOp::Delete => {
+                    effects.extend(entry_effect_argv(&key, None));
                     self.db_store.delete(&key).await;
                 }
             }
         }
+        effects
     }
 }
 
 impl<'a> LuaCacheNode {
-    fn new(db_store: DirectCacheNode) -> Self {
+    pub fn new(db_store: DirectCacheNode) -> Self {
         LuaCacheNode {
             db_store,
             differ_map: HashMap::new(),
@@ -128,12 +165,6 @@ impl MemoryCacheNode {
             evicition: Mutex::new(policy_instance),
         }
     }
-
-    /* 没办法 这个锁封装的问题 暂时留一个接口 后续补充调整
-     */
-    fn get_memory_usage(&self) -> usize {
-        self.approx_memory.load(Ordering::Relaxed)
-    }
 }
 
 // 场景 A: 普通模式的包装器
@@ -163,6 +194,9 @@ impl KvOperator for DirectCacheNode {
                 //值差异
                 let memory_differ = value.data_size as isize - size_before as isize;
 
+                // 通知追踪了这个 key 的客户端缓存失效
+                TRACKING.invalidate_key(&key);
+
                 //插入数值的时候 消耗掉这个
                 rw_lock_write_guard.db_store.insert(key, value);
 
@@ -197,6 +231,7 @@ impl KvOperator for DirectCacheNode {
                     rw_lock_write_guard
                         .approx_memory
                         .fetch_sub(value.data_size, Ordering::Relaxed);
+                    TRACKING.invalidate_key(key);
                 }
             }
             DirectCacheNode::Readguard(rw_lock_read_guard) => {}
@@ -243,7 +278,13 @@ impl KvOperator for DirectCacheNode {
                 // 5. 【第二查】根据标记行动
                 // 此时 store 是完全自由的
                 if should_remove {
-                    store.remove(key);
+                    // 惰性过期和主动删除一样 要把分片的内存账和淘汰策略一起清掉
+                    if let Some(value) = store.remove(key) {
+                        node.approx_memory
+                            .fetch_sub(value.data_size, Ordering::Relaxed);
+                    }
+                    eviction.lock().await.on_delete(key.clone());
+                    TRACKING.invalidate_key(key);
                     None
                 } else {
                     // 没过期，重新获取并返回
@@ -282,6 +323,27 @@ impl KvOperator for DirectCacheNode {
         }
     }
 
+    // 淘汰策略和失效通知留给之后的 insert 去做
+    async fn take(&mut self, key: &Arc<String>) -> Option<ValueEntry> {
+        // select 顺带把过期的清掉
+        self.select(key).await?;
+        match self {
+            DirectCacheNode::Writeguard(guard) => {
+                let value = guard.db_store.remove(key)?;
+                guard.approx_memory.fetch_sub(value.data_size, Ordering::Relaxed);
+                Some(value)
+            }
+            DirectCacheNode::Readguard(_) => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<Arc<String>> {
+        match self {
+            DirectCacheNode::Writeguard(guard) => guard.db_store.keys().cloned().collect(),
+            DirectCacheNode::Readguard(guard) => guard.db_store.keys().cloned().collect(),
+        }
+    }
+
     fn as_lock_owner(self: Box<Self>) -> Option<Box<dyn LockOwner>> {
         Some(self)
     }
@@ -310,36 +372,6 @@ impl LockOwner for DirectCacheNode {
             DirectCacheNode::Readguard(rw_lock_read_guard) => None,
         }
     }
-
-    fn add_memory(&self, size: usize) {
-        match self {
-            DirectCacheNode::Writeguard(rw_lock_write_guard) => {
-                rw_lock_write_guard
-                    .approx_memory
-                    .fetch_add(size, Ordering::Relaxed);
-            }
-            DirectCacheNode::Readguard(rw_lock_read_guard) => {
-                rw_lock_read_guard
-                    .approx_memory
-                    .fetch_add(size, Ordering::Relaxed);
-            }
-        }
-    }
-
-    fn sub_memory(&self, size: usize) {
-        match self {
-            DirectCacheNode::Writeguard(rw_lock_write_guard) => {
-                rw_lock_write_guard
-                    .approx_memory
-                    .fetch_sub(size, Ordering::Relaxed);
-            }
-            DirectCacheNode::Readguard(rw_lock_read_guard) => {
-                rw_lock_read_guard
-                    .approx_memory
-                    .fetch_sub(size, Ordering::Relaxed);
-            }
-        }
-    }
 }
 
 #[derive(Default, Clone)]
@@ -353,6 +385,11 @@ pub trait KvOperator: Send + Sync {
     async fn insert(&mut self, key: Arc<String>, value: ValueEntry);
     async fn select(&mut self, key: &Arc<String>) -> Option<&ValueEntry>;
     async fn delete(&mut self, key: &Arc<String>);
+    // 把值整个拿出来 原地改完再 insert 回去 省掉一次整体拷贝
+    // 拿出来之后这个 key 就不在了 一定要 insert 回去
+    async fn take(&mut self, key: &Arc<String>) -> Option<ValueEntry>;
+    // 分片里所有的 key 整库清空这种操作用
+    fn keys(&self) -> Vec<Arc<String>>;
 
     // 【核心修改】
     // 不要用 into_inner(self)，要用引用！
@@ -369,7 +406,8 @@ pub trait KvOperator: Send + Sync {
 }
 #[async_trait]
 pub trait Transactional: KvOperator {
-    async fn commit(&mut self);
+    // 提交之后返回改动对应的命令 用来写 AOF
+    async fn commit(&mut self) -> Vec<Vec<Bytes>>;
 }
 
 //数据库最基本的三个操作
@@ -381,9 +419,6 @@ pub trait LockOwner: KvOperator {
     // 2. 暴露驱逐策略 (返回引用 &dyn，而不是 Box)
     async fn get_eviction_policy(&self) -> Option<MutexGuard<'_, Box<dyn EvictionPolicy>>>;
 
-    // 修改内存记账 (封装成行为更好，不要直接暴露 Atomic)
-    fn add_memory(&self, size: usize);
-    fn sub_memory(&self, size: usize);
 }
 
 impl MemoryCache {
@@ -414,55 +449,15 @@ impl MemoryCache {
         (hash_value as usize) % NUM_SHARDS
     }
 
-    pub async fn get_lock_write(&self, key: &Arc<String>) -> Box<dyn KvOperator> {
-        let shard_index = MemoryCache::get_shard_index(&key);
-        let shard = self.message[shard_index].clone().write_owned().await;
-        Box::new(DirectCacheNode::Writeguard(shard))
-    }
-
-    // Lua 调度层调用这个
-    // 注意：这里传入了 differ_map
-    pub async fn lock_write_lua(&self, key: &Arc<String>) -> (Box<dyn KvOperator>, usize) {
-        let shard_index = MemoryCache::get_shard_index(&key);
-        let shard = self.message[shard_index].clone().write_owned().await;
-        (
-            Box::new(LuaCacheNode::new(DirectCacheNode::Writeguard(shard))),
-            shard_index,
-        )
-    }
-
-    //这个是lua 真正用的
-    pub async fn get_lua_lock_write_shard_index(&self, shard_index: usize) -> Box<dyn KvOperator> {
-        let shard = self.message[shard_index].clone().write_owned().await;
-        Box::new(LuaCacheNode::new(DirectCacheNode::Writeguard(shard)))
-    }
-
     pub async fn get_lock_write_shard_index(&self, shard_index: usize) -> Box<dyn KvOperator> {
         let shard = self.message[shard_index].clone().write_owned().await;
         Box::new(DirectCacheNode::Writeguard(shard))
     }
 
-    pub async fn get_lock_read(&self, key: &Arc<String>) -> Box<dyn KvOperator> {
-        let shard_index = MemoryCache::get_shard_index(&key);
-        let shard = self.message[shard_index].clone().read_owned().await;
-        Box::new(DirectCacheNode::Readguard(shard))
-    }
-
     pub async fn get_lock_read_shard_index(&self, shard_index: usize) -> Box<dyn KvOperator> {
         let shard = self.message[shard_index].clone().read_owned().await;
         Box::new(DirectCacheNode::Readguard(shard))
     }
-
-    // Lua 调度层调用这个
-    // 注意：这里传入了 differ_map
-    pub async fn lock_read_lua(&self, key: &Arc<String>) -> (Box<dyn KvOperator>, usize) {
-        let shard_index = MemoryCache::get_shard_index(&key);
-        let shard = self.message[shard_index].clone().read_owned().await;
-        (
-            Box::new(LuaCacheNode::new(DirectCacheNode::Readguard(shard))),
-            shard_index,
-        )
-    }
 }
 
 pub trait EvictionPolicy: Send + Sync {
@@ -477,3 +472,38 @@ pub trait EvictionPolicy: Send + Sync {
     // 挑选一个删除者
     fn pop_victim(&mut self) -> Option<Arc<String>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Element, Value};
+
+    fn entry(value: &'static str, expires_at: Option<u64>) -> ValueEntry {
+        ValueEntry::new(Value::Simple(Element::String(Bytes::from_static(value.as_bytes()))), expires_at)
+    }
+
+    #[tokio::test]
+    async fn test_lazy_expire_accounting() {
+        crate::core_time::refresh_cached_time();
+        let cache = MemoryCache::new(&EvictionType::LRU);
+        let (expired, live) = (Arc::new("expired".to_string()), Arc::new("live".to_string()));
+        let live_size = entry("v", None).data_size;
+        let mut shard = cache.get_lock_write_shard_index(0).await;
+        shard.insert(expired.clone(), entry("old", Some(1))).await;
+        shard.insert(live.clone(), entry("v", None)).await;
+        drop(shard);
+        // 读锁下过期的只是看不到 删不掉
+        let mut reader = cache.get_lock_read_shard_index(0).await;
+        assert!(reader.select(&expired).await.is_none());
+        assert!(reader.as_lock_owner().unwrap().get_memory_usage() > live_size);
+        // 写锁下的惰性过期 内存账和淘汰策略要跟着一起清
+        let mut shard = cache.get_lock_write_shard_index(0).await;
+        assert!(shard.select(&expired).await.is_none());
+        assert!(shard.select(&live).await.is_some());
+        let shard = shard.as_lock_owner().unwrap();
+        assert_eq!(shard.get_memory_usage(), live_size);
+        let mut policy = shard.get_eviction_policy().await.unwrap();
+        assert_eq!(policy.pop_victim(), Some(live));
+        assert_eq!(policy.pop_victim(), None);
+    }
+}
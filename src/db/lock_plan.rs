@@ -0,0 +1,298 @@
+use crate::{
+    context::CONN_STATE,
+    core_keyspec::{KeyAccess, KeyRef},
+    db::{
+        LockedDb, Storage,
+        capture::Writing,
+        eviction::{DirectCacheNode, KvOperator, LuaCacheNode, MemoryCache, NUM_SHARDS},
+    },
+    error::Command,
+};
+
+/*
+   多分片锁规划
+   1.先根据命令的 key_specs 算出要锁哪些 (db, shard) 以及读写
+   2.按 (db, shard) 排序去重 同一个分片既读又写就只拿写锁
+   3.所有人都按同一个顺序加锁 多 key 命令之间、命令和 lua 脚本之间就不会死锁
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardLock {
+    pub db_index: usize,
+    pub shard_index: usize,
+    pub access: KeyAccess,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LockPlan {
+    pub locks: Vec<ShardLock>,
+}
+
+impl LockPlan {
+    pub fn new(db_index: usize, keys: &[KeyRef]) -> Self {
+        let locks = keys
+            .iter()
+            .map(|key| ShardLock {
+                db_index,
+                shard_index: MemoryCache::get_shard_index(&key.key.as_str()),
+                access: key.access,
+            })
+            .collect();
+        Self::from_locks(locks)
+    }
+
+    /// 几个计划合成一个 可以跨 db AOF 里的 MULTI/EXEC 整段一次加锁用
+    pub fn merge(plans: impl IntoIterator<Item = LockPlan>) -> Self {
+        Self::from_locks(plans.into_iter().flat_map(|plan| plan.locks).collect())
+    }
+
+    fn from_locks(mut locks: Vec<ShardLock>) -> Self {
+        locks.sort_by_key(|lock| (lock.db_index, lock.shard_index));
+        // dedup_by 的第一个参数是后面的元素 第二个是留下来的元素
+        locks.dedup_by(|next, kept| {
+            if next.db_index == kept.db_index && next.shard_index == kept.shard_index {
+                if next.access == KeyAccess::Write {
+                    kept.access = KeyAccess::Write;
+                }
+                true
+            } else {
+                false
+            }
+        });
+        LockPlan { locks }
+    }
+
+    /// 整个 db 所有分片的写锁 清库这种操作用
+    pub fn whole_db(db_index: usize) -> Self {
+        let locks = (0..NUM_SHARDS)
+            .map(|shard_index| ShardLock {
+                db_index,
+                shard_index,
+                access: KeyAccess::Write,
+            })
+            .collect();
+        LockPlan { locks }
+    }
+
+    /// 当前连接选中的 db 上 这条命令需要的锁
+    pub fn for_command(command: &Command) -> Self {
+        let db_index = CONN_STATE.with(|state| state.selected_db.get());
+        Self::new(db_index, &command.keys())
+    }
+}
+
+/// 执行器拿到的多分片视图 按 key 找到它所在分片的锁
+#[derive(Default)]
+pub struct LockedShards {
+    shards: Vec<(usize, usize, LockedDb)>,
+    // 有写锁才有 和锁一起放开
+    writing: Option<Writing>,
+}
+
+impl LockedShards {
+    fn position(&self, db_index: usize, key: &str) -> Option<usize> {
+        let shard_index = MemoryCache::get_shard_index(&key);
+        self.shards
+            .binary_search_by_key(&(db_index, shard_index), |(db, shard, _)| (*db, *shard))
+            .ok()
+    }
+
+    /// key 所在分片的锁 读锁写锁都可以拿来读
+    pub fn read(&mut self, key: &str) -> Option<&mut Box<dyn KvOperator>> {
+        let db_index = CONN_STATE.with(|state| state.selected_db.get());
+        let index = self.position(db_index, key)?;
+        Some(self.shards[index].2.operator())
+    }
+
+    /// key 所在分片的写锁 只有计划里声明了写才拿得到
+    pub fn write(&mut self, key: &str) -> Option<&mut Box<dyn KvOperator>> {
+        let db_index = CONN_STATE.with(|state| state.selected_db.get());
+        let index = self.position(db_index, key)?;
+        match &mut self.shards[index].2 {
+            LockedDb::Write(operator) => Some(operator),
+            LockedDb::Read(_) => None,
+        }
+    }
+
+    /// 检查 key 是否已经在视图里 并且读写权限足够
+    pub fn covers(&self, key: &KeyRef) -> bool {
+        let db_index = CONN_STATE.with(|state| state.selected_db.get());
+        match self.position(db_index, &key.key) {
+            Some(index) => !matches!(
+                (&self.shards[index].2, key.access),
+                (LockedDb::Read(_), KeyAccess::Write)
+            ),
+            None => false,
+        }
+    }
+
+    /// 视图里所有分片的操作接口 按加锁的顺序
+    pub fn operators(&mut self) -> impl Iterator<Item = &mut Box<dyn KvOperator>> {
+        self.shards.iter_mut().map(|(_, _, lock)| lock.operator())
+    }
+
+    /// 有写锁的话进入的快照纪元 发 AOF 的时候带上
+    pub fn epoch(&self) -> u64 {
+        self.writing.as_ref().map_or(0, Writing::epoch)
+    }
+
+    /// 拿走纪元 交出锁之后 AOF 还没发 要留到发完 (lua 提交事务用)
+    pub fn take_writing(&mut self) -> Option<Writing> {
+        self.writing.take()
+    }
+
+    /// 释放视图 把里面的锁一个个交出来 (lua 提交事务用)
+    pub fn into_locks(self) -> impl Iterator<Item = LockedDb> {
+        self.shards.into_iter().map(|(_, _, lock)| lock)
+    }
+}
+
+impl Storage {
+    /// 按计划的顺序一个个加锁
+    pub async fn lock_plan(&self, plan: &LockPlan) -> LockedShards {
+        self.lock_shards(plan, false).await
+    }
+
+    /// lua 用的版本：全部拿写锁 并且外面包一层事务节点 脚本结束才统一提交
+    pub async fn lock_plan_lua(&self, plan: &LockPlan) -> LockedShards {
+        self.lock_shards(plan, true).await
+    }
+
+    // 有写锁的话 拿齐之后进入快照的纪元 改之前先给快照留一份 见 capture
+    async fn lock_shards(&self, plan: &LockPlan, lua: bool) -> LockedShards {
+        let mut guards = Vec::with_capacity(plan.locks.len());
+        for lock in &plan.locks {
+            let shard = self.store[lock.db_index].message[lock.shard_index].clone();
+            let guard = match lock.access {
+                KeyAccess::Read if !lua => DirectCacheNode::Readguard(shard.read_owned().await),
+                _ => DirectCacheNode::Writeguard(shard.write_owned().await),
+            };
+            guards.push((lock.db_index, lock.shard_index, guard));
+        }
+        let writing = guards
+            .iter()
+            .any(|(_, _, guard)| matches!(guard, DirectCacheNode::Writeguard(_)))
+            .then(|| self.capture.enter());
+        let shards = guards
+            .into_iter()
+            .map(|(db_index, shard_index, guard)| {
+                let locked = match guard {
+                    DirectCacheNode::Writeguard(node) => {
+                        if let Some(writing) = &writing {
+                            writing.copy_before_write(db_index, shard_index, &node);
+                        }
+                        let node = DirectCacheNode::Writeguard(node);
+                        LockedDb::Write(if lua {
+                            Box::new(LuaCacheNode::new(node))
+                        } else {
+                            Box::new(node)
+                        })
+                    }
+                    read => LockedDb::Read(Box::new(read)),
+                };
+                (db_index, shard_index, locked)
+            })
+            .collect();
+        LockedShards { shards, writing }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::EvictionType,
+        context::ConnectionState,
+        db::Db,
+        error::MSetCommand,
+    };
+
+    fn key(key: &str, access: KeyAccess) -> KeyRef {
+        KeyRef {
+            key: key.to_string(),
+            access,
+        }
+    }
+
+    fn shard(key: &str) -> usize {
+        MemoryCache::get_shard_index(&key)
+    }
+
+    // 找两个在同一个分片的 key 和一个在别的分片的 key
+    fn keys_by_shard() -> (String, String, String) {
+        let mut keys = (0..).map(|i| format!("k{}", i));
+        let first = keys.next().unwrap();
+        let same = keys.clone().find(|k| shard(k) == shard(&first)).unwrap();
+        let other = keys.find(|k| shard(k) != shard(&first)).unwrap();
+        (first, same, other)
+    }
+
+    #[test]
+    fn test_plan_order_and_dedup() {
+        let (first, same, other) = keys_by_shard();
+        let plan = LockPlan::new(
+            2,
+            &[key(&other, KeyAccess::Read), key(&first, KeyAccess::Read), key(&same, KeyAccess::Write)],
+        );
+        // 同一个分片只锁一次 既读又写就拿写锁 按分片号排好
+        let mut expected = vec![
+            ShardLock {
+                db_index: 2,
+                shard_index: shard(&first),
+                access: KeyAccess::Write,
+            },
+            ShardLock {
+                db_index: 2,
+                shard_index: shard(&other),
+                access: KeyAccess::Read,
+            },
+        ];
+        expected.sort_by_key(|lock| lock.shard_index);
+        assert_eq!(plan.locks, expected);
+
+        // 跨库合并 先按库排
+        let merged = LockPlan::merge([LockPlan::new(3, &[key(&first, KeyAccess::Read)]), plan]);
+        let order: Vec<_> = merged.locks.iter().map(|lock| (lock.db_index, lock.shard_index)).collect();
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(order, sorted);
+        assert_eq!(merged.locks.last().unwrap().db_index, 3);
+
+        let whole = LockPlan::whole_db(1);
+        assert_eq!(whole.locks.len(), NUM_SHARDS);
+        assert!(whole.locks.iter().all(|lock| lock.access == KeyAccess::Write));
+    }
+
+    #[tokio::test]
+    async fn test_locked_shards() {
+        let (first, _, other) = keys_by_shard();
+        let db = Db::new(&EvictionType::LRU);
+        let mset = Command::MSet(MSetCommand {
+            pairs: vec![(std::sync::Arc::new(first.clone()), bytes::Bytes::from_static(b"1"))],
+        });
+        CONN_STATE
+            .scope(ConnectionState::new(4, None, 1), async {
+                // 计划用的是连接当前选中的库
+                let plan = LockPlan::for_command(&mset);
+                assert_eq!(plan.locks.len(), 1);
+                assert_eq!(plan.locks[0].db_index, 4);
+
+                let read = LockPlan::new(4, &[key(&other, KeyAccess::Read)]);
+                let mut view = db.store.lock_plan(&LockPlan::merge([plan, read])).await;
+                assert!(view.covers(&key(&first, KeyAccess::Write)));
+                assert!(view.covers(&key(&other, KeyAccess::Read)));
+                // 读锁不能拿来写 没锁的分片什么都拿不到
+                assert!(!view.covers(&key(&other, KeyAccess::Write)));
+                assert!(view.write(&other).is_none());
+                assert!(view.read(&other).is_some());
+                assert!(view.write(&first).is_some());
+                let missing = (0..)
+                    .map(|i| format!("m{}", i))
+                    .find(|k| shard(k) != shard(&first) && shard(k) != shard(&other))
+                    .unwrap();
+                assert!(view.read(&missing).is_none());
+                assert!(!view.covers(&key(&missing, KeyAccess::Read)));
+            })
+            .await;
+    }
+}
@@ -1,7 +1,9 @@
 use bytes::Bytes;
 use itoa::Buffer;
 use std::sync::Arc;
+pub mod capture;
 pub mod eviction;
+pub mod lock_plan;
 mod generic;
 mod hash;
 mod list;
@@ -11,10 +13,12 @@ mod string;
 
 use crate::{
     config::EvictionType,
-    context::CONN_STATE,
+    core_time::get_cached_time_ms,
+    db::capture::Capture,
     db::eviction::{
         KvOperator, LockOwner, MemoryCache,
     },
+    types::ValueEntry,
 };
 
 // 3. 定义并公开那个唯一的、组合好的顶层结构
@@ -36,10 +40,21 @@ pub enum LockedDb {
     Read(Box<dyn KvOperator>),
 }
 
+impl LockedDb {
+    // 不关心读写 只要底下的操作接口
+    pub fn operator(&mut self) -> &mut Box<dyn KvOperator> {
+        match self {
+            LockedDb::Write(operator) | LockedDb::Read(operator) => operator,
+        }
+    }
+}
+
 // 这个数组 最外层的arc 是为了共享
 #[derive(Clone, Default)]
 pub struct Storage {
     pub(crate) store: Arc<Vec<Arc<MemoryCache>>>,
+    // 时间点快照和写入之间的协调 见 capture
+    capture: Arc<Capture>,
 }
 
 //内核通用接口
@@ -62,34 +77,10 @@ impl Storage {
         // 4. 返回“初始化好”的 self
         Storage {
             store: Arc::new(local_vec),
+            capture: Arc::default(),
         }
     }
 
-    // lock() 方法现在返回这个新的 LockedDb 守卫，而不是原始的 MutexGuard
-    pub async fn lock_write(&self, key: &Arc<String>) -> LockedDb {
-        let select_db = CONN_STATE.with(|state| state.selected_db);
-        LockedDb::Write(self.store.get(select_db).unwrap().get_lock_write(key).await)
-    }
-
-    // lock() 方法现在返回这个新的 LockedDb 守卫，而不是原始的 MutexGuard
-    pub async fn lock_read(&self, key: &Arc<String>) -> LockedDb {
-        let select_db = CONN_STATE.with(|state| state.selected_db);
-        LockedDb::Read(self.store.get(select_db).unwrap().get_lock_read(key).await)
-    }
-
-    /*
-    下面俩方法是lua 的方法
-     */
-    pub async fn lock_write_lua<'a>(&'a self, shard_index: usize) -> LockedDb {
-        let select_db = CONN_STATE.with(|state| state.selected_db);
-        LockedDb::Write(self.store.get(select_db).unwrap().get_lua_lock_write_shard_index(shard_index).await)
-    }
-
-    pub async fn lock_read_lua<'a>(&'a self, shard_index: usize) -> LockedDb  {
-        let select_db = CONN_STATE.with(|state| state.selected_db);
-        LockedDb::Read(self.store.get(select_db).unwrap().get_lock_read_shard_index(shard_index).await)
-    }
-
     // 修改后（正确）：
     pub async fn get_lock_write(
         &self,
@@ -117,6 +108,30 @@ impl Storage {
             .await
             .as_lock_owner().unwrap()
     }
+
+    /// 复制一个分片里还没过期的数据 读锁只拿到复制完为止
+    pub async fn shard_entries(
+        &self,
+        db_index: usize,
+        shard_index: usize,
+    ) -> Vec<(Arc<String>, ValueEntry)> {
+        let node = self.store[db_index].message[shard_index].read().await;
+        let now = get_cached_time_ms();
+        node.db_store
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| at >= now))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// 启动加载的时候直接放进对应的分片
+    pub async fn restore(&self, db_index: usize, key: Arc<String>, entry: ValueEntry) {
+        let shard_index = MemoryCache::get_shard_index(&key);
+        self.get_lock_write(db_index, shard_index)
+            .await
+            .insert(key, entry)
+            .await;
+    }
 }
 
 // 一个直接从 Bytes 高效解析 i64 的函数
@@ -11,6 +11,10 @@ pub enum KvError {
     #[error("协议解析错误: {0}")]
     ProtocolError(String),
 
+    // 要和 redis 的报错一字不差的 原样回给客户端
+    #[error("{0}")]
+    Reply(String),
+
     #[error("意外的连接关闭")]
     UnexpectedEof,
 
@@ -26,9 +30,38 @@ pub enum KvError {
 pub enum Command {
     Set(SetCommand), // 不再有 { ... }，而是直接包裹 Set 结构体
     Get(GetCommand),
+    MGet(MGetCommand),
+    MSet(MSetCommand),
+    Del(DelCommand),
+    Rename(RenameCommand),
+    PExpireAt(PExpireAtCommand),
     Ping(PingCommand),
     Unimplement(UnimplementCommand),
-    EvalCommand(EvalCommand)
+    EvalCommand(EvalCommand),
+    Client(ClientCommand),
+    Hello(HelloCommand),
+    Subscribe(SubscribeCommand),
+    Unsubscribe(UnsubscribeCommand),
+    HSet(HSetCommand),
+    HGet(HGetCommand),
+    HGetAll(HGetAllCommand),
+    ZRange(ZRangeCommand),
+    RPush(RPushCommand),
+    SAdd(SAddCommand),
+    ZAdd(ZAddCommand),
+    Auth(AuthCommand),
+    Select(SelectCommand),
+    Acl(AclCommand),
+    BgRewriteAof(BgRewriteAofCommand),
+    Info(InfoCommand),
+    Save(SaveCommand),
+    BgSave(BgSaveCommand),
+    LastSave(LastSaveCommand),
+    Debug(DebugCommand),
+    ReplicaOf(ReplicaOfCommand),
+    Replconf(ReplconfCommand),
+    Psync(PsyncCommand),
+    WaitAof(WaitAofCommand),
 }
 
 // 每一个 struct 现在都是一个独立的、清晰的命令“实体”
@@ -38,6 +71,8 @@ pub struct SetCommand {
     pub value: Bytes,
     pub expiration: Option<Expiration>,
     pub condition: Option<SetCondition>,
+    // memcached 的 flags redis 没有这个参数 AOF 和复制流里写成 MCFLAGS 客户端的 SET 不认
+    pub flags: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +80,33 @@ pub struct GetCommand {
     pub key: Arc<String>,
 }
 
+#[derive(Debug, Clone)]
+pub struct MGetCommand {
+    pub keys: Vec<Arc<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MSetCommand {
+    pub pairs: Vec<(Arc<String>, Bytes)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DelCommand {
+    pub keys: Vec<Arc<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenameCommand {
+    pub key: Arc<String>,
+    pub new_key: Arc<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PExpireAtCommand {
+    pub key: Arc<String>,
+    pub timestamp_ms: i64, // 绝对时间 毫秒 redis 的 AOF 里 SET 后面跟的就是它
+}
+
 #[derive(Debug, Clone)]
 pub struct PingCommand {
     pub value: Option<String>,
@@ -63,6 +125,254 @@ pub struct EvalCommand {
     pub args:Vec<String>
 }
 
+#[derive(Debug, Clone)]
+pub struct ClientCommand {
+    pub sub: ClientSubCommand,
+}
+
+// CLIENT 命令下的各个子命令
+#[derive(Debug, Clone)]
+pub enum ClientSubCommand {
+    Id,
+    Tracking(TrackingOptions),
+    Caching(bool),
+    GetRedir,
+    TrackingInfo,
+    // LIST [TYPE normal] [ID id ...]  client_type 不是 normal 的时候回空
+    List {
+        client_type: Option<String>,
+        ids: Vec<u64>,
+    },
+    Info,
+    SetName(String),
+    GetName,
+    Kill(ClientKillFilter),
+    // 毫秒 和 是否暂停所有命令 (false 只暂停写命令)
+    Pause { timeout: u64, all: bool },
+    Unpause,
+    Reply(ClientReply),
+    NoEvict(bool),
+    // LIB-NAME / LIB-VER
+    SetInfo { attr: String, value: String },
+}
+
+// CLIENT KILL 的过滤条件 多个条件要同时满足
+// 老格式 CLIENT KILL addr 只按地址匹配 找不到要回错误
+#[derive(Debug, Clone, Default)]
+pub struct ClientKillFilter {
+    pub legacy: bool,
+    pub id: Option<u64>,
+    pub addr: Option<String>,
+    pub user: Option<String>,
+    // 空闲了至少这么多秒
+    pub idle: Option<u64>,
+    // 连上来至少这么多秒
+    pub maxage: Option<u64>,
+    pub skipme: bool,
+}
+
+// CLIENT REPLY ON|OFF|SKIP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientReply {
+    On,
+    Off,
+    // 跳过下一条命令的回复
+    Skip,
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+#[derive(Debug, Clone)]
+pub struct HelloCommand {
+    pub protover: Option<i64>,
+    pub auth: Option<(String, String)>,
+    pub setname: Option<String>,
+}
+
+// SUBSCRIBE channel [channel ...] 没有 PUBLISH 只用来收客户端缓存的失效通知
+#[derive(Debug, Clone)]
+pub struct SubscribeCommand {
+    pub channels: Vec<String>,
+}
+
+// UNSUBSCRIBE [channel ...] 不带频道就是全部退订
+#[derive(Debug, Clone)]
+pub struct UnsubscribeCommand {
+    pub channels: Vec<String>,
+}
+
+// AUTH password 或者 AUTH username password 只给密码的时候就是 default 用户
+#[derive(Debug, Clone)]
+pub struct AuthCommand {
+    pub username: Option<String>,
+    pub password: String,
+}
+
+// 库号在执行的时候再按库的个数检查
+#[derive(Debug, Clone)]
+pub struct SelectCommand {
+    pub index: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AclCommand {
+    pub sub: AclSubCommand,
+}
+
+#[derive(Debug, Clone)]
+pub enum AclSubCommand {
+    SetUser { name: String, rules: Vec<String> },
+    DelUser(Vec<String>),
+    GetUser(String),
+    List,
+    Users,
+    WhoAmI,
+    Cat(Option<String>),
+    // ACL LOG [count | RESET]
+    Log { count: Option<usize>, reset: bool },
+    Save,
+    Load,
+}
+
+// BGREWRITEAOF 没有参数
+#[derive(Debug, Clone)]
+pub struct BgRewriteAofCommand;
+
+// SAVE 没有参数
+#[derive(Debug, Clone)]
+pub struct SaveCommand;
+
+// BGSAVE [SCHEDULE]
+#[derive(Debug, Clone)]
+pub struct BgSaveCommand {
+    pub schedule: bool,
+}
+
+// LASTSAVE 没有参数
+#[derive(Debug, Clone)]
+pub struct LastSaveCommand;
+
+#[derive(Debug, Clone)]
+pub struct DebugCommand {
+    pub sub: DebugSubCommand,
+}
+
+#[derive(Debug, Clone)]
+pub enum DebugSubCommand {
+    // DEBUG EXPORT-RDB <path> 把所有库导出成 redis 能读的 RDB 文件
+    ExportRdb(String),
+}
+
+// REPLICAOF host port / REPLICAOF NO ONE (SLAVEOF 是老名字) None 表示变回主节点
+#[derive(Debug, Clone)]
+pub struct ReplicaOfCommand {
+    pub master: Option<(String, u16)>,
+}
+
+// REPLCONF 是 replica 和主节点之间握手、回报进度用的
+#[derive(Debug, Clone)]
+pub struct ReplconfCommand {
+    pub options: Vec<ReplconfOption>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplconfOption {
+    // replica 自己对外服务的端口 INFO replication 里显示
+    ListeningPort(u16),
+    // replica 支持的能力 eof / psync2
+    Capa(String),
+    // replica 已经处理到的复制偏移量
+    Ack(u64),
+    // 主节点要 replica 马上回一次 ACK
+    GetAck,
+}
+
+// PSYNC replid offset 偏移量 -1 (或者 SYNC) 表示要全量同步
+#[derive(Debug, Clone)]
+pub struct PsyncCommand {
+    pub replid: String,
+    pub offset: i64,
+}
+
+// WAITAOF numlocal numreplicas timeout 等这个连接之前的写入都 fsync 到本地 AOF
+// timeout 是毫秒 0 表示一直等
+#[derive(Debug, Clone)]
+pub struct WaitAofCommand {
+    pub numlocal: i64,
+    pub numreplicas: i64,
+    pub timeout_ms: u64,
+}
+
+// INFO [section [section ...]] 不带参数就是 default
+#[derive(Debug, Clone)]
+pub struct InfoCommand {
+    pub sections: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HSetCommand {
+    pub key: Arc<String>,
+    pub pairs: Vec<(String, Bytes)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetCommand {
+    pub key: Arc<String>,
+    pub field: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetAllCommand {
+    pub key: Arc<String>,
+}
+
+// 列表 集合 有序集合目前只有追加的命令
+// 脚本改过 RDB 导入进来的这些类型之后 AOF 和复制流里记的就是它们
+#[derive(Debug, Clone)]
+pub struct RPushCommand {
+    pub key: Arc<String>,
+    pub values: Vec<Bytes>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SAddCommand {
+    pub key: Arc<String>,
+    pub members: Vec<Bytes>,
+}
+
+// ZADD key score member [score member ...] 不支持 NX/XX 这些选项
+#[derive(Debug, Clone)]
+pub struct ZAddCommand {
+    pub key: Arc<String>,
+    pub members: Vec<(f64, Bytes)>,
+}
+
+// ZRANGE key start stop [WITHSCORES] 只支持按下标取
+#[derive(Debug, Clone)]
+pub struct ZRangeCommand {
+    pub key: Arc<String>,
+    pub start: i64,
+    pub stop: i64,
+    pub with_scores: bool,
+}
+
+// CLIENT TRACKING 的四种模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackingMode {
+    Default,
+    Bcast,
+    OptIn,
+    OptOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackingOptions {
+    pub enable: bool,
+    pub mode: TrackingMode,
+    pub redirect: Option<u64>,
+    pub prefixes: Vec<String>,
+    pub noloop: bool,
+}
+
 #[derive(Debug, Clone)]
 pub enum Expiration {
     EX(u64),   // 秒
@@ -84,6 +394,18 @@ pub enum Frame {
     Integer(i64),
     Null,
     Error(String),
+    // 下面是 RESP3 新增的类型 RESP2 的连接在序列化的时候会降级
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    // 格式 (txt/mkd) 和内容
+    Verbatim(String, Bytes),
+    // 服务端主动推送 比如客户端缓存的失效通知
+    Push(Vec<Frame>),
+    // 附加在某个回复前面的属性 客户端不认识可以直接忽略
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
 }
 
 pub enum ToBulk {
@@ -98,13 +420,43 @@ pub enum IsAof {
 }
 
 impl Command {
-    pub fn get_key(&self) -> Option<&Arc<String>>{
+    // 命令名 用来去命令表里查声明
+    pub fn name(&self) -> &str {
         match self {
-            Command::Set(set_command) => Some(&set_command.key),
-            Command::Get(get_command) => Some(&get_command.key),
-            Command::Ping(_ping_command) => None,
-            Command::Unimplement(_unimplement_command) => None,
-            Command::EvalCommand(_eval_command) => None,
+            Command::Set(_) => "set",
+            Command::Get(_) => "get",
+            Command::MGet(_) => "mget",
+            Command::MSet(_) => "mset",
+            Command::Del(_) => "del",
+            Command::Rename(_) => "rename",
+            Command::PExpireAt(_) => "pexpireat",
+            Command::Ping(_) => "ping",
+            Command::Unimplement(unimplement_command) => &unimplement_command.command,
+            Command::EvalCommand(_) => "eval",
+            Command::Client(_) => "client",
+            Command::Hello(_) => "hello",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::HSet(_) => "hset",
+            Command::HGet(_) => "hget",
+            Command::HGetAll(_) => "hgetall",
+            Command::ZRange(_) => "zrange",
+            Command::RPush(_) => "rpush",
+            Command::SAdd(_) => "sadd",
+            Command::ZAdd(_) => "zadd",
+            Command::Auth(_) => "auth",
+            Command::Select(_) => "select",
+            Command::Acl(_) => "acl",
+            Command::BgRewriteAof(_) => "bgrewriteaof",
+            Command::Info(_) => "info",
+            Command::Save(_) => "save",
+            Command::BgSave(_) => "bgsave",
+            Command::LastSave(_) => "lastsave",
+            Command::Debug(_) => "debug",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::Replconf(_) => "replconf",
+            Command::Psync(_) => "psync",
+            Command::WaitAof(_) => "waitaof",
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,111 @@
+use serde_json::{Map, Number, Value, json};
+
+use crate::error::Frame;
+
+/*
+   Frame 转 JSON
+   1.字符串类的 (Simple/Bulk/Verbatim/BigNumber) 都是 JSON 字符串 二进制按 UTF-8 有损转换
+   2.Map 的 key 都是字符串的时候转成对象 否则转成 [key, value] 的数组 不丢数据
+   3.Double 的 inf/nan JSON 表示不了 转成字符串
+   4.Attribute 只要里面的值
+*/
+pub fn frame_to_json(frame: &Frame) -> Value {
+    match frame {
+        Frame::Simple(s) | Frame::Error(s) | Frame::BigNumber(s) => Value::String(s.clone()),
+        Frame::Bulk(bytes) | Frame::Verbatim(_, bytes) => {
+            Value::String(String::from_utf8_lossy(bytes).into_owned())
+        }
+        Frame::Integer(i) => json!(i),
+        Frame::Null => Value::Null,
+        Frame::Boolean(b) => Value::Bool(*b),
+        Frame::Double(d) => match Number::from_f64(*d) {
+            Some(number) => Value::Number(number),
+            None => Value::String(d.to_string()),
+        },
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => {
+            Value::Array(items.iter().map(frame_to_json).collect())
+        }
+        Frame::Map(pairs) => map_to_json(pairs),
+        Frame::Attribute(_, inner) => frame_to_json(inner),
+    }
+}
+
+fn map_to_json(pairs: &[(Frame, Frame)]) -> Value {
+    let keys: Option<Vec<String>> = pairs
+        .iter()
+        .map(|(key, _)| match frame_to_json(key) {
+            Value::String(s) => Some(s),
+            _ => None,
+        })
+        .collect();
+    match keys {
+        Some(keys) => Value::Object(
+            keys.into_iter()
+                .zip(pairs.iter().map(|(_, value)| frame_to_json(value)))
+                .collect::<Map<_, _>>(),
+        ),
+        None => Value::Array(
+            pairs
+                .iter()
+                .map(|(key, value)| json!([frame_to_json(key), frame_to_json(value)]))
+                .collect(),
+        ),
+    }
+}
+
+/// POST /command 的请求体 必须是非空数组 数字和布尔值按字面转成字符串
+pub fn json_to_argv(body: &[u8]) -> Result<Vec<String>, String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let Value::Array(items) = value else {
+        return Err("request body must be a JSON array".into());
+    };
+    if items.is_empty() {
+        return Err("command array must not be empty".into());
+    }
+    items
+        .into_iter()
+        .map(|item| match item {
+            Value::String(s) => Ok(s),
+            Value::Number(n) => Ok(n.to_string()),
+            Value::Bool(b) => Ok(b.to_string()),
+            _ => Err("command arguments must be strings or numbers".to_string()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_frame_to_json() {
+        let frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"a")),
+            Frame::Integer(3),
+            Frame::Null,
+            Frame::Double(f64::INFINITY),
+            Frame::Map(vec![(
+                Frame::Bulk(Bytes::from_static(b"k")),
+                Frame::Boolean(true),
+            )]),
+            Frame::Map(vec![(Frame::Integer(1), Frame::Double(1.5))]),
+        ]);
+        assert_eq!(
+            frame_to_json(&frame),
+            json!(["a", 3, null, "inf", {"k": true}, [[1, 1.5]]])
+        );
+    }
+
+    #[test]
+    fn test_json_to_argv() {
+        assert_eq!(
+            json_to_argv(br#"["SET", "k", 10, true]"#).unwrap(),
+            vec!["SET", "k", "10", "true"]
+        );
+        assert!(json_to_argv(b"[]").is_err());
+        assert!(json_to_argv(br#"{"cmd": "GET"}"#).is_err());
+        assert!(json_to_argv(br#"["GET", null]"#).is_err());
+        assert!(json_to_argv(b"not json").is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::{convert::Infallible, error::Error};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Incoming,
+    header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use serde_json::{Value, json};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    acl::ACL,
+    context::{CONN_STATE, ConnectionContent, ConnectionState},
+    core_aof::{AOF_OFFSET, wait_aof_durable},
+    core_execute::execute_command_normal,
+    core_keyspec::CommandFlag,
+    core_loading::LOADING,
+    core_replication::REPLICATION,
+    db::Db,
+    error::{Command, Frame},
+    http::json::{frame_to_json, json_to_argv},
+};
+
+mod json;
+
+/*
+   HTTP/JSON 网关
+   1.GET/PUT/DELETE /db/{n}/keys/{key} 对应 GET/SET/DEL  PUT 的请求体就是值 可以带 ?ex=秒 或者 ?px=毫秒
+   2.POST /command 请求体是 JSON 数组 比如 ["HGETALL","user:1"]  ?db=n 选库 默认 0
+   3.和 RESP 走同一条路：Command::try_from -> ACL 检查 -> execute_command_normal AOF、淘汰、失效通知都一样
+   4.认证用 HTTP Basic 没带的话按 default 用户 (default 要密码就回 401)
+   5.每个请求在自己的 CONN_STATE 里执行 选库和登录用户只对这个请求有效
+   6.只放行读写 key 的数据命令 SUBSCRIBE/CLIENT/MULTI/REPLICAOF 这些跟连接或者整个节点绑在一起的
+     在一次性的请求里没有意义 还会把状态挂在这个请求的 client_id 上 一律 400
+   回复统一是 {"result": ...} 或者 {"error": "..."}
+*/
+
+// 请求体最大多少 和一个大 value 差不多就够了
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+type HttpResponse = Response<Full<Bytes>>;
+
+pub async fn handle_http_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    db: Db,
+    connection_content: ConnectionContent,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut receiver = connection_content.shutdown_tx.subscribe();
+    let service = service_fn(|request| {
+        let db = db.clone();
+        let connection_content = connection_content.clone();
+        async move { Ok::<_, Infallible>(route(request, &db, &connection_content).await) }
+    });
+    let connection = http1::Builder::new().serve_connection(TokioIo::new(socket), service);
+    tokio::pin!(connection);
+    tokio::select! {
+        res = connection.as_mut() => res?,
+        _ = receiver.recv() => {
+            // 停机的时候把正在处理的请求做完再断开
+            connection.as_mut().graceful_shutdown();
+            connection.await?;
+        }
+    }
+    Ok(())
+}
+
+async fn route(request: Request<Incoming>, db: &Db, content: &ConnectionContent) -> HttpResponse {
+    let user = match authenticate(request.headers()) {
+        Ok(user) => user,
+        Err((StatusCode::UNAUTHORIZED, msg)) => return unauthorized(&msg),
+        Err((status, msg)) => return error_response(status, &msg),
+    };
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or("").to_string();
+    let body = match Limited::new(request.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"),
+    };
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (db_index, argv) = match (&method, segments.as_slice()) {
+        (&Method::GET | &Method::PUT | &Method::DELETE, ["db", index, "keys", key]) => {
+            let Some(db_index) = parse_db_index(index, db) else {
+                return error_response(StatusCode::BAD_REQUEST, "invalid db index");
+            };
+            let key = Bytes::from(percent_decode_str(key).collect::<Vec<u8>>());
+            let argv = match method {
+                Method::GET => vec![Bytes::from_static(b"GET"), key],
+                Method::DELETE => vec![Bytes::from_static(b"DEL"), key],
+                _ => {
+                    let mut argv = vec![Bytes::from_static(b"SET"), key, body];
+                    for (name, value) in query_pairs(&query) {
+                        if name == "ex" || name == "px" {
+                            argv.push(Bytes::from(name.to_uppercase()));
+                            argv.push(Bytes::from(value));
+                        }
+                    }
+                    argv
+                }
+            };
+            (db_index, argv)
+        }
+        (&Method::POST, ["command"]) => {
+            let index = query_pairs(&query)
+                .find(|(name, _)| name == "db")
+                .map(|(_, value)| value)
+                .unwrap_or_else(|| "0".to_string());
+            let Some(db_index) = parse_db_index(&index, db) else {
+                return error_response(StatusCode::BAD_REQUEST, "invalid db index");
+            };
+            match json_to_argv(&body) {
+                Ok(argv) => (db_index, argv.into_iter().map(Bytes::from).collect()),
+                Err(msg) => return error_response(StatusCode::BAD_REQUEST, &msg),
+            }
+        }
+        (_, ["db", _, "keys", _] | ["command"]) => {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        _ => return error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+    // 每个请求一个独立的连接状态 选库和登录用户只在这个请求里生效
+    let state = CONN_STATE.with(|state| {
+        ConnectionState::new(db_index, state.client_address.clone(), state.client_id)
+    });
+    *state.user.borrow_mut() = Some(user);
+    let not_found_on_null = method == Method::GET;
+    CONN_STATE
+        .scope(state, execute_argv(argv, db, content, not_found_on_null))
+        .await
+}
+
+async fn execute_argv(
+    argv: Vec<Bytes>,
+    db: &Db,
+    content: &ConnectionContent,
+    not_found_on_null: bool,
+) -> HttpResponse {
+    let frame = Frame::Array(argv.into_iter().map(Frame::Bulk).collect());
+    let command = match Command::try_from(frame) {
+        Ok(command) => command,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    if !is_data_command(&command) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("ERR '{}' command is not allowed over HTTP", command.name()),
+        );
+    }
+    if let Err(msg) = ACL.check_command(&command, "toplevel") {
+        return error_response(error_status(&msg), &msg);
+    }
+    if let Err(msg) = LOADING.check_command(&command) {
+        return error_response(error_status(&msg), &msg);
+    }
+    if let Err(msg) = REPLICATION.check_command(&command) {
+        return error_response(error_status(&msg), &msg);
+    }
+    if let Err(msg) = AOF_OFFSET.check_command(&command) {
+        return error_response(error_status(&msg), &msg);
+    }
+    let result = execute_command_normal(command, db, content.clone()).await;
+    // appendfsync always 的时候等落盘了再回复
+    if let Err(msg) = wait_aof_durable().await {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("MISCONF Errors writing to the AOF file: {}", msg),
+        );
+    }
+    match result {
+        Ok(Frame::Error(msg)) => error_response(error_status(&msg), &msg),
+        Ok(Frame::Null) if not_found_on_null => {
+            json_response(StatusCode::NOT_FOUND, json!({ "result": null }))
+        }
+        Ok(frame) => json_response(StatusCode::OK, json!({ "result": frame_to_json(&frame) })),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Basic 认证 没带就用 default 用户 返回登录成功的用户名
+fn authenticate(headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+    let Some(header) = headers.get(AUTHORIZATION) else {
+        return ACL.default_login().ok_or_else(|| {
+            (StatusCode::UNAUTHORIZED, "NOAUTH Authentication required.".to_string())
+        });
+    };
+    let credentials = header
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let Some((username, password)) = credentials
+        .as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+    else {
+        return Err((StatusCode::BAD_REQUEST, "malformed Authorization header".to_string()));
+    };
+    match ACL.authenticate(username, password) {
+        Ok(()) => Ok(username.to_string()),
+        Err(msg) => Err((StatusCode::UNAUTHORIZED, msg)),
+    }
+}
+
+// 命令表里带 key 而且是读或者写的命令才算数据命令 不在表里的 (MULTI 这些) 也不行
+fn is_data_command(command: &Command) -> bool {
+    command.spec().is_some_and(|spec| {
+        !spec.key_specs.is_empty()
+            && (spec.has_flag(CommandFlag::Write) || spec.has_flag(CommandFlag::ReadOnly))
+    })
+}
+
+fn parse_db_index(index: &str, db: &Db) -> Option<usize> {
+    index
+        .parse::<usize>()
+        .ok()
+        .filter(|index| *index < db.store.store.len())
+}
+
+fn query_pairs(query: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    query.split('&').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+        Some((decode(name).to_lowercase(), decode(value)))
+    })
+}
+
+// 错误码按 redis 错误前缀区分
+fn error_status(msg: &str) -> StatusCode {
+    match msg.split_whitespace().next() {
+        Some("NOAUTH" | "WRONGPASS") => StatusCode::UNAUTHORIZED,
+        Some("NOPERM") => StatusCode::FORBIDDEN,
+        Some("LOADING") => StatusCode::SERVICE_UNAVAILABLE,
+        Some("READONLY") => StatusCode::FORBIDDEN,
+        Some("MISCONF") => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error_response(status: StatusCode, msg: &str) -> HttpResponse {
+    json_response(status, json!({ "error": msg }))
+}
+
+fn unauthorized(msg: &str) -> HttpResponse {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, msg);
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"kv\""));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EvictionType;
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", STANDARD.encode(credentials));
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(error_status("NOAUTH Authentication required."), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_status("WRONGPASS invalid username-password pair"), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_status("NOPERM User x has no permissions"), StatusCode::FORBIDDEN);
+        assert_eq!(error_status("READONLY You can't write against a read only replica."), StatusCode::FORBIDDEN);
+        assert_eq!(error_status("LOADING Redis is loading the dataset in memory"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error_status("WRONGTYPE Operation against a key"), StatusCode::BAD_REQUEST);
+        assert_eq!(error_status(""), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        // 用全局的 ACL 起个别的测试不会用的名字
+        ACL.set_user("http-test", &["on".into(), ">secret".into(), "+@all".into()]).unwrap();
+        assert_eq!(authenticate(&basic("http-test:secret")), Ok("http-test".to_string()));
+        // 密码里可以有冒号 只按第一个分
+        ACL.set_user("http-test", &[">a:b".into()]).unwrap();
+        assert_eq!(authenticate(&basic("http-test:a:b")), Ok("http-test".to_string()));
+
+        let (status, msg) = authenticate(&basic("http-test:wrong")).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(msg.starts_with("WRONGPASS"));
+
+        for header in ["Bearer abc", "Basic !!!", &format!("Basic {}", STANDARD.encode("no-colon"))] {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(header).unwrap());
+            assert_eq!(authenticate(&headers).unwrap_err().0, StatusCode::BAD_REQUEST, "{}", header);
+        }
+    }
+
+    #[test]
+    fn test_is_data_command() {
+        let command = |argv: &[&str]| {
+            let frames = argv
+                .iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect();
+            Command::try_from(Frame::Array(frames)).unwrap()
+        };
+        for argv in [&["GET", "a"][..], &["SET", "a", "1"], &["HGETALL", "h"], &["DEL", "a", "b"]] {
+            assert!(is_data_command(&command(argv)), "{:?}", argv);
+        }
+        for argv in [
+            &["SUBSCRIBE", "x"][..],
+            &["CLIENT", "TRACKING", "on"],
+            &["HELLO", "3"],
+            &["PSYNC", "?", "-1"],
+            &["REPLICAOF", "no", "one"],
+            &["MULTI"],
+            &["PING"],
+            &["EVAL", "return 1", "0"],
+        ] {
+            assert!(!is_data_command(&command(argv)), "{:?}", argv);
+        }
+    }
+
+    #[test]
+    fn test_parse_db_index() {
+        let db = Db::new(&EvictionType::LRU);
+        assert_eq!(parse_db_index("0", &db), Some(0));
+        assert_eq!(parse_db_index("15", &db), Some(15));
+        assert_eq!(parse_db_index("16", &db), None);
+        assert_eq!(parse_db_index("-1", &db), None);
+        assert_eq!(parse_db_index("abc", &db), None);
+        assert_eq!(parse_db_index("", &db), None);
+    }
+
+    #[test]
+    fn test_query_pairs() {
+        let pairs: Vec<_> = query_pairs("EX=10&db=%31&bad&key=a%20b").collect();
+        assert_eq!(
+            pairs,
+            [
+                ("ex".to_string(), "10".to_string()),
+                ("db".to_string(), "1".to_string()),
+                ("key".to_string(), "a b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unauthorized_response() {
+        let response = unauthorized("NOAUTH Authentication required.");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Basic realm=\"kv\"");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    }
+}
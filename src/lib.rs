@@ -1,33 +1,69 @@
+mod acl;
 mod aof_exchange;
 mod command_exchange;
 mod command_execute;
 mod config;
 mod context;
 mod core_aof;
+mod core_aof_check;
+mod core_aof_manifest;
+mod core_aof_rewrite;
+mod core_client;
+mod core_codec;
 mod core_exchange;
 mod core_execute;
 mod core_explain;
+mod core_keyspec;
+mod core_loading;
+mod core_rdb;
+mod core_rdb_export;
+mod core_rdb_import;
+mod core_replication;
+mod core_snapshot;
 mod core_time;
+mod core_tracking;
 mod db;
 mod error;
+mod http;
+mod listener;
+mod memcached;
 mod server;
 mod shutdown;
+mod tls;
 mod types;
 mod lua;
 
+// kv-check-aof 用
+pub use crate::core_aof_check::check_aof;
+// kv-import-rdb 用
+pub use crate::core_rdb_import::import_rdb;
+// kv-export-rdb 用
+pub use crate::core_rdb_export::export_rdb;
+
+use crate::acl::ACL;
 use crate::config::CONFIG;
-use crate::context::{CONN_STATE, ConnectionContent, ConnectionState};
-use crate::core_aof::{AofMessage, aof_writer_task, explain_execute_aofcommand};
-use crate::core_time::start_time_caching_task;
+use crate::context::{CONN_STATE, ConnectionContent, ConnectionState, next_client_id};
+use crate::core_aof::{AofMessage, aof_writer_task, load_aof};
+use crate::core_aof_manifest::AofManifest;
+use crate::core_aof_rewrite::{AOF_REWRITE, aof_rewrite_cron};
+use crate::core_loading::LOADING;
+use crate::core_rdb::load_rdb;
+use crate::core_replication::{REPLICATION, replication_cron};
+use crate::core_snapshot::{load_snapshot, snapshot_cron};
+use crate::core_time::{refresh_cached_time, start_time_caching_task};
 use crate::db::Db;
 use crate::lua::lua_vm::init_lua_vm;
 use crate::lua::lua_work::start_lua_actor;
+use crate::http::handle_http_connection;
+use crate::listener::{Listener, Protocol, bind_all};
+use crate::memcached::handle_memcached_connection;
 use crate::server::handle_connection;
 use crate::shutdown::{ShutDown, shutdown_listener};
 use mlua::Lua;
 use tokio::task::JoinHandle;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use futures::future::join_all;
 use tokio::sync::mpsc::{self};
 use tokio::sync::{Mutex, broadcast};
 
@@ -52,39 +88,158 @@ pub async fn run() {
     //初始化并且直接获取sender
     let lua_sender = start_lua_actor();
 
-    let aop_file_path = "database.aof";
+    tracing_subscriber::fmt::init();
+    // 找到 (或者建好) AOF 的清单 老的单文件 AOF 在这一步挪进目录
+    let (manifest, aof_fresh) = match AofManifest::prepare(&CONFIG) {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            eprintln!("AOF 打开失败: {}", e);
+            std::process::exit(1);
+        }
+    };
     // 启动专门的 AOF 写入后台任务
-    let aof_task = tokio::spawn(aof_writer_task(rx, aop_file_path, app_shutdown_tx.clone()));
+    let aof_task = tokio::spawn(aof_writer_task(rx, manifest.clone(), app_shutdown_tx.clone()));
 
-    tracing_subscriber::fmt::init();
-    // 1. 绑定监听地址
-    // "127.0.0.1:6379" 是 Redis 的默认端口，我们沿用它可以方便地用 `redis-cli` 测试
-    // 如果端口占用失败 直接报错退出
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
-    println!("服务器启动，监听于 127.0.0.1:6379");
+    // 1. 按配置绑定所有监听地址 (TCP/IPv6/unix socket)
+    // 默认还是 127.0.0.1:6379 可以方便地用 `redis-cli` 测试
+    // 任何一个地址绑定失败 直接报错退出
+    let listeners = match bind_all(&CONFIG).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("监听失败: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for listener in &listeners {
+        // 端口 0 的时候这里打印的是系统实际分配的端口
+        println!("服务器启动，监听于 {}", listener.local_addr());
+    }
+
+    // ACL 文件存在就加载 格式有问题直接拒绝启动 免得带着错误的权限跑起来
+    if std::path::Path::new(&CONFIG.aclfile).exists() {
+        if let Err(e) = ACL.load_file(&CONFIG.aclfile) {
+            eprintln!("ACL 文件加载失败: {}", e);
+            std::process::exit(1);
+        }
+        println!("ACL 用户加载成功");
+    }
+    // memcached 文本协议没有登录 default 要密码的话它就等于一个绕过认证的口子
+    if CONFIG.memcached_port.is_some() && ACL.default_login().is_none() {
+        eprintln!("default 用户需要密码 memcached 协议没法认证 请关掉 memcached-port 或者给 default 设 nopass");
+        std::process::exit(1);
+    }
 
+    //开始时间获取任务
+    // 加载数据的时候就要用当前时间判断过期 所以要在加载之前起来 而且马上就得是对的
+    refresh_cached_time();
+    let time_task = tokio::spawn(start_time_caching_task(infra_shutdown_tx.clone()));
     //创建db
-    let mut db = Db::new(&CONFIG.eviction_type);
+    let db = Db::new(&CONFIG.eviction_type);
+    // 监听先起来再加载数据 加载完之前连上来的客户端只能 PING/INFO 这些 其他命令回 -LOADING
+    let total_bytes = if aof_fresh {
+        [Some(&CONFIG.dbfilename), CONFIG.import_rdb.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| std::fs::metadata(path).map_or(0, |meta| meta.len()))
+            .sum()
+    } else {
+        manifest.total_size()
+    };
+    LOADING.start(total_bytes);
+    let connect_db = db.clone();
+    let connect_shutdown = app_shutdown_tx.clone();
+    // 连主节点的后台任务执行复制流用
+    let replication_content = ConnectionContent {
+        aof_tx: aof_tx.clone(),
+        shutdown_tx: app_shutdown_tx.clone(),
+        lua_sender: lua_sender.clone(),
+        receivce_lua: lua_vm_receiver.clone(),
+    };
+    //包含任务队列 所有监听器接进来的连接都放在同一个队列里 停机的时候一起等
+    let connect_task = tokio::spawn(async move {
+        let connect_task_vec: Arc<Mutex<Vec<JoinHandle<()>>>> =
+        Arc::new(Vec::new().into());
+        // 每个监听器一个接收循环
+        let accept_tasks: Vec<JoinHandle<()>> = listeners
+            .into_iter()
+            .map(|listener| {
+                let connect_content = ConnectionContent {
+                    aof_tx: aof_tx.clone(),
+                    shutdown_tx: connect_shutdown.clone(),
+                    lua_sender: lua_sender.clone(),
+                    receivce_lua: lua_vm_receiver.clone(),
+                };
+                tokio::spawn(accept_loop(
+                    listener,
+                    connect_db.clone(),
+                    connect_content,
+                    connect_task_vec.clone(),
+                ))
+            })
+            .collect();
+        join_all(accept_tasks).await;
+        connect_task_vec
+    });
     // 模拟一个新的客户端连接进来
     let client_addr = "192.168.1.10:54321".to_string();
-    let initial_state = ConnectionState {
-        selected_db: 0, // 默认连接到 1 号数据库
-        client_address: Some(client_addr)
-    };
-    CONN_STATE
-        .scope(initial_state, async {
-            match explain_execute_aofcommand(aop_file_path, &mut db).await {
-                Err(e) => {
-                    panic!("aof 清理失败  {}", e)
+    let initial_state = ConnectionState::new(0, Some(client_addr), 0);
+    // 有 AOF 就以 AOF 为准 全新启动才加载快照
+    // 加载完马上重写一次 AOF 不然下次启动 AOF 不空了 快照里的数据就丢了
+    if aof_fresh {
+        match load_snapshot(&db, Path::new(&CONFIG.dbfilename)).await {
+            Ok(Some(loaded)) => {
+                println!("快照加载成功 {} 个 key", loaded);
+                AOF_REWRITE.schedule();
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("快照 {} 加载失败: {}", CONFIG.dbfilename, e);
+                std::process::exit(1);
+            }
+        }
+        // 从 redis 迁移过来 RDB 里的 key 覆盖快照里的同名 key
+        if let Some(path) = &CONFIG.import_rdb {
+            match load_rdb(&db, Path::new(path)).await {
+                Ok((loaded, skipped)) => {
+                    for skipped in &skipped {
+                        tracing::warn!("RDB 导入跳过 {}", skipped);
+                    }
+                    println!("RDB {} 导入成功 {} 个 key 跳过 {} 项", path, loaded, skipped.len());
+                    AOF_REWRITE.schedule();
                 }
-                _ => {
-                    println!("aof数据恢复成功")
+                Err(e) => {
+                    eprintln!("RDB {} 导入失败: {}", path, e);
+                    std::process::exit(1);
                 }
             }
-        })
-        .await;
-    //开始时间获取任务
-    let time_task = tokio::spawn(start_time_caching_task(infra_shutdown_tx.clone()));
+        }
+    } else {
+        if let Some(path) = &CONFIG.import_rdb {
+            tracing::warn!("AOF 里已经有数据了 不导入 RDB {}", path);
+        }
+        CONN_STATE
+            .scope(initial_state, async {
+                match load_aof(&manifest, &db, CONFIG.aof_load_truncated).await {
+                    Err(e) => {
+                        eprintln!("AOF 加载失败: {}", e);
+                        std::process::exit(1);
+                    }
+                    Ok(report) => {
+                        // 一般是从 redis 迁移过来的 AOF 里有 kv 还不支持的命令
+                        for line in &report {
+                            tracing::warn!("AOF 加载跳过 {}", line);
+                        }
+                        println!("aof数据恢复成功")
+                    }
+                }
+            })
+            .await;
+        // 从老的单文件 AOF 升级上来的 base 还是 RESP 格式 redis 7 的 base 是 RDB 重写一次换成快照
+        if manifest.base.as_ref().is_some_and(|base| !base.is_snapshot()) {
+            AOF_REWRITE.schedule();
+        }
+    }
+    LOADING.finish();
     /*
      * db克隆代价很小
      * 同时开启两个异步任务
@@ -99,75 +254,16 @@ pub async fn run() {
             .store
             .eviction_memory(1024 * 1024 * 8, app_shutdown_tx.clone()),
     );
-    let connect_shutdown = app_shutdown_tx.clone();
-    //包含任务队列
-    let connect_task = tokio::spawn(async move {
-        let connect_task_vec: Arc<Mutex<Vec<JoinHandle<()>>>> =
-        Arc::new(Vec::new().into());
-        // 2. 接受连接循环
-        loop {
-            let connect_content = ConnectionContent {
-                aof_tx:aof_tx.clone(),
-                shutdown_tx: connect_shutdown.clone(),
-                lua_sender:lua_sender.clone(),
-                receivce_lua:lua_vm_receiver.clone()
-            };
-            let mut receiver = connect_content.shutdown_tx.subscribe();
-            // 等待一个新的客户端连接
-            // 并不是包裹了一层 所以整体代码侵入行为降低
-            // 现在整体等待被包裹成两个了
-            let (socket, addr) = tokio::select! {
-                res = listener.accept() =>{
-                    match res {
-                        Ok(res) => {
-                            res
-                        },
-                        Err(_) => {
-                            break;
-                        },
-                    }
-                }
-                _ = receiver.recv() =>{
-                    break;
-                }
-            };
-            // let (socket, _) = listener.accept().await;
-            tracing::info!("接收到新连接");
-            let db = db.clone();
-
-            let initial_state = ConnectionState {
-                selected_db: 0, // 默认连接到 1 号数据库
-                client_address: Some(addr.to_string())
-            };
-            // CONN_STATE
-            //     .scope(initial_state, async {
-            //         // 3. 为每个连接生成一个新的异步任务
-            //         tokio::task::spawn(async move {
-            //             // 在这个新任务中处理连接
-            //             if let Err(e) = handle_connection(socket, db, tx_clone).await {
-            //                 tracing::error!("处理时出错: {}", e);
-            //             }
-            //         });
-            //     })
-            //     .await;
-            // 2. 【正确！】spawn 一个新任务
-            let connect_task = tokio::task::spawn(async move {
-                // 3. 【正确！】在新任务【内部】设置 TaskLocal
-                CONN_STATE
-                    .scope(initial_state, async move {
-                        // 现在，这个 handle_connection 任务
-                        // 以及它调用的所有函数 (比如 lock_read)
-                        // 都可以安全地调用 CONN_STATE.with() 了！
-                        if let Err(e) = handle_connection(socket, db, connect_content).await {
-                            tracing::error!("处理时出错: {}", e);
-                        }
-                    })
-                    .await; // .await 这个 scope
-            });
-            connect_task_vec.lock().await.push(connect_task);
-        }
-        connect_task_vec
-    });
+    // 重写和快照都要等数据加载完 不然会把只加载了一半的数据当成全量
+    // AOF 涨得太大了自动重写
+    tokio::spawn(aof_rewrite_cron(db.clone(), app_shutdown_tx.clone()));
+    // 按 save 规则自动保存快照
+    tokio::spawn(snapshot_cron(db.clone(), app_shutdown_tx.clone()));
+    // 配了 replicaof 就在自己的数据加载完之后开始同步 主节点的数据会把它整个换掉
+    if let Some((host, port)) = &CONFIG.replicaof {
+        REPLICATION.start_link(host.clone(), *port, db.clone(), replication_content);
+    }
+    tokio::spawn(replication_cron(app_shutdown_tx.clone()));
     let shutdown = ShutDown{
         aof_task,
         time_task,
@@ -181,3 +277,77 @@ pub async fn run() {
     //收集关联后开启监听线程
     shutdown.shutdown().await;
 }
+
+async fn accept_loop(
+    listener: Listener,
+    db: Db,
+    connect_content: ConnectionContent,
+    connect_task_vec: Arc<Mutex<Vec<JoinHandle<()>>>>,
+) {
+    let mut receiver = connect_content.shutdown_tx.subscribe();
+    let protocol = listener.protocol();
+    // 2. 接受连接循环
+    loop {
+        // 等待一个新的客户端连接
+        // 并不是包裹了一层 所以整体代码侵入行为降低
+        // 现在整体等待被包裹成两个了
+        let (socket, addr) = tokio::select! {
+            res = listener.accept() =>{
+                match res {
+                    Ok(res) => {
+                        res
+                    },
+                    Err(_) => {
+                        break;
+                    },
+                }
+            }
+            _ = receiver.recv() =>{
+                break;
+            }
+        };
+        tracing::info!("接收到新连接");
+        let db = db.clone();
+        let connect_content = connect_content.clone();
+
+        // 2. 【正确！】spawn 一个新任务
+        let connect_task = tokio::task::spawn(async move {
+            // TLS 的握手在这里做 失败了只影响这一个连接
+            let socket = match socket.establish().await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    tracing::error!("TLS 握手失败 {}: {}", addr, e);
+                    return;
+                }
+            };
+            // 默认连接到 0 号数据库 协议从 RESP2 开始
+            let initial_state = ConnectionState::new(0, Some(addr), next_client_id());
+            // 证书的 CN 正好是一个 ACL 用户就直接用它登录
+            // 否则 default 用户不需要密码的话 连上来就是已登录状态
+            let certificate_user = socket
+                .peer_common_name()
+                .filter(|_| CONFIG.tls_auth_clients_user)
+                .and_then(|name| ACL.certificate_login(&name));
+            *initial_state.user.borrow_mut() = certificate_user.or_else(|| ACL.default_login());
+            // 3. 【正确！】在新任务【内部】设置 TaskLocal
+            CONN_STATE
+                .scope(initial_state, async move {
+                    // 现在，这个 handle_connection 任务
+                    // 以及它调用的所有函数 (比如 lock_read)
+                    // 都可以安全地调用 CONN_STATE.with() 了！
+                    let result = match protocol {
+                        Protocol::Resp => handle_connection(socket, db, connect_content).await,
+                        Protocol::Memcached => {
+                            handle_memcached_connection(socket, db, connect_content).await
+                        }
+                        Protocol::Http => handle_http_connection(socket, db, connect_content).await,
+                    };
+                    if let Err(e) = result {
+                        tracing::error!("处理时出错: {}", e);
+                    }
+                })
+                .await; // .await 这个 scope
+        });
+        connect_task_vec.lock().await.push(connect_task);
+    }
+}
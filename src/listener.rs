@@ -0,0 +1,324 @@
+use std::{
+    io,
+    os::unix::fs::PermissionsExt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::{
+    config::Config,
+    tls::{build_acceptor, peer_common_name},
+};
+
+/*
+   监听器
+   1.按配置绑定 TCP (每个 bind 地址一个 支持 IPv6) 和 unix domain socket
+   2.不同的监听器接进来的连接统一成 Stream 后面的连接处理不用关心是哪种 socket
+   3.unix socket 的文件启动时先删掉旧的 监听器释放的时候再删掉
+   4.TLS 握手放到连接自己的任务里做 慢吞吞的客户端不会卡住整个接收循环
+   5.memcached 和 HTTP 端口接进来的连接走各自的协议 其他的都是 RESP
+*/
+
+// 握手超过这个时间还没完成就断开
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+    Tls(TcpListener, TlsAcceptor),
+    Memcached(TcpListener),
+    Http(TcpListener),
+}
+
+// 连接上跑的是哪种协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Resp,
+    Memcached,
+    Http,
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// 刚接进来的连接 TLS 的还没有握手
+pub enum Incoming {
+    Ready(Stream),
+    Tls(TcpStream, TlsAcceptor),
+}
+
+/// 按配置把所有监听器都绑定好 任何一个失败都算启动失败
+pub async fn bind_all(config: &Config) -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for host in &config.bind {
+        let listener = TcpListener::bind((host.as_str(), config.port))
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("绑定 {}:{} 失败: {}", host, config.port, e)))?;
+        listeners.push(Listener::Tcp(listener));
+    }
+    if let Some(tls_port) = config.tls_port {
+        let acceptor = build_acceptor(config).map_err(io::Error::other)?;
+        for host in &config.bind {
+            let listener = TcpListener::bind((host.as_str(), tls_port))
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("绑定 TLS {}:{} 失败: {}", host, tls_port, e)))?;
+            listeners.push(Listener::Tls(listener, acceptor.clone()));
+        }
+    }
+    if let Some(memcached_port) = config.memcached_port {
+        for host in &config.bind {
+            let listener = TcpListener::bind((host.as_str(), memcached_port))
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("绑定 memcached {}:{} 失败: {}", host, memcached_port, e)))?;
+            listeners.push(Listener::Memcached(listener));
+        }
+    }
+    if let Some(http_port) = config.http_port {
+        for host in &config.bind {
+            let listener = TcpListener::bind((host.as_str(), http_port))
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("绑定 HTTP {}:{} 失败: {}", host, http_port, e)))?;
+            listeners.push(Listener::Http(listener));
+        }
+    }
+    if let Some(path) = &config.unixsocket {
+        // 上次没有正常退出留下的 socket 文件会让 bind 失败
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        if let Some(perm) = config.unixsocketperm {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(perm))?;
+        }
+        listeners.push(Listener::Unix(listener, path.clone()));
+    }
+    Ok(listeners)
+}
+
+impl Listener {
+    /// 实际监听的地址 端口 0 的时候这里才能看到系统分配的端口
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            Listener::Tls(listener, _) => listener
+                .local_addr()
+                .map(|addr| format!("{} (tls)", addr))
+                .unwrap_or_default(),
+            Listener::Memcached(listener) => listener
+                .local_addr()
+                .map(|addr| format!("{} (memcached)", addr))
+                .unwrap_or_default(),
+            Listener::Http(listener) => listener
+                .local_addr()
+                .map(|addr| format!("{} (http)", addr))
+                .unwrap_or_default(),
+            Listener::Unix(_, path) => path.clone(),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Listener::Memcached(_) => Protocol::Memcached,
+            Listener::Http(_) => Protocol::Http,
+            _ => Protocol::Resp,
+        }
+    }
+
+    /// 接一个新连接 顺带返回客户端地址 unix socket 和 redis 一样写成 "路径:0"
+    pub async fn accept(&self) -> io::Result<(Incoming, String)> {
+        match self {
+            Listener::Tcp(listener) | Listener::Memcached(listener) | Listener::Http(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Incoming::Ready(Stream::Tcp(socket)), addr.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Incoming::Ready(Stream::Unix(socket)), format!("{}:0", path)))
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Incoming::Tls(socket, acceptor.clone()), addr.to_string()))
+            }
+        }
+    }
+}
+
+impl Incoming {
+    /// 完成 TLS 握手 普通连接原样返回
+    pub async fn establish(self) -> io::Result<Stream> {
+        match self {
+            Incoming::Ready(stream) => Ok(stream),
+            Incoming::Tls(socket, acceptor) => {
+                let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS 握手超时"))??;
+                Ok(Stream::Tls(Box::new(stream)))
+            }
+        }
+    }
+}
+
+impl Stream {
+    /// TLS 客户端证书里的 CN 普通连接没有
+    pub fn peer_common_name(&self) -> Option<String> {
+        match self {
+            Stream::Tls(stream) => peer_common_name(stream.get_ref().1.peer_certificates()),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config() -> Config {
+        Config { port: 0, ..Config::default() }
+    }
+
+    // 连上去写一点东西 确认对面 accept 出来的是同一个连接
+    async fn roundtrip(listener: &Listener, connect: impl Future<Output = io::Result<Stream>>) -> String {
+        let mut client = connect.await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let (incoming, addr) = listener.accept().await.unwrap();
+        let mut server = incoming.establish().await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        addr
+    }
+
+    fn tcp_port(listener: &Listener) -> u16 {
+        match listener {
+            Listener::Tcp(listener) | Listener::Memcached(listener) | Listener::Http(listener) => {
+                listener.local_addr().unwrap().port()
+            }
+            _ => panic!("不是 TCP 监听器"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_multiple_hosts() {
+        let mut config = config();
+        // 127.0.0.0/8 都是回环地址 不用 IPv6 也能测多个地址
+        config.bind = vec!["127.0.0.1".into(), "127.0.0.2".into()];
+        config.memcached_port = Some(0);
+        config.http_port = Some(0);
+        let listeners = bind_all(&config).await.unwrap();
+        let protocols: Vec<_> = listeners.iter().map(Listener::protocol).collect();
+        // 每个端口在每个 bind 地址上都有一个
+        assert_eq!(
+            protocols,
+            [Protocol::Resp, Protocol::Resp, Protocol::Memcached, Protocol::Memcached, Protocol::Http, Protocol::Http]
+        );
+        assert!(listeners[1].local_addr().starts_with("127.0.0.2:"));
+        assert!(listeners[2].local_addr().ends_with("(memcached)"));
+        for (listener, host) in listeners.iter().zip(["127.0.0.1", "127.0.0.2"].iter().cycle()) {
+            let port = tcp_port(listener);
+            assert!(listener.local_addr().starts_with(host));
+            roundtrip(listener, async { TcpStream::connect((*host, port)).await.map(Stream::Tcp) }).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let mut config = config();
+        config.port = taken.local_addr().unwrap().port();
+        let Err(e) = bind_all(&config).await else {
+            panic!("端口被占用了应该绑定失败");
+        };
+        assert!(e.to_string().contains(&format!("127.0.0.1:{}", config.port)));
+        // 前面的地址绑好了 后面的绑不上也算失败
+        config.port = 0;
+        config.bind = vec!["127.0.0.1".into(), "no.such.host.invalid".into()];
+        assert!(bind_all(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("kv-listener-{}.sock", std::process::id()));
+        let path_str = path.to_str().unwrap().to_string();
+        // 上次没删掉的 socket 文件不影响启动
+        std::fs::write(&path, b"stale").unwrap();
+        let mut config = config();
+        config.bind = Vec::new();
+        config.unixsocket = Some(path_str.clone());
+        config.unixsocketperm = Some(0o700);
+        let listeners = bind_all(&config).await.unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr(), path_str);
+        assert_eq!(listeners[0].protocol(), Protocol::Resp);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+
+        let addr = roundtrip(&listeners[0], async { UnixStream::connect(&path).await.map(Stream::Unix) }).await;
+        assert_eq!(addr, format!("{}:0", path_str));
+
+        // 监听器释放的时候把 socket 文件删掉
+        drop(listeners);
+        assert!(!path.exists());
+    }
+}
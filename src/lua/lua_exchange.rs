@@ -83,6 +83,9 @@ impl<'lua> IntoLua<'lua> for Frame {
             // Redis Lua 环境中，空值会被映射为 boolean false
             // 这虽然很怪，但是是 Redis 的标准行为
             Frame::Null => Ok(Value::Boolean(false)),
+
+            // 7. RESP3 的类型 脚本里固定是 RESP2 先降级再转换
+            other => other.into_resp2().into_lua(lua),
         }
     }
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
 use flume::Sender;
 use mlua::Lua;
@@ -9,11 +9,12 @@ use tokio::{
 
 use crate::{
     command_execute::CommandContext,
+    acl::ACL,
+    aof_exchange::{AofContent, send_multi_aof},
     core_execute::execute_command_hook,
-    db::{
-        LockedDb,
-        eviction::{KvOperator, MemoryCache},
-    },
+    core_keyspec::CommandFlag,
+    core_replication::REPLICATION,
+    db::{LockedDb, capture::Writing, lock_plan::LockPlan},
     error::{Command, EvalCommand, Frame, KvError},
     lua::lua_exchange::lua_value_to_bulk_frame,
 };
@@ -34,18 +35,8 @@ impl EvalCommand {
         //获取lua 实体 进行操作
         let lua = receiver.recv_async().await.unwrap();
 
-        //直接转成集合
-        let mut shard_indices: Vec<usize> = self
-            .keys
-            .clone()
-            .into_iter()
-            .map(|k| MemoryCache::get_shard_index(&k))
-            .collect();
-        shard_indices.sort_unstable();
-        shard_indices.dedup();
-
-        // 准备一个容器存放抢到的锁，这就是 LuaContext 的雏形
-        let sessions = Arc::new(Mutex::new(HashMap::new()));
+        // 和普通命令走同一个锁规划 EVAL 声明的 KEYS 全部按写锁处理
+        let plan = LockPlan::for_command(&Command::EvalCommand(self.clone()));
 
         // 1. 定义一个临时闭包，专门负责设置环境
         let setup_env = |lua: &Lua| -> mlua::Result<()> {
@@ -74,18 +65,19 @@ impl EvalCommand {
         setup_env(&lua).map_err(|e| {
             KvError::ProtocolError(format!("Lua全局变量(KEYS/ARGV)注入失败: {}", e))
         })?;
-        // 3. 按顺序遍历，一个个抢
-        for shard_index in shard_indices {
-            let db = command_content.db.clone().unwrap();
-            // lock_shard_write 是异步的，这里会等待直到拿到锁
-            // 因为是按顺序的，所以绝对不会死锁
-            let guard = db.store.lock_write_lua(shard_index).await;
-
-            // 抢到了！包装成 Session 塞进 Map
-            sessions.lock().await.insert(shard_index, guard);
-        }
+        // 3. 按计划的顺序一个个抢 所有人都按 (db, shard) 排序加锁 所以绝对不会死锁
+        //    抢到的锁外面包着事务节点 这就是 LuaContext 的雏形
+        let view = command_content
+            .db
+            .clone()
+            .unwrap()
+            .store
+            .lock_plan_lua(&plan)
+            .await;
+        let sessions = Arc::new(Mutex::new(view));
 
         let db = command_content.db.clone();
+        let aof_content = command_content.connect_content.clone();
         let sessions_clone = sessions.clone();
         //    我们正在创建一个 Lua 能调用的 Rust 异步函数
         let redis_call = lua
@@ -125,28 +117,32 @@ impl EvalCommand {
                         };
 
                         let command = Command::try_from(Frame::Array(frames))
-                            .map_err(|e| mlua::Error::runtime("redis.call 之后进行类型转换"))?;
-
-                        if let Some(key) = command.get_key() {
-                            let shard_index = MemoryCache::get_shard_index(key);
-                            let mut lock = sessions.lock().await;
-                            let lock = lock.get_mut(&shard_index);
-
-                            // 执行层代码复用
-                            // 修正点：
-                            // 1. 去掉了闭包里多余的 `->`
-                            // 2. 修正了末尾的括号数量
-                            // 3. 这一行应该是作为返回值，所以去掉了 let frame = (或者是你确实需要赋值，看下文逻辑)
-                            // 这里假设你是想返回 execute_command_hook 的结果：
-                            let frame = execute_command_hook(&command, db_clone, content, lock)
-                                .await
-                                .map_err(|_| mlua::Error::runtime("lua 脚本内部命令执行失败"))
-                                .unwrap();
-                            Ok(frame)
-                        } else {
-                            // 修正点：加上了 missing 的 else
-                            Err(mlua::Error::runtime("lua 脚本内部未知错误"))
+                            .map_err(|e| mlua::Error::runtime(e.to_string()))?;
+                        if command
+                            .spec()
+                            .is_some_and(|spec| spec.has_flag(CommandFlag::NoScript))
+                        {
+                            return Err(mlua::Error::runtime(
+                                "This Redis command is not allowed from script",
+                            ));
                         }
+                        ACL.check_command(&command, "lua").map_err(mlua::Error::runtime)?;
+                        // 只读的 replica 上脚本能跑 但是不能写
+                        REPLICATION.check_command(&command).map_err(mlua::Error::runtime)?;
+
+                        let mut view = sessions.lock().await;
+                        // 脚本里只能碰 KEYS 声明过的 key 没锁住的分片不能访问
+                        if let Some(key) = command.keys().iter().find(|key| !view.covers(key)) {
+                            return Err(mlua::Error::runtime(format!(
+                                "lua 脚本访问了未在 KEYS 中声明的 key: {}",
+                                key.key
+                            )));
+                        }
+
+                        // 执行层代码复用 多 key 命令直接用同一个视图
+                        execute_command_hook(&command, db_clone, content, Some(&mut *view))
+                            .await
+                            .map_err(|e| mlua::Error::runtime(e.to_string()))
                     }
                 },
             )
@@ -173,13 +169,32 @@ impl EvalCommand {
             // 这样你的系统内部就统一了
             KvError::ProtocolError(format!("Lua脚本错误: {}", e))
         });
-        let mut entry = sessions.lock().await;
-        //拿出arc 的所有权 并且全部提交
-        for (_size, lock) in entry.drain() {
+        //拿出视图的所有权 并且全部提交
+        //脚本的改动按提交后的结果包在 MULTI/EXEC 里写 AOF 写完才放锁 和普通命令一样保证顺序
+        let mut view = std::mem::take(&mut *sessions.lock().await);
+        let writing = view.take_writing();
+        let mut committed = Vec::new();
+        let mut effects = Vec::new();
+        for lock in view.into_locks() {
             if let LockedDb::Write(lock_mut) = lock {
-                lock_mut.as_transactional().unwrap().commit().await;
+                let mut node = lock_mut.as_transactional().unwrap();
+                effects.extend(node.commit().await);
+                committed.push(node);
             }
         }
+        if let Some(content) = &aof_content {
+            send_multi_aof(
+                AofContent {
+                    aof_tx: &content.aof_tx,
+                    shutdown_tx: &content.shutdown_tx,
+                    epoch: writing.as_ref().map_or(0, Writing::epoch),
+                },
+                effects,
+            )
+            .await;
+        }
+        drop(committed);
+        drop(writing);
         final_result
     }
 }
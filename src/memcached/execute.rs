@@ -0,0 +1,430 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    acl::ACL,
+    aof_exchange::{AofContent, send_argv_aof},
+    command_execute::parse_int_from_bytes,
+    context::{CONN_STATE, ConnectionContent},
+    core_aof::AOF_OFFSET,
+    core_keyspec::{KeyAccess, KeyRef},
+    core_loading::LOADING,
+    core_replication::REPLICATION,
+    core_time::get_cached_time_ms,
+    db::{
+        Db,
+        lock_plan::{LockPlan, LockedShards},
+    },
+    error::{Command, Frame},
+    memcached::protocol::{Request, StoreMode},
+    types::{Element, Value, ValueEntry},
+};
+
+/*
+   memcached 命令落到同一份 Storage 上
+   1.值就是 Simple 字符串 flags 和 CAS 版本号存在 ValueEntry 里 过期时间也是同一个 expires_at
+   2.RESP 写进来的字符串 memcached 一样能读 反过来也一样 其他类型在 memcached 看来就是不存在
+   3.写操作和 RESP 一样在持有锁的时候写 AOF 落成等价的 SET ... PXAT / DEL
+     flags 非 0 的时候带上 MCFLAGS 重放和复制之后 flags 不丢 (客户端的 SET 不认 见 Command::from_replay)
+   4.权限按连接登录的 ACL 用户检查 每条请求换成等价的 RESP 命令去查 (get -> MGET 写 -> SET ...)
+     ACL SETUSER 把 default 关掉或者改了权限 已经连着的 memcached 客户端下一条就生效
+*/
+
+// exptime 不超过 30 天算相对秒数 超过了就是 unix 时间戳 memcached 的规矩
+const RELATIVE_EXPTIME_MAX: i64 = 60 * 60 * 24 * 30;
+
+enum Expiry {
+    Never,
+    At(u64),
+    // 负数或者已经过去的时间戳 相当于马上过期
+    Expired,
+}
+
+fn expiry_from_exptime(exptime: i64) -> Expiry {
+    let now = get_cached_time_ms();
+    let expires_at = match exptime {
+        0 => return Expiry::Never,
+        e if e < 0 => return Expiry::Expired,
+        e if e <= RELATIVE_EXPTIME_MAX => now + e as u64 * 1000,
+        e => e as u64 * 1000,
+    };
+    if expires_at <= now {
+        Expiry::Expired
+    } else {
+        Expiry::At(expires_at)
+    }
+}
+
+// 存储里的值还原成 memcached 的数据块 不是字符串类型就当没有
+fn item_data(entry: &ValueEntry) -> Option<Bytes> {
+    match &entry.data {
+        Value::Simple(Element::String(bytes)) => Some(bytes.clone()),
+        Value::Simple(Element::Int(i)) => Some(parse_int_from_bytes(*i)),
+        _ => None,
+    }
+}
+
+// 当前连接选中的库上 单个 key 的锁
+async fn lock_key(db: &Db, key: &str, access: KeyAccess) -> LockedShards {
+    let db_index = CONN_STATE.with(|state| state.selected_db.get());
+    let plan = LockPlan::new(
+        db_index,
+        &[KeyRef {
+            key: key.to_string(),
+            access,
+        }],
+    );
+    db.store.lock_plan(&plan).await
+}
+
+fn aof_content(content: &ConnectionContent, epoch: u64) -> AofContent<'_> {
+    AofContent {
+        aof_tx: &content.aof_tx,
+        shutdown_tx: &content.shutdown_tx,
+        epoch,
+    }
+}
+
+// 写入在 AOF 里落成 SET key value [PXAT ms] [MCFLAGS flags]
+async fn append_set_aof(content: &ConnectionContent, epoch: u64, key: &str, entry: &ValueEntry) {
+    let Some(data) = item_data(entry) else {
+        return;
+    };
+    let mut argv = vec![Bytes::from_static(b"SET"), Bytes::from(key.to_string()), data];
+    if let Some(expires_at) = entry.expires_at {
+        argv.push(Bytes::from_static(b"PXAT"));
+        argv.push(parse_int_from_bytes(expires_at as i64));
+    }
+    if entry.flags != 0 {
+        argv.push(Bytes::from_static(b"MCFLAGS"));
+        argv.push(parse_int_from_bytes(entry.flags as i64));
+    }
+    send_argv_aof(aof_content(content, epoch), argv).await;
+}
+
+async fn append_del_aof(content: &ConnectionContent, epoch: u64, keys: Vec<Arc<String>>) {
+    let mut argv = vec![Bytes::from_static(b"DEL")];
+    argv.extend(keys.iter().map(|key| Bytes::from(key.to_string())));
+    send_argv_aof(aof_content(content, epoch), argv).await;
+}
+
+// 权限检查用的等价 RESP 命令 flush_all 不在命令表里 只有 allcommands 的用户能执行
+fn acl_command(request: &Request) -> Option<Command> {
+    let argv: Vec<&str> = match request {
+        Request::Get { keys, .. } => std::iter::once("MGET").chain(keys.iter().map(String::as_str)).collect(),
+        Request::Store { key, .. } | Request::Arith { key, .. } => vec!["SET", key, ""],
+        Request::Delete { key, .. } => vec!["DEL", key],
+        Request::Touch { key, .. } => vec!["PEXPIREAT", key, "0"],
+        Request::FlushAll { .. } => vec!["FLUSHALL"],
+        Request::Version | Request::Quit => return None,
+    };
+    let frames = argv
+        .into_iter()
+        .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+        .collect();
+    Command::try_from(Frame::Array(frames)).ok()
+}
+
+/// 执行一条请求 返回要写回客户端的内容 noreply 的时候是空的
+pub async fn execute(request: Request, db: &Db, content: &ConnectionContent) -> Vec<u8> {
+    if let Some(command) = acl_command(&request)
+        && let Err(msg) = ACL.check_command(&command, "toplevel")
+    {
+        return format!("CLIENT_ERROR {}\r\n", msg).into_bytes();
+    }
+    // 启动加载数据期间只回版本号 别的等加载完再说
+    if LOADING.is_loading() && !matches!(request, Request::Version | Request::Quit) {
+        return b"SERVER_ERROR loading the dataset in memory\r\n".to_vec();
+    }
+    // 只读的 replica 上只能读
+    if REPLICATION.read_only()
+        && !matches!(request, Request::Get { .. } | Request::Version | Request::Quit)
+    {
+        return b"SERVER_ERROR You can't write against a read only replica\r\n".to_vec();
+    }
+    // AOF 写坏了 写命令都拒绝
+    if let Some(msg) = AOF_OFFSET.poisoned()
+        && !matches!(request, Request::Get { .. } | Request::Version | Request::Quit)
+    {
+        return format!("SERVER_ERROR Errors writing to the AOF file: {}\r\n", msg).into_bytes();
+    }
+    let (reply, noreply) = match request {
+        Request::Get { keys, with_cas } => (get(&keys, with_cas, db).await, false),
+        Request::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data,
+            cas_unique,
+            noreply,
+        } => {
+            let item = StoreItem {
+                flags,
+                exptime,
+                data,
+                cas_unique,
+            };
+            (store(mode, key, item, db, content).await, noreply)
+        }
+        Request::Delete { key, noreply } => (delete(key, db, content).await, noreply),
+        Request::Arith {
+            key,
+            delta,
+            incr,
+            noreply,
+        } => (arith(key, delta, incr, db, content).await, noreply),
+        Request::Touch {
+            key,
+            exptime,
+            noreply,
+        } => (touch(key, exptime, db, content).await, noreply),
+        Request::FlushAll { delay, noreply } => {
+            flush_all(delay, db, content).await;
+            ("OK\r\n".into(), noreply)
+        }
+        Request::Version => (format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into(), false),
+        // 连接循环自己处理 quit
+        Request::Quit => (Vec::new(), true),
+    };
+    if noreply { Vec::new() } else { reply }
+}
+
+async fn get(keys: &[String], with_cas: bool, db: &Db) -> Vec<u8> {
+    let mut reply = Vec::new();
+    for key in keys {
+        let key = Arc::new(key.clone());
+        let mut view = lock_key(db, &key, KeyAccess::Read).await;
+        let Some(map) = view.read(&key) else {
+            continue;
+        };
+        let Some(entry) = map.select(&key).await else {
+            continue;
+        };
+        let Some(data) = item_data(entry) else {
+            continue;
+        };
+        let header = if with_cas {
+            format!("VALUE {} {} {} {}\r\n", key, entry.flags, data.len(), entry.cas)
+        } else {
+            format!("VALUE {} {} {}\r\n", key, entry.flags, data.len())
+        };
+        reply.extend_from_slice(header.as_bytes());
+        reply.extend_from_slice(&data);
+        reply.extend_from_slice(b"\r\n");
+    }
+    reply.extend_from_slice(b"END\r\n");
+    reply
+}
+
+struct StoreItem {
+    flags: u32,
+    exptime: i64,
+    data: Bytes,
+    cas_unique: u64,
+}
+
+async fn store(
+    mode: StoreMode,
+    key: String,
+    item: StoreItem,
+    db: &Db,
+    content: &ConnectionContent,
+) -> Vec<u8> {
+    let key = Arc::new(key);
+    let mut view = lock_key(db, &key, KeyAccess::Write).await;
+    let epoch = view.epoch();
+    let Some(map) = view.write(&key) else {
+        return b"SERVER_ERROR out of memory\r\n".to_vec();
+    };
+    // (数据 flags 过期时间 CAS) 不是字符串类型的 key 数据是 None
+    let current = map.select(&key).await.map(|entry| {
+        (item_data(entry), entry.flags, entry.expires_at, entry.cas)
+    });
+    let entry = match (mode, current) {
+        (StoreMode::Add, Some(_)) => return b"NOT_STORED\r\n".to_vec(),
+        (StoreMode::Replace | StoreMode::Append | StoreMode::Prepend, None) => {
+            return b"NOT_STORED\r\n".to_vec();
+        }
+        (StoreMode::Cas, None) => return b"NOT_FOUND\r\n".to_vec(),
+        (StoreMode::Cas, Some((_, _, _, cas))) if cas != item.cas_unique => {
+            return b"EXISTS\r\n".to_vec();
+        }
+        // append/prepend 不改 flags 和过期时间
+        (StoreMode::Append | StoreMode::Prepend, Some((data, flags, expires_at, _))) => {
+            let Some(data) = data else {
+                return b"NOT_STORED\r\n".to_vec();
+            };
+            let mut joined = BytesMut::with_capacity(data.len() + item.data.len());
+            if mode == StoreMode::Append {
+                joined.extend_from_slice(&data);
+                joined.extend_from_slice(&item.data);
+            } else {
+                joined.extend_from_slice(&item.data);
+                joined.extend_from_slice(&data);
+            }
+            ValueEntry::new(Value::Simple(Element::String(joined.freeze())), expires_at)
+                .with_flags(flags)
+        }
+        _ => {
+            let expires_at = match expiry_from_exptime(item.exptime) {
+                Expiry::Never => None,
+                Expiry::At(expires_at) => Some(expires_at),
+                // 存进去马上就过期 等于删掉
+                Expiry::Expired => {
+                    map.delete(&key).await;
+                    append_del_aof(content, epoch, vec![key]).await;
+                    return b"STORED\r\n".to_vec();
+                }
+            };
+            ValueEntry::new(Value::Simple(Element::String(item.data)), expires_at)
+                .with_flags(item.flags)
+        }
+    };
+    append_set_aof(content, epoch, &key, &entry).await;
+    map.insert(key, entry).await;
+    b"STORED\r\n".to_vec()
+}
+
+async fn delete(key: String, db: &Db, content: &ConnectionContent) -> Vec<u8> {
+    let key = Arc::new(key);
+    let mut view = lock_key(db, &key, KeyAccess::Write).await;
+    let epoch = view.epoch();
+    let Some(map) = view.write(&key) else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    if map.select(&key).await.is_none() {
+        return b"NOT_FOUND\r\n".to_vec();
+    }
+    map.delete(&key).await;
+    append_del_aof(content, epoch, vec![key]).await;
+    b"DELETED\r\n".to_vec()
+}
+
+async fn arith(key: String, delta: u64, incr: bool, db: &Db, content: &ConnectionContent) -> Vec<u8> {
+    let key = Arc::new(key);
+    let mut view = lock_key(db, &key, KeyAccess::Write).await;
+    let epoch = view.epoch();
+    let Some(map) = view.write(&key) else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    let Some(entry) = map.select(&key).await else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    let current = item_data(entry)
+        .and_then(|data| std::str::from_utf8(&data).ok()?.trim_end().parse::<u64>().ok());
+    let Some(current) = current else {
+        return b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec();
+    };
+    // incr 溢出回绕 decr 最小到 0 都是 memcached 的行为
+    let value = if incr {
+        current.wrapping_add(delta)
+    } else {
+        current.saturating_sub(delta)
+    };
+    let element = match i64::try_from(value) {
+        Ok(i) => Element::Int(i),
+        Err(_) => Element::String(Bytes::from(value.to_string())),
+    };
+    let entry = ValueEntry::new(Value::Simple(element), entry.expires_at).with_flags(entry.flags);
+    append_set_aof(content, epoch, &key, &entry).await;
+    map.insert(key, entry).await;
+    format!("{}\r\n", value).into_bytes()
+}
+
+async fn touch(key: String, exptime: i64, db: &Db, content: &ConnectionContent) -> Vec<u8> {
+    let key = Arc::new(key);
+    let mut view = lock_key(db, &key, KeyAccess::Write).await;
+    let epoch = view.epoch();
+    let Some(map) = view.write(&key) else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    let Some(entry) = map.select(&key).await else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    let expires_at = match expiry_from_exptime(exptime) {
+        Expiry::Never => None,
+        Expiry::At(expires_at) => Some(expires_at),
+        Expiry::Expired => {
+            map.delete(&key).await;
+            append_del_aof(content, epoch, vec![key]).await;
+            return b"TOUCHED\r\n".to_vec();
+        }
+    };
+    // 只改过期时间 数据、flags、CAS 都不变
+    let mut touched = ValueEntry::new(entry.data.clone(), expires_at).with_flags(entry.flags);
+    touched.cas = entry.cas;
+    append_set_aof(content, epoch, &key, &touched).await;
+    map.insert(key, touched).await;
+    b"TOUCHED\r\n".to_vec()
+}
+
+/// 清空当前库 带延迟的话到点了再清 到点之前写进来的也一起清掉 和 memcached 一样
+async fn flush_all(delay: i64, db: &Db, content: &ConnectionContent) {
+    let db_index = CONN_STATE.with(|state| state.selected_db.get());
+    let Some(delay) = flush_delay(delay, get_cached_time_ms()) else {
+        flush_db(db_index, db, content).await;
+        return;
+    };
+    let db = db.clone();
+    let content = content.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        flush_db(db_index, &db, &content).await;
+    });
+}
+
+// 和 exptime 一样 超过 30 天的是 unix 时间戳 None 表示马上清
+fn flush_delay(delay: i64, now_ms: u64) -> Option<Duration> {
+    let delay_ms = match delay {
+        d if d <= 0 => return None,
+        d if d <= RELATIVE_EXPTIME_MAX => d as u64 * 1000,
+        d => (d as u64 * 1000).checked_sub(now_ms)?,
+    };
+    (delay_ms > 0).then(|| Duration::from_millis(delay_ms))
+}
+
+async fn flush_db(db_index: usize, db: &Db, content: &ConnectionContent) {
+    let mut view = db.store.lock_plan(&LockPlan::whole_db(db_index)).await;
+    let epoch = view.epoch();
+    let mut deleted = Vec::new();
+    for operator in view.operators() {
+        for key in operator.keys() {
+            operator.delete(&key).await;
+            deleted.push(key);
+        }
+    }
+    if !deleted.is_empty() {
+        append_del_aof(content, epoch, deleted).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_delay() {
+        let now_ms = 1_700_000_000_000;
+        assert_eq!(flush_delay(0, now_ms), None);
+        assert_eq!(flush_delay(-1, now_ms), None);
+        assert_eq!(flush_delay(10, now_ms), Some(Duration::from_secs(10)));
+        assert_eq!(flush_delay(RELATIVE_EXPTIME_MAX, now_ms), Some(Duration::from_secs(RELATIVE_EXPTIME_MAX as u64)));
+        // 超过 30 天的是绝对时间
+        assert_eq!(flush_delay(1_700_000_060, now_ms), Some(Duration::from_secs(60)));
+        assert_eq!(flush_delay(1_699_999_000, now_ms), None);
+        assert_eq!(flush_delay(1_700_000_000, now_ms), None);
+    }
+
+    #[test]
+    fn test_acl_command() {
+        let get = Request::Get { keys: vec!["a".into(), "b".into()], with_cas: false };
+        assert_eq!(acl_command(&get).unwrap().name(), "mget");
+        let delete = Request::Delete { key: "a".into(), noreply: false };
+        assert_eq!(acl_command(&delete).unwrap().name(), "del");
+        let flush = Request::FlushAll { delay: 0, noreply: false };
+        assert!(acl_command(&flush).unwrap().spec().is_none());
+        assert!(acl_command(&Request::Version).is_none());
+    }
+}
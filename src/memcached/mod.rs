@@ -0,0 +1,81 @@
+use std::error::Error;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    context::ConnectionContent,
+    core_codec::FrameCodec,
+    db::Db,
+    memcached::{
+        execute::execute,
+        protocol::{Parsed, Request, parse_request},
+    },
+};
+
+mod execute;
+mod protocol;
+
+/*
+   memcached 文本协议的前端
+   1.单独的端口 连接的读写缓冲、批量 flush、背压都复用 FrameCodec
+   2.解析在 protocol 里 执行在 execute 里 数据和 RESP 是同一份
+*/
+
+pub async fn handle_memcached_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    db: Db,
+    connection_content: ConnectionContent,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut codec = FrameCodec::new(socket);
+    let mut receiver = connection_content.shutdown_tx.subscribe();
+    loop {
+        let n = tokio::select! {
+            res = codec.read_more() => res?,
+            _ = receiver.recv() => break,
+        };
+        // 客户端主动关闭
+        if n == 0 {
+            break;
+        }
+        let keep_open = execute_buffered(&mut codec, &db, &connection_content).await?;
+        let durable = codec.flush_durable(aof_error_reply).await?;
+        if !keep_open || !durable {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// 把缓冲区里完整的请求按顺序执行完 返回 false 表示要断开连接
+async fn execute_buffered<S: AsyncRead + AsyncWrite + Unpin>(
+    codec: &mut FrameCodec<S>,
+    db: &Db,
+    connection_content: &ConnectionContent,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    loop {
+        if codec.should_flush() && !codec.flush_durable(aof_error_reply).await? {
+            return Ok(false);
+        }
+        match parse_request(codec.buffered()) {
+            Parsed::Incomplete => return Ok(true),
+            Parsed::Request(Request::Quit, _) => return Ok(false),
+            Parsed::Request(request, size) => {
+                codec.consume(size);
+                let reply = execute(request, db, connection_content).await;
+                codec.write_raw(&reply);
+            }
+            Parsed::Invalid(reply, size) => {
+                codec.consume(size);
+                codec.write_raw(format!("{}\r\n", reply).as_bytes());
+            }
+            Parsed::Fatal(reply) => {
+                codec.write_raw(format!("{}\r\n", reply).as_bytes());
+                return Ok(false);
+            }
+        }
+    }
+}
+
+fn aof_error_reply(msg: &str) -> Vec<u8> {
+    format!("SERVER_ERROR {}\r\n", msg).into_bytes()
+}
@@ -0,0 +1,309 @@
+use bytes::Bytes;
+
+/*
+   memcached 文本协议的解析
+   1.一行一个命令 以 \r\n 结尾 (兼容只有 \n 的)
+   2.存储类命令 (set/add/replace/append/prepend/cas) 命令行后面还跟着 <bytes> 字节的数据块和 \r\n
+   3.命令行格式有问题只跳过这一行 回一个错误 后面的命令照常处理
+     数据块没有以 \r\n 结尾就把 <bytes>+2 一起跳过 和 memcached 的 swallow 一样
+*/
+
+// 命令行最长多少 超过了还没有换行说明不是正常的客户端
+const MAX_LINE_SIZE: usize = 2048;
+// key 最长 250 字节 memcached 的硬限制
+const MAX_KEY_SIZE: usize = 250;
+// 单个值最大 1MB 和 memcached 默认的 item_size_max 一样
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // gets 比 get 多回一个 CAS 版本号
+    Get {
+        keys: Vec<String>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Bytes,
+        // 只有 cas 命令用
+        cas_unique: u64,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    // incr 和 decr
+    Arith {
+        key: String,
+        delta: u64,
+        incr: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: i64,
+        noreply: bool,
+    },
+    FlushAll {
+        delay: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Parsed {
+    // 数据还不完整 等下次读
+    Incomplete,
+    // 一条完整的请求 和它占用的字节数
+    Request(Request, usize),
+    // 这条请求有问题 回复这个错误 跳过这么多字节继续
+    Invalid(String, usize),
+    // 数据已经没法对齐了 回复错误之后断开连接
+    Fatal(String),
+}
+
+pub fn parse_request(buf: &[u8]) -> Parsed {
+    let Some(newline) = memchr::memchr(b'\n', buf) else {
+        if buf.len() > MAX_LINE_SIZE {
+            return Parsed::Fatal("CLIENT_ERROR line too long".into());
+        }
+        return Parsed::Incomplete;
+    };
+    let line_size = newline + 1;
+    let line = match buf[..newline].last() {
+        Some(b'\r') => &buf[..newline - 1],
+        _ => &buf[..newline],
+    };
+    let tokens: Vec<&[u8]> = line
+        .split(|b| *b == b' ')
+        .filter(|token| !token.is_empty())
+        .collect();
+    let Some(name) = tokens.first() else {
+        return Parsed::Invalid("ERROR".into(), line_size);
+    };
+    let args = &tokens[1..];
+    let store_mode = match name.to_ascii_lowercase().as_slice() {
+        b"set" => StoreMode::Set,
+        b"add" => StoreMode::Add,
+        b"replace" => StoreMode::Replace,
+        b"append" => StoreMode::Append,
+        b"prepend" => StoreMode::Prepend,
+        b"cas" => StoreMode::Cas,
+        other => {
+            return match parse_simple(other, args) {
+                Ok(request) => Parsed::Request(request, line_size),
+                Err(reply) => Parsed::Invalid(reply, line_size),
+            };
+        }
+    };
+    parse_store(store_mode, args, &buf[line_size..], line_size)
+}
+
+/// 存储类命令 <key> <flags> <exptime> <bytes> [<cas unique>] [noreply] 后面跟数据块
+fn parse_store(mode: StoreMode, args: &[&[u8]], rest: &[u8], line_size: usize) -> Parsed {
+    let required = if mode == StoreMode::Cas { 5 } else { 4 };
+    let (args, noreply) = split_noreply(args);
+    if args.len() != required {
+        return Parsed::Invalid("ERROR".into(), line_size);
+    }
+    let header = (|| {
+        let key = parse_key(args[0])?;
+        let flags = parse_number::<u32>(args[1])?;
+        let exptime = parse_number::<i64>(args[2])?;
+        let size = parse_number::<usize>(args[3])?;
+        let cas_unique = match mode {
+            StoreMode::Cas => parse_number::<u64>(args[4])?,
+            _ => 0,
+        };
+        Some((key, flags, exptime, size, cas_unique))
+    })();
+    let Some((key, flags, exptime, size, cas_unique)) = header else {
+        return Parsed::Invalid(BAD_FORMAT.into(), line_size);
+    };
+    if size > MAX_ITEM_SIZE {
+        // 数据块太大了 不能为了跳过它一直攒在内存里 直接断开
+        return Parsed::Fatal("SERVER_ERROR object too large for cache".into());
+    }
+    if rest.len() < size + 2 {
+        return Parsed::Incomplete;
+    }
+    let total = line_size + size + 2;
+    if &rest[size..size + 2] != b"\r\n" {
+        return Parsed::Invalid("CLIENT_ERROR bad data chunk".into(), total);
+    }
+    Parsed::Request(
+        Request::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data: Bytes::copy_from_slice(&rest[..size]),
+            cas_unique,
+            noreply,
+        },
+        total,
+    )
+}
+
+/// 没有数据块的命令 出错返回要回给客户端的那一行
+fn parse_simple(name: &[u8], args: &[&[u8]]) -> Result<Request, String> {
+    let (args, noreply) = split_noreply(args);
+    match (name, args) {
+        (b"get" | b"gets", [_, ..]) => Ok(Request::Get {
+            keys: args
+                .iter()
+                .map(|key| parse_key(key))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(BAD_FORMAT)?,
+            with_cas: name == b"gets",
+        }),
+        (b"delete", [key]) => Ok(Request::Delete {
+            key: parse_key(key).ok_or(BAD_FORMAT)?,
+            noreply,
+        }),
+        (b"incr" | b"decr", [key, delta]) => Ok(Request::Arith {
+            key: parse_key(key).ok_or(BAD_FORMAT)?,
+            delta: parse_number(delta).ok_or("CLIENT_ERROR invalid numeric delta argument")?,
+            incr: name == b"incr",
+            noreply,
+        }),
+        (b"touch", [key, exptime]) => Ok(Request::Touch {
+            key: parse_key(key).ok_or(BAD_FORMAT)?,
+            exptime: parse_number(exptime).ok_or("CLIENT_ERROR invalid exptime argument")?,
+            noreply,
+        }),
+        (b"flush_all", []) => Ok(Request::FlushAll { delay: 0, noreply }),
+        (b"flush_all", [delay]) => Ok(Request::FlushAll {
+            delay: parse_number(delay).ok_or(BAD_FORMAT)?,
+            noreply,
+        }),
+        (b"version", []) => Ok(Request::Version),
+        (b"quit", []) => Ok(Request::Quit),
+        _ => Err("ERROR".into()),
+    }
+}
+
+// 最后一个参数是 noreply 的话拿掉 并且记下来
+fn split_noreply<'a, 'b>(args: &'a [&'b [u8]]) -> (&'a [&'b [u8]], bool) {
+    match args.split_last() {
+        Some((last, rest)) if *last == b"noreply" => (rest, true),
+        _ => (args, false),
+    }
+}
+
+fn parse_key(key: &[u8]) -> Option<String> {
+    if key.len() > MAX_KEY_SIZE || key.iter().any(|b| b.is_ascii_control()) {
+        return None;
+    }
+    String::from_utf8(key.to_vec()).ok()
+}
+
+fn parse_number<T: std::str::FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_store() {
+        let buf = b"set foo 5 0 3\r\nbar\r\nget";
+        let Parsed::Request(request, size) = parse_request(buf) else {
+            panic!("解析失败");
+        };
+        assert_eq!(size, 20);
+        assert_eq!(
+            request,
+            Request::Store {
+                mode: StoreMode::Set,
+                key: "foo".into(),
+                flags: 5,
+                exptime: 0,
+                data: Bytes::from_static(b"bar"),
+                cas_unique: 0,
+                noreply: false,
+            }
+        );
+        // 数据块没到齐
+        assert_eq!(parse_request(b"set foo 0 0 3\r\nba"), Parsed::Incomplete);
+        assert_eq!(parse_request(b"get foo"), Parsed::Incomplete);
+
+        let Parsed::Request(Request::Store { mode, cas_unique, noreply, .. }, _) =
+            parse_request(b"cas foo 0 0 1 42 noreply\r\nx\r\n")
+        else {
+            panic!("解析失败");
+        };
+        assert_eq!((mode, cas_unique, noreply), (StoreMode::Cas, 42, true));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        // 数据块长度不对 整块跳过
+        assert_eq!(
+            parse_request(b"set foo 0 0 2\r\nbar\r\n"),
+            Parsed::Invalid("CLIENT_ERROR bad data chunk".into(), 19)
+        );
+        assert_eq!(
+            parse_request(b"set foo x 0 3\r\nbar\r\n"),
+            Parsed::Invalid(BAD_FORMAT.into(), 15)
+        );
+        assert_eq!(parse_request(b"bogus\r\n"), Parsed::Invalid("ERROR".into(), 7));
+        assert_eq!(parse_request(b"get\r\n"), Parsed::Invalid("ERROR".into(), 5));
+        assert_eq!(
+            parse_request(b"incr foo abc\r\n"),
+            Parsed::Invalid("CLIENT_ERROR invalid numeric delta argument".into(), 14)
+        );
+        let long_key = format!("get {}\r\n", "k".repeat(251));
+        assert!(matches!(parse_request(long_key.as_bytes()), Parsed::Invalid(..)));
+        assert!(matches!(parse_request(&[b'x'; 4096]), Parsed::Fatal(_)));
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        assert_eq!(
+            parse_request(b"gets a b\n"),
+            Parsed::Request(
+                Request::Get {
+                    keys: vec!["a".into(), "b".into()],
+                    with_cas: true
+                },
+                9
+            )
+        );
+        assert_eq!(
+            parse_request(b"decr n 5 noreply\r\n"),
+            Parsed::Request(
+                Request::Arith {
+                    key: "n".into(),
+                    delta: 5,
+                    incr: false,
+                    noreply: true
+                },
+                18
+            )
+        );
+        assert_eq!(
+            parse_request(b"flush_all 10\r\n"),
+            Parsed::Request(Request::FlushAll { delay: 10, noreply: false }, 14)
+        );
+    }
+}
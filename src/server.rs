@@ -1,99 +1,134 @@
-use crate::context::ConnectionContent;
+use crate::acl::ACL;
+use crate::context::{CONN_STATE, ConnectionContent};
+use crate::core_aof::AOF_OFFSET;
+use crate::core_client::{CLIENTS, killed, unless_killed};
 use crate::core_execute::execute_command_normal;
-use crate::core_explain::parse_frame;
+use crate::core_codec::FrameCodec;
+use crate::core_explain::ProtoLimits;
+use crate::core_loading::LOADING;
+use crate::core_replication::{REPLICATION, serve_replica};
+use crate::core_tracking::{TRACKING, push_to_resp2};
 use crate::db::Db;
-use crate::error::{Command, Frame};
-use bytes::{Buf, BytesMut};
+use crate::error::{ClientReply, Command, Frame, PsyncCommand};
 use std::error::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc, watch};
 
 // 1. 我们先定义一个“统一”的返回类型
 enum ConnectionEvent {
-    GotData(usize), // "获胜者"是“数据”，usize 是字节数
+    GotData,        // "获胜者"是“数据” 数据已经读进缓冲区了
     Shutdown,       // "获胜者"是“关闭信号”
     ClientClosed,   // "获胜者"是“客户端自己关了”
+    Push(Frame),    // "获胜者"是“服务端主动推送” 比如客户端缓存失效通知
+    Killed,         // "获胜者"是“被 CLIENT KILL 了”
+}
+
+// 一批请求处理完之后这个连接接下来怎么办
+enum AfterBatch {
+    KeepOpen,
+    // 协议已经乱了 回完错误之后断开 (被 KILL 也是)
+    // 坏帧后面的字节找不到下一帧从哪开始 和 redis 一样不去重新对齐
+    Close,
+    // 对面发了 PSYNC 这个连接从此变成发给 replica 的复制流
+    Replica(PsyncCommand),
 }
 
 // 处理单个客户端连接的函数
-pub async fn handle_connection(
-    mut socket: TcpStream,
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
     mut db: Db,
     mut connection_content: ConnectionContent,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // 1. 使用 Vec<u8> 作为缓冲区
-    let mut buf = BytesMut::with_capacity(1024);
-    // 目前来说用的模式是1 是 redis 格式 0 是单个字符模式
-    let type_fix = 1;
+    // 1. 读写缓冲区都在编解码器里
+    let mut codec = FrameCodec::new(socket).with_limits(ProtoLimits::client());
     //创建订阅者
     let mut receiver = connection_content.shutdown_tx.clone().subscribe();
+    //服务端推送通道 注册之后别的连接的写操作才能找到这里
+    let client_id = CONN_STATE.with(|state| state.client_id);
+    let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Frame>();
+    TRACKING.register_client(client_id, push_tx);
+    // 登记到在线连接表 CLIENT KILL 通过这个通道让连接退出
+    let kill_rx = CLIENTS.register();
+    let result = connection_loop(
+        &mut codec,
+        &mut db,
+        &mut connection_content,
+        &mut receiver,
+        &mut push_rx,
+        &kill_rx,
+    )
+    .await;
+    CLIENTS.unregister(client_id);
+    TRACKING.unregister_client(client_id);
+    result
+}
+
+async fn connection_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    codec: &mut FrameCodec<S>,
+    db: &mut Db,
+    connection_content: &mut ConnectionContent,
+    receiver: &mut broadcast::Receiver<()>,
+    push_rx: &mut mpsc::UnboundedReceiver<Frame>,
+    kill_rx: &watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     // 4. 在该连接的循环中读取数据
     'connection_loop: loop {
         let event = tokio::select! {
-            res = socket.read_buf(&mut buf) =>{
+            res = codec.read_more() =>{
                 let n = res?; // 如果有 I/O 错误，? 会让函数提前 return Err
                 if n == 0 {
                     // 客户端主动关闭
                     ConnectionEvent::ClientClosed
                 } else {
                     // 成功读到 n 字节数据
-                    ConnectionEvent::GotData(n)
+                    ConnectionEvent::GotData
                 }
             }
             _ = receiver.recv() =>{
                     ConnectionEvent::Shutdown
             }
+            Some(frame) = push_rx.recv() =>{
+                    ConnectionEvent::Push(frame)
+            }
+            _ = killed(kill_rx.clone()) =>{
+                    ConnectionEvent::Killed
+            }
         };
 
         match event {
-            ConnectionEvent::GotData(n) => {
-                if type_fix == 0 {
-                    if let Some(index) = find_crlf_idiomatic(&buf) {
-                        println!("{}", index);
-                        // try_parse_command_RESP(&buf[..index], &mut socket).expect("命令处理错误");
-
-                        println!("接收到 {} 字节:  {:?}", n, &buf[..n]);
-
-                        // 5. 【Echo 逻辑】将收到的数据原封不动写回给客户端
-                        socket.write_all(&buf[..index + 2]).await?;
-
-                        buf.advance(index + 2);
+            ConnectionEvent::GotData => {
+                // RESP 和 inline 两种格式在 parse_request 里按每条请求的首字节自动区分
+                let after = explain_execute_command(codec, db, connection_content).await?;
+                // 这一批管道命令的回复一次性写出去 always 模式下整批只等一次落盘
+                // 对面不读的话会卡在这里 被 KILL 了直接断开
+                let Some(durable) = unless_killed(codec.flush_durable(aof_error_reply)).await else {
+                    break 'connection_loop;
+                };
+                if !durable? {
+                    break 'connection_loop;
+                }
+                match after {
+                    AfterBatch::KeepOpen => {}
+                    AfterBatch::Close => break 'connection_loop,
+                    AfterBatch::Replica(psync) => {
+                        serve_replica(codec, db, psync, receiver).await?;
+                        break 'connection_loop;
                     }
-                } else {
-                    println!("{:?}", std::str::from_utf8(&buf));
-                    match explain_execute_command(
-                        &mut buf,
-                        &mut db,
-                        &mut connection_content,
-                    )
-                    .await
-                    {
-                        Ok(result) => {
-                            print!("{}", result.len());
-                            for item in result {
-                                socket.write_all(&item).await?;
-                            }
-                        }
-                        Err(e) => {
-                            // 转换失败（语义错误），准备一个错误响应
-                            let error_response = Frame::Error(e.to_string());
-                            socket.write_all(&error_response.serialize()).await?;
-                            //错误处理 裁减掉错误指令
-                            match buf.windows(2).position(|window| window == b"*") {
-                                Some(index) => {
-                                    buf.advance(index);
-                                }
-                                None => {
-                                    buf.clear();
-                                }
-                            }
-                            // 继续处理缓冲区里的下一个命令
-                            continue;
-                        }
-                    };
                 }
-
-                println!("已回送数据");
+            }
+            ConnectionEvent::Push(frame) => {
+                let protocol = CONN_STATE.with(|state| state.protocol.get());
+                let frame = if protocol >= 3 { frame } else { push_to_resp2(frame) };
+                codec.write_frame(&frame, protocol);
+                match unless_killed(codec.flush()).await {
+                    Some(result) => result?,
+                    None => break 'connection_loop,
+                }
+            }
+            ConnectionEvent::Killed => {
+                // 空闲的时候在这里退出 卡在等待上的由 unless_killed 退出 都不会在命令半路断开
+                println!("连接被 CLIENT KILL 关闭。");
+                break 'connection_loop;
             }
             ConnectionEvent::Shutdown => {
                 println!("客户端主动关闭，退出循环。");
@@ -109,14 +144,13 @@ pub async fn handle_connection(
     Ok(())
 }
 
-async fn explain_execute_command(
-    buf: &mut BytesMut,
+/// 把缓冲区里所有完整的请求按顺序执行完 每条请求都有自己的一条回复
+/// 遇到 PSYNC 就停下来 后面的数据都是复制流的
+async fn explain_execute_command<S: AsyncRead + AsyncWrite + Unpin>(
+    codec: &mut FrameCodec<S>,
     db: &mut Db,
     command_content: &mut ConnectionContent,
-) -> Result<Vec<Vec<u8>>, Box<dyn Error + Send + Sync>> {
-    let mut vec_result: Vec<Vec<u8>> = Vec::new();
-    let mut vec: &[u8] = buf.as_ref();
-    let mut total_size: usize = 0;
+) -> Result<AfterBatch, Box<dyn Error + Send + Sync>> {
     /*
      * 首先盘点一下 由于分层 并且命令是字符串 所以每层都有可能出现错误
      * 1.第一层就是字符串解析成frame层 这个层面会出现的错误有 这个层面 只看是否能结构化成frame 和 具体指令要求无关
@@ -124,38 +158,301 @@ async fn explain_execute_command(
      *  2.发现比如格式错误
      *    1.中间/r/n没有
      *    2.字符串长度和实际标注不匹配
-     *  第一层总体来说就是协议报错 是最底层的问题
+     *  第一层总体来说就是协议报错 是最底层的问题 后面的数据已经没法对齐了
+     *  和 redis 一样 前面的命令照常回复 回一个错误之后断开连接
      * 2.第二层就是frame 转换成command 这个就是要对于frame 生成结构严整
      *  1.首先就是遇到未知指令 返回直接返回说命令没有实现
      *  2.经典的命令长度不匹配 直接返回错误
-     * 这一层是指令格式校验
+     * 这一层是指令格式校验 帧的边界是清楚的 所以只影响这一条命令
      * 3.执行层面的话 这里错误比较少
      *   1.一半就是按照校验执行就行 执行出错的时候很少
      *   2.就是兼容没有实现的指令 这一步返回特定返回值 不需要再上一层就直接返回错误
+     *   同样只影响这一条命令
      */
-    while let Ok(Some((frame, size))) = parse_frame(vec) {
-        match Command::try_from(frame) {
-            //这个错误事第一个指令就错误的错误 就是结构性质错误
-            Ok(command) => match frame {
-                _ => {
-                    //这个事指令错误 而不是结构化错误
-                    let result: Frame = execute_command_normal(command, db, command_content.clone()).await?;
-                    vec_result.push(result.serialize());
-                    vec = &vec[size..];
-                    total_size += size;
-                }
-            },
+    let client_id = CONN_STATE.with(|state| state.client_id);
+    loop {
+        // 管道里还有命令的时候被 KILL 了 剩下的不执行了
+        if CLIENTS.is_killed(client_id) {
+            return Ok(AfterBatch::Close);
+        }
+        // 回复攒太多了先写出去 客户端不读的话就卡在这里 不会继续吃新的请求
+        if codec.should_flush() {
+            let Some(durable) = unless_killed(codec.flush_durable(aof_error_reply)).await else {
+                return Ok(AfterBatch::Close);
+            };
+            if !durable? {
+                return Ok(AfterBatch::Close);
+            }
+        }
+        let frame = match codec.next_request() {
+            Ok(Some(frame)) => frame,
+            // 剩下的数据不完整 等下一次读
+            Ok(None) => return Ok(AfterBatch::KeepOpen),
             Err(e) => {
-                buf.advance(total_size);
-                return Err(e.into());
+                let protocol = CONN_STATE.with(|state| state.protocol.get());
+                codec.write_frame(&Frame::Error(e.to_string()), protocol);
+                return Ok(AfterBatch::Close);
+            }
+        };
+        // 上一条是 CLIENT REPLY SKIP 的话 这一条的回复不发
+        let skip_reply = CONN_STATE.with(|state| {
+            let skip = state.reply.get() == ClientReply::Skip;
+            if skip {
+                state.reply.set(ClientReply::On);
+            }
+            skip
+        });
+        // SUBSCRIBE 这种每个参数各回一条的 执行结果是装在 Array 里的多条回复
+        let mut multi_reply = false;
+        let result = match Command::try_from(frame) {
+            // 结构没问题 先过一遍 ACL 权限不够的直接回错误 不影响后面的命令
+            Ok(command) => {
+                multi_reply = matches!(command, Command::Subscribe(_) | Command::Unsubscribe(_));
+                CLIENTS.touch(
+                    &command_label(&command),
+                    codec.buffered().len(),
+                    codec.pending_output(),
+                );
+                match ACL
+                    .check_command(&command, "toplevel")
+                    .and_then(|_| LOADING.check_command(&command))
+                    .and_then(|_| REPLICATION.check_command(&command))
+                    .and_then(|_| TRACKING.check_command(&command))
+                    .and_then(|_| AOF_OFFSET.check_command(&command))
+                {
+                    Ok(()) => {
+                        if let Command::Psync(psync) = command {
+                            return Ok(AfterBatch::Replica(psync));
+                        }
+                        // CLIENT PAUSE 期间在这里等着
+                        if unless_killed(CLIENTS.wait_unpaused(&command)).await.is_none() {
+                            return Ok(AfterBatch::Close);
+                        }
+                        execute_command_normal(command, db, command_content.clone())
+                            .await
+                            .unwrap_or_else(|e| Frame::Error(e.to_string()))
+                    }
+                    Err(msg) => Frame::Error(msg),
+                }
+            }
+            Err(e) => Frame::Error(e.to_string()),
+        };
+        // HELLO 会在中途切换协议 所以每条命令执行完再读一次协议版本
+        // CLIENT REPLY OFF/SKIP 在执行之后才生效 所以它自己的 OK 也不发
+        let (protocol, reply) = CONN_STATE.with(|state| (state.protocol.get(), state.reply.get()));
+        if reply == ClientReply::On && !skip_reply {
+            match result {
+                Frame::Array(replies) if multi_reply => {
+                    for reply in &replies {
+                        codec.write_frame(reply, protocol);
+                    }
+                }
+                result => codec.write_frame(&result, protocol),
             }
         }
     }
-    buf.advance(total_size);
-    Ok(vec_result)
 }
-// 在缓冲区中查找 CRLF (`\r\n`) 的地道写法。
-// 如果找到，返回 `\r` 的位置索引。
-fn find_crlf_idiomatic(buf: &[u8]) -> Option<usize> {
-    buf.windows(2).position(|window| window == b"\r\n")
+
+// CLIENT LIST 里的 cmd 字段 带子命令的写成 client|list
+fn command_label(command: &Command) -> String {
+    let name = command.name().to_lowercase();
+    match command.spec() {
+        Some(spec) if spec.has_subcommands() => match command.argv().get(1) {
+            Some(sub) => format!("{}|{}", name, String::from_utf8_lossy(sub).to_lowercase()),
+            None => name,
+        },
+        _ => name,
+    }
+}
+
+// 写入没能落盘 和 redis 一样用 MISCONF
+fn aof_error_reply(msg: &str) -> Vec<u8> {
+    let protocol = CONN_STATE.with(|state| state.protocol.get());
+    Frame::Error(format!("MISCONF Errors writing to the AOF file: {}", msg)).serialize_with(protocol)
+}
+
+// 连接级别的测试 别的模块 (比如 core_client) 也用这里的连接
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::config::EvictionType;
+    use crate::context::ConnectionState;
+    use crate::core_aof::AofMessage;
+
+    // 记下服务端刷了几次回复
+    struct CountingStream {
+        inner: DuplexStream,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    pub(crate) struct TestConnection {
+        pub(crate) client: DuplexStream,
+        flushes: Arc<AtomicUsize>,
+        handle: JoinHandle<Result<(), String>>,
+        // 写命令的 AOF 发到这里 留着通道才不会关
+        _aof_rx: mpsc::Receiver<AofMessage>,
+    }
+
+    impl TestConnection {
+        /// 在内存管道上跑一个客户端连接
+        pub(crate) fn open(db: &Db, client_id: u64) -> Self {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let flushes = Arc::new(AtomicUsize::new(0));
+            let (aof_tx, aof_rx) = mpsc::channel(1024);
+            let (shutdown_tx, _) = broadcast::channel(1);
+            let (lua_sender, _) = mpsc::channel(1);
+            let (_, receivce_lua) = flume::unbounded();
+            let content = ConnectionContent {
+                aof_tx,
+                shutdown_tx,
+                lua_sender,
+                receivce_lua,
+            };
+            let stream = CountingStream {
+                inner: server,
+                flushes: flushes.clone(),
+            };
+            let state = ConnectionState::new(0, Some(format!("127.0.0.1:{}", client_id)), client_id);
+            *state.user.borrow_mut() = ACL.default_login();
+            let db = db.clone();
+            let handle = tokio::spawn(CONN_STATE.scope(state, async move {
+                handle_connection(stream, db, content).await.map_err(|e| e.to_string())
+            }));
+            TestConnection {
+                client,
+                flushes,
+                handle,
+                _aof_rx: aof_rx,
+            }
+        }
+
+        /// 发一段原始数据 读到 len 个字节的回复为止
+        pub(crate) async fn exchange(&mut self, request: &[u8], len: usize) -> String {
+            self.client.write_all(request).await.unwrap();
+            self.read(len).await
+        }
+
+        pub(crate) async fn read(&mut self, len: usize) -> String {
+            let mut reply = vec![0u8; len];
+            tokio::time::timeout(Duration::from_secs(5), self.client.read_exact(&mut reply))
+                .await
+                .expect("等回复超时了")
+                .unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+
+        /// 发一条命令 读到 expected 那么长 再比较
+        pub(crate) async fn call(&mut self, argv: &[&str], expected: &str) {
+            let reply = self.exchange(&request(argv), expected.len()).await;
+            assert_eq!(reply, expected, "{:?}", argv);
+        }
+
+        /// 发一条回复是 bulk 的命令 (RESP2 下的 CLIENT LIST/INFO) 返回内容
+        pub(crate) async fn call_bulk(&mut self, argv: &[&str]) -> String {
+            self.client.write_all(&request(argv)).await.unwrap();
+            let header = self.read_line().await;
+            let len: usize = header
+                .strip_prefix('$')
+                .and_then(|len| len.trim_end().parse().ok())
+                .unwrap_or_else(|| panic!("{:?} 回的不是 bulk: {}", argv, header));
+            let body = self.read(len + 2).await;
+            body[..len].to_string()
+        }
+
+        /// 读一行回复 带上结尾的 \r\n
+        pub(crate) async fn read_line(&mut self) -> String {
+            let mut line = Vec::new();
+            while !line.ends_with(b"\r\n") {
+                let mut byte = [0u8; 1];
+                tokio::time::timeout(Duration::from_secs(5), self.client.read_exact(&mut byte))
+                    .await
+                    .expect("等回复超时了")
+                    .unwrap();
+                line.push(byte[0]);
+            }
+            String::from_utf8(line).unwrap()
+        }
+
+        /// 这段时间里什么都没回
+        pub(crate) async fn silent_for(&mut self, duration: Duration) -> bool {
+            let mut byte = [0u8; 1];
+            tokio::time::timeout(duration, self.client.read_exact(&mut byte)).await.is_err()
+        }
+    }
+
+    pub(crate) fn request(argv: &[&str]) -> Vec<u8> {
+        let mut request = format!("*{}\r\n", argv.len());
+        for arg in argv {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        request.into_bytes()
+    }
+
+    // 中间一条有错 只有它自己回错误 前后的回复都在 顺序也不乱 整批只刷一次
+    #[tokio::test]
+    async fn test_pipeline_error_in_the_middle() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut conn = TestConnection::open(&db, 9_100_001);
+        let mut pipeline = request(&["SET", "pipe", "1"]);
+        pipeline.extend(request(&["NOSUCHCOMMAND", "x"]));
+        pipeline.extend(request(&["GET", "pipe"]));
+        pipeline.extend(b"PING\r\n");
+        let expected = "+OK\r\n-ERR unknown command 'NOSUCHCOMMAND'\r\n$1\r\n1\r\n+PONG\r\n";
+        assert_eq!(conn.exchange(&pipeline, expected.len()).await, expected);
+        assert_eq!(conn.flushes.load(Ordering::SeqCst), 1);
+        // 连接还是好的
+        conn.call(&["PING"], "+PONG\r\n").await;
+        assert_eq!(conn.flushes.load(Ordering::SeqCst), 2);
+    }
+
+    // 帧本身坏了后面的字节没法再对齐 和 redis 一样前面的照常回复 回一个协议错误之后断开
+    #[tokio::test]
+    async fn test_framing_error_closes_connection() {
+        let db = Db::new(&EvictionType::LRU);
+        let mut conn = TestConnection::open(&db, 9_100_002);
+        let mut pipeline = request(&["PING"]);
+        pipeline.extend(b"*1\r\n$x\r\nPING\r\n");
+        pipeline.extend(request(&["PING"]));
+        conn.client.write_all(&pipeline).await.unwrap();
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.client.read_to_end(&mut reply))
+            .await
+            .expect("连接没有断开")
+            .unwrap();
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(reply.starts_with("+PONG\r\n-"), "{}", reply);
+        // 坏帧后面的 PING 不执行
+        assert_eq!(reply.matches("PONG").count(), 1, "{}", reply);
+        assert!(conn.handle.await.unwrap().is_ok());
+    }
 }
@@ -0,0 +1,226 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+    },
+};
+
+use crate::config::{Config, TlsAuthClients};
+
+/*
+   TLS 相关
+   1.按配置读证书、私钥、CA 组装 rustls 的 ServerConfig
+   2.tls-auth-clients yes 必须带 CA 签发的客户端证书 optional 带了就校验 no 不要客户端证书
+   3.握手之后可以从客户端证书里拿 CN 映射成 ACL 用户
+*/
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("打开证书 {} 失败: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("解析证书 {} 失败: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{} 里没有证书", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("打开私钥 {} 失败: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("解析私钥 {} 失败: {}", path, e))?
+        .ok_or_else(|| format!("{} 里没有私钥", path))
+}
+
+/// 按配置组装 TLS 接收器 证书有问题直接返回错误 由启动流程决定退出
+pub fn build_acceptor(config: &Config) -> Result<TlsAcceptor, String> {
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err("开启 tls-port 需要同时配置 tls-cert-file 和 tls-key-file".into());
+    };
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth_clients => {
+            let Some(ca_file) = &config.tls_ca_cert_file else {
+                return Err("校验客户端证书需要配置 tls-ca-cert-file".into());
+            };
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(|e| format!("CA 证书不可用: {}", e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth_clients {
+                TlsAuthClients::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+    };
+    let server_config = builder
+        .with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+        .map_err(|e| format!("证书和私钥不匹配: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// 客户端证书 subject 里的 CN 没带证书或者没有 CN 就是 None
+pub fn peer_common_name(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let cert = certs?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, pki_types::ServerName},
+    };
+
+    struct TestPki {
+        dir: std::path::PathBuf,
+        ca: Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl TestPki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("kv-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "kv test ca");
+            let ca = params.self_signed(&ca_key).unwrap();
+            std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+            TestPki { dir, ca, ca_key }
+        }
+
+        fn issue(&self, common_name: &str, usage: ExtendedKeyUsagePurpose) -> (Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            (cert, key)
+        }
+
+        fn path(&self, file: &str) -> Option<String> {
+            Some(self.dir.join(file).to_string_lossy().to_string())
+        }
+
+        fn server_config(&self, auth_clients: TlsAuthClients) -> Config {
+            let (cert, key) = self.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+            std::fs::write(self.dir.join("server.crt"), cert.pem()).unwrap();
+            std::fs::write(self.dir.join("server.key"), key.serialize_pem()).unwrap();
+            Config {
+                tls_port: Some(0),
+                tls_cert_file: self.path("server.crt"),
+                tls_key_file: self.path("server.key"),
+                tls_ca_cert_file: self.path("ca.crt"),
+                tls_auth_clients: auth_clients,
+                ..Config::default()
+            }
+        }
+
+        fn connector(&self, client_cn: Option<&str>) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match client_cn {
+                Some(cn) => {
+                    let (cert, key) = self.issue(cn, ExtendedKeyUsagePurpose::ClientAuth);
+                    let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+                    builder
+                        .with_client_auth_cert(vec![cert.der().clone()], key)
+                        .unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// 在内存管道上跑一次握手 返回服务端看到的客户端 CN 握手失败返回 Err
+    async fn handshake(
+        acceptor: TlsAcceptor,
+        connector: TlsConnector,
+    ) -> Result<Option<String>, std::io::Error> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(b"+OK\r\n").await?;
+            stream.flush().await?;
+            Ok::<_, std::io::Error>(peer_common_name(stream.get_ref().1.peer_certificates()))
+        });
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = async move {
+            let mut stream = connector.connect(name, client_io).await?;
+            stream.write_all(b"PING").await?;
+            stream.flush().await?;
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, std::io::Error>(())
+        };
+        let (client_result, server_result) = tokio::join!(client, server);
+        let server_result = server_result.unwrap();
+        client_result?;
+        server_result
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let pki = TestPki::new("mutual");
+        let acceptor = build_acceptor(&pki.server_config(TlsAuthClients::Yes)).unwrap();
+        let cn = handshake(acceptor.clone(), pki.connector(Some("alice"))).await.unwrap();
+        assert_eq!(cn.as_deref(), Some("alice"));
+        // 必须带证书的时候 没带证书握手失败
+        assert!(handshake(acceptor, pki.connector(None)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_cert() {
+        let pki = TestPki::new("optional");
+        let acceptor = build_acceptor(&pki.server_config(TlsAuthClients::Optional)).unwrap();
+        assert_eq!(handshake(acceptor.clone(), pki.connector(None)).await.unwrap(), None);
+        let cn = handshake(acceptor, pki.connector(Some("bob"))).await.unwrap();
+        assert_eq!(cn.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_build_acceptor_errors() {
+        let pki = TestPki::new("errors");
+        let mut config = pki.server_config(TlsAuthClients::Yes);
+        config.tls_ca_cert_file = None;
+        assert!(build_acceptor(&config).is_err());
+        config.tls_auth_clients = TlsAuthClients::No;
+        assert!(build_acceptor(&config).is_ok());
+        config.tls_key_file = pki.path("ca.crt");
+        assert!(build_acceptor(&config).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use bytes::Bytes;
 
@@ -10,7 +11,7 @@ pub enum Element {
 }
 
 // 第二步：修改顶层的 Value 枚举，让集合类型使用 Element
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     // 对于简单的 K-V，值就是一个 Element
     Simple(Element),
@@ -19,6 +20,8 @@ pub enum Value {
     List(VecDeque<Element>),
     Hash(HashMap<String, Element>), // Hash 的 value 也是 Element
     Set(HashSet<Element>),
+    // 有序集合 成员 -> 分数 目前只有 ZADD/ZRANGE 大部分是从 RDB 导入进来的
+    ZSet(HashMap<Element, f64>),
 }
 
 #[derive(Clone, Debug)]
@@ -27,8 +30,15 @@ pub struct ValueEntry {
     pub expires_at: Option<u64>, // u64 用来存过期时间点的时间戳
     // 关键！这个 Entry 内部的数据(不含key)总共占了多少内存
     pub data_size: usize,
+    // memcached 的 flags 客户端自己定义的 32 位 原样存原样还 RESP 写入的都是 0
+    pub flags: u32,
+    // memcached 的 CAS 版本号 每次写入都会生成新的 entry 所以天然就是每次写入换一个
+    pub cas: u64,
 }
 
+// CAS 版本号全局递增 从 1 开始 0 在 memcached 里表示没有
+static NEXT_CAS: AtomicU64 = AtomicU64::new(1);
+
 impl Value {
     // 只计算分配在【堆 (Heap)】上的额外内存
     // 不包含 Value 枚举本身在栈上的大小
@@ -61,6 +71,12 @@ impl Value {
                 let container_heap = set.capacity() * std::mem::size_of::<Element>();
                 elements_heap + container_heap
             }
+
+            Value::ZSet(zset) => {
+                let elements_heap: usize = zset.keys().map(|e| e.heap_size()).sum();
+                let container_heap = zset.capacity() * (std::mem::size_of::<Element>() + std::mem::size_of::<f64>());
+                elements_heap + container_heap
+            }
         }
     }
 }
@@ -92,9 +108,16 @@ impl ValueEntry {
             data,
             expires_at,
             data_size: total_size, // 这回准了！
+            flags: 0,
+            cas: NEXT_CAS.fetch_add(1, Ordering::Relaxed),
         }
     }
 
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
     pub fn get_size(&self) -> usize {
         self.data_size
     }
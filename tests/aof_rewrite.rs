@@ -0,0 +1,91 @@
+// AOF 重写的端到端测试 要真的重启进程从 AOF 加载才看得出重写出来的 base 和 incr 对不对得上
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use common::{Client, Server, wait_until};
+
+// 几对来回改名的 key 总有几对不在同一个分片
+const PAIRS: usize = 8;
+
+fn info_field(client: &mut Client, field: &str) -> String {
+    let info = client.call(&["INFO", "persistence"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+        .unwrap_or_else(|| panic!("INFO 里没有 {}", field))
+        .to_string()
+}
+
+// RPUSH 重放两遍会多出元素 RENAME 在两个分片之间复制的间隙执行会把值弄丢
+#[test]
+fn test_rewrite_with_concurrent_writes() {
+    let mut server = Server::start("rewrite", &[]);
+    let mut client = server.connect();
+    // 数据多一点 复制的时间长一些 写入更容易落在重写中间
+    for batch in 0..50 {
+        let mut argv = vec!["MSET".to_string()];
+        for i in 0..1000 {
+            argv.push(format!("fill:{}:{}", batch, i));
+            argv.push("x".repeat(32));
+        }
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        assert_eq!(client.call(&argv), "+OK\r\n");
+    }
+    for pair in 0..PAIRS {
+        let key = format!("ren:{}:a", pair);
+        assert_eq!(client.call(&["SET", &key, &pair.to_string()]), "+OK\r\n");
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut writer = server.connect();
+    let writing = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut pushed = 0;
+            // 每一对现在叫 a 还是 b
+            let mut renamed = [false; PAIRS];
+            while !stop.load(Ordering::SeqCst) {
+                pushed += 1;
+                assert_eq!(writer.call(&["RPUSH", "list", "v"]), format!(":{}\r\n", pushed));
+                for (pair, at_b) in renamed.iter_mut().enumerate() {
+                    let (from, to) = if *at_b { ("b", "a") } else { ("a", "b") };
+                    let from = format!("ren:{}:{}", pair, from);
+                    let to = format!("ren:{}:{}", pair, to);
+                    assert_eq!(writer.call(&["RENAME", &from, &to]), "+OK\r\n");
+                    *at_b = !*at_b;
+                }
+            }
+            // 下面是 kill -9 everysec 下最后几条可能还没写进文件 等它们落盘再重启
+            assert_eq!(writer.call(&["WAITAOF", "1", "0", "0"]), "*2\r\n:1\r\n:0\r\n");
+            (pushed, renamed)
+        })
+    };
+    let before: u64 = info_field(&mut client, "aof_rewrites").parse().unwrap();
+    for rewrites in 1..=3 {
+        // 启动时可能排了一次重写 等它做完再开始
+        wait_until("上一次重写", || info_field(&mut client, "aof_rewrite_in_progress") == "0");
+        assert!(client.call(&["BGREWRITEAOF"]).starts_with('+'));
+        let expected = (before + rewrites).to_string();
+        wait_until("重写完成", || info_field(&mut client, "aof_rewrites") == expected);
+    }
+    stop.store(true, Ordering::SeqCst);
+    let (pushed, renamed) = writing.join().unwrap();
+    assert_eq!(info_field(&mut client, "aof_last_bgrewrite_status"), "ok");
+    assert_eq!(info_field(&mut client, "aof_last_write_status"), "ok");
+
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["RPUSH", "list", "v"]), format!(":{}\r\n", pushed + 1));
+    for (pair, at_b) in renamed.into_iter().enumerate() {
+        let (present, absent) = if at_b { ("b", "a") } else { ("a", "b") };
+        let value = pair.to_string();
+        assert_eq!(
+            client.call(&["GET", &format!("ren:{}:{}", pair, present)]),
+            format!("${}\r\n{}\r\n", value.len(), value)
+        );
+        assert_eq!(client.call(&["GET", &format!("ren:{}:{}", pair, absent)]), "$-1\r\n");
+    }
+    assert_eq!(client.call(&["GET", "fill:49:999"]), format!("$32\r\n{}\r\n", "x".repeat(32)));
+}
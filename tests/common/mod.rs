@@ -0,0 +1,130 @@
+// 集成测试共用的 起一个 kv 进程 用 RESP 和它说话
+// 每个 tests/*.rs 是单独的 crate 不是每个都用得到全部的函数
+#![allow(dead_code)]
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    dir: PathBuf,
+    args: Vec<String>,
+}
+
+impl Server {
+    pub fn start(name: &str, args: &[&str]) -> Server {
+        let port = free_port();
+        let dir = std::env::temp_dir().join(format!("kv-test-{}-{}-{}", name, std::process::id(), port));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let child = spawn(port, &dir, &args);
+        let server = Server { child, port, dir, args };
+        wait_until("服务启动", || TcpStream::connect(("127.0.0.1", port)).is_ok());
+        server
+    }
+
+    /// 杀掉进程 在同一个目录同一个端口上再起一次 等数据加载完
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.child = spawn(self.port, &self.dir, &self.args);
+        wait_until("服务重启", || {
+            TcpStream::connect(("127.0.0.1", self.port)).is_ok()
+                && !self.connect().call(&["GET", "restart"]).starts_with("-LOADING")
+        });
+    }
+
+    pub fn connect(&self) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Client { stream }
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// 日志追加写 重启之前的也留着
+fn spawn(port: u16, dir: &Path, args: &[String]) -> Child {
+    let log = File::options()
+        .create(true)
+        .append(true)
+        .open(dir.join("server.log"))
+        .unwrap();
+    Command::new(env!("CARGO_BIN_EXE_kv"))
+        .arg("--port")
+        .arg(port.to_string())
+        .args(args)
+        .current_dir(dir)
+        .stdout(log.try_clone().unwrap())
+        .stderr(log)
+        .stdin(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    // 这里的回复都是单行的或者一个 bulk 读到 \r\n 结尾就够了
+    pub fn call(&mut self, argv: &[&str]) -> String {
+        let mut request = format!("*{}\r\n", argv.len());
+        for arg in argv {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.stream.write_all(request.as_bytes()).unwrap();
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = self.stream.read(&mut buf).unwrap();
+            assert!(n > 0, "连接被关了");
+            reply.extend_from_slice(&buf[..n]);
+            if complete(&reply) {
+                return String::from_utf8_lossy(&reply).into_owned();
+            }
+        }
+    }
+}
+
+fn complete(reply: &[u8]) -> bool {
+    let Some(end) = reply.windows(2).position(|w| w == b"\r\n") else {
+        return false;
+    };
+    match reply[0] {
+        b'$' => {
+            let len: i64 = std::str::from_utf8(&reply[1..end]).unwrap().parse().unwrap();
+            len < 0 || reply.len() >= end + 2 + len as usize + 2
+        }
+        _ => true,
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub fn wait_until(what: &str, mut check: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !check() {
+        assert!(started.elapsed() < Duration::from_secs(15), "等 {} 超时了", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,112 @@
+// 主从复制的端到端测试 CONFIG 和 REPLICATION 都是进程里全局的 一个进程里起不了两个节点
+// 所以这里直接起两个 kv 进程 各自在自己的临时目录里跑
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use common::{Server, wait_until};
+
+#[test]
+fn test_full_sync_continue_and_readonly() {
+    let master = Server::start("master", &[]);
+    let mut client = master.connect();
+    // 全量同步前就有的数据 走快照过去
+    assert_eq!(client.call(&["SET", "before", "1"]), "+OK\r\n");
+    assert_eq!(client.call(&["HSET", "hash", "field", "v"]), ":1\r\n");
+
+    let port = master.port.to_string();
+    let replica = Server::start("replica", &["--replicaof", "127.0.0.1", &port]);
+    let mut reader = replica.connect();
+    wait_until("全量同步", || reader.call(&["GET", "before"]) == "$1\r\n1\r\n");
+    assert_eq!(reader.call(&["HGET", "hash", "field"]), "$1\r\nv\r\n");
+
+    // 之后的写入走复制流
+    assert_eq!(client.call(&["SET", "after", "2"]), "+OK\r\n");
+    wait_until("复制流", || reader.call(&["GET", "after"]) == "$1\r\n2\r\n");
+
+    // replica 上不能写
+    assert!(reader.call(&["SET", "after", "3"]).starts_with("-READONLY"));
+    // HELLO 报的角色要对 回复很短 一次就读完了
+    assert!(reader.call(&["HELLO"]).contains("$4\r\nrole\r\n$7\r\nreplica\r\n"));
+    assert!(client.call(&["HELLO"]).contains("$4\r\nrole\r\n$6\r\nmaster\r\n"));
+
+    // 把复制连接踢掉 断开期间的写入要靠增量同步补上
+    let list = client.call(&["CLIENT", "LIST"]);
+    let replica_id = list
+        .lines()
+        .find(|line| line.contains("cmd=psync"))
+        .and_then(|line| line.split(' ').find_map(|field| field.strip_prefix("id=")))
+        .expect("主节点上找不到复制连接")
+        .to_string();
+    assert_eq!(client.call(&["CLIENT", "KILL", "ID", &replica_id]), ":1\r\n");
+    assert_eq!(client.call(&["SET", "during", "3"]), "+OK\r\n");
+    wait_until("增量同步", || reader.call(&["GET", "during"]) == "$1\r\n3\r\n");
+    assert!(master.log().contains("增量同步"), "重连之后应该是增量同步\n{}", master.log());
+    assert_eq!(reader.call(&["GET", "before"]), "$1\r\n1\r\n");
+}
+
+// 全量同步期间主节点一直在写 RPUSH 多执行一遍会多出元素 RENAME 漏掉或者多执行都会把值弄丢
+#[test]
+fn test_writes_during_full_sync() {
+    const PAIRS: usize = 8;
+    let master = Server::start("master-busy", &[]);
+    let mut client = master.connect();
+    // 快照大一点 同步的时间长一些
+    for batch in 0..50 {
+        let mut argv = vec!["MSET".to_string()];
+        for i in 0..1000 {
+            argv.push(format!("fill:{}:{}", batch, i));
+            argv.push("x".repeat(32));
+        }
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        assert_eq!(client.call(&argv), "+OK\r\n");
+    }
+    for pair in 0..PAIRS {
+        assert_eq!(client.call(&["SET", &format!("ren:{}:a", pair), &pair.to_string()]), "+OK\r\n");
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut writer = master.connect();
+    let writing = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut pushed = 0;
+            let mut renamed = [false; PAIRS];
+            while !stop.load(Ordering::SeqCst) {
+                pushed += 1;
+                assert_eq!(writer.call(&["RPUSH", "list", "v"]), format!(":{}\r\n", pushed));
+                for (pair, at_b) in renamed.iter_mut().enumerate() {
+                    let (from, to) = if *at_b { ("b", "a") } else { ("a", "b") };
+                    let from = format!("ren:{}:{}", pair, from);
+                    let to = format!("ren:{}:{}", pair, to);
+                    assert_eq!(writer.call(&["RENAME", &from, &to]), "+OK\r\n");
+                    *at_b = !*at_b;
+                }
+            }
+            (pushed, renamed)
+        })
+    };
+    let port = master.port.to_string();
+    let replica = Server::start("replica-busy", &["--replicaof", "127.0.0.1", &port]);
+    let mut reader = replica.connect();
+    wait_until("全量同步", || reader.call(&["GET", "fill:49:999"]).starts_with("$32"));
+    stop.store(true, Ordering::SeqCst);
+    let (pushed, renamed) = writing.join().unwrap();
+    assert_eq!(client.call(&["SET", "done", "1"]), "+OK\r\n");
+    wait_until("复制流追上", || reader.call(&["GET", "done"]) == "$1\r\n1\r\n");
+
+    for (pair, at_b) in renamed.into_iter().enumerate() {
+        let (present, absent) = if at_b { ("b", "a") } else { ("a", "b") };
+        let value = pair.to_string();
+        assert_eq!(
+            reader.call(&["GET", &format!("ren:{}:{}", pair, present)]),
+            format!("${}\r\n{}\r\n", value.len(), value)
+        );
+        assert_eq!(reader.call(&["GET", &format!("ren:{}:{}", pair, absent)]), "$-1\r\n");
+    }
+    // replica 上读不了列表长度 提升成主节点之后 RPUSH 的回复就是
+    assert_eq!(reader.call(&["REPLICAOF", "NO", "ONE"]), "+OK\r\n");
+    assert_eq!(reader.call(&["RPUSH", "list", "v"]), format!(":{}\r\n", pushed + 1));
+}